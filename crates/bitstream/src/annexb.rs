@@ -1,3 +1,5 @@
+use crate::nalu::{NalUnitType, NaluHeader};
+
 #[allow(dead_code)]
 pub fn split_annexb_nalus(data: &[u8]) -> Vec<&[u8]> {
     let mut nalus = Vec::new();
@@ -14,10 +16,10 @@ pub fn split_annexb_nalus(data: &[u8]) -> Vec<&[u8]> {
             continue;
         };
 
-        if let Some(start) = nalu_start {
-            if start < i {
-                nalus.push(&data[start..i]);
-            }
+        if let Some(start) = nalu_start
+            && start < i
+        {
+            nalus.push(&data[start..i]);
         }
 
         nalu_start = Some(i + start_code_len);
@@ -25,15 +27,78 @@ pub fn split_annexb_nalus(data: &[u8]) -> Vec<&[u8]> {
     }
 
     // Push the final NALU if any
-    if let Some(start) = nalu_start {
-        if start < data.len() {
-            nalus.push(&data[start..]);
-        }
+    if let Some(start) = nalu_start
+        && start < data.len()
+    {
+        nalus.push(&data[start..]);
     }
 
     nalus
 }
 
+/// Offset of the last start code in `data`, counting the zero_byte of a 4-byte start code. NALUs
+/// before it are complete, while the one after it may still be missing bytes.
+pub fn find_last_start_code(data: &[u8]) -> Option<usize> {
+    let i = data.windows(3).rposition(|window| window == [0, 0, 1])?;
+    Some(if i > 0 && data[i - 1] == 0 { i - 1 } else { i })
+}
+
+/// Splits an Annex B byte stream like `split_annexb_nalus`, pairing each NALU with the type in its
+/// header byte. A NALU whose header has the forbidden bit set yields an error in its place.
+pub fn classify_annexb_nalus(
+    data: &[u8],
+) -> impl Iterator<Item = anyhow::Result<(NalUnitType, &[u8])>> {
+    split_annexb_nalus(data)
+        .into_iter()
+        .map(|nalu| Ok((NaluHeader::new(nalu[0])?.kind(), nalu)))
+}
+
+/// Splits an Annex B byte stream that arrives in chunks of any size. Bytes after the last start
+/// code are held back until the next start code (or `finish`) shows where their NALU ends.
+#[derive(Debug, Clone, Default)]
+pub struct AnnexBSplitter {
+    pending: Vec<u8>,
+}
+
+impl AnnexBSplitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a chunk and returns the NALUs it completed, without their start codes
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Vec<u8>> {
+        self.pending.extend_from_slice(chunk);
+        let Some(end) = find_last_start_code(&self.pending) else {
+            return Vec::new();
+        };
+        let nalus = split_annexb_nalus(&self.pending[..end])
+            .into_iter()
+            .map(<[u8]>::to_vec)
+            .collect();
+        self.pending.drain(..end);
+        nalus
+    }
+
+    /// `push`, with each completed NALU paired with its parsed header. A NALU whose header has
+    /// the forbidden bit set yields an error in its place.
+    pub fn nalus_with_header(
+        &mut self,
+        chunk: &[u8],
+    ) -> impl Iterator<Item = anyhow::Result<(NaluHeader, Vec<u8>)>> + use<> {
+        self.push(chunk)
+            .into_iter()
+            .map(|nalu| Ok((NaluHeader::new(nalu[0])?, nalu)))
+    }
+
+    /// Returns the NALU still being held back, once the stream has ended
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        let pending = std::mem::take(&mut self.pending);
+        split_annexb_nalus(&pending)
+            .first()
+            .map(|nalu| nalu.to_vec())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -166,4 +231,99 @@ mod tests {
         assert_eq!(nalus.len(), 1);
         assert_eq!(nalus[0], &[0x65, 0x66, 0x67]);
     }
+
+    // Each NALU comes with its type, in stream order
+    #[test]
+    fn test_classify_mixed_stream() {
+        let data = &[
+            0x00, 0x00, 0x00, 0x01, 0x67, 0x42, // SPS
+            0x00, 0x00, 0x01, 0x68, 0xCE, // PPS
+            0x00, 0x00, 0x01, 0x65, 0x88, // IDR slice
+            0x00, 0x00, 0x01, 0x41, 0x9A, // non-IDR slice
+        ];
+
+        let kinds: Vec<_> = classify_annexb_nalus(data)
+            .map(|nalu| nalu.unwrap().0)
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                NalUnitType::Sps,
+                NalUnitType::Pps,
+                NalUnitType::IdrSlice,
+                NalUnitType::NonIdrSlice
+            ]
+        );
+
+        let (_, pps) = classify_annexb_nalus(data).nth(1).unwrap().unwrap();
+        assert_eq!(pps, &[0x68, 0xCE]);
+    }
+
+    // A forbidden bit is reported for its NALU without ending the iteration
+    #[test]
+    fn test_classify_forbidden_bit() {
+        let data = &[0x00, 0x00, 0x01, 0xE7, 0x00, 0x00, 0x01, 0x68, 0xCE];
+
+        let nalus: Vec<_> = classify_annexb_nalus(data).collect();
+        assert_eq!(nalus.len(), 2);
+        assert!(nalus[0].is_err());
+        assert_eq!(nalus[1].as_ref().unwrap().0, NalUnitType::Pps);
+    }
+
+    // The same NALUs come out however the stream is chunked, including through split start codes
+    #[test]
+    fn test_splitter_chunked_stream() {
+        let data = &[
+            0x00, 0x00, 0x00, 0x01, 0x67, 0x42, 0x00, // SPS
+            0x00, 0x00, 0x01, 0x68, 0xCE, // PPS
+            0x00, 0x00, 0x00, 0x01, 0x06, 0x05, // SEI
+            0x00, 0x00, 0x01, 0x65, 0x88, 0x84, // IDR slice
+        ];
+        let expected = split_annexb_nalus(data);
+
+        for chunk_size in 1..=data.len() {
+            let mut splitter = AnnexBSplitter::new();
+            let mut kinds = Vec::new();
+            let mut nalus = Vec::new();
+            for chunk in data.chunks(chunk_size) {
+                for nalu in splitter.nalus_with_header(chunk) {
+                    let (header, nalu) = nalu.unwrap();
+                    kinds.push(header.kind());
+                    nalus.push(nalu);
+                }
+            }
+            assert_eq!(
+                kinds,
+                [NalUnitType::Sps, NalUnitType::Pps, NalUnitType::Sei]
+            );
+
+            // The slice only ends with the stream
+            nalus.extend(splitter.finish());
+            assert_eq!(nalus, expected, "chunk size {}", chunk_size);
+            assert_eq!(splitter.finish(), None);
+        }
+    }
+
+    #[test]
+    fn test_splitter_forbidden_bit() {
+        let mut splitter = AnnexBSplitter::new();
+        let nalus: Vec<_> = splitter
+            .nalus_with_header(&[0x00, 0x00, 0x01, 0xE7, 0x00, 0x00, 0x01, 0x68, 0xCE])
+            .collect();
+        assert_eq!(nalus.len(), 1);
+        assert!(nalus[0].is_err());
+
+        let nalus: Vec<_> = splitter.nalus_with_header(&[0x00, 0x00, 0x01]).collect();
+        assert_eq!(nalus[0].as_ref().unwrap().0.kind(), NalUnitType::Pps);
+    }
+
+    #[test]
+    fn test_find_last_start_code() {
+        assert_eq!(find_last_start_code(&[0x67, 0x00, 0x00]), None);
+        assert_eq!(find_last_start_code(&[0x00, 0x00, 0x01, 0x67]), Some(0));
+        assert_eq!(
+            find_last_start_code(&[0x00, 0x00, 0x01, 0x67, 0x00, 0x00, 0x00, 0x01, 0x68]),
+            Some(4)
+        );
+    }
 }
@@ -1,13 +1,30 @@
+use crate::rbsp::ebsp_to_rbsp_cow;
 use anyhow::anyhow;
 
+/// Each parameter set in the AVCC header is preceded by a 16-bit size
+const NALU_SIZE_FIELD_LEN: usize = 2;
+
+/// The AVCC record's profile and level bytes next to the ones its first SPS carries, when they
+/// disagree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileLevelMismatch {
+    pub record_profile: u8,
+    pub record_level: u8,
+    pub sps_profile_idc: u8,
+    pub sps_level_idc: u8,
+}
+
+/// An AVCDecoderConfigurationRecord, as carried in the `avcC` box of MP4 files
 #[derive(Debug)]
 #[allow(dead_code)]
-struct AVCHeader<'input> {
+pub struct AVCHeader<'input> {
     version: u8,
     avc_profile: u8,
     avc_compatability: u8,
     avc_level: u8,
     nalu_length_size_minus_one: u8,
+    /// The 3 reserved bits above numOfSequenceParameterSets, which should all be set
+    sps_count_reserved: u8,
     sps: Vec<&'input [u8]>,
     pps: Vec<&'input [u8]>,
 }
@@ -39,20 +56,17 @@ impl<'input> AVCHeader<'input> {
         }
 
         let mut offset = 5;
+        let sps_count_reserved = data[offset] >> 5;
         let sps_count = data[offset] & 0b0001_1111; //number of SPS NALUs
-        if data.len() < (7 + sps_count) as usize {
-            return Err(anyhow!("AVCC header is below minimum size"));
-        }
-
         offset += 1;
+        // Every SPS takes at least its size field, and the PPS count byte follows them
+        Self::check_min_size(data, offset + sps_count as usize * NALU_SIZE_FIELD_LEN + 1)?;
         let sps = Self::parse_nalus(data, sps_count, &mut offset, "SPS")?;
 
+        Self::check_min_size(data, offset + 1)?;
         let pps_count = data[offset] & 0b0001_1111; //number of PPS NALUs
-        if data.len() < offset + 1 + pps_count as usize {
-            return Err(anyhow!("AVCC header is below minimum size"));
-        }
-
         offset += 1;
+        Self::check_min_size(data, offset + pps_count as usize * NALU_SIZE_FIELD_LEN)?;
         let pps = Self::parse_nalus(data, pps_count, &mut offset, "PPS")?;
 
         Ok(Self {
@@ -61,11 +75,78 @@ impl<'input> AVCHeader<'input> {
             avc_compatability,
             avc_level,
             nalu_length_size_minus_one,
+            sps_count_reserved,
             sps,
             pps,
         })
     }
 
+    /// `new`, but also failing on a record whose reserved bits above numOfSequenceParameterSets
+    /// aren't all set. `new` only reports those through `sps_count_reserved_bits_valid`, since
+    /// some muxers leave them zeroed and the count itself is unaffected.
+    pub fn new_strict(data: &'input [u8]) -> anyhow::Result<Self> {
+        let header = Self::new(data)?;
+        if !header.sps_count_reserved_bits_valid() {
+            return Err(anyhow!(
+                "Invalid reserved bits in AVCC SPS count byte: {:#010b}",
+                data[5]
+            ));
+        }
+        Ok(header)
+    }
+
+    /// Whether the 3 reserved bits above numOfSequenceParameterSets are `111`
+    pub fn sps_count_reserved_bits_valid(&self) -> bool {
+        self.sps_count_reserved == 0b111
+    }
+
+    /// The SPS NALUs of the record, borrowed from the input so they can be parsed in place
+    pub fn sps_nalus(&self) -> &[&'input [u8]] {
+        &self.sps
+    }
+
+    /// The PPS NALUs of the record, borrowed from the input
+    pub fn pps_nalus(&self) -> &[&'input [u8]] {
+        &self.pps
+    }
+
+    /// Compares `avc_profile` and `avc_level` with profile_idc and level_idc of the first SPS.
+    /// A mismatch is reported rather than failed on, since the SPS is what decoding follows. Errors
+    /// only when that SPS is too short to hold the fields.
+    pub fn validate(&self) -> anyhow::Result<Option<ProfileLevelMismatch>> {
+        let Some(sps) = self.sps.first() else {
+            return Ok(None);
+        };
+        // profile_idc, constraint flags and level_idc follow the NALU header byte
+        let rbsp = ebsp_to_rbsp_cow(sps.get(1..).unwrap_or_default());
+        let [sps_profile_idc, _, sps_level_idc, ..] = rbsp[..] else {
+            return Err(anyhow!(
+                "AVCC SPS is too short for profile_idc and level_idc"
+            ));
+        };
+
+        if (sps_profile_idc, sps_level_idc) == (self.avc_profile, self.avc_level) {
+            return Ok(None);
+        }
+        Ok(Some(ProfileLevelMismatch {
+            record_profile: self.avc_profile,
+            record_level: self.avc_level,
+            sps_profile_idc,
+            sps_level_idc,
+        }))
+    }
+
+    fn check_min_size(data: &[u8], min_size: usize) -> anyhow::Result<()> {
+        if data.len() < min_size {
+            return Err(anyhow!(
+                "AVCC header is below minimum size: {} bytes, expected at least {}",
+                data.len(),
+                min_size
+            ));
+        }
+        Ok(())
+    }
+
     /// Reads all NALUs from an AVCC formatted stream
     fn parse_nalus<'a>(
         data: &'a [u8],
@@ -218,6 +299,51 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_avcc_profile_level_match_sps() -> Result<()> {
+        let sps: &[u8] = &[0x67, 0x42, 0x00, 0x1E, 0xAB];
+        let header_bytes = build_avcc_header(1, 3, &[sps], &[&[0x68]]);
+        assert_eq!(AVCHeader::new(&header_bytes)?.validate()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_avcc_profile_level_mismatch() -> Result<()> {
+        // A High profile, level 4.0 SPS in a record claiming Baseline at level 3.0
+        let sps: &[u8] = &[0x67, 0x64, 0x00, 0x28, 0xAC];
+        let header_bytes = build_avcc_header(1, 3, &[sps], &[&[0x68]]);
+        let header = AVCHeader::new(&header_bytes)?;
+
+        assert_eq!(
+            header.validate()?,
+            Some(ProfileLevelMismatch {
+                record_profile: 0x42,
+                record_level: 0x1E,
+                sps_profile_idc: 0x64,
+                sps_level_idc: 0x28,
+            })
+        );
+
+        let truncated = build_avcc_header(1, 3, &[&[0x67, 0x64]], &[]);
+        assert!(AVCHeader::new(&truncated)?.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_avcc_sps_count_reserved_bits() -> Result<()> {
+        let mut header_bytes = build_avcc_header(1, 3, &[&[0xAA]], &[&[0xCC]]);
+        assert!(AVCHeader::new(&header_bytes)?.sps_count_reserved_bits_valid());
+        AVCHeader::new_strict(&header_bytes)?;
+
+        header_bytes[5] &= 0b0001_1111;
+        let parsed = AVCHeader::new(&header_bytes)?;
+        assert!(!parsed.sps_count_reserved_bits_valid());
+        assert_eq!(parsed.sps_nalus(), [&[0xAA][..]]);
+        let err = AVCHeader::new_strict(&header_bytes).unwrap_err();
+        assert!(err.to_string().contains("SPS count byte"));
+        Ok(())
+    }
+
     #[test]
     fn test_avcc_header_incorrect_version() {
         let header_bytes = build_avcc_header(2, 0, &[], &[]);
@@ -266,6 +392,53 @@ mod tests {
         assert!(err.to_string().contains("Not enough data for SPS payload"));
     }
 
+    #[test]
+    fn test_avcc_header_maximum_counts() -> Result<()> {
+        let sps_data: Vec<&[u8]> = vec![&[0x67]; 31];
+        let pps_data: Vec<&[u8]> = vec![&[0x68, 0xCE]; 31];
+
+        let header_bytes = build_avcc_header(1, 3, &sps_data, &pps_data);
+        let parsed = AVCHeader::new(&header_bytes)?;
+        assert_eq!(parsed.sps.len(), 31);
+        assert_eq!(parsed.pps.len(), 31);
+        assert!(parsed.pps.iter().all(|pps| *pps == [0x68, 0xCE]));
+
+        // Dropping the last byte truncates the final PPS payload
+        let err = AVCHeader::new(&header_bytes[..header_bytes.len() - 1]).unwrap_err();
+        assert!(err.to_string().contains("Not enough data for PPS payload"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_avcc_header_truncated_at_count_bytes() {
+        // Two empty SPS need four size bytes and the PPS count; one byte short of that
+        let mut header_bytes = build_avcc_header(1, 0, &[&[], &[]], &[]);
+        header_bytes.truncate(header_bytes.len() - 1);
+        let err = AVCHeader::new(&header_bytes).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("AVCC header is below minimum size")
+        );
+
+        // The PPS count byte is missing after a complete SPS
+        let mut header_bytes = build_avcc_header(1, 0, &[&[0x67, 0x42]], &[]);
+        header_bytes.truncate(header_bytes.len() - 1);
+        let err = AVCHeader::new(&header_bytes).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("AVCC header is below minimum size")
+        );
+
+        // A PPS count of one without its size field
+        let mut header_bytes = build_avcc_header(1, 0, &[], &[]);
+        header_bytes[6] = 0b11100001;
+        let err = AVCHeader::new(&header_bytes).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("AVCC header is below minimum size")
+        );
+    }
+
     /// Helper to build raw AVCC-like stream:
     fn build_avcc_stream(nalu_length_size: usize, nalus: &[&[u8]]) -> Vec<u8> {
         let mut out = Vec::new();
@@ -1,4 +1,63 @@
-use anyhow::{Result, anyhow};
+use core::fmt;
+
+/// Why a `BitReader` read failed. Only depends on `core`, so it works without `std`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitReaderError {
+    /// The data ends before the read does. `position` is the bit the read started at.
+    InsufficientBits { position: usize },
+    /// More bits to rewind than have been read
+    RewindTooFar,
+    /// An Exp-Golomb code with more than 31 leading zeros, or a value that doesn't fit
+    ExpGolombOverflow,
+    /// A u(v) field wider than 32 bits
+    FieldTooWide { bits: usize },
+    /// `skip_to` a bit before the cursor
+    SkipBackwards { position: usize, target: usize },
+    /// A bounded Exp-Golomb value outside its range
+    OutOfRange { value: i32, min: i32, max: i32 },
+    /// An unsigned Exp-Golomb value above its maximum
+    AboveMaximum { value: u32, max: u32 },
+    /// rbsp_trailing_bits that aren't a one bit and zero bits up to the end of the data.
+    /// `position` is where they were expected to start.
+    InvalidTrailingBits { position: usize },
+}
+
+impl fmt::Display for BitReaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitReaderError::InsufficientBits { position } => {
+                write!(f, "Not enough bits to read at bit {}", position)
+            }
+            BitReaderError::RewindTooFar => write!(f, "Too many bits to rewind backwards"),
+            BitReaderError::ExpGolombOverflow => write!(f, "Exp-Golomb code overflows"),
+            BitReaderError::FieldTooWide { bits } => {
+                write!(f, "u(v) fields are at most 32 bits, not {}", bits)
+            }
+            BitReaderError::SkipBackwards { position, target } => {
+                write!(
+                    f,
+                    "Cannot skip back from bit {} to bit {}",
+                    position, target
+                )
+            }
+            BitReaderError::OutOfRange { value, min, max } => write!(
+                f,
+                "Signed Exp-Golomb value {} is outside {}..={}",
+                value, min, max
+            ),
+            BitReaderError::AboveMaximum { value, max } => {
+                write!(f, "Exp-Golomb value {} is above {}", value, max)
+            }
+            BitReaderError::InvalidTrailingBits { position } => {
+                write!(f, "Invalid rbsp_trailing_bits at bit {}", position)
+            }
+        }
+    }
+}
+
+impl core::error::Error for BitReaderError {}
+
+type Result<T> = core::result::Result<T, BitReaderError>;
 
 #[allow(dead_code)]
 #[derive(Clone)]
@@ -27,6 +86,21 @@ impl<'input> BitReader<'input> {
         Ok(val)
     }
 
+    /// u(v): a field whose width `v` comes from earlier syntax elements. A width of 0 reads
+    /// nothing and gives 0.
+    pub fn read_uv(&mut self, v: usize) -> Result<u32> {
+        match v {
+            0 => Ok(0),
+            1..=32 => self.read(v),
+            bits => Err(BitReaderError::FieldTooWide { bits }),
+        }
+    }
+
+    /// Reads a single bit as a flag
+    pub fn read_flag(&mut self) -> Result<bool> {
+        Ok(self.read(1)? == 1)
+    }
+
     /// Doesn't change internal position, but allows a read of N bits ahead.
     /// TODO: We can make this much more efficient later on at the optimization stage.
     pub fn peek(&self, n: usize) -> Result<u32> {
@@ -34,9 +108,11 @@ impl<'input> BitReader<'input> {
         let mut bit_offset: usize = self.bit_offset as usize;
         let mut read_out = 0u32;
         let mut bits_read = 0;
-        let bits_remaining = (self.byte_buf.len() - byte_index) * 8 + (7 - bit_offset);
+        let bits_remaining = (self.byte_buf.len() - byte_index) * 8 - (7 - bit_offset);
         if bits_remaining < n {
-            return Err(anyhow!("Not enough space to read!"));
+            return Err(BitReaderError::InsufficientBits {
+                position: self.position(),
+            });
         }
 
         while bits_read < n {
@@ -65,11 +141,24 @@ impl<'input> BitReader<'input> {
         Ok(read_out)
     }
 
+    /// `peek` for an `n`-bit two's complement field, sign-extended from its top bit. A width of
+    /// 0 gives 0.
+    pub fn peek_signed(&self, n: usize) -> Result<i32> {
+        match n {
+            0 => Ok(0),
+            1..=32 => {
+                let shift = 32 - n as u32;
+                Ok(((self.peek(n)? << shift) as i32) >> shift)
+            }
+            bits => Err(BitReaderError::FieldTooWide { bits }),
+        }
+    }
+
     /// Decrements the internal bit/byte index. Reads N bits backwards.
     pub fn rewind(&mut self, n: usize) -> Result<()> {
         let prior_bits = self.byte_index * 8 + (7 - self.bit_offset as usize);
         if prior_bits < n {
-            return Err(anyhow!("Too many bits to rewind backwards"));
+            return Err(BitReaderError::RewindTooFar);
         }
 
         let new_global_index = prior_bits - n;
@@ -84,18 +173,120 @@ impl<'input> BitReader<'input> {
         self.byte_index * 8 + (7 - self.bit_offset as usize)
     }
 
-    /// Unsigned Exp-Golomb
-    pub fn read_ue(&mut self) -> Result<u32> {
-        let mut leading_zero_bits = 0;
+    /// Moves the cursor forward to the absolute bit position `target_bit`, such as the end of a
+    /// payload whose size is known
+    pub fn skip_to(&mut self, target_bit: usize) -> Result<()> {
+        let position = self.position();
+        if target_bit < position {
+            return Err(BitReaderError::SkipBackwards {
+                position,
+                target: target_bit,
+            });
+        }
+        self.advance(target_bit - position)
+    }
+
+    /// Runs a speculative multi-step read, putting the cursor back where it was if `f` fails
+    pub fn try_read<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let (byte_index, bit_offset) = (self.byte_index, self.bit_offset);
+        let result = f(self);
+        if result.is_err() {
+            self.byte_index = byte_index;
+            self.bit_offset = bit_offset;
+        }
+        result
+    }
 
-        // Count leading zeros
-        while self.read(1)? == 0 {
-            leading_zero_bits += 1;
-            if leading_zero_bits > 31 {
-                return Err(anyhow!("Too many leading zeros in Exp-Golomb"));
+    /// Reads rbsp_trailing_bits (7.3.2.11): the rbsp_stop_one_bit, then zero bits up to the byte
+    /// boundary, which must also be the end of the data
+    pub fn read_rbsp_trailing_bits(&mut self) -> Result<()> {
+        let position = self.position();
+        let error = BitReaderError::InvalidTrailingBits { position };
+        if !self.read_flag().map_err(|_| error)? {
+            return Err(error);
+        }
+        while !self.byte_aligned() {
+            if self.read_flag()? {
+                return Err(error);
             }
         }
+        if self.has_bits(1) {
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Returns the bytes after the cursor and moves it to the end of the data. A partly read
+    /// byte is skipped, so the result starts at the next byte boundary.
+    pub fn drain_to_end(&mut self) -> Vec<u8> {
+        let start = if self.byte_aligned() {
+            self.byte_index
+        } else {
+            self.byte_index + 1
+        };
+        let rest = self.byte_buf.get(start..).unwrap_or_default().to_vec();
+        self.byte_index = self.byte_buf.len();
+        self.bit_offset = 7;
+        rest
+    }
 
+    /// Whether at least `n` more bits can be read
+    pub fn has_bits(&self, n: usize) -> bool {
+        self.byte_buf.len() * 8 - self.position() >= n
+    }
+
+    /// Whether the cursor sits on a byte boundary
+    pub fn byte_aligned(&self) -> bool {
+        self.bit_offset == 7
+    }
+
+    /// Implemented as in 7.2 more_rbsp_data(): true while there is data before the rbsp_stop_one_bit.
+    /// The stop bit is the last set bit in the buffer.
+    pub fn more_rbsp_data(&self) -> bool {
+        let Some(last_byte) = self.byte_buf.iter().rposition(|&b| b != 0) else {
+            return false;
+        };
+        let stop_bit = last_byte * 8 + (7 - self.byte_buf[last_byte].trailing_zeros() as usize);
+
+        self.position() < stop_bit
+    }
+
+    /// Counts the zero bits up to the next one bit and moves the cursor past that one, scanning a
+    /// byte at a time. The cursor doesn't move if no one bit follows.
+    pub fn count_leading_zeros(&mut self) -> Result<usize> {
+        let mut zeros = 0;
+        let mut byte_index = self.byte_index;
+        let mut bits_left = self.bit_offset as u32 + 1;
+
+        while let Some(&byte) = self.byte_buf.get(byte_index) {
+            // Only the bits at and after the cursor count
+            let remaining = byte as u32 & ((1 << bits_left) - 1);
+            if remaining == 0 {
+                zeros += bits_left as usize;
+                byte_index += 1;
+                bits_left = 8;
+                continue;
+            }
+
+            let lz = remaining.leading_zeros() - (32 - bits_left);
+            zeros += lz as usize;
+            let position = byte_index * 8 + (8 - bits_left + lz) as usize + 1;
+            self.byte_index = position / 8;
+            self.bit_offset = 7 - (position % 8) as u8;
+            return Ok(zeros);
+        }
+
+        Err(BitReaderError::InsufficientBits {
+            position: self.position(),
+        })
+    }
+
+    /// Unsigned Exp-Golomb
+    pub fn read_ue(&mut self) -> Result<u32> {
+        let leading_zero_bits = self.count_leading_zeros()?;
+        if leading_zero_bits > 31 {
+            return Err(BitReaderError::ExpGolombOverflow);
+        }
         if leading_zero_bits == 0 {
             return Ok(0);
         }
@@ -105,15 +296,33 @@ impl<'input> BitReader<'input> {
         Ok((1 << leading_zero_bits) - 1 + suffix)
     }
 
-    /// Same encoding as read_ue, but maps unsigned to signed integers
+    /// Same encoding as read_ue, but maps unsigned to signed integers (9.1.1)
     pub fn read_se(&mut self) -> Result<i32> {
-        let ue_val = self.read_ue()?;
+        let ue_val = self.read_ue()? as i64;
         let signed_val = if ue_val % 2 == 0 {
-            -((ue_val / 2) as i32)
+            -(ue_val / 2)
         } else {
-            ((ue_val + 1) / 2) as i32
+            (ue_val + 1) / 2
         };
-        Ok(signed_val)
+        i32::try_from(signed_val).map_err(|_| BitReaderError::ExpGolombOverflow)
+    }
+
+    /// Signed Exp-Golomb, rejecting values outside `min..=max`
+    pub fn read_se_bounded(&mut self, min: i32, max: i32) -> Result<i32> {
+        let value = self.read_se()?;
+        if !(min..=max).contains(&value) {
+            return Err(BitReaderError::OutOfRange { value, min, max });
+        }
+        Ok(value)
+    }
+
+    /// ue(v) with an upper bound from the semantics of the syntax element
+    pub fn read_ue_max(&mut self, max: u32) -> Result<u32> {
+        let value = self.read_ue()?;
+        if value > max {
+            return Err(BitReaderError::AboveMaximum { value, max });
+        }
+        Ok(value)
     }
 
     /// Move the cursor forward by n bits
@@ -122,7 +331,9 @@ impl<'input> BitReader<'input> {
         let global_bit_index = self.byte_index * 8 + (7 - self.bit_offset as usize);
 
         if global_bit_index + n > total_bits {
-            return Err(anyhow!("Not enough bits to advance!"));
+            return Err(BitReaderError::InsufficientBits {
+                position: global_bit_index,
+            });
         }
 
         let new_global_bit_index = global_bit_index + n;
@@ -186,6 +397,28 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_peek_signed() -> anyhow::Result<()> {
+        let data = &[0b0111_1000, 0b1000_0000, 0xff, 0xff, 0xff, 0xff];
+        let mut reader = BitReader::from_bytes(data);
+
+        assert_eq!(reader.peek_signed(4)?, 7);
+        assert_eq!(reader.peek_signed(0)?, 0);
+        assert_eq!(reader.position(), 0);
+        reader.skip_to(4)?;
+        assert_eq!(reader.peek_signed(4)?, -8);
+        assert_eq!(reader.peek_signed(1)?, -1);
+        assert_eq!(reader.position(), 4);
+        reader.skip_to(16)?;
+        assert_eq!(reader.peek_signed(32)?, -1);
+        assert_eq!(
+            reader.peek_signed(33),
+            Err(BitReaderError::FieldTooWide { bits: 33 })
+        );
+        assert_eq!(reader.position(), 16);
+        Ok(())
+    }
+
     #[test]
     fn test_rewind_functionality() -> anyhow::Result<()> {
         let data = &[0b11110000];
@@ -219,6 +452,48 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_more_rbsp_data() -> anyhow::Result<()> {
+        // Three payload bits (101), then the stop bit and alignment zeros
+        let data = &[0b1011_0000];
+        let mut reader = BitReader::from_bytes(data);
+        assert!(reader.more_rbsp_data());
+        reader.read(3)?;
+        assert!(!reader.more_rbsp_data());
+
+        // The stop bit can live in a later byte than the payload
+        let data = &[0b1100_0000, 0b1000_0000];
+        let mut reader = BitReader::from_bytes(data);
+        reader.read(8)?;
+        assert!(!reader.more_rbsp_data());
+
+        assert!(!BitReader::from_bytes(&[0, 0]).more_rbsp_data());
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_leading_zeros() -> anyhow::Result<()> {
+        // 1, then 0001, then 21 zeros running across two whole bytes before their one
+        let data = [0b1000_1000, 0b0000_0000, 0b0000_0000, 0b0010_0000];
+        let mut reader = BitReader::from_bytes(&data);
+
+        assert_eq!(reader.count_leading_zeros()?, 0);
+        assert_eq!(reader.position(), 1);
+        assert_eq!(reader.count_leading_zeros()?, 3);
+        assert_eq!(reader.position(), 5);
+        assert_eq!(reader.count_leading_zeros()?, 21);
+        assert_eq!(reader.position(), 27);
+
+        // 15 zeros from a byte boundary, then nothing but zeros
+        let data = [0b0000_0000, 0b0000_0001, 0b0000_0000];
+        let mut reader = BitReader::from_bytes(&data);
+        assert_eq!(reader.count_leading_zeros()?, 15);
+        assert_eq!(reader.position(), 16);
+        assert!(reader.count_leading_zeros().is_err());
+        assert_eq!(reader.position(), 16);
+        Ok(())
+    }
+
     #[test]
     fn test_read_ue_complex() -> anyhow::Result<()> {
         // Encode 10 in UE:
@@ -268,6 +543,155 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_read_se_bounded() -> anyhow::Result<()> {
+        // "00101" → UE4 → SE -2, twice
+        let mut reader = BitReader::from_bytes(&[0b00101001, 0b01000000]);
+        assert_eq!(reader.read_se_bounded(-12, 12)?, -2);
+        assert_eq!(reader.read_se_bounded(-2, 0)?, -2);
+
+        // "00100" → UE3 → SE 2
+        let mut reader = BitReader::from_bytes(&[0b00100000]);
+        assert!(reader.read_se_bounded(-2, 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_ue_max() -> anyhow::Result<()> {
+        // "00100" → 3, "011" → 2
+        let mut reader = BitReader::from_bytes(&[0b00100011]);
+        assert_eq!(reader.read_ue_max(3)?, 3);
+        assert_eq!(
+            reader.read_ue_max(1),
+            Err(BitReaderError::AboveMaximum { value: 2, max: 1 })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_se_at_the_largest_code() -> anyhow::Result<()> {
+        // 31 leading zeros with an all-ones suffix is ue 2^32 - 2, the largest ue(v) this reader
+        // accepts. Its signed mapping is computed in i64 so it can't wrap on the way to i32.
+        let mut reader = BitReader::from_bytes(&[0, 0, 0, 1, 0xFF, 0xFF, 0xFF, 0xFE]);
+        assert_eq!(reader.read_se()?, -(i32::MAX));
+
+        // ue 2^32 - 3 maps to the largest positive value
+        let mut reader = BitReader::from_bytes(&[0, 0, 0, 1, 0xFF, 0xFF, 0xFF, 0xFC]);
+        assert_eq!(reader.read_se()?, i32::MAX);
+        assert!(
+            BitReader::from_bytes(&[0, 0, 0, 1, 0xFF, 0xFF, 0xFF, 0xFC])
+                .read_se_bounded(-26, 25)
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_to() -> anyhow::Result<()> {
+        let mut reader = BitReader::from_bytes(&[0xFF, 0x0F, 0xA5]);
+        reader.read(3)?;
+        reader.skip_to(12)?;
+        assert_eq!(reader.position(), 12);
+        assert_eq!(reader.read(4)?, 0xF);
+
+        reader.skip_to(16)?;
+        assert_eq!(reader.read(8)?, 0xA5);
+
+        assert!(reader.skip_to(8).is_err(), "Skipping backwards is an error");
+        assert!(reader.skip_to(25).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_read_restores_the_cursor() -> anyhow::Result<()> {
+        let mut reader = BitReader::from_bytes(&[0b1010_0000, 0xFF]);
+        reader.read(1)?;
+
+        let result = reader.try_read(|r| {
+            let first = r.read(4)?;
+            let second = r.read(16)?;
+            Ok((first, second))
+        });
+        assert!(result.is_err());
+        assert_eq!(reader.position(), 1);
+
+        assert_eq!(reader.try_read(|r| r.read(3))?, 0b010);
+        assert_eq!(reader.position(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_uv() -> anyhow::Result<()> {
+        let mut reader = BitReader::from_bytes(&[0b1011_0110]);
+        assert_eq!(reader.read_uv(0)?, 0);
+        assert_eq!(reader.position(), 0);
+        assert_eq!(reader.read_uv(5)?, 0b10110);
+        assert_eq!(reader.position(), 5);
+        assert!(reader.read_uv(4).is_err());
+        assert!(reader.read_uv(33).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_rbsp_trailing_bits() -> anyhow::Result<()> {
+        // Three payload bits, the stop bit and four alignment zeros
+        let mut reader = BitReader::from_bytes(&[0b1011_0000]);
+        reader.read(3)?;
+        reader.read_rbsp_trailing_bits()?;
+        assert_eq!(reader.position(), 8);
+
+        // A full byte of trailing bits after aligned data
+        let mut reader = BitReader::from_bytes(&[0xFF, 0x80]);
+        reader.read(8)?;
+        reader.read_rbsp_trailing_bits()?;
+
+        let invalid = |data: &[u8], payload_bits: usize| {
+            let mut reader = BitReader::from_bytes(data);
+            reader.read(payload_bits).unwrap();
+            reader.read_rbsp_trailing_bits()
+        };
+        let error = Err(BitReaderError::InvalidTrailingBits { position: 3 });
+        // No stop bit, a one among the alignment bits, data after them, and no room at all
+        assert_eq!(invalid(&[0b1010_0000], 3), error);
+        assert_eq!(invalid(&[0b1011_0100], 3), error);
+        assert_eq!(invalid(&[0b1011_0000, 0x80], 3), error);
+        assert_eq!(
+            invalid(&[0xFF], 8),
+            Err(BitReaderError::InvalidTrailingBits { position: 8 })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_drain_to_end() -> anyhow::Result<()> {
+        let data = [0xA5, 0x12, 0x34, 0x56];
+        let mut reader = BitReader::from_bytes(&data);
+        reader.read(8)?;
+        assert_eq!(reader.drain_to_end(), &data[1..]);
+        assert!(!reader.has_bits(1));
+        assert!(reader.drain_to_end().is_empty());
+
+        // The rest of a partly read byte is dropped
+        let mut reader = BitReader::from_bytes(&data);
+        reader.read(3)?;
+        reader.read_flag()?;
+        assert_eq!(reader.drain_to_end(), &data[1..]);
+        assert_eq!(reader.position(), 32);
+        Ok(())
+    }
+
+    #[test]
+    fn test_has_bits() -> anyhow::Result<()> {
+        let mut reader = BitReader::from_bytes(&[0xFF, 0x00]);
+        assert!(reader.has_bits(16));
+        assert!(!reader.has_bits(17));
+
+        reader.read(3)?;
+        assert!(reader.has_bits(13));
+        assert!(!reader.has_bits(14));
+        Ok(())
+    }
+
     #[test]
     fn test_error_on_insufficient_bits() {
         let data = &[0b00000000]; // 8 bits (so reading 9 should error out)
@@ -275,6 +699,15 @@ mod tests {
         assert!(reader.read(9).is_err());
     }
 
+    #[test]
+    fn test_error_on_insufficient_bits_mid_byte() {
+        let data = &[0b11110000];
+        let mut reader = BitReader::from_bytes(data);
+        reader.read(4).unwrap();
+        assert!(reader.peek(5).is_err());
+        assert_eq!(reader.read(4).unwrap(), 0);
+    }
+
     #[test]
     fn test_error_on_rewind_too_far() {
         let data = &[0b11110000];
@@ -284,6 +717,65 @@ mod tests {
         assert!(reader.rewind(8).is_err()); // Shouldn't be allowed to rewind backwards 8 bits over 4 read so far
     }
 
+    #[test]
+    fn test_error_variants_and_positions() {
+        let mut reader = BitReader::from_bytes(&[0b1111_0000]);
+        reader.read(3).unwrap();
+        assert_eq!(
+            reader.read(6),
+            Err(BitReaderError::InsufficientBits { position: 3 })
+        );
+        assert_eq!(
+            reader.position(),
+            3,
+            "A failed read doesn't move the cursor"
+        );
+        assert_eq!(reader.rewind(4), Err(BitReaderError::RewindTooFar));
+        assert_eq!(
+            reader.skip_to(9),
+            Err(BitReaderError::InsufficientBits { position: 3 })
+        );
+        assert_eq!(
+            reader.skip_to(2),
+            Err(BitReaderError::SkipBackwards {
+                position: 3,
+                target: 2
+            })
+        );
+        assert_eq!(
+            reader.read_uv(33),
+            Err(BitReaderError::FieldTooWide { bits: 33 })
+        );
+
+        // Four zero bits without a one bit after them
+        let mut reader = BitReader::from_bytes(&[0b1111_0000]);
+        reader.read(4).unwrap();
+        assert_eq!(
+            reader.read_ue(),
+            Err(BitReaderError::InsufficientBits { position: 4 })
+        );
+
+        // An Exp-Golomb prefix of 32 zeros, then its suffix cut short
+        let mut reader = BitReader::from_bytes(&[0, 0, 0, 0, 0x80]);
+        assert_eq!(reader.read_ue(), Err(BitReaderError::ExpGolombOverflow));
+        let mut reader = BitReader::from_bytes(&[0, 0, 0x80]);
+        assert_eq!(
+            reader.read_ue(),
+            Err(BitReaderError::InsufficientBits { position: 17 })
+        );
+
+        // "00100" → SE 2
+        let mut reader = BitReader::from_bytes(&[0b0010_0000]);
+        assert_eq!(
+            reader.read_se_bounded(-1, 1),
+            Err(BitReaderError::OutOfRange {
+                value: 2,
+                min: -1,
+                max: 1
+            })
+        );
+    }
+
     #[test]
     fn test_multiple_sequential_reads() -> anyhow::Result<()> {
         let data = &[0b11001100, 0b10101010, 0b11110000];
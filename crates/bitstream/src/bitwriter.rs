@@ -0,0 +1,130 @@
+/// The write-side counterpart of `BitReader`. Bits are appended MSB first.
+#[derive(Debug, Clone, Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    bit_len: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends the low `n` bits of `value`
+    pub fn write(&mut self, value: u32, n: usize) {
+        debug_assert!(n <= 32, "Cannot write more than 32 bits at once");
+        for i in (0..n).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    pub fn write_flag(&mut self, flag: bool) {
+        self.write_bit(flag);
+    }
+
+    /// Unsigned Exp-Golomb
+    pub fn write_ue(&mut self, value: u32) {
+        let code_num = value as u64 + 1;
+        let len = 64 - code_num.leading_zeros() as usize;
+        for _ in 0..len - 1 {
+            self.write_bit(false);
+        }
+        for i in (0..len).rev() {
+            self.write_bit((code_num >> i) & 1 == 1);
+        }
+    }
+
+    /// Signed Exp-Golomb, mapped as in 9.1.1
+    pub fn write_se(&mut self, value: i32) {
+        let mapped = if value > 0 {
+            (value as u32) * 2 - 1
+        } else {
+            value.unsigned_abs() * 2
+        };
+        self.write_ue(mapped);
+    }
+
+    /// Writes the stop bit followed by zero bits up to the next byte boundary
+    pub fn write_rbsp_trailing_bits(&mut self) {
+        self.write_bit(true);
+        while !self.byte_aligned() {
+            self.write_bit(false);
+        }
+    }
+
+    /// Number of bits written so far
+    pub fn position(&self) -> usize {
+        self.bit_len
+    }
+
+    pub fn byte_aligned(&self) -> bool {
+        self.bit_len.is_multiple_of(8)
+    }
+
+    /// Returns the written bytes. A partial final byte is zero padded.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if self.bit_len.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        if bit {
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= 0x80 >> (self.bit_len % 8);
+        }
+        self.bit_len += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitreader::BitReader;
+
+    #[test]
+    fn test_write_fixed_bits() {
+        let mut writer = BitWriter::new();
+        writer.write(0b101, 3);
+        writer.write(0b11110, 5);
+        writer.write(0xA, 4);
+        assert_eq!(writer.position(), 12);
+        assert_eq!(writer.into_bytes(), &[0b1011_1110, 0b1010_0000]);
+    }
+
+    #[test]
+    fn test_exp_golomb_round_trip() -> anyhow::Result<()> {
+        let mut writer = BitWriter::new();
+        for v in [0, 1, 2, 3, 10, 255, 65535] {
+            writer.write_ue(v);
+        }
+        for v in [0, 1, -1, 2, -2, 1000, -1000] {
+            writer.write_se(v);
+        }
+        let bytes = writer.into_bytes();
+
+        let mut reader = BitReader::from_bytes(&bytes);
+        for v in [0, 1, 2, 3, 10, 255, 65535] {
+            assert_eq!(reader.read_ue()?, v);
+        }
+        for v in [0, 1, -1, 2, -2, 1000, -1000] {
+            assert_eq!(reader.read_se()?, v);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_trailing_bits() {
+        let mut writer = BitWriter::new();
+        writer.write(0b11, 2);
+        writer.write_rbsp_trailing_bits();
+        assert!(writer.byte_aligned());
+        assert_eq!(writer.into_bytes(), &[0b1110_0000]);
+
+        let mut writer = BitWriter::new();
+        writer.write(0xFF, 8);
+        writer.write_rbsp_trailing_bits();
+        assert_eq!(writer.into_bytes(), &[0xFF, 0x80]);
+    }
+}
@@ -1,4 +1,10 @@
-mod annexb;
-mod avcc;
-mod bitreader;
-mod nalu;
+pub mod annexb;
+pub mod avcc;
+pub mod bitreader;
+pub mod bitwriter;
+pub mod nalu;
+pub mod rbsp;
+
+pub use bitreader::{BitReader, BitReaderError};
+pub use bitwriter::BitWriter;
+pub use nalu::{NalUnitType, NaluHeader};
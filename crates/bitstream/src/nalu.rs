@@ -1,16 +1,83 @@
 use anyhow::anyhow;
 
+/// NAL unit type codes, as in Table 7-1 in Rec. ITU-T H.264 (04/2013)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NalUnitType {
+    Unspecified(u8),
+    NonIdrSlice,
+    SliceDataPartitionA,
+    SliceDataPartitionB,
+    SliceDataPartitionC,
+    IdrSlice,
+    Sei,
+    Sps,
+    Pps,
+    AccessUnitDelimiter,
+    EndOfSequence,
+    EndOfStream,
+    FillerData,
+    SpsExtension,
+    PrefixNalu,
+    SubsetSps,
+    DepthParameterSet,
+    Reserved(u8),
+    AuxiliarySlice,
+    SliceExtension,
+    SliceExtensionDepth,
+}
+
+impl From<u8> for NalUnitType {
+    fn from(nal_unit_type: u8) -> Self {
+        match nal_unit_type {
+            1 => NalUnitType::NonIdrSlice,
+            2 => NalUnitType::SliceDataPartitionA,
+            3 => NalUnitType::SliceDataPartitionB,
+            4 => NalUnitType::SliceDataPartitionC,
+            5 => NalUnitType::IdrSlice,
+            6 => NalUnitType::Sei,
+            7 => NalUnitType::Sps,
+            8 => NalUnitType::Pps,
+            9 => NalUnitType::AccessUnitDelimiter,
+            10 => NalUnitType::EndOfSequence,
+            11 => NalUnitType::EndOfStream,
+            12 => NalUnitType::FillerData,
+            13 => NalUnitType::SpsExtension,
+            14 => NalUnitType::PrefixNalu,
+            15 => NalUnitType::SubsetSps,
+            16 => NalUnitType::DepthParameterSet,
+            17 | 18 | 22 | 23 => NalUnitType::Reserved(nal_unit_type),
+            19 => NalUnitType::AuxiliarySlice,
+            20 => NalUnitType::SliceExtension,
+            21 => NalUnitType::SliceExtensionDepth,
+            _ => NalUnitType::Unspecified(nal_unit_type),
+        }
+    }
+}
+
+impl NalUnitType {
+    /// Whether the NALU carries slice data (VCL), per Table 7-1's base-layer classification
+    pub fn is_vcl(self) -> bool {
+        matches!(
+            self,
+            NalUnitType::NonIdrSlice
+                | NalUnitType::SliceDataPartitionA
+                | NalUnitType::SliceDataPartitionB
+                | NalUnitType::SliceDataPartitionC
+                | NalUnitType::IdrSlice
+        )
+    }
+}
+
 /// Implemented as in 7.3.1 NAL unit syntax in Rec. ITU-T H.264 (04/2013)
 /// Struct for holding NALU header information from a parsed byte
-#[allow(dead_code)]
-struct NaluHeader {
-    forbidden_zero_bit: u8,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NaluHeader {
+    pub forbidden_zero_bit: u8,
     /// Must be 0 to be considered valid
-    nal_ref_idc: u8,
-    nal_unit_type: u8,
+    pub nal_ref_idc: u8,
+    pub nal_unit_type: u8,
 }
 
-#[allow(dead_code)]
 impl NaluHeader {
     /// Creates a new NaluHeader struct. Parses the byte for the forbidden bit, ref idc and unit type.
     pub fn new(byte: u8) -> anyhow::Result<NaluHeader> {
@@ -28,6 +95,11 @@ impl NaluHeader {
             nal_unit_type,
         })
     }
+
+    /// The typed form of `nal_unit_type`
+    pub fn kind(&self) -> NalUnitType {
+        NalUnitType::from(self.nal_unit_type)
+    }
 }
 
 #[cfg(test)]
@@ -75,4 +147,20 @@ mod tests {
         assert_eq!(header_7f.nal_ref_idc, 3);
         assert_eq!(header_7f.nal_unit_type, 31);
     }
+
+    #[test]
+    fn test_nal_unit_type_kinds() {
+        assert_eq!(NaluHeader::new(0x67).unwrap().kind(), NalUnitType::Sps);
+        assert_eq!(NaluHeader::new(0x68).unwrap().kind(), NalUnitType::Pps);
+        assert_eq!(NaluHeader::new(0x65).unwrap().kind(), NalUnitType::IdrSlice);
+        assert_eq!(
+            NaluHeader::new(0x6F).unwrap().kind(),
+            NalUnitType::SubsetSps
+        );
+        assert_eq!(NalUnitType::from(17), NalUnitType::Reserved(17));
+        assert_eq!(NalUnitType::from(30), NalUnitType::Unspecified(30));
+
+        assert!(NalUnitType::NonIdrSlice.is_vcl());
+        assert!(!NalUnitType::Sei.is_vcl());
+    }
 }
@@ -0,0 +1,154 @@
+use std::borrow::Cow;
+
+/// Strips emulation prevention bytes (7.4.1), turning a NALU payload (EBSP) into its RBSP.
+/// Any `0x03` that follows two consecutive zero bytes is dropped.
+pub fn ebsp_to_rbsp(ebsp: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(ebsp.len());
+    let mut zero_count = 0;
+
+    for &byte in ebsp {
+        if zero_count >= 2 && byte == 0x03 {
+            zero_count = 0;
+            continue;
+        }
+
+        zero_count = if byte == 0 { zero_count + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+
+    rbsp
+}
+
+/// `ebsp_to_rbsp` that borrows the payload when it holds no emulation prevention bytes, which is
+/// the case for most NALUs, and only allocates when there are bytes to strip
+pub fn ebsp_to_rbsp_cow(ebsp: &[u8]) -> Cow<'_, [u8]> {
+    if count_emulation_bytes(ebsp) == 0 {
+        Cow::Borrowed(ebsp)
+    } else {
+        Cow::Owned(ebsp_to_rbsp(ebsp))
+    }
+}
+
+/// Counts the emulation prevention bytes `ebsp_to_rbsp` would strip, so converters can size
+/// their output exactly: `ebsp.len() - count_emulation_bytes(ebsp)` is the RBSP length.
+pub fn count_emulation_bytes(ebsp: &[u8]) -> usize {
+    let mut count = 0;
+    let mut zero_count = 0;
+
+    for &byte in ebsp {
+        if zero_count >= 2 && byte == 0x03 {
+            count += 1;
+            zero_count = 0;
+            continue;
+        }
+
+        zero_count = if byte == 0 { zero_count + 1 } else { 0 };
+    }
+
+    count
+}
+
+/// The inverse of `ebsp_to_rbsp`. Inserts an emulation prevention byte wherever two zero bytes
+/// would otherwise be followed by a byte in `0x00..=0x03`, or would end the payload.
+pub fn rbsp_to_ebsp(rbsp: &[u8]) -> Vec<u8> {
+    let mut ebsp = Vec::with_capacity(rbsp.len() + rbsp.len() / 64);
+    let mut zero_count = 0;
+
+    for &byte in rbsp {
+        if zero_count >= 2 && byte <= 0x03 {
+            ebsp.push(0x03);
+            zero_count = 0;
+        }
+
+        zero_count = if byte == 0 { zero_count + 1 } else { 0 };
+        ebsp.push(byte);
+    }
+
+    if zero_count >= 2 {
+        ebsp.push(0x03);
+    }
+
+    ebsp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_emulation_bytes() {
+        let data = &[0x67, 0x42, 0x00, 0x1E, 0xFF];
+        assert_eq!(ebsp_to_rbsp(data), data);
+    }
+
+    #[test]
+    fn test_strip_emulation_bytes() {
+        let ebsp = &[0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03];
+        assert_eq!(
+            ebsp_to_rbsp(ebsp),
+            &[0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_three_after_single_zero_is_kept() {
+        let ebsp = &[0x00, 0x03, 0x00, 0x00, 0x04];
+        assert_eq!(ebsp_to_rbsp(ebsp), ebsp);
+    }
+
+    #[test]
+    fn test_count_no_emulation_bytes() {
+        assert_eq!(count_emulation_bytes(&[]), 0);
+        assert_eq!(count_emulation_bytes(&[0x00, 0x03, 0x00, 0x01, 0x03]), 0);
+    }
+
+    #[test]
+    fn test_count_single_emulation_byte() {
+        let ebsp = &[0x65, 0x00, 0x00, 0x03, 0x01, 0x88];
+        assert_eq!(count_emulation_bytes(ebsp), 1);
+        assert_eq!(
+            ebsp.len() - count_emulation_bytes(ebsp),
+            ebsp_to_rbsp(ebsp).len()
+        );
+    }
+
+    #[test]
+    fn test_count_several_emulation_bytes() {
+        // Back-to-back sequences, where the byte after an emulation byte starts a new zero run
+        let ebsp = &[
+            0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x02, 0xFF, 0x00, 0x00, 0x03,
+        ];
+        assert_eq!(count_emulation_bytes(ebsp), 4);
+        assert_eq!(
+            ebsp.len() - count_emulation_bytes(ebsp),
+            ebsp_to_rbsp(ebsp).len()
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let rbsp = &[
+            0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x02, 0x10, 0x00, 0x00,
+        ];
+        let ebsp = rbsp_to_ebsp(rbsp);
+        assert_eq!(
+            ebsp,
+            &[
+                0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x03, 0x02, 0x10, 0x00, 0x00,
+                0x03
+            ]
+        );
+        assert_eq!(ebsp_to_rbsp(&ebsp), rbsp);
+    }
+
+    #[test]
+    fn test_cow_borrows_without_emulation_bytes() {
+        let plain = [0x67, 0x42, 0x00, 0x1E, 0xFF];
+        assert!(matches!(ebsp_to_rbsp_cow(&plain), Cow::Borrowed(rbsp) if rbsp == plain));
+
+        let emulated = [0x65, 0x00, 0x00, 0x03, 0x01];
+        assert!(
+            matches!(ebsp_to_rbsp_cow(&emulated), Cow::Owned(rbsp) if rbsp == [0x65, 0x00, 0x00, 0x01])
+        );
+    }
+}
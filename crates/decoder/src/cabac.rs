@@ -0,0 +1,334 @@
+use anyhow::{Result, anyhow};
+use bitstream::BitReader;
+use parser::SliceType;
+
+/// rangeTabLPS, indexed by pStateIdx and qCodIRangeIdx (Table 9-44)
+#[rustfmt::skip]
+pub(crate) const RANGE_TAB_LPS: [[u8; 4]; 64] = [
+    [128, 176, 208, 240], [128, 167, 197, 227], [128, 158, 187, 216], [123, 150, 178, 205],
+    [116, 142, 169, 195], [111, 135, 160, 185], [105, 128, 152, 175], [100, 122, 144, 166],
+    [95, 116, 137, 158], [90, 110, 130, 150], [85, 104, 123, 142], [81, 99, 117, 135],
+    [77, 94, 111, 128], [73, 89, 105, 122], [69, 85, 100, 116], [66, 80, 95, 110],
+    [62, 76, 90, 104], [59, 72, 86, 99], [56, 69, 81, 94], [53, 65, 77, 89],
+    [51, 62, 73, 85], [48, 59, 69, 80], [46, 56, 66, 76], [43, 53, 63, 72],
+    [41, 50, 59, 69], [39, 48, 56, 65], [37, 45, 54, 62], [35, 43, 51, 59],
+    [33, 41, 48, 56], [32, 39, 46, 53], [30, 37, 43, 50], [29, 35, 41, 48],
+    [27, 33, 39, 45], [26, 31, 37, 43], [24, 30, 35, 41], [23, 28, 33, 39],
+    [22, 27, 32, 37], [21, 26, 30, 35], [20, 24, 29, 33], [19, 23, 27, 31],
+    [18, 22, 26, 30], [17, 21, 25, 28], [16, 20, 23, 27], [15, 19, 22, 25],
+    [14, 18, 21, 24], [14, 17, 20, 23], [13, 16, 19, 22], [12, 15, 18, 21],
+    [12, 14, 17, 20], [11, 14, 16, 19], [11, 13, 15, 18], [10, 12, 15, 17],
+    [10, 12, 14, 16], [9, 11, 13, 15], [9, 11, 12, 14], [8, 10, 12, 14],
+    [8, 9, 11, 13], [7, 9, 11, 12], [7, 9, 10, 12], [7, 8, 10, 11],
+    [6, 8, 9, 11], [6, 7, 9, 10], [6, 7, 8, 9], [2, 2, 2, 2],
+];
+
+/// transIdxLPS, the state after decoding the least probable symbol (Table 9-45)
+#[rustfmt::skip]
+pub(crate) const TRANS_IDX_LPS: [u8; 64] = [
+    0, 0, 1, 2, 2, 4, 4, 5, 6, 7, 8, 9, 9, 11, 11, 12,
+    13, 13, 15, 15, 16, 16, 18, 18, 19, 19, 21, 21, 22, 22, 23, 24,
+    24, 25, 26, 26, 27, 27, 28, 29, 29, 30, 30, 30, 31, 32, 32, 33,
+    33, 33, 34, 34, 35, 35, 35, 36, 36, 36, 37, 37, 37, 38, 38, 63,
+];
+
+/// transIdxMPS (Table 9-45): every state moves up by one, except the top two
+pub(crate) fn trans_idx_mps(p_state_idx: u8) -> u8 {
+    if p_state_idx < 62 {
+        p_state_idx + 1
+    } else {
+        p_state_idx
+    }
+}
+
+/// (m, n) of ctxIdx 0 to 10, mb_type in SI and I slices, the same for every slice (Table 9-12)
+#[rustfmt::skip]
+const CONTEXT_INIT_0_10: [(i32, i32); 11] = [
+    (20, -15), (2, 54), (3, 74), (20, -15), (2, 54), (3, 74), (-28, 127), (-23, 104), (-6, 53),
+    (-1, 54), (7, 51),
+];
+
+/// (m, n) of ctxIdx 11 to 23, mb_skip_flag, mb_type and sub_mb_type in P and SP slices, for each
+/// cabac_init_idc (Table 9-13)
+#[rustfmt::skip]
+const CONTEXT_INIT_11_23: [[(i32, i32); 13]; 3] = [
+    [
+        (23, 33), (23, 2), (21, 0), (1, 9), (0, 49), (-37, 118), (5, 57), (-13, 78), (-11, 65),
+        (1, 62), (12, 49), (-4, 73), (17, 50),
+    ],
+    [
+        (22, 25), (34, 0), (16, 0), (-2, 9), (4, 41), (-29, 118), (2, 65), (-6, 71), (-13, 79),
+        (5, 52), (9, 50), (-3, 70), (10, 54),
+    ],
+    [
+        (29, 16), (25, 0), (14, 0), (-10, 51), (-3, 62), (-27, 99), (26, 16), (-4, 85),
+        (-24, 102), (5, 57), (6, 57), (-17, 73), (14, 57),
+    ],
+];
+
+/// ctxIdxOffset of mb_type in I slices
+pub(crate) const CTX_MB_TYPE_I: usize = 3;
+/// ctxIdxOffset of mb_skip_flag in P and SP slices
+pub(crate) const CTX_MB_SKIP_P: usize = 11;
+/// ctxIdxOffset of the mb_type prefix in P and SP slices
+pub(crate) const CTX_MB_TYPE_P_PREFIX: usize = 14;
+/// ctxIdxOffset of the mb_type suffix in P and SP slices, an intra macroblock type
+pub(crate) const CTX_MB_TYPE_P_SUFFIX: usize = 17;
+
+/// Initialises the context variables of a slice (9.3.1.1). Only the ctxIdx the macroblock
+/// layer reads are there so far.
+pub(crate) fn init_contexts(
+    slice_type: SliceType,
+    cabac_init_idc: u32,
+    slice_qp: i32,
+) -> Result<Vec<CabacContext>> {
+    let init = |&(m, n): &(i32, i32)| CabacContext::new(m, n, slice_qp);
+    let mut contexts: Vec<CabacContext> = CONTEXT_INIT_0_10.iter().map(init).collect();
+    if !slice_type.is_intra() {
+        let table = CONTEXT_INIT_11_23
+            .get(cabac_init_idc as usize)
+            .ok_or_else(|| anyhow!("Invalid cabac_init_idc: {}", cabac_init_idc))?;
+        contexts.extend(table.iter().map(init));
+    }
+    Ok(contexts)
+}
+
+/// The probability state of one context variable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct CabacContext {
+    pub p_state_idx: u8,
+    pub val_mps: bool,
+}
+
+impl CabacContext {
+    /// 9.3.1.1 Initialisation process for context variables, from the (m, n) pair of the context
+    /// and SliceQPY
+    pub fn new(m: i32, n: i32, slice_qp: i32) -> Self {
+        let pre_ctx_state = (((m * slice_qp.clamp(0, 51)) >> 4) + n).clamp(1, 126);
+        if pre_ctx_state <= 63 {
+            Self {
+                p_state_idx: (63 - pre_ctx_state) as u8,
+                val_mps: false,
+            }
+        } else {
+            Self {
+                p_state_idx: (pre_ctx_state - 64) as u8,
+                val_mps: true,
+            }
+        }
+    }
+}
+
+/// The arithmetic decoding engine of 9.3.3.2, reading straight from the slice data
+pub(crate) struct CabacDecoder<'a, 'input> {
+    reader: &'a mut BitReader<'input>,
+    range: u32,
+    offset: u32,
+}
+
+impl<'a, 'input> CabacDecoder<'a, 'input> {
+    /// 9.3.1.2 Initialisation process for the arithmetic decoding engine. The reader must sit
+    /// on the byte-aligned start of the CABAC data.
+    pub fn new(reader: &'a mut BitReader<'input>) -> Result<Self> {
+        let mut decoder = Self {
+            reader,
+            range: 510,
+            offset: 0,
+        };
+        decoder.restart()?;
+        Ok(decoder)
+    }
+
+    /// Initialises the engine again from the reader's position, as after the samples of an
+    /// I_PCM macroblock
+    pub fn restart(&mut self) -> Result<()> {
+        let offset = self.reader.read(9)?;
+        if offset >= 510 {
+            return Err(anyhow!("Invalid initial codIOffset: {}", offset));
+        }
+        self.range = 510;
+        self.offset = offset;
+        Ok(())
+    }
+
+    /// The underlying reader, for the raw samples of an I_PCM macroblock. It sits just past the
+    /// bits the encoder flushed after the terminating mb_type bin.
+    pub fn reader(&mut self) -> &mut BitReader<'input> {
+        self.reader
+    }
+
+    /// 9.3.3.2.1 DecodeDecision
+    pub fn decode_decision(&mut self, ctx: &mut CabacContext) -> Result<bool> {
+        let q_cod_i_range_idx = ((self.range >> 6) & 3) as usize;
+        let range_lps = RANGE_TAB_LPS[ctx.p_state_idx as usize][q_cod_i_range_idx] as u32;
+        self.range -= range_lps;
+
+        let bin = if self.offset >= self.range {
+            let bin = !ctx.val_mps;
+            self.offset -= self.range;
+            self.range = range_lps;
+            if ctx.p_state_idx == 0 {
+                ctx.val_mps = !ctx.val_mps;
+            }
+            ctx.p_state_idx = TRANS_IDX_LPS[ctx.p_state_idx as usize];
+            bin
+        } else {
+            ctx.p_state_idx = trans_idx_mps(ctx.p_state_idx);
+            ctx.val_mps
+        };
+        self.renorm()?;
+        Ok(bin)
+    }
+
+    /// 9.3.3.2.3 DecodeBypass
+    // Nothing bypass coded is decoded yet: it starts with the residual levels and motion vectors
+    #[allow(dead_code)]
+    pub fn decode_bypass(&mut self) -> Result<bool> {
+        self.offset = (self.offset << 1) | self.reader.read(1)?;
+        if self.offset >= self.range {
+            self.offset -= self.range;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// 9.3.3.2.2.3 DecodeTerminate, for end_of_slice_flag and the bin that signals I_PCM. After
+    /// a 1 the reader sits just past the last bit the encoder flushed.
+    pub fn decode_terminate(&mut self) -> Result<bool> {
+        self.range -= 2;
+        if self.offset >= self.range {
+            return Ok(true);
+        }
+        self.renorm()?;
+        Ok(false)
+    }
+
+    /// Checks what follows an end_of_slice_flag of 1. The flushed data ends with the
+    /// rbsp_stop_one_bit, so only alignment zero bits and cabac_zero_words (7.3.2.10) may remain.
+    pub fn finish_slice(self) -> Result<()> {
+        let reader = self.reader;
+        while reader.has_bits(1) {
+            if reader.read_flag()? {
+                return Err(anyhow!(
+                    "Data follows the end of the CABAC slice at bit {}",
+                    reader.position() - 1
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// 9.3.3.2.2 RenormD
+    fn renorm(&mut self) -> Result<()> {
+        while self.range < 256 {
+            self.range <<= 1;
+            self.offset = (self.offset << 1) | self.reader.read(1)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::CabacWriter;
+    use bitstream::BitWriter;
+
+    #[test]
+    fn test_context_initialisation() {
+        // preCtxState 63 is the last state with valMPS 0
+        assert_eq!(
+            CabacContext::new(0, 63, 26),
+            CabacContext {
+                p_state_idx: 0,
+                val_mps: false
+            }
+        );
+        // (m, n) = (20, -15) at QP 40: (20 * 40 >> 4) - 15 = 35
+        assert_eq!(
+            CabacContext::new(20, -15, 40),
+            CabacContext {
+                p_state_idx: 28,
+                val_mps: false
+            }
+        );
+        // Clipped to 126
+        assert_eq!(
+            CabacContext::new(64, 126, 51),
+            CabacContext {
+                p_state_idx: 62,
+                val_mps: true
+            }
+        );
+    }
+
+    #[test]
+    fn test_decisions_bypass_and_terminate_round_trip() -> Result<()> {
+        let bins = [
+            true, true, false, true, true, true, false, false, true, true,
+        ];
+        let mut writer = BitWriter::new();
+        let mut encoder = CabacWriter::new(&mut writer);
+        let mut ctx = CabacContext::new(-10, 70, 30);
+        for (i, &bin) in bins.iter().enumerate() {
+            encoder.encode_decision(&mut ctx, bin);
+            encoder.encode_bypass(i % 3 == 0);
+            encoder.encode_terminate(false);
+        }
+        encoder.encode_terminate(true);
+        let end = writer.position();
+        let bytes = writer.into_bytes();
+
+        let mut reader = BitReader::from_bytes(&bytes);
+        {
+            let mut decoder = CabacDecoder::new(&mut reader)?;
+            let mut ctx = CabacContext::new(-10, 70, 30);
+            for (i, &bin) in bins.iter().enumerate() {
+                assert_eq!(decoder.decode_decision(&mut ctx)?, bin);
+                assert_eq!(decoder.decode_bypass()?, i % 3 == 0);
+                assert!(!decoder.decode_terminate()?);
+            }
+            assert!(decoder.decode_terminate()?);
+        }
+
+        // The engine has read exactly up to the rbsp_stop_one_bit that ends the flush
+        assert_eq!(reader.position(), end);
+        Ok(())
+    }
+
+    #[test]
+    fn test_slice_end_before_cabac_zero_words() -> Result<()> {
+        // end_of_slice_flag is 0 for the first two macroblocks and 1 for the third
+        let mut writer = BitWriter::new();
+        let mut encoder = CabacWriter::new(&mut writer);
+        for end_of_slice in [false, false, true] {
+            encoder.encode_terminate(end_of_slice);
+        }
+        while !writer.byte_aligned() {
+            writer.write_flag(false);
+        }
+        writer.write(0, 16); // cabac_zero_word
+        writer.write(0, 16); // cabac_zero_word
+        let bytes = writer.into_bytes();
+
+        let mut reader = BitReader::from_bytes(&bytes);
+        let mut decoder = CabacDecoder::new(&mut reader)?;
+        let mut macroblocks = 1;
+        while !decoder.decode_terminate()? {
+            macroblocks += 1;
+        }
+        assert_eq!(macroblocks, 3);
+        decoder.finish_slice()?;
+
+        // Anything but zero words after the stop bit is an error
+        let mut corrupt = bytes.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] = 0x01;
+        let mut reader = BitReader::from_bytes(&corrupt);
+        let mut decoder = CabacDecoder::new(&mut reader)?;
+        while !decoder.decode_terminate()? {}
+        assert!(decoder.finish_slice().is_err());
+        Ok(())
+    }
+}
@@ -1 +1,394 @@
+//! Context-adaptive variable length coding of residual blocks (9.2)
 
+use anyhow::{Result, anyhow};
+use bitstream::BitReader;
+
+/// Code lengths of coeff_token (Table 9-5), indexed by `4 * TotalCoeff + TrailingOnes`, for the
+/// three VLC tables selected by 0 <= nC < 2, 2 <= nC < 4 and 4 <= nC < 8
+#[rustfmt::skip]
+const COEFF_TOKEN_LEN: [[u8; 68]; 3] = [
+    [
+        1, 0, 0, 0, 6, 2, 0, 0, 8, 6, 3, 0, 9, 8, 7, 5, 10, 9, 8, 6, 11, 10, 9, 7,
+        13, 11, 10, 8, 13, 13, 11, 9, 13, 13, 13, 10, 14, 14, 13, 11, 14, 14, 14, 13,
+        15, 15, 14, 14, 15, 15, 15, 14, 16, 15, 15, 15, 16, 16, 16, 15, 16, 16, 16, 16,
+        16, 16, 16, 16,
+    ],
+    [
+        2, 0, 0, 0, 6, 2, 0, 0, 6, 5, 3, 0, 7, 6, 6, 4, 8, 6, 6, 4, 8, 7, 7, 5,
+        9, 8, 8, 6, 11, 9, 9, 6, 11, 11, 11, 7, 12, 11, 11, 9, 12, 12, 12, 11,
+        12, 12, 12, 11, 13, 13, 13, 12, 13, 13, 13, 13, 13, 14, 13, 13, 14, 14, 14, 13,
+        14, 14, 14, 14,
+    ],
+    [
+        4, 0, 0, 0, 6, 4, 0, 0, 6, 5, 4, 0, 6, 5, 5, 4, 7, 5, 5, 4, 7, 5, 5, 4,
+        7, 6, 6, 4, 7, 6, 6, 4, 8, 7, 7, 5, 8, 8, 7, 6, 9, 8, 8, 7, 9, 9, 8, 8,
+        9, 9, 9, 8, 10, 9, 9, 9, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10,
+    ],
+];
+
+#[rustfmt::skip]
+const COEFF_TOKEN_BITS: [[u16; 68]; 3] = [
+    [
+        1, 0, 0, 0, 5, 1, 0, 0, 7, 4, 1, 0, 7, 6, 5, 3, 7, 6, 5, 3, 7, 6, 5, 4,
+        15, 6, 5, 4, 11, 14, 5, 4, 8, 10, 13, 4, 15, 14, 9, 4, 11, 10, 13, 12,
+        15, 14, 9, 12, 11, 10, 13, 8, 15, 1, 9, 12, 11, 14, 13, 8, 7, 10, 9, 12,
+        4, 6, 5, 8,
+    ],
+    [
+        3, 0, 0, 0, 11, 2, 0, 0, 7, 7, 3, 0, 7, 10, 9, 5, 7, 6, 5, 4, 4, 6, 5, 6,
+        7, 6, 5, 8, 15, 6, 5, 4, 11, 14, 13, 4, 15, 10, 9, 4, 11, 14, 13, 12,
+        8, 10, 9, 8, 15, 14, 13, 12, 11, 10, 9, 12, 7, 11, 6, 8, 9, 8, 10, 1,
+        7, 6, 5, 4,
+    ],
+    [
+        15, 0, 0, 0, 15, 14, 0, 0, 11, 15, 13, 0, 8, 12, 14, 12, 15, 10, 11, 11,
+        11, 8, 9, 10, 9, 14, 13, 9, 8, 10, 9, 8, 15, 14, 13, 13, 11, 14, 10, 12,
+        15, 10, 13, 12, 11, 14, 9, 12, 8, 10, 13, 8, 13, 7, 9, 12, 9, 12, 11, 10,
+        5, 8, 7, 6, 1, 4, 3, 2,
+    ],
+];
+
+/// coeff_token for 4:2:0 chroma DC (nC == -1)
+#[rustfmt::skip]
+const CHROMA_DC_COEFF_TOKEN_LEN: [u8; 20] = [
+    2, 0, 0, 0, 6, 1, 0, 0, 6, 6, 3, 0, 6, 7, 7, 6, 6, 8, 8, 7,
+];
+
+#[rustfmt::skip]
+const CHROMA_DC_COEFF_TOKEN_BITS: [u16; 20] = [
+    1, 0, 0, 0, 7, 1, 0, 0, 4, 6, 1, 0, 3, 3, 2, 5, 2, 3, 2, 0,
+];
+
+/// total_zeros for 4x4 blocks (Tables 9-7 and 9-8), indexed by TotalCoeff - 1
+#[rustfmt::skip]
+const TOTAL_ZEROS_LEN: [&[u8]; 15] = [
+    &[1, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 9],
+    &[3, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 6, 6, 6, 6],
+    &[4, 3, 3, 3, 4, 4, 3, 3, 4, 5, 5, 6, 5, 6],
+    &[5, 3, 4, 4, 3, 3, 3, 4, 3, 4, 5, 5, 5],
+    &[4, 4, 4, 3, 3, 3, 3, 3, 4, 5, 4, 5],
+    &[6, 5, 3, 3, 3, 3, 3, 3, 4, 3, 6],
+    &[6, 5, 3, 3, 3, 2, 3, 4, 3, 6],
+    &[6, 4, 5, 3, 2, 2, 3, 3, 6],
+    &[6, 6, 4, 2, 2, 3, 2, 5],
+    &[5, 5, 3, 2, 2, 2, 4],
+    &[4, 4, 3, 3, 1, 3],
+    &[4, 4, 2, 1, 3],
+    &[3, 3, 1, 2],
+    &[2, 2, 1],
+    &[1, 1],
+];
+
+#[rustfmt::skip]
+const TOTAL_ZEROS_BITS: [&[u16]; 15] = [
+    &[1, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 1],
+    &[7, 6, 5, 4, 3, 5, 4, 3, 2, 3, 2, 3, 2, 1, 0],
+    &[5, 7, 6, 5, 4, 3, 4, 3, 2, 3, 2, 1, 1, 0],
+    &[3, 7, 5, 4, 6, 5, 4, 3, 3, 2, 2, 1, 0],
+    &[5, 4, 3, 7, 6, 5, 4, 3, 2, 1, 1, 0],
+    &[1, 1, 7, 6, 5, 4, 3, 2, 1, 1, 0],
+    &[1, 1, 5, 4, 3, 3, 2, 1, 1, 0],
+    &[1, 1, 1, 3, 3, 2, 2, 1, 0],
+    &[1, 0, 1, 3, 2, 1, 1, 1],
+    &[1, 0, 1, 3, 2, 1, 1],
+    &[0, 1, 1, 2, 1, 3],
+    &[0, 1, 1, 1, 1],
+    &[0, 1, 1, 1],
+    &[0, 1, 1],
+    &[0, 1],
+];
+
+/// total_zeros for 4:2:0 chroma DC (Table 9-9a)
+const CHROMA_DC_TOTAL_ZEROS_LEN: [&[u8]; 3] = [&[1, 2, 3, 3], &[1, 2, 2], &[1, 1]];
+const CHROMA_DC_TOTAL_ZEROS_BITS: [&[u16]; 3] = [&[1, 1, 1, 0], &[1, 1, 0], &[1, 0]];
+
+/// run_before (Table 9-10), indexed by Min(zerosLeft, 7) - 1
+#[rustfmt::skip]
+const RUN_BEFORE_LEN: [&[u8]; 7] = [
+    &[1, 1],
+    &[1, 2, 2],
+    &[2, 2, 2, 2],
+    &[2, 2, 2, 3, 3],
+    &[2, 2, 3, 3, 3, 3],
+    &[2, 3, 3, 3, 3, 3, 3],
+    &[3, 3, 3, 3, 3, 3, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+];
+
+#[rustfmt::skip]
+const RUN_BEFORE_BITS: [&[u16]; 7] = [
+    &[1, 0],
+    &[1, 1, 0],
+    &[3, 2, 1, 0],
+    &[3, 2, 1, 1, 0],
+    &[3, 2, 3, 2, 1, 0],
+    &[3, 0, 1, 3, 2, 5, 4],
+    &[7, 6, 5, 4, 3, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1],
+];
+
+/// coded_block_pattern for each me(v) codeNum with ChromaArrayType 1 or 2 (Table 9-4)
+#[rustfmt::skip]
+const INTRA_CBP: [u8; 48] = [
+    47, 31, 15, 0, 23, 27, 29, 30, 7, 11, 13, 14, 39, 43, 45, 46,
+    16, 3, 5, 10, 12, 19, 21, 26, 28, 35, 37, 42, 44, 1, 2, 4,
+    8, 17, 18, 20, 24, 6, 9, 22, 25, 32, 33, 34, 36, 40, 38, 41,
+];
+
+#[rustfmt::skip]
+const INTER_CBP: [u8; 48] = [
+    0, 16, 1, 2, 4, 8, 32, 3, 5, 10, 12, 15, 47, 7, 11, 13,
+    14, 6, 9, 31, 35, 37, 42, 44, 33, 34, 36, 40, 39, 43, 45, 46,
+    17, 18, 20, 24, 19, 21, 26, 28, 23, 27, 29, 30, 22, 25, 38, 41,
+];
+
+/// coded_block_pattern for each me(v) codeNum with ChromaArrayType 0 or 3, which code no chroma
+/// bits (Table 9-4)
+#[rustfmt::skip]
+const INTRA_CBP_NO_CHROMA: [u8; 16] = [15, 0, 7, 11, 13, 14, 3, 5, 10, 12, 1, 2, 4, 8, 6, 9];
+
+#[rustfmt::skip]
+const INTER_CBP_NO_CHROMA: [u8; 16] = [0, 1, 2, 4, 8, 3, 5, 10, 12, 15, 7, 11, 13, 14, 6, 9];
+
+/// Reads the codeword of a VLC table bit by bit and returns the index of the matching entry.
+/// Entries with a zero length are unused.
+fn read_vlc(reader: &mut BitReader, lens: &[u8], bits: &[u16]) -> Result<usize> {
+    let max_len = lens.iter().copied().max().unwrap_or(0);
+    let mut code = 0u16;
+
+    for len in 1..=max_len {
+        code = (code << 1) | reader.read(1)? as u16;
+        if let Some(index) = (0..lens.len()).find(|&i| lens[i] == len && bits[i] == code) {
+            return Ok(index);
+        }
+    }
+
+    Err(anyhow!("Invalid VLC codeword"))
+}
+
+/// coded_block_pattern, mapped from me(v) as in 9.1.2
+pub(crate) fn read_coded_block_pattern(
+    reader: &mut BitReader,
+    intra: bool,
+    chroma_array_type: u32,
+) -> Result<u8> {
+    let code_num = reader.read_ue()? as usize;
+    let table: &[u8] = match (intra, chroma_array_type) {
+        (true, 1 | 2) => &INTRA_CBP,
+        (false, 1 | 2) => &INTER_CBP,
+        (true, _) => &INTRA_CBP_NO_CHROMA,
+        (false, _) => &INTER_CBP_NO_CHROMA,
+    };
+    table
+        .get(code_num)
+        .copied()
+        .ok_or_else(|| anyhow!("Invalid coded_block_pattern codeNum: {}", code_num))
+}
+
+/// Returns (TotalCoeff, TrailingOnes), as in 9.2.1. `nc` is -1 for 4:2:0 chroma DC.
+fn read_coeff_token(reader: &mut BitReader, nc: i32) -> Result<(usize, usize)> {
+    let index = match nc {
+        -1 => read_vlc(
+            reader,
+            &CHROMA_DC_COEFF_TOKEN_LEN,
+            &CHROMA_DC_COEFF_TOKEN_BITS,
+        )?,
+        0..=1 => read_vlc(reader, &COEFF_TOKEN_LEN[0], &COEFF_TOKEN_BITS[0])?,
+        2..=3 => read_vlc(reader, &COEFF_TOKEN_LEN[1], &COEFF_TOKEN_BITS[1])?,
+        4..=7 => read_vlc(reader, &COEFF_TOKEN_LEN[2], &COEFF_TOKEN_BITS[2])?,
+        _ => {
+            // 6-bit fixed length code, with 0b000011 standing for no coefficients
+            let code = reader.read(6)? as usize;
+            return match code {
+                3 => Ok((0, 0)),
+                _ if (code & 3) > (code >> 2) => Err(anyhow!("Invalid coeff_token: {}", code)),
+                _ => Ok(((code >> 2) + 1, code & 3)),
+            };
+        }
+    };
+
+    Ok((index / 4, index % 4))
+}
+
+/// Implemented as in 7.3.5.3.2 Residual block CAVLC syntax. Levels are stored at
+/// `coeff_level[start_idx..=end_idx]` in scanning order, and the block's TotalCoeff is returned.
+pub(crate) fn read_residual_block(
+    reader: &mut BitReader,
+    coeff_level: &mut [i32],
+    start_idx: usize,
+    end_idx: usize,
+    max_num_coeff: usize,
+    nc: i32,
+) -> Result<u8> {
+    coeff_level[..max_num_coeff].fill(0);
+
+    let (total_coeff, trailing_ones) = read_coeff_token(reader, nc)?;
+    if total_coeff == 0 {
+        return Ok(0);
+    }
+    if total_coeff > end_idx - start_idx + 1 {
+        return Err(anyhow!(
+            "TotalCoeff {} exceeds the {} coefficients of the block",
+            total_coeff,
+            end_idx - start_idx + 1
+        ));
+    }
+
+    let mut level_val = [0i32; 16];
+    let mut suffix_length = if total_coeff > 10 && trailing_ones < 3 {
+        1
+    } else {
+        0
+    };
+
+    for (i, level) in level_val.iter_mut().enumerate().take(total_coeff) {
+        if i < trailing_ones {
+            *level = if reader.read_flag()? { -1 } else { 1 };
+            continue;
+        }
+
+        // level_prefix (9.2.2.1)
+        let level_prefix = reader.count_leading_zeros()?;
+        if level_prefix > 31 {
+            return Err(anyhow!("Invalid level_prefix"));
+        }
+
+        let mut level_code = (level_prefix.min(15) << suffix_length) as i32;
+        if suffix_length > 0 || level_prefix >= 14 {
+            let level_suffix_size = if level_prefix == 14 && suffix_length == 0 {
+                4
+            } else if level_prefix >= 15 {
+                level_prefix - 3
+            } else {
+                suffix_length
+            };
+            if level_suffix_size > 0 {
+                level_code += reader.read(level_suffix_size)? as i32;
+            }
+        }
+        if level_prefix >= 15 && suffix_length == 0 {
+            level_code += 15;
+        }
+        if level_prefix >= 16 {
+            level_code += (1 << (level_prefix - 3)) - 4096;
+        }
+        if i == trailing_ones && trailing_ones < 3 {
+            level_code += 2;
+        }
+
+        *level = if level_code % 2 == 0 {
+            (level_code + 2) >> 1
+        } else {
+            (-level_code - 1) >> 1
+        };
+
+        if suffix_length == 0 {
+            suffix_length = 1;
+        }
+        if level.abs() > (3 << (suffix_length - 1)) && suffix_length < 6 {
+            suffix_length += 1;
+        }
+    }
+
+    let mut zeros_left = if total_coeff < end_idx - start_idx + 1 {
+        let index = total_coeff - 1;
+        let total_zeros = if max_num_coeff == 4 {
+            read_vlc(
+                reader,
+                CHROMA_DC_TOTAL_ZEROS_LEN[index],
+                CHROMA_DC_TOTAL_ZEROS_BITS[index],
+            )?
+        } else {
+            read_vlc(reader, TOTAL_ZEROS_LEN[index], TOTAL_ZEROS_BITS[index])?
+        };
+        if total_zeros + total_coeff > end_idx - start_idx + 1 {
+            return Err(anyhow!("total_zeros {} overflows the block", total_zeros));
+        }
+        total_zeros
+    } else {
+        0
+    };
+
+    let mut run_val = [0usize; 16];
+    for run in run_val.iter_mut().take(total_coeff - 1) {
+        if zeros_left > 0 {
+            let index = zeros_left.min(7) - 1;
+            *run = read_vlc(reader, RUN_BEFORE_LEN[index], RUN_BEFORE_BITS[index])?;
+            if *run > zeros_left {
+                return Err(anyhow!("run_before {} exceeds zerosLeft", *run));
+            }
+            zeros_left -= *run;
+        }
+    }
+    run_val[total_coeff - 1] = zeros_left;
+
+    let mut coeff_num = start_idx;
+    for i in (0..total_coeff).rev() {
+        coeff_num += run_val[i];
+        coeff_level[coeff_num] = level_val[i];
+        coeff_num += 1;
+    }
+
+    Ok(total_coeff as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitstream::BitWriter;
+
+    #[test]
+    fn test_residual_block_from_standard_example() -> Result<()> {
+        // The 4x4 block 0, 3, -1, 0 / 0, -1, 1, 0 / 1, 0, 0, 0 / 0, 0, 0, 0 in zig-zag order is
+        // 0, 3, 0, 1, -1, -1, 0, 1, 0..., coded with nC = 0 as 000010001110010111101101
+        let mut writer = BitWriter::new();
+        writer.write(0b0000_1000_1110_0101_1110_1101, 24);
+        let bytes = writer.into_bytes();
+
+        let mut levels = [0; 16];
+        let total_coeff = read_residual_block(
+            &mut BitReader::from_bytes(&bytes),
+            &mut levels,
+            0,
+            15,
+            16,
+            0,
+        )?;
+        assert_eq!(total_coeff, 5);
+        assert_eq!(&levels[..8], &[0, 3, 0, 1, -1, -1, 0, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_high_nc_fixed_length_coeff_token() -> Result<()> {
+        let mut writer = BitWriter::new();
+        writer.write(0b000011, 6); // TotalCoeff 0
+        writer.write(0b000101, 6); // TotalCoeff 2, TrailingOnes 1
+        let bytes = writer.into_bytes();
+        let mut reader = BitReader::from_bytes(&bytes);
+
+        assert_eq!(read_coeff_token(&mut reader, 8)?, (0, 0));
+        assert_eq!(read_coeff_token(&mut reader, 8)?, (2, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_coded_block_pattern_mapping() -> Result<()> {
+        let mut writer = BitWriter::new();
+        writer.write_ue(0);
+        writer.write_ue(0);
+        writer.write_ue(48);
+        writer.write_ue(0);
+        writer.write_ue(9);
+        writer.write_ue(16);
+        let bytes = writer.into_bytes();
+        let mut reader = BitReader::from_bytes(&bytes);
+
+        assert_eq!(read_coded_block_pattern(&mut reader, true, 1)?, 47);
+        assert_eq!(read_coded_block_pattern(&mut reader, false, 1)?, 0);
+        assert!(read_coded_block_pattern(&mut reader, true, 1).is_err());
+        // Monochrome codes luma bits only
+        assert_eq!(read_coded_block_pattern(&mut reader, true, 0)?, 15);
+        assert_eq!(read_coded_block_pattern(&mut reader, false, 0)?, 15);
+        assert!(read_coded_block_pattern(&mut reader, false, 0).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+/// YCbCr → RGB matrix, as signalled by `matrix_coefficients` in the VUI (Table E-5 in Rec. ITU-T H.264)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorMatrix {
+    #[default]
+    Bt601,
+    Bt709,
+    Bt2020,
+}
+
+impl ColorMatrix {
+    /// The (Kr, Kb) luma weights of the matrix
+    fn weights(self) -> (f32, f32) {
+        match self {
+            ColorMatrix::Bt601 => (0.299, 0.114),
+            ColorMatrix::Bt709 => (0.2126, 0.0722),
+            ColorMatrix::Bt2020 => (0.2627, 0.0593),
+        }
+    }
+}
+
+/// Sample range, as signalled by `video_full_range_flag` in the VUI.
+/// Limited range puts luma in 16..=235 and chroma in 16..=240, full range uses all of 0..=255.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorRange {
+    #[default]
+    Limited,
+    Full,
+}
+
+/// Converts a single 8-bit YCbCr sample triple into RGB
+pub fn yuv_to_rgb(y: u8, cb: u8, cr: u8, matrix: ColorMatrix, range: ColorRange) -> [u8; 3] {
+    let (kr, kb) = matrix.weights();
+    let kg = 1.0 - kr - kb;
+
+    let (luma, pb, pr) = match range {
+        ColorRange::Limited => (
+            (y as f32 - 16.0) / 219.0,
+            (cb as f32 - 128.0) / 224.0,
+            (cr as f32 - 128.0) / 224.0,
+        ),
+        ColorRange::Full => (
+            y as f32 / 255.0,
+            (cb as f32 - 128.0) / 255.0,
+            (cr as f32 - 128.0) / 255.0,
+        ),
+    };
+
+    let r = luma + 2.0 * (1.0 - kr) * pr;
+    let b = luma + 2.0 * (1.0 - kb) * pb;
+    let g = luma - (2.0 * kb * (1.0 - kb) / kg) * pb - (2.0 * kr * (1.0 - kr) / kg) * pr;
+
+    [to_u8(r), to_u8(g), to_u8(b)]
+}
+
+fn to_u8(v: f32) -> u8 {
+    (v * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limited_range_extremes() {
+        let black = yuv_to_rgb(16, 128, 128, ColorMatrix::Bt601, ColorRange::Limited);
+        assert_eq!(black, [0, 0, 0]);
+
+        let white = yuv_to_rgb(235, 128, 128, ColorMatrix::Bt709, ColorRange::Limited);
+        assert_eq!(white, [255, 255, 255]);
+    }
+
+    #[test]
+    fn test_full_range_grey_and_black() {
+        let grey = yuv_to_rgb(128, 128, 128, ColorMatrix::Bt601, ColorRange::Full);
+        assert_eq!(grey, [128, 128, 128]);
+
+        let black = yuv_to_rgb(0, 128, 128, ColorMatrix::Bt709, ColorRange::Full);
+        assert_eq!(black, [0, 0, 0]);
+
+        // Limited-range black read as full range is a dark grey rather than black
+        let lifted = yuv_to_rgb(16, 128, 128, ColorMatrix::Bt601, ColorRange::Full);
+        assert_eq!(lifted, [16, 16, 16]);
+    }
+
+    #[test]
+    fn test_saturated_red() {
+        // BT.601 limited range red is roughly (81, 90, 240)
+        let [r, g, b] = yuv_to_rgb(81, 90, 240, ColorMatrix::Bt601, ColorRange::Limited);
+        assert!(r >= 253, "Expected red to saturate, got {}", r);
+        assert!(g <= 2, "Expected no green, got {}", g);
+        assert!(b <= 2, "Expected no blue, got {}", b);
+    }
+}
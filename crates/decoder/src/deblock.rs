@@ -0,0 +1,500 @@
+//! Deblocking filter process (8.7), for frames and field pictures. MBAFF frames are rejected
+//! before they're decoded, so every macroblock here is a frame macroblock of a frame or a field
+//! macroblock of a field, and mixedModeEdgeFlag is always 0.
+
+use crate::frame::Plane;
+use crate::macroblock::{MbType, mb_chroma_size};
+use crate::picture::{MbInfo, Picture};
+use crate::transform::chroma_qp;
+use parser::{Pps, SliceHeader, SliceType, Sps};
+
+/// alpha' for each indexA (Table 8-16)
+#[rustfmt::skip]
+const ALPHA: [u8; 52] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    4, 4, 5, 6, 7, 8, 9, 10, 12, 13, 15, 17, 20, 22, 25, 28,
+    32, 36, 40, 45, 50, 56, 63, 71, 80, 90, 101, 113, 127, 144, 162, 182,
+    203, 226, 255, 255,
+];
+
+/// beta' for each indexB (Table 8-16)
+#[rustfmt::skip]
+const BETA: [u8; 52] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 6, 6, 7, 7, 8, 8,
+    9, 9, 10, 10, 11, 11, 12, 12, 13, 13, 14, 14, 15, 15, 16, 16,
+    17, 17, 18, 18,
+];
+
+/// tC0' for bS 1, 2 and 3 at each indexA (Table 8-17)
+#[rustfmt::skip]
+const TC0: [[u8; 3]; 52] = [
+    [0, 0, 0], [0, 0, 0], [0, 0, 0], [0, 0, 0], [0, 0, 0], [0, 0, 0], [0, 0, 0], [0, 0, 0],
+    [0, 0, 0], [0, 0, 0], [0, 0, 0], [0, 0, 0], [0, 0, 0], [0, 0, 0], [0, 0, 0], [0, 0, 0],
+    [0, 0, 0], [0, 0, 1], [0, 0, 1], [0, 0, 1], [0, 0, 1], [0, 1, 1], [0, 1, 1], [1, 1, 1],
+    [1, 1, 1], [1, 1, 1], [1, 1, 1], [1, 1, 2], [1, 1, 2], [1, 1, 2], [1, 1, 2], [1, 2, 3],
+    [1, 2, 3], [2, 2, 3], [2, 2, 4], [2, 3, 4], [2, 3, 4], [3, 3, 5], [3, 4, 6], [3, 4, 6],
+    [4, 5, 7], [4, 5, 8], [4, 6, 9], [5, 7, 10], [6, 8, 11], [6, 8, 13], [7, 10, 14],
+    [8, 11, 16], [9, 12, 18], [10, 13, 20], [11, 15, 23], [13, 17, 25],
+];
+
+/// The slice header fields that control how the edges of the slice's macroblocks are filtered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct SliceFilter {
+    pub disable_deblocking_filter_idc: u32,
+    pub alpha_c0_offset_div2: i32,
+    pub beta_offset_div2: i32,
+    /// SP and SI slices, whose macroblocks are filtered as intra ones
+    pub switching: bool,
+}
+
+impl SliceFilter {
+    pub fn from_header(header: &SliceHeader) -> Self {
+        Self {
+            disable_deblocking_filter_idc: header.disable_deblocking_filter_idc,
+            alpha_c0_offset_div2: header.slice_alpha_c0_offset_div2,
+            beta_offset_div2: header.slice_beta_offset_div2,
+            switching: matches!(header.kind(), SliceType::SP | SliceType::SI),
+        }
+    }
+}
+
+/// qPp or qPq of a luma edge (8.7.2.2): QPY of the macroblock, except that I_PCM macroblocks and
+/// lossless macroblocks, whose QP'Y is 0 with `qpprime_y_zero_transform_bypass_flag`, count as 0
+pub(crate) fn filter_qp(info: &MbInfo, sps: &Sps) -> i32 {
+    let lossless = sps.qpprime_y_zero_transform_bypass_flag && info.qp + sps.qp_bd_offset_y() == 0;
+    if info.mb_type == MbType::IPcm || lossless {
+        0
+    } else {
+        info.qp
+    }
+}
+
+/// The thresholds of an edge between two macroblocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct EdgeThresholds {
+    /// indexA, which also selects tC0
+    pub index_a: usize,
+    pub alpha: i32,
+    pub beta: i32,
+}
+
+/// 8.7.2.2: alpha and beta from the average of the two macroblocks' filter QPs, shifted by the
+/// slice's `slice_alpha_c0_offset_div2` and `slice_beta_offset_div2`. An alpha of 0 leaves the
+/// edge unfiltered.
+pub(crate) fn edge_thresholds(
+    qp_p: i32,
+    qp_q: i32,
+    alpha_c0_offset_div2: i32,
+    beta_offset_div2: i32,
+) -> EdgeThresholds {
+    // qPav (8-461)
+    let qp_av = (qp_p + qp_q + 1) >> 1;
+    let index_a = (qp_av + (alpha_c0_offset_div2 << 1)).clamp(0, 51) as usize;
+    let index_b = (qp_av + (beta_offset_div2 << 1)).clamp(0, 51) as usize;
+    EdgeThresholds {
+        index_a,
+        alpha: ALPHA[index_a] as i32,
+        beta: BETA[index_b] as i32,
+    }
+}
+
+/// Filters the edges of every decoded macroblock of `picture` in macroblock address order (8.7).
+/// Each macroblock's vertical edges are filtered left to right, then its horizontal edges top to
+/// bottom, so an edge sees the samples the edges before it left.
+pub(crate) fn deblock_picture(picture: &mut Picture, sps: &Sps, pps: &Pps) {
+    let field = picture.structure.is_field();
+    let chroma_format = picture.frame.chroma_format;
+    let (sub_width, sub_height) = chroma_format.subsampling();
+    let (mb_width_c, mb_height_c) = mb_chroma_size(chroma_format);
+    // 4:4:4 chroma is filtered like luma (chromaStyleFilteringFlag)
+    let chroma_style = sub_width != 1;
+
+    for mb_addr in 0..picture.mb_info.len() {
+        let q = picture.mb_info[mb_addr];
+        if !q.decoded {
+            continue;
+        }
+        let filter = picture.slice_filters[q.slice_num as usize];
+        if filter.disable_deblocking_filter_idc == 1 {
+            continue;
+        }
+
+        // filterLeftMbEdgeFlag and filterTopMbEdgeFlag: picture edges aren't filtered, nor are
+        // slice edges with disable_deblocking_filter_idc 2
+        let (mb_x, mb_y) = picture.mb_position(mb_addr);
+        let neighbour = |addr: usize| {
+            let p = picture.mb_info[addr];
+            (p.decoded && (filter.disable_deblocking_filter_idc != 2 || p.slice_num == q.slice_num))
+                .then_some(p)
+        };
+        let left = (mb_x > 0).then(|| neighbour(mb_addr - 1)).flatten();
+        let top = (mb_y > 0)
+            .then(|| neighbour(mb_addr - picture.width_in_mbs))
+            .flatten();
+
+        let intra = |mb: &MbInfo| {
+            mb.mb_type.is_intra() || picture.slice_filters[mb.slice_num as usize].switching
+        };
+        for vertical in [true, false] {
+            // The 8x8 transform is rejected, so every 4x4 block edge is a transform edge
+            for edge in 0..4 {
+                let p = match (edge, vertical) {
+                    (0, true) => left,
+                    (0, false) => top,
+                    _ => Some(q),
+                };
+                let Some(p) = p else {
+                    continue;
+                };
+
+                let mut bs = [0; 4];
+                for (k, bs) in bs.iter_mut().enumerate() {
+                    // The 4x4 blocks either side of the edge, in raster order
+                    let (q_blk, p_blk) = if vertical {
+                        (k * 4 + edge, k * 4 + (edge + 3) % 4)
+                    } else {
+                        (edge * 4 + k, (edge + 3) % 4 * 4 + k)
+                    };
+                    *bs = boundary_strength(
+                        (&p, p_blk, intra(&p)),
+                        (&q, q_blk, intra(&q)),
+                        edge == 0,
+                        vertical,
+                        field,
+                    );
+                }
+                if bs == [0; 4] {
+                    continue;
+                }
+
+                let thresholds = edge_thresholds(
+                    filter_qp(&p, sps),
+                    filter_qp(&q, sps),
+                    filter.alpha_c0_offset_div2,
+                    filter.beta_offset_div2,
+                );
+                let plane = &mut picture.frame.y;
+                let (x, y) = (mb_x * 16, mb_y * 16);
+                let edge_at = if vertical {
+                    (x + edge * 4, y)
+                } else {
+                    (x, y + edge * 4)
+                };
+                filter_edge(plane, edge_at, vertical, 16, (bs, 1), thresholds, false);
+
+                // Chroma edges lie on every 4th chroma sample, taking the bS of the luma edge at
+                // the same position
+                if mb_width_c == 0 {
+                    continue;
+                }
+                let (sub_across, sub_along) = if vertical {
+                    (sub_width, sub_height)
+                } else {
+                    (sub_height, sub_width)
+                };
+                if edge % sub_across != 0 {
+                    continue;
+                }
+                let chroma_edge = edge * 4 / sub_across;
+                let (x, y) = (mb_x * mb_width_c, mb_y * mb_height_c);
+                let (edge_at, len) = if vertical {
+                    ((x + chroma_edge, y), mb_height_c)
+                } else {
+                    ((x, y + chroma_edge), mb_width_c)
+                };
+                for (plane, offset) in [
+                    (&mut picture.frame.cb, pps.chroma_qp_index_offset),
+                    (&mut picture.frame.cr, pps.second_chroma_qp_index_offset),
+                ] {
+                    // I_PCM macroblocks already hold a QPY of 0
+                    let thresholds = edge_thresholds(
+                        chroma_qp(p.qp, offset),
+                        chroma_qp(q.qp, offset),
+                        filter.alpha_c0_offset_div2,
+                        filter.beta_offset_div2,
+                    );
+                    filter_edge(
+                        plane,
+                        edge_at,
+                        vertical,
+                        len,
+                        (bs, sub_along),
+                        thresholds,
+                        chroma_style,
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// bS of the edge between the 4x4 luma block `p_blk` of macroblock `p` and `q_blk` of `q`, each
+/// given with whether it's filtered as intra (8.7.2.1)
+fn boundary_strength(
+    (p, p_blk, p_intra): (&MbInfo, usize, bool),
+    (q, q_blk, q_intra): (&MbInfo, usize, bool),
+    mb_edge: bool,
+    vertical: bool,
+    field: bool,
+) -> u8 {
+    if p_intra || q_intra {
+        // Horizontal macroblock edges between field macroblocks are filtered with bS 3
+        return if mb_edge && (vertical || !field) {
+            4
+        } else {
+            3
+        };
+    }
+    if p.total_coeff[p_blk] != 0 || q.total_coeff[q_blk] != 0 {
+        return 2;
+    }
+
+    // P macroblocks predict each partition from a single vector. Whether the references are the
+    // same depends on the pictures, not on the indices into RefPicList0.
+    let quadrant = |blk: usize| blk / 8 * 2 + blk % 4 / 2;
+    let (p_mv, q_mv) = (p.mv_l0[p_blk], q.mv_l0[q_blk]);
+    // A vertical difference of 4 in quarter frame samples is 2 in quarter field samples
+    let mv_limit_y = if field { 2 } else { 4 };
+    (p.ref_pic_l0[quadrant(p_blk)] != q.ref_pic_l0[quadrant(q_blk)]
+        || p_mv[0].abs_diff(q_mv[0]) >= 4
+        || p_mv[1].abs_diff(q_mv[1]) >= mv_limit_y) as u8
+}
+
+/// Filters the `len` lines of samples crossing one edge of `plane`, whose q0 samples start at
+/// `(x, y)` (8.7.2.3, 8.7.2.4). `bs` holds the bS of each 4 luma samples along the edge, along
+/// with the luma samples per sample of `plane` in that direction.
+fn filter_edge(
+    plane: &mut Plane,
+    (x, y): (usize, usize),
+    vertical: bool,
+    len: usize,
+    (bs, sub_along): ([u8; 4], usize),
+    thresholds: EdgeThresholds,
+    chroma_style: bool,
+) {
+    let EdgeThresholds {
+        index_a,
+        alpha,
+        beta,
+    } = thresholds;
+    if alpha == 0 {
+        return;
+    }
+    let (across, along) = if vertical {
+        (1, plane.stride)
+    } else {
+        (plane.stride, 1)
+    };
+
+    for line in 0..len {
+        let bs = bs[line * sub_along / 4];
+        if bs == 0 {
+            continue;
+        }
+        let q0_at = (y * plane.stride + x) + line * along;
+        let data = &mut plane.data;
+        let sample = |i: isize| data[(q0_at as isize + i * across as isize) as usize] as i32;
+        let (p1, p0, q0, q1) = (sample(-2), sample(-1), sample(0), sample(1));
+        // filterSamplesFlag (8-468)
+        if (p0 - q0).abs() >= alpha || (p1 - p0).abs() >= beta || (q1 - q0).abs() >= beta {
+            continue;
+        }
+
+        let mut filtered = [None; 6];
+        if chroma_style {
+            if bs < 4 {
+                let tc = TC0[index_a][bs as usize - 1] as i32 + 1;
+                let delta = ((((q0 - p0) << 2) + (p1 - q1) + 4) >> 3).clamp(-tc, tc);
+                filtered[2] = Some(p0 + delta);
+                filtered[3] = Some(q0 - delta);
+            } else {
+                filtered[2] = Some((2 * p1 + p0 + q1 + 2) >> 2);
+                filtered[3] = Some((2 * q1 + q0 + p1 + 2) >> 2);
+            }
+        } else {
+            let (p2, q2) = (sample(-3), sample(2));
+            let ap = (p2 - p0).abs() < beta;
+            let aq = (q2 - q0).abs() < beta;
+            if bs < 4 {
+                let tc0 = TC0[index_a][bs as usize - 1] as i32;
+                let tc = tc0 + ap as i32 + aq as i32;
+                let delta = ((((q0 - p0) << 2) + (p1 - q1) + 4) >> 3).clamp(-tc, tc);
+                filtered[2] = Some(p0 + delta);
+                filtered[3] = Some(q0 - delta);
+                if ap {
+                    filtered[1] =
+                        Some(p1 + ((p2 + ((p0 + q0 + 1) >> 1) - (p1 << 1)) >> 1).clamp(-tc0, tc0));
+                }
+                if aq {
+                    filtered[4] =
+                        Some(q1 + ((q2 + ((p0 + q0 + 1) >> 1) - (q1 << 1)) >> 1).clamp(-tc0, tc0));
+                }
+            } else {
+                let strong = (p0 - q0).abs() < (alpha >> 2) + 2;
+                if ap && strong {
+                    let p3 = sample(-4);
+                    filtered[2] = Some((p2 + 2 * p1 + 2 * p0 + 2 * q0 + q1 + 4) >> 3);
+                    filtered[1] = Some((p2 + p1 + p0 + q0 + 2) >> 2);
+                    filtered[0] = Some((2 * p3 + 3 * p2 + p1 + p0 + q0 + 4) >> 3);
+                } else {
+                    filtered[2] = Some((2 * p1 + p0 + q1 + 2) >> 2);
+                }
+                if aq && strong {
+                    let q3 = sample(3);
+                    filtered[3] = Some((p1 + 2 * p0 + 2 * q0 + 2 * q1 + q2 + 4) >> 3);
+                    filtered[4] = Some((p0 + q0 + q1 + q2 + 2) >> 2);
+                    filtered[5] = Some((2 * q3 + 3 * q2 + q1 + q0 + p0 + 4) >> 3);
+                } else {
+                    filtered[3] = Some((2 * q1 + q0 + p1 + 2) >> 2);
+                }
+            }
+        }
+
+        // p2, p1, p0, q0, q1, q2
+        for (i, value) in (-3..3).zip(filtered) {
+            if let Some(value) = value {
+                data[(q0_at as isize + i * across as isize) as usize] = value.clamp(0, 255) as u8;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mb(mb_type: MbType, qp: i32) -> MbInfo {
+        MbInfo {
+            decoded: true,
+            mb_type,
+            qp,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_pcm_edge_uses_qp_zero() {
+        let sps = Sps::default();
+        let pcm = mb(MbType::IPcm, 30);
+        let intra = mb(MbType::INxN, 26);
+        assert_eq!(filter_qp(&pcm, &sps), 0);
+        assert_eq!(filter_qp(&intra, &sps), 26);
+
+        // Between two QP 26 macroblocks the edge is filtered
+        let both = edge_thresholds(26, 26, 0, 0);
+        assert_eq!((both.alpha, both.beta), (15, 6));
+
+        // Next to I_PCM, qPav drops to 13, where alpha' and beta' are 0
+        let edge = edge_thresholds(filter_qp(&pcm, &sps), filter_qp(&intra, &sps), 0, 0);
+        assert_eq!(
+            edge,
+            EdgeThresholds {
+                index_a: 13,
+                alpha: 0,
+                beta: 0
+            }
+        );
+
+        // The slice offsets move indexA and indexB, clipped to 0..=51
+        let edge = edge_thresholds(0, 26, 6, 6);
+        assert_eq!((edge.index_a, edge.alpha, edge.beta), (25, 13, 4));
+        assert_eq!(edge_thresholds(51, 51, 6, 6).index_a, 51);
+    }
+
+    #[test]
+    fn test_lossless_macroblocks_use_qp_zero() {
+        let sps = Sps {
+            qpprime_y_zero_transform_bypass_flag: true,
+            bit_depth_luma_minus8: 2,
+            ..Default::default()
+        };
+        // QP'Y = QPY + QpBdOffsetY, so QPY -12 is lossless at 10 bits
+        assert_eq!(filter_qp(&mb(MbType::INxN, -12), &sps), 0);
+        assert_eq!(filter_qp(&mb(MbType::INxN, -11), &sps), -11);
+
+        let lossy = Sps {
+            qpprime_y_zero_transform_bypass_flag: false,
+            ..sps
+        };
+        assert_eq!(filter_qp(&mb(MbType::INxN, -12), &lossy), -12);
+    }
+
+    #[test]
+    fn test_boundary_strength() {
+        let intra = mb(MbType::INxN, 26);
+        let inter = MbInfo {
+            ref_pic_l0: [4; 4],
+            ..mb(MbType::PL016x16, 26)
+        };
+        let bs = |p: &MbInfo, q: &MbInfo, mb_edge, vertical, field| {
+            boundary_strength(
+                (p, 3, p.mb_type.is_intra()),
+                (q, 0, q.mb_type.is_intra()),
+                mb_edge,
+                vertical,
+                field,
+            )
+        };
+
+        // Intra macroblock edges are 4, except horizontal ones in a field, and internal edges 3
+        assert_eq!(bs(&intra, &inter, true, true, false), 4);
+        assert_eq!(bs(&inter, &intra, true, false, false), 4);
+        assert_eq!(bs(&intra, &inter, true, true, true), 4);
+        assert_eq!(bs(&intra, &inter, true, false, true), 3);
+        assert_eq!(bs(&intra, &intra, false, true, false), 3);
+
+        // Coefficients on either side
+        let coded = MbInfo {
+            total_coeff: [1; 16],
+            ..inter
+        };
+        assert_eq!(bs(&inter, &coded, true, true, false), 2);
+
+        // The same vector and picture leave the edge alone, whatever refIdxL0 selected it
+        let other_index = MbInfo {
+            ref_idx_l0: [1; 4],
+            ..inter
+        };
+        assert_eq!(bs(&inter, &other_index, true, true, false), 0);
+        let other_picture = MbInfo {
+            ref_pic_l0: [2; 4],
+            ..inter
+        };
+        assert_eq!(bs(&inter, &other_picture, true, true, false), 1);
+
+        // A vector a whole sample apart horizontally, or half a sample vertically in a field
+        let moved = |mv| MbInfo {
+            mv_l0: [mv; 16],
+            ..inter
+        };
+        assert_eq!(bs(&inter, &moved([-4, 0]), true, true, false), 1);
+        assert_eq!(bs(&inter, &moved([3, 3]), true, true, false), 0);
+        assert_eq!(bs(&inter, &moved([0, 2]), true, true, false), 0);
+        assert_eq!(bs(&inter, &moved([0, 2]), true, true, true), 1);
+    }
+
+    #[test]
+    fn test_filter_edge_bs4() {
+        let filter = |chroma_style| {
+            let mut plane = Plane::new(8, 1);
+            plane.data[..8].copy_from_slice(&[60, 60, 60, 60, 66, 66, 66, 66]);
+            // indexA 30: alpha 25, beta 8
+            let thresholds = edge_thresholds(30, 30, 0, 0);
+            filter_edge(
+                &mut plane,
+                (4, 0),
+                true,
+                1,
+                ([4; 4], 1),
+                thresholds,
+                chroma_style,
+            );
+            plane.row(0).to_vec()
+        };
+
+        // |p0 - q0| < (alpha >> 2) + 2, so luma takes the strong filter on both sides
+        assert_eq!(filter(false), [60, 61, 62, 62, 64, 65, 65, 66]);
+        assert_eq!(filter(true), [60, 60, 60, 62, 65, 66, 66, 66]);
+    }
+}
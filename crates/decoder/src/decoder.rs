@@ -0,0 +1,2469 @@
+use crate::color::{ColorMatrix, ColorRange};
+use crate::deblock::{SliceFilter, deblock_picture};
+use crate::dpb::{Dpb, Reference};
+use crate::frame::{ChromaFormat, CropWindow, OutputFormat, YuvFrame};
+use crate::macroblock::MbType;
+use crate::picture::{DecodedPicture, Picture, PictureStructure};
+use crate::poc::PocState;
+use crate::scratch::DecodeScratch;
+use crate::slice_data::{SliceContext, decode_slice_data};
+use crate::slice_group::derive_slice_group_map;
+use crate::transform::weight_scales_4x4;
+use anyhow::{Context, Result, anyhow};
+use bitstream::annexb::{find_last_start_code, split_annexb_nalus};
+use bitstream::rbsp::ebsp_to_rbsp_cow;
+use bitstream::{BitReader, NalUnitType, NaluHeader};
+use parser::sps::SpsExtension;
+use parser::{
+    AccessUnitDelimiter, ExtensionData, Nalu, ParameterSetStore, Pps, ScalingMatrix, SeiMessage,
+    SliceHeader, SliceType, Sps, is_new_picture, parse_nalu, parse_sei,
+};
+use std::cell::OnceCell;
+use std::collections::VecDeque;
+
+/// Default cap on luma samples per picture: MaxFS of level 6.2 (Table A-1), 139264 macroblocks
+const DEFAULT_MAX_LUMA_SAMPLES: usize = 139_264 * 256;
+/// num_ref_idx_l0_active_minus1 is at most 31 (7.4.3)
+const MAX_NUM_REF_IDX_ACTIVE: usize = 32;
+
+/// How the decoder handles a slice whose reference picture list can't be filled, such as after a
+/// reference picture was lost in transmission
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConcealmentMode {
+    /// Fail the slice once it uses a missing reference
+    #[default]
+    Error,
+    /// Stand in the nearest available reference, or gray when there is none
+    SubstituteNearest,
+    /// Stand in a mid-gray picture
+    Gray,
+}
+
+/// What a slice header said, reported to the `on_slice` callback
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SliceDiagnostics {
+    pub slice_type: SliceType,
+    pub first_mb_in_slice: u32,
+    /// SliceQPY at the start of the slice
+    pub qp: i32,
+    /// num_ref_idx_l0_active_minus1 + 1, or 0 for slices without inter prediction
+    pub num_ref_idx_l0_active: u32,
+    /// num_ref_idx_l1_active_minus1 + 1, or 0 for slices other than B
+    pub num_ref_idx_l1_active: u32,
+    /// Whether `disable_deblocking_filter_idc` asks for the deblocking filter on the slice
+    pub deblocking_filter_enabled: bool,
+}
+
+/// Which kind of parameter set a `ParamSetChange` is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamSetKind {
+    Sps,
+    Pps,
+}
+
+/// A parameter set a slice activated in place of the previously active one, reported to the
+/// `on_parameter_set_change` callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParamSetChange {
+    pub kind: ParamSetKind,
+    /// seq_parameter_set_id or pic_parameter_set_id
+    pub id: u32,
+    /// Whether a set with the same id was active before with different contents, rather than
+    /// this being the first activation or a switch to another id
+    pub changed: bool,
+}
+
+/// A boxed `on_parameter_set_change` callback
+struct ParamSetCallback(Box<dyn Fn(&ParamSetChange) + Send>);
+
+impl std::fmt::Debug for ParamSetCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ParamSetCallback")
+    }
+}
+
+/// A boxed `on_slice` callback, wrapped so `Decoder` can still derive `Debug`
+struct SliceCallback(Box<dyn Fn(&SliceDiagnostics) + Send>);
+
+impl std::fmt::Debug for SliceCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SliceCallback")
+    }
+}
+
+/// Where decoding stands relative to a recovery point, for players that joined a stream mid-way
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SeekHint {
+    /// recovery_frame_cnt of the recovery point SEI still being waited on
+    pub recovery_frame_cnt: Option<u32>,
+    /// Index, in decoding order, of the first picture guaranteed to be correct
+    pub first_valid_frame: Option<u64>,
+    /// Whether the most recently decoded picture is guaranteed to be correct
+    pub output_valid: bool,
+}
+
+/// Counters accumulated since the decoder was created or its stats were last taken, for
+/// reporting over time windows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DecoderStats {
+    /// NALUs passed to the decoder, whether decoded or skipped
+    pub nalus: u64,
+    /// Size of those NALUs, header byte included
+    pub bytes: u64,
+    /// Pictures completed
+    pub pictures: u64,
+    /// Macroblocks of the completed pictures by prediction: intra (I_PCM included), inter, and
+    /// P_Skip
+    pub intra_mbs: u64,
+    pub inter_mbs: u64,
+    pub skipped_mbs: u64,
+}
+
+impl DecoderStats {
+    /// Zeroes every counter
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Stream parameters from the first SPS, as returned by `Decoder::probe`
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamInfo {
+    /// Luma width after frame cropping
+    pub width: usize,
+    /// Luma height of a frame after frame cropping
+    pub height: usize,
+    /// profile_idc
+    pub profile: u8,
+    /// level_idc
+    pub level: u8,
+    pub chroma_format: ChromaFormat,
+    /// BitDepthY
+    pub bit_depth: u8,
+    /// Frames per second from the VUI timing info, time_scale / (2 * num_units_in_tick) (E.2.1)
+    pub frame_rate: Option<f64>,
+    /// Whether pictures may be coded as fields, i.e. frame_mbs_only_flag is 0
+    pub interlaced: bool,
+    /// mb_adaptive_frame_field_flag
+    pub mbaff: bool,
+}
+
+impl StreamInfo {
+    /// Checks the stream against what the decoder implements, so callers can turn a stream away
+    /// before decoding any of it
+    pub fn is_decodable(&self) -> Result<(), UnsupportedFeature> {
+        // Scalable (Annex G) and multiview (Annex H) profiles
+        if matches!(self.profile, 83 | 86 | 118 | 128 | 134 | 135 | 138 | 139) {
+            return Err(UnsupportedFeature::Profile(self.profile));
+        }
+        if !matches!(
+            self.chroma_format,
+            ChromaFormat::Yuv420 | ChromaFormat::Monochrome
+        ) {
+            return Err(UnsupportedFeature::ChromaFormat(self.chroma_format));
+        }
+        if self.bit_depth != 8 {
+            return Err(UnsupportedFeature::BitDepth(self.bit_depth));
+        }
+        if self.mbaff {
+            return Err(UnsupportedFeature::Mbaff);
+        }
+        Ok(())
+    }
+}
+
+/// A coding feature the decoder doesn't implement, as reported by `StreamInfo::is_decodable`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnsupportedFeature {
+    /// An SVC or MVC profile_idc
+    Profile(u8),
+    ChromaFormat(ChromaFormat),
+    BitDepth(u8),
+    /// Macroblock-adaptive frame/field coding
+    Mbaff,
+}
+
+impl std::fmt::Display for UnsupportedFeature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnsupportedFeature::Profile(profile) => {
+                write!(f, "profile_idc {} is not supported", profile)
+            }
+            UnsupportedFeature::ChromaFormat(format) => {
+                write!(f, "{:?} chroma is not supported", format)
+            }
+            UnsupportedFeature::BitDepth(depth) => {
+                write!(f, "{}-bit samples are not supported", depth)
+            }
+            UnsupportedFeature::Mbaff => write!(f, "MBAFF is not supported"),
+        }
+    }
+}
+
+impl std::error::Error for UnsupportedFeature {}
+
+/// Top-level decoding state. NALUs are fed in decoding order and routed on their `nal_unit_type`;
+/// decoded pictures come out of `next_frame` in output order.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    store: ParameterSetStore,
+    /// SPS of the coded video sequence being decoded
+    active_sps: Option<Sps>,
+    /// PPS of the last slice
+    active_pps: Option<Pps>,
+    /// Picture whose slices are still arriving
+    current: Option<Picture>,
+    dpb: Dpb,
+    poc: PocState,
+    /// Per-macroblock temporaries, reused across every macroblock and picture
+    scratch: Box<DecodeScratch>,
+    output: VecDeque<DecodedPicture>,
+    decoded_frames: u64,
+    skipped_nalus: u64,
+    /// Pictures larger than this are refused before their planes are allocated
+    max_luma_samples: Option<usize>,
+    /// Caps the DPB size the SPS asks for
+    max_dpb_frames: Option<usize>,
+    concealment: ConcealmentMode,
+    /// Decode IDR pictures only, skipping every other slice
+    keyframes_only: bool,
+    /// Require well-formed rbsp_trailing_bits after every SPS and PPS
+    strict_trailing_bits: bool,
+    output_format: OutputFormat,
+    on_slice: Option<SliceCallback>,
+    on_parameter_set_change: Option<ParamSetCallback>,
+    /// From the last recovery point SEI, until output is correct again
+    recovery_frame_cnt: Option<u32>,
+    first_valid_frame: Option<u64>,
+    /// SEI RBSPs of the access unit whose first slice hasn't arrived yet
+    pending_sei: Vec<Vec<u8>>,
+    /// Access unit delimiter in front of that first slice
+    pending_aud: Option<AccessUnitDelimiter>,
+    stats: DecoderStats,
+    /// Headers of the last slice, to tell where the next picture starts
+    last_slice: Option<(SliceHeader, NaluHeader)>,
+    /// Set by `resync` until an IDR picture or a recovery point is reached
+    resyncing: bool,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Caps the size of the pictures the decoder allocates, in luma samples
+    pub fn with_max_luma_samples(mut self, max_luma_samples: usize) -> Self {
+        self.max_luma_samples = Some(max_luma_samples);
+        self
+    }
+
+    /// Sets how missing reference pictures are concealed
+    pub fn with_concealment(mut self, concealment: ConcealmentMode) -> Self {
+        self.concealment = concealment;
+        self
+    }
+
+    /// Decodes IDR pictures only, for thumbnails and scrubbing. Non-IDR slices are skipped
+    /// without being parsed, and IDR pictures aren't kept for reference since nothing that's
+    /// decoded predicts from them. Parameter sets are still tracked.
+    pub fn with_keyframes_only(mut self, keyframes_only: bool) -> Self {
+        self.keyframes_only = keyframes_only;
+        self
+    }
+
+    /// Rejects an SPS or PPS whose syntax isn't followed by exactly rbsp_trailing_bits (7.3.2.11).
+    /// Off by default, since encoders that pad parameter sets are common and otherwise harmless.
+    pub fn with_strict_trailing_bits(mut self, strict: bool) -> Self {
+        self.strict_trailing_bits = strict;
+        self
+    }
+
+    /// Caps the DPB at `max_dpb_frames` frames from the next picture on, whatever the level and
+    /// VUI allow, for predictable memory use. Pictures are bumped to the output earlier and fewer
+    /// reference frames are kept, so a stream that needs more buffering than this can be output
+    /// out of order or predict from pictures that were already dropped.
+    pub fn set_max_dpb_frames(&mut self, max_dpb_frames: usize) {
+        self.max_dpb_frames = Some(max_dpb_frames.max(1));
+    }
+
+    /// Sets the layout of the `buffer` of the pictures handed out from now on. RGB output uses
+    /// the BT.601 matrix.
+    pub fn set_output_format(&mut self, format: OutputFormat) {
+        self.output_format = format;
+    }
+
+    /// Calls `callback` with a summary of every slice header, right after it's parsed
+    pub fn on_slice(&mut self, callback: impl Fn(&SliceDiagnostics) + Send + 'static) {
+        self.on_slice = Some(SliceCallback(Box::new(callback)));
+    }
+
+    /// Calls `callback` whenever a slice activates an SPS or PPS other than the one already
+    /// active, including when a set is re-sent under the same id with different contents
+    pub fn on_parameter_set_change(&mut self, callback: impl Fn(&ParamSetChange) + Send + 'static) {
+        self.on_parameter_set_change = Some(ParamSetCallback(Box::new(callback)));
+    }
+
+    /// Whether the output can be trusted yet, from IDR pictures and recovery point SEIs (D.2.8)
+    pub fn seek_hint(&self) -> SeekHint {
+        SeekHint {
+            recovery_frame_cnt: self.recovery_frame_cnt,
+            first_valid_frame: self.first_valid_frame,
+            output_valid: self.output_valid(),
+        }
+    }
+
+    /// Starts over after a seek to an arbitrary point in the stream. Every picture that's still
+    /// pending or not yet taken is dropped, and slices are skipped until the next IDR picture or
+    /// picture with a recovery point SEI, where decoding resumes. Parameter sets are kept.
+    pub fn resync(&mut self) {
+        self.current = None;
+        self.dpb.clear();
+        self.output.clear();
+        self.pending_sei.clear();
+        self.pending_aud = None;
+        self.last_slice = None;
+        self.recovery_frame_cnt = None;
+        self.first_valid_frame = None;
+        self.resyncing = true;
+    }
+
+    /// Every parameter set received so far
+    pub fn parameter_sets(&self) -> &ParameterSetStore {
+        &self.store
+    }
+
+    /// Statistics accumulated since the decoder was created or `take_stats` was last called
+    pub fn stats(&self) -> &DecoderStats {
+        &self.stats
+    }
+
+    /// Returns the accumulated statistics and starts a new window. Unlike the lifetime counters
+    /// such as `decoded_frame_count`, these start over from zero.
+    pub fn take_stats(&mut self) -> DecoderStats {
+        std::mem::take(&mut self.stats)
+    }
+
+    /// Number of pictures fully decoded so far
+    pub fn decoded_frame_count(&self) -> u64 {
+        self.decoded_frames
+    }
+
+    /// Number of decoded pictures that haven't been taken with `next_frame` yet, whether still
+    /// held for reordering or already in the output queue. The picture being decoded isn't
+    /// counted until it's complete.
+    pub fn pending_frames(&self) -> usize {
+        self.dpb.pending_output() + self.output.len()
+    }
+
+    /// Number of NALUs that were ignored, either because the decoder has no use for their type or
+    /// because they carry no payload
+    pub fn skipped_nalu_count(&self) -> u64 {
+        self.skipped_nalus
+    }
+
+    /// Scans an Annex B byte stream up to its first SPS and reports the stream parameters it
+    /// sets, without decoding any picture
+    pub fn probe(data: &[u8]) -> Result<StreamInfo> {
+        for data in split_annexb_nalus(data) {
+            if NaluHeader::new(data[0])?.kind() != NalUnitType::Sps {
+                continue;
+            }
+            let rbsp = ebsp_to_rbsp_cow(&data[1..]);
+            let sps = Sps::parse(&mut BitReader::from_bytes(&rbsp))?;
+            let chroma_format = ChromaFormat::from_idc(sps.chroma_array_type())?;
+            let crop = crop_window(&sps, chroma_format, false);
+            let (width, height) = sps.coded_resolution();
+            let (width, height) = (width as usize, height as usize);
+            let frame_rate = sps
+                .vui_parameters
+                .as_ref()
+                .filter(|vui| vui.timing_info_present_flag && vui.num_units_in_tick > 0)
+                .map(|vui| vui.time_scale as f64 / (2.0 * vui.num_units_in_tick as f64));
+
+            return Ok(StreamInfo {
+                width: width.saturating_sub(crop.left + crop.right),
+                height: height.saturating_sub(crop.top + crop.bottom),
+                profile: sps.profile_idc,
+                level: sps.level_idc,
+                chroma_format,
+                bit_depth: 8 + sps.bit_depth_luma_minus8 as u8,
+                frame_rate,
+                interlaced: !sps.frame_mbs_only_flag,
+                mbaff: sps.mb_adaptive_frame_field_flag,
+            });
+        }
+        Err(anyhow!("No SPS found in the stream"))
+    }
+
+    /// Decodes a single NALU, given without its start code or length prefix
+    pub fn decode_nalu(&mut self, data: &[u8]) -> Result<()> {
+        self.stats.nalus += 1;
+        self.stats.bytes += data.len() as u64;
+        let nalu = parse_nalu(data)?;
+        let mut reader = BitReader::from_bytes(&nalu.rbsp);
+
+        match nalu.header.kind() {
+            // A slice NALU cut down to its header byte carries nothing to decode, so it's dropped
+            // rather than failing the whole access unit
+            NalUnitType::NonIdrSlice | NalUnitType::IdrSlice if nalu.rbsp.is_empty() => {
+                self.skipped_nalus += 1
+            }
+            NalUnitType::NonIdrSlice if self.resyncing && !self.recovery_point_pending() => {
+                self.pending_sei.clear();
+                self.pending_aud = None;
+                self.skipped_nalus += 1;
+            }
+            NalUnitType::NonIdrSlice if self.keyframes_only => {
+                self.finish_picture();
+                // SEI messages of the skipped access unit don't apply to the next one
+                self.pending_sei.clear();
+                self.pending_aud = None;
+                self.skipped_nalus += 1;
+            }
+            NalUnitType::NonIdrSlice | NalUnitType::IdrSlice => {
+                self.resyncing = false;
+                self.decode_slice(&nalu)?
+            }
+            NalUnitType::Sps => {
+                self.finish_picture();
+                let sps = Sps::parse(&mut reader)?;
+                self.check_trailing_bits(&sps.extension_data, &mut reader)?;
+                self.store.insert_sps(sps);
+            }
+            NalUnitType::SubsetSps => {
+                self.finish_picture();
+                self.store
+                    .insert_subset_sps(Sps::parse_subset(&mut reader)?);
+            }
+            NalUnitType::SpsExtension => {
+                self.finish_picture();
+                self.store
+                    .insert_sps_extension(SpsExtension::parse(&mut reader)?);
+            }
+            NalUnitType::Pps => {
+                self.finish_picture();
+                let pps = Pps::parse(&mut reader, &self.store)?;
+                self.check_trailing_bits(&pps.extension_data, &mut reader)?;
+                self.store.insert_pps(pps);
+            }
+            // Prefix NALUs only describe the SVC/MVC layer of the slice that follows, which the
+            // base layer decode doesn't need
+            NalUnitType::PrefixNalu => self.skipped_nalus += 1,
+            // Picture timing depends on the SPS the access unit's first slice activates, so SEI
+            // messages are parsed once that's known
+            NalUnitType::Sei => {
+                self.finish_picture();
+                self.pending_sei.push(nalu.rbsp);
+            }
+            // These start a new access unit (7.4.1.2.3), so the pending picture is complete
+            NalUnitType::AccessUnitDelimiter => {
+                self.finish_picture();
+                self.pending_aud = Some(AccessUnitDelimiter::parse(&mut reader)?);
+            }
+            NalUnitType::EndOfSequence | NalUnitType::EndOfStream => self.finish_picture(),
+            _ => self.skipped_nalus += 1,
+        }
+
+        Ok(())
+    }
+
+    /// Decodes every NALU of an Annex B byte stream
+    pub fn decode_annexb(&mut self, data: &[u8]) -> Result<()> {
+        for nalu in split_annexb_nalus(data) {
+            self.decode_nalu(nalu)?;
+        }
+        Ok(())
+    }
+
+    /// Decodes NALUs as a demuxer yields them, without start codes or length prefixes, and returns
+    /// the pictures that became ready for output. The last picture stays open for slices still to
+    /// come, so it's only returned by a later call or after `flush`.
+    pub fn feed_nalus<'a, I: IntoIterator<Item = &'a [u8]>>(
+        &mut self,
+        nalus: I,
+    ) -> Result<Vec<DecodedPicture>> {
+        for nalu in nalus {
+            self.decode_nalu(nalu)?;
+        }
+        Ok(std::iter::from_fn(|| self.pop_output()).collect())
+    }
+
+    /// Decodes the NALUs of one complete access unit, as MP4 samples and Matroska blocks carry
+    /// them, and returns the next picture in output order. Access unit boundaries aren't
+    /// detected: the picture is completed once the last NALU is decoded. While reordering holds
+    /// pictures back, this returns None and `flush` releases them at the end of the stream.
+    pub fn decode_access_unit(&mut self, au: &[&[u8]]) -> Result<Option<DecodedPicture>> {
+        for nalu in au {
+            self.decode_nalu(nalu)?;
+        }
+        self.finish_picture();
+        Ok(self.pop_output())
+    }
+
+    /// Decodes the complete NALUs at the start of an Annex B buffer, returning how many bytes were
+    /// consumed and the frames that became ready. The NALU after the last start code might still
+    /// be incomplete, so it's left for the caller to feed again with the bytes that follow it.
+    pub fn feed_partial(&mut self, data: &[u8]) -> Result<(usize, Vec<YuvFrame>)> {
+        let consumed = find_last_start_code(data).unwrap_or(0);
+        self.decode_annexb(&data[..consumed])?;
+        let frames = self.output.drain(..).map(|picture| picture.frame).collect();
+        Ok((consumed, frames))
+    }
+
+    /// Completes the pending picture and moves every picture still held for reordering to the
+    /// output queue. Call once the stream has ended.
+    pub fn flush(&mut self) {
+        self.finish_picture();
+        self.dpb.flush(&mut self.output);
+    }
+
+    /// Takes the next picture in output order, if one is ready
+    pub fn next_frame(&mut self) -> Option<DecodedPicture> {
+        self.pop_output()
+    }
+
+    /// Takes the next picture off the output queue, converting it to the output format
+    fn pop_output(&mut self) -> Option<DecodedPicture> {
+        let mut picture = self.output.pop_front()?;
+        picture.buffer = picture
+            .frame
+            .to_output_format(self.output_format, ColorMatrix::default());
+        Some(picture)
+    }
+
+    /// Makes `sps` and `pps` the active sets once a slice has been accepted into its picture, and
+    /// tells the `on_parameter_set_change` callback about those that differ from the active ones.
+    /// Both are updated together here, so a slice whose activation fails leaves them as they
+    /// were and the next slice reports the change again only when it succeeds.
+    fn activate(&mut self, sps: &Sps, pps: &Pps) {
+        let sps_change = match &self.active_sps {
+            Some(active) if active == sps => None,
+            active => Some(ParamSetChange {
+                kind: ParamSetKind::Sps,
+                id: sps.seq_parameter_set_id,
+                changed: active
+                    .as_ref()
+                    .is_some_and(|active| active.seq_parameter_set_id == sps.seq_parameter_set_id),
+            }),
+        };
+        let pps_change = match &self.active_pps {
+            Some(active) if active == pps => None,
+            active => Some(ParamSetChange {
+                kind: ParamSetKind::Pps,
+                id: pps.pic_parameter_set_id,
+                changed: active
+                    .as_ref()
+                    .is_some_and(|active| active.pic_parameter_set_id == pps.pic_parameter_set_id),
+            }),
+        };
+        if sps_change.is_some() {
+            self.active_sps = Some(sps.clone());
+        }
+        if pps_change.is_some() {
+            self.active_pps = Some(pps.clone());
+        }
+
+        if let Some(ParamSetCallback(callback)) = &self.on_parameter_set_change {
+            for change in sps_change.iter().chain(&pps_change) {
+                callback(change);
+            }
+        }
+    }
+
+    fn decode_slice(&mut self, nalu: &Nalu) -> Result<()> {
+        let mut reader = BitReader::from_bytes(&nalu.rbsp);
+        let header = SliceHeader::parse(&mut reader, &nalu.header, &self.store)?;
+
+        // A slice that starts a picture can be lost, so the headers are compared as well
+        let new_picture = header.first_mb_in_slice == 0
+            || self.last_slice.as_ref().is_some_and(|(prev, prev_nal)| {
+                is_new_picture(prev, &header, prev_nal, &nalu.header)
+            });
+        if new_picture {
+            self.finish_picture();
+        }
+        self.last_slice = Some((header.clone(), nalu.header));
+
+        let active = self.store.activate(header.pic_parameter_set_id)?;
+        let (sps, pps) = (active.sps.clone(), active.pps.clone());
+
+        if let Some(SliceCallback(callback)) = &self.on_slice {
+            let slice_type = header.kind();
+            callback(&SliceDiagnostics {
+                slice_type,
+                first_mb_in_slice: header.first_mb_in_slice,
+                qp: pps.pic_init_qp() + header.slice_qp_delta,
+                num_ref_idx_l0_active: if slice_type.is_intra() {
+                    0
+                } else {
+                    header.num_ref_idx_l0_active_minus1 + 1
+                },
+                num_ref_idx_l1_active: if slice_type == SliceType::B {
+                    header.num_ref_idx_l1_active_minus1 + 1
+                } else {
+                    0
+                },
+                deblocking_filter_enabled: header.disable_deblocking_filter_idc != 1,
+            });
+        }
+
+        // MbaffFrameFlag (7-25) changes macroblock addressing, so such frames can't be decoded
+        // as progressive ones
+        if sps.mb_adaptive_frame_field_flag && !header.field_pic_flag {
+            return Err(anyhow!("MBAFF is not yet supported"));
+        }
+        // B_Skip, B_Direct_16x16 and bi-predicted partitions have no prediction path yet
+        if header.kind() == SliceType::B {
+            return Err(anyhow!("B slices are not supported"));
+        }
+
+        if self.current.is_none() {
+            self.start_picture(&sps, &header, nalu)?;
+        }
+        self.activate(&sps, &pps);
+        let Some(picture) = self.current.as_mut() else {
+            unreachable!("start_picture always sets the current picture");
+        };
+        picture.add_slice_type(&header)?;
+
+        // Initial RefPicList0 for P slices (8.2.4.2.1): short-term frames by descending PicNum
+        let num_refs = header.num_ref_idx_l0_active_minus1 as usize + 1;
+        let mut ref_list0: Vec<_> = self
+            .dpb
+            .build_ref_list_p(header.frame_num, sps.max_frame_num())
+            .into_iter()
+            .take(num_refs)
+            .map(|e| &e.picture)
+            .collect();
+
+        let gray = OnceCell::new();
+        if matches!(header.kind(), SliceType::P | SliceType::SP) {
+            fill_missing_refs(&mut ref_list0, num_refs, self.concealment, || {
+                gray.get_or_init(|| gray_picture(picture))
+            });
+        }
+
+        let mut ctx = SliceContext {
+            sps: &sps,
+            pps: &pps,
+            header: &header,
+            slice_type: header.kind(),
+            slice_num: picture.slice_count,
+            qp: pps.pic_init_qp() + header.slice_qp_delta,
+            ref_list0,
+            weight_scales: weight_scales_4x4(&ScalingMatrix::resolve(&sps, &pps)),
+            slice_group_map: if pps.num_slice_groups_minus1 > 0 {
+                derive_slice_group_map(&pps, &sps, &header)?
+            } else {
+                Vec::new()
+            },
+        };
+        picture.slice_count += 1;
+        picture
+            .slice_filters
+            .push(SliceFilter::from_header(&header));
+        decode_slice_data(&mut reader, &mut ctx, picture, &mut self.scratch)
+    }
+
+    /// Allocates the picture the slice `header` starts and derives its POC
+    fn start_picture(&mut self, sps: &Sps, header: &SliceHeader, nalu: &Nalu) -> Result<()> {
+        check_picture_size(
+            sps,
+            self.max_luma_samples.unwrap_or(DEFAULT_MAX_LUMA_SAMPLES),
+        )?;
+        let idr = nalu.header.kind() == NalUnitType::IdrSlice;
+        if !idr
+            && self
+                .active_sps
+                .as_ref()
+                .is_some_and(|active| active.requires_reinit(sps))
+        {
+            return Err(anyhow!(
+                "SPS {} changes the picture format without an IDR picture",
+                sps.seq_parameter_set_id
+            ));
+        }
+        // Every picture of the previous sequence is bumped out before the IDR, so a change of
+        // format leaves nothing behind that would mix sizes. With no_output_of_prior_pics_flag
+        // they're discarded instead (C.4.4).
+        if idr {
+            if header
+                .dec_ref_pic_marking
+                .as_ref()
+                .is_some_and(|marking| marking.no_output_of_prior_pics_flag)
+            {
+                self.dpb.clear();
+            } else {
+                self.dpb.flush(&mut self.output);
+                self.dpb.mark_all_unused();
+            }
+        }
+
+        let mut pic_struct = None;
+        for rbsp in std::mem::take(&mut self.pending_sei) {
+            for message in parse_sei(&rbsp, Some(sps))? {
+                match message {
+                    SeiMessage::PicTiming(timing) => pic_struct = timing.pic_struct,
+                    SeiMessage::RecoveryPoint(recovery) if !self.output_valid() => {
+                        self.recovery_frame_cnt = Some(recovery.recovery_frame_cnt)
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // Output is correct from an IDR picture, or recovery_frame_cnt frames after the picture
+        // a recovery point SEI is attached to
+        let index = self.decoded_frames;
+        if idr && self.first_valid_frame.is_none_or(|first| first > index) {
+            self.first_valid_frame = Some(index);
+        } else if let Some(count) = self.recovery_frame_cnt
+            && self.first_valid_frame.is_none()
+        {
+            self.first_valid_frame = Some(index + count as u64);
+        }
+
+        let (capacity, max_reorder) = dpb_limits(sps);
+        let capacity = self
+            .max_dpb_frames
+            .map_or(capacity, |max| capacity.min(max));
+        self.dpb.set_limits(capacity, max_reorder);
+        let vui = sps.vui_parameters.as_ref();
+
+        let structure = PictureStructure::from_header(header);
+        let chroma_format = ChromaFormat::from_idc(sps.chroma_array_type())?;
+        let width_in_mbs = sps.pic_width_in_mbs() as usize;
+        // Halved for a field
+        let height_in_mbs =
+            sps.frame_height_in_mbs() as usize / (1 + structure.is_field() as usize);
+
+        let mut frame = YuvFrame::new(width_in_mbs * 16, height_in_mbs * 16, chroma_format);
+        frame.crop = crop_window(sps, chroma_format, structure.is_field());
+        if frame.crop.left + frame.crop.right >= frame.width
+            || frame.crop.top + frame.crop.bottom >= frame.height
+        {
+            return Err(anyhow!("Frame cropping exceeds the picture size"));
+        }
+        if vui.is_some_and(|vui| vui.video_full_range_flag) {
+            frame.range = ColorRange::Full;
+        }
+
+        let mut picture = Picture::new(frame, width_in_mbs, height_in_mbs);
+        picture.poc = self.poc.compute(sps, header, &nalu.header)?;
+        picture.frame_num = header.frame_num;
+        // After memory_management_control_operation 5 the picture counts as POC 0 and frame_num
+        // 0 (8.2.1, 7.4.3). Everything before it is output first (C.4.4), while the references
+        // it may predict from are only dropped once it's decoded.
+        if header.has_mmco5() {
+            self.poc.reset_after_mmco5(header);
+            self.dpb.flush(&mut self.output);
+            picture.poc = 0;
+            picture.frame_num = 0;
+            picture.mmco5 = true;
+        }
+        picture.idr = idr;
+        picture.sps_id = sps.seq_parameter_set_id;
+        picture.pps_id = header.pic_parameter_set_id;
+        picture.reference = nalu.header.nal_ref_idc != 0;
+        picture.structure = structure;
+        picture.pic_struct = pic_struct;
+        picture.primary_pic_type = self.pending_aud.take().map(|aud| aud.primary_pic_type);
+
+        self.current = Some(picture);
+        Ok(())
+    }
+
+    /// Whether an SEI of the access unit whose first slice hasn't arrived holds a recovery point
+    fn recovery_point_pending(&self) -> bool {
+        self.pending_sei.iter().any(|rbsp| {
+            parse_sei(rbsp, None).is_ok_and(|messages| {
+                messages
+                    .iter()
+                    .any(|message| matches!(message, SeiMessage::RecoveryPoint(_)))
+            })
+        })
+    }
+
+    /// In strict mode, checks that a parameter set ends in rbsp_trailing_bits, with no extension
+    /// data in between
+    fn check_trailing_bits(
+        &self,
+        extension_data: &ExtensionData,
+        reader: &mut BitReader,
+    ) -> Result<()> {
+        if self.strict_trailing_bits {
+            if !extension_data.is_empty() {
+                return Err(anyhow!(
+                    "Parameter set has {} bits of unknown data before rbsp_trailing_bits",
+                    extension_data.bits
+                ));
+            }
+            reader
+                .read_rbsp_trailing_bits()
+                .context("Parameter set isn't followed by rbsp_trailing_bits")?;
+        }
+        Ok(())
+    }
+
+    /// Marks the pending picture (8.2.5) and stores it in the DPB
+    fn finish_picture(&mut self) {
+        let Some(mut picture) = self.current.take() else {
+            return;
+        };
+        if let (Some(sps), Some(pps)) = (&self.active_sps, &self.active_pps) {
+            deblock_picture(&mut picture, sps, pps);
+        }
+        self.stats.pictures += 1;
+        for info in picture.mb_info.iter().filter(|info| info.decoded) {
+            match info.mb_type {
+                MbType::PSkip => self.stats.skipped_mbs += 1,
+                mb_type if mb_type.is_intra() => self.stats.intra_mbs += 1,
+                _ => self.stats.inter_mbs += 1,
+            }
+        }
+
+        let reference = if picture.reference && !self.keyframes_only {
+            if picture.mmco5 {
+                self.dpb.mark_all_unused();
+            } else if !picture.idr {
+                let max_refs = self
+                    .active_sps
+                    .as_ref()
+                    .map_or(1, |sps| sps.max_num_ref_frames as usize);
+                // A DPB cut down to fewer frames than that has to drop references earlier
+                let max_refs = self
+                    .max_dpb_frames
+                    .map_or(max_refs, |max| max_refs.min(max));
+                self.dpb.sliding_window(max_refs);
+            }
+            Reference::ShortTerm
+        } else {
+            Reference::Unused
+        };
+
+        self.dpb
+            .store(picture.into_decoded(), reference, &mut self.output);
+        self.decoded_frames += 1;
+        if self.output_valid() {
+            self.recovery_frame_cnt = None;
+        }
+    }
+
+    fn output_valid(&self) -> bool {
+        self.first_valid_frame
+            .is_some_and(|first| self.decoded_frames > first)
+    }
+}
+
+/// DPB size in frames and the number of pictures that may wait for reordering. The VUI bitstream
+/// restriction gives both (E.2.1); without it the level's MaxDpbFrames bounds both.
+fn dpb_limits(sps: &Sps) -> (usize, usize) {
+    let capacity = (sps.max_dpb_frames().max(sps.max_num_ref_frames).max(1)) as usize;
+    // POC type 2 output order is decoding order
+    let max_reorder = if sps.pic_order_cnt_type == 2 {
+        0
+    } else {
+        sps.max_num_reorder_frames() as usize
+    };
+    (capacity, max_reorder)
+}
+
+/// The SPS frame cropping offsets in luma samples of a frame, or of a field when `field` is set
+fn crop_window(sps: &Sps, chroma_format: ChromaFormat, field: bool) -> CropWindow {
+    if !sps.frame_cropping_flag {
+        return CropWindow::default();
+    }
+    // CropUnitX and CropUnitY (7-19 to 7-22)
+    let (crop_x, crop_y) = match chroma_format {
+        ChromaFormat::Monochrome => (1, 1),
+        other => other.subsampling(),
+    };
+    // The offsets are in frame lines, of which a field holds every other one
+    let crop_y = crop_y * (2 - sps.frame_mbs_only_flag as usize) / (1 + field as usize);
+    CropWindow {
+        left: sps.frame_crop_left_offset as usize * crop_x,
+        right: sps.frame_crop_right_offset as usize * crop_x,
+        top: sps.frame_crop_top_offset as usize * crop_y,
+        bottom: sps.frame_crop_bottom_offset as usize * crop_y,
+    }
+}
+
+/// Pads `ref_list0` to `num_refs` entries, at most 32, as `concealment` asks. The list is in
+/// descending PicNum order, so its first entry is the nearest reference.
+fn fill_missing_refs<'a>(
+    ref_list0: &mut Vec<&'a DecodedPicture>,
+    num_refs: usize,
+    concealment: ConcealmentMode,
+    gray: impl FnOnce() -> &'a DecodedPicture,
+) {
+    let num_refs = num_refs.min(MAX_NUM_REF_IDX_ACTIVE);
+    if ref_list0.len() >= num_refs {
+        return;
+    }
+    match (concealment, ref_list0.first()) {
+        (ConcealmentMode::Error, _) => {}
+        (ConcealmentMode::SubstituteNearest, Some(&nearest)) => ref_list0.resize(num_refs, nearest),
+        _ => ref_list0.resize(num_refs, gray()),
+    }
+}
+
+/// A mid-gray picture with the size, format and structure of `like`
+fn gray_picture(like: &Picture) -> DecodedPicture {
+    let mut frame = YuvFrame::new(
+        like.frame.width,
+        like.frame.height,
+        like.frame.chroma_format,
+    );
+    for plane in [&mut frame.y, &mut frame.cb, &mut frame.cr] {
+        plane.data.fill(128);
+    }
+    let mut gray = Picture::new(frame, 0, 0);
+    gray.structure = like.structure;
+    gray.into_decoded()
+}
+
+/// Rejects an SPS whose frame size is empty or above `max_luma_samples`, so a corrupt SPS
+/// fails at activation instead of when the frame planes are allocated
+fn check_picture_size(sps: &Sps, max_luma_samples: usize) -> Result<()> {
+    let width_in_mbs = sps.pic_width_in_mbs_minus1.wrapping_add(1) as usize;
+    let height_in_mbs = sps.frame_height_in_mbs() as usize;
+    if width_in_mbs == 0 || height_in_mbs == 0 {
+        return Err(anyhow!(
+            "SPS {} has an empty picture size",
+            sps.seq_parameter_set_id
+        ));
+    }
+
+    let luma_samples = width_in_mbs
+        .saturating_mul(height_in_mbs)
+        .saturating_mul(256);
+    if luma_samples > max_luma_samples {
+        return Err(anyhow!(
+            "SPS {} picture size {}x{} macroblocks exceeds the limit of {} luma samples",
+            sps.seq_parameter_set_id,
+            width_in_mbs,
+            height_in_mbs,
+            max_luma_samples
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        allocations, constrained_intra_pps_rbsp, default_scaling_sps_rbsp, i16x16_idr_slice_rbsp,
+        idr_slice_rbsp, interlaced_sps_rbsp, monochrome_sps_rbsp, nalu, p_skip_slice_rbsp,
+        p_slice_rbsp, pcm_idr_slice_rbsp, pcm_idr_slice_rbsp_at, pcm_mmco5_slice_rbsp,
+        pcm_poc_slice_rbsp, pcm_slice_rbsp, pcm_slice_rbsp_at, poc_sps_rbsp, poc_vui_sps_rbsp,
+        pps_rbsp, sps_rbsp, sps_rbsp_with_profile, write_i4x4_mb, write_i16x16_dc_mb,
+    };
+    use bitstream::BitWriter;
+    use parser::vui::VuiParameters;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_parameter_set_routing() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 4, 3)))?;
+        decoder.decode_nalu(&nalu(3, 15, &sps_rbsp_with_profile(83, 1, 8, 6)))?;
+        decoder.decode_nalu(&nalu(3, 13, &[0b1100_0000]))?; // id 0, aux_format_idc 0
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(2, 0)))?;
+
+        let store = decoder.parameter_sets();
+        assert_eq!(store.sps(0).map(|s| s.pic_width_in_mbs()), Some(4));
+        assert!(store.sps(1).is_none(), "Subset SPS must not shadow SPS ids");
+        assert_eq!(store.subset_sps(1).map(|s| s.profile_idc), Some(83));
+        assert_eq!(store.sps_extension(0).map(|e| e.aux_format_idc), Some(0));
+        assert_eq!(store.pps(2).map(|p| p.seq_parameter_set_id), Some(0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_parameter_set_errors() {
+        let mut decoder = Decoder::new();
+        assert!(decoder.decode_nalu(&nalu(3, 7, &[66, 0])).is_err());
+        assert!(decoder.decode_nalu(&[0x80]).is_err());
+        assert_eq!(decoder.parameter_sets().sps_count(), 0);
+    }
+
+    #[test]
+    fn test_empty_and_header_only_nalus() -> Result<()> {
+        let mut decoder = Decoder::new();
+        assert!(decoder.decode_nalu(&[]).is_err());
+
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 1, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &[]))?;
+        decoder.decode_nalu(&nalu(2, 1, &[]))?;
+        assert_eq!(decoder.skipped_nalu_count(), 2);
+
+        // Header-only non-VCL NALUs still fail to parse, but without panicking
+        assert!(decoder.decode_nalu(&nalu(3, 8, &[])).is_err());
+
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(1, 0x40)))?;
+        decoder.flush();
+        assert_eq!(decoder.decoded_frame_count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefix_nalu_before_base_slice() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 1, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        // SVC extension header, then an empty prefix_nal_unit_rbsp
+        decoder.decode_nalu(&[0x6E, 0xC0, 0x00, 0x03, 0x80])?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(1, 0x40)))?;
+        decoder.flush();
+
+        assert_eq!(decoder.skipped_nalu_count(), 1);
+        let picture = decoder
+            .next_frame()
+            .ok_or_else(|| anyhow!("Base layer picture wasn't decoded"))?;
+        assert!(picture.frame.y.data.iter().all(|&s| s == 0x40));
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_reference_concealment() -> Result<()> {
+        let decode = |concealment| -> Result<Option<DecodedPicture>> {
+            let mut decoder = Decoder::new().with_concealment(concealment);
+            decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 2, 1)))?;
+            decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+            decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(2, 0x40)))?;
+            decoder.finish_picture();
+            // The IDR picture is lost as a reference
+            decoder.dpb.mark_all_unused();
+
+            decoder.decode_nalu(&nalu(2, 1, &p_skip_slice_rbsp(1, 2)))?;
+            decoder.flush();
+            Ok(decoder.output.pop_back())
+        };
+
+        let err = decode(ConcealmentMode::Error).expect_err("Missing references must fail");
+        assert!(err.to_string().contains("no reference picture"));
+
+        for concealment in [ConcealmentMode::Gray, ConcealmentMode::SubstituteNearest] {
+            let picture = decode(concealment)?.ok_or_else(|| anyhow!("No P picture"))?;
+            assert_eq!(picture.frame_num, 1);
+            assert!(picture.frame.y.data.iter().all(|&s| s == 128));
+            assert!(picture.frame.cr.data.iter().all(|&s| s == 128));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_b_slices_are_rejected() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 2, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(2, 0x40)))?;
+
+        let mut writer = BitWriter::new();
+        writer.write_ue(0); // first_mb_in_slice
+        writer.write_ue(6); // slice_type, B
+        writer.write_ue(0); // pic_parameter_set_id
+        writer.write(1, 4); // frame_num
+        writer.write_flag(true); // direct_spatial_mv_pred_flag
+        writer.write_flag(false); // num_ref_idx_active_override_flag
+        writer.write_flag(false); // ref_pic_list_modification_flag_l0
+        writer.write_flag(false); // ref_pic_list_modification_flag_l1
+        writer.write_se(0); // slice_qp_delta
+        writer.write_ue(1); // disable_deblocking_filter_idc
+        writer.write_ue(2); // mb_skip_run
+        writer.write_rbsp_trailing_bits();
+        let err = decoder
+            .decode_nalu(&nalu(0, 1, &writer.into_bytes()))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "B slices are not supported");
+
+        // Only the IDR picture comes out
+        decoder.flush();
+        let picture = decoder
+            .next_frame()
+            .ok_or_else(|| anyhow!("The IDR picture wasn't output"))?;
+        assert_eq!(picture.frame_num, 0);
+        assert!(decoder.next_frame().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_p_l0_16x16_motion_vectors() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 2, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp_at(0, 1, 0x40)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp_at(1, 1, 0x80)))?;
+        decoder.finish_picture();
+
+        let slice = p_slice_rbsp(1, |writer| {
+            // The left macroblock moves a whole macroblock right, onto the right one
+            writer.write_ue(0); // mb_skip_run
+            writer.write_ue(0); // mb_type, P_L0_16x16
+            writer.write_se(64); // mvd_l0, in quarter samples
+            writer.write_se(0);
+            writer.write_ue(0); // coded_block_pattern 0
+            // The right one predicts the left one's vector, and its fractional mvd points far
+            // below and left of the picture
+            writer.write_ue(0); // mb_skip_run
+            writer.write_ue(0); // mb_type, P_L0_16x16
+            writer.write_se(-(1 << 28) + 1);
+            writer.write_se(1 << 28);
+            writer.write_ue(0); // coded_block_pattern 0
+        });
+        decoder.decode_nalu(&nalu(2, 1, &slice))?;
+        decoder.flush();
+
+        let picture = decoder
+            .output
+            .pop_back()
+            .ok_or_else(|| anyhow!("No P picture"))?;
+        assert_eq!(picture.frame_num, 1);
+        for plane in [&picture.frame.y, &picture.frame.cb, &picture.frame.cr] {
+            let half = plane.width / 2;
+            for y in 0..plane.height {
+                assert_eq!(plane.row(y)[..half], vec![0x80; half]);
+                assert_eq!(plane.row(y)[half..], vec![0x40; half]);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_deblocking_filters_a_motion_edge() -> Result<()> {
+        // With `split`, each macroblock is its own slice
+        let decode = |disable_deblocking_filter_idc, split| -> Result<DecodedPicture> {
+            let mut decoder = Decoder::new();
+            decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 2, 1)))?;
+            decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+            decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp_at(0, 1, 0x40)))?;
+            decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp_at(1, 1, 0x44)))?;
+            decoder.finish_picture();
+
+            let mut writer = BitWriter::new();
+            // The left macroblock stays put and the right one moves a macroblock right, past
+            // the picture edge, so both keep their samples but their vectors differ
+            for (mb_addr, mvd_x) in [(0, 0), (1, 64)] {
+                if mb_addr == 0 || split {
+                    writer.write_ue(mb_addr); // first_mb_in_slice
+                    writer.write_ue(5); // slice_type, P
+                    writer.write_ue(0); // pic_parameter_set_id
+                    writer.write(1, 4); // frame_num
+                    writer.write_flag(false); // num_ref_idx_active_override_flag
+                    writer.write_flag(false); // ref_pic_list_modification_flag_l0
+                    writer.write_flag(false); // adaptive_ref_pic_marking_mode_flag
+                    writer.write_se(0); // slice_qp_delta
+                    writer.write_ue(disable_deblocking_filter_idc);
+                    if disable_deblocking_filter_idc != 1 {
+                        writer.write_se(0); // slice_alpha_c0_offset_div2
+                        writer.write_se(0); // slice_beta_offset_div2
+                    }
+                }
+                writer.write_ue(0); // mb_skip_run
+                writer.write_ue(0); // mb_type, P_L0_16x16
+                writer.write_se(mvd_x);
+                writer.write_se(0);
+                writer.write_ue(0); // coded_block_pattern 0
+                if mb_addr == 1 || split {
+                    writer.write_rbsp_trailing_bits();
+                    let rbsp = std::mem::replace(&mut writer, BitWriter::new()).into_bytes();
+                    decoder.decode_nalu(&nalu(2, 1, &rbsp))?;
+                }
+            }
+            decoder.flush();
+            decoder
+                .output
+                .pop_back()
+                .ok_or_else(|| anyhow!("No P picture"))
+        };
+
+        // The macroblock edge has bS 1, and at QP 26 a step of 4 is smoothed over 2 samples
+        // either side in luma and 1 in chroma. idc 2 only stops filtering at slice edges.
+        for (idc, split) in [(0, false), (0, true), (2, false)] {
+            let picture = decode(idc, split)?;
+            for y in 0..16 {
+                assert_eq!(
+                    picture.frame.y.row(y)[12..20],
+                    [0x40, 0x40, 0x41, 0x42, 0x42, 0x43, 0x44, 0x44]
+                );
+            }
+            for plane in [&picture.frame.cb, &picture.frame.cr] {
+                for y in 0..8 {
+                    assert_eq!(plane.row(y)[6..10], [0x40, 0x42, 0x42, 0x44]);
+                }
+            }
+            // The internal edges have bS 0
+            assert!(picture.frame.y.row(0)[..13].iter().all(|&s| s == 0x40));
+            assert!(picture.frame.y.row(0)[19..].iter().all(|&s| s == 0x44));
+        }
+
+        for (idc, split) in [(1, false), (2, true)] {
+            let picture = decode(idc, split)?;
+            assert_eq!(picture.frame.y.row(0)[15..17], [0x40, 0x44]);
+            assert_eq!(picture.frame.cb.row(0)[7..9], [0x40, 0x44]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_p_16x8_partitions() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 2, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp_at(0, 1, 0x40)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp_at(1, 1, 0x80)))?;
+        decoder.finish_picture();
+
+        let slice = p_slice_rbsp(1, |writer| {
+            writer.write_ue(0); // mb_skip_run
+            writer.write_ue(1); // mb_type, P_L0_L0_16x8
+            // The upper partition moves onto the right macroblock. The lower one predicts that
+            // vector from the partition above it, and cancels it.
+            writer.write_se(64);
+            writer.write_se(0);
+            writer.write_se(-64);
+            writer.write_se(0);
+            writer.write_ue(0); // coded_block_pattern 0
+            // The skipped right macroblock stays still, as there's nothing above it
+            writer.write_ue(1); // mb_skip_run
+        });
+        decoder.decode_nalu(&nalu(2, 1, &slice))?;
+        decoder.flush();
+
+        let picture = decoder
+            .output
+            .pop_back()
+            .ok_or_else(|| anyhow!("No P picture"))?;
+        for plane in [&picture.frame.y, &picture.frame.cb, &picture.frame.cr] {
+            let (half_width, half_height) = (plane.width / 2, plane.height / 2);
+            for y in 0..plane.height {
+                let left = if y < half_height { 0x80 } else { 0x40 };
+                assert_eq!(plane.row(y)[..half_width], vec![left; half_width]);
+                assert_eq!(plane.row(y)[half_width..], vec![0x80; half_width]);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_substitute_nearest_pads_with_the_first_reference() {
+        let frame = |sample| {
+            let mut frame = YuvFrame::new(16, 16, ChromaFormat::Yuv420);
+            frame.y.data.fill(sample);
+            Picture::new(frame, 1, 1).into_decoded()
+        };
+        let (nearest, older, gray) = (frame(2), frame(1), frame(128));
+
+        // A single reference with three more requested
+        let mut ref_list0 = vec![&nearest];
+        fill_missing_refs(
+            &mut ref_list0,
+            4,
+            ConcealmentMode::SubstituteNearest,
+            || &gray,
+        );
+        assert_eq!(ref_list0, [&nearest; 4]);
+
+        // RefPicList0 is in descending PicNum order, so the older reference comes last
+        let mut ref_list0 = vec![&nearest, &older];
+        fill_missing_refs(
+            &mut ref_list0,
+            4,
+            ConcealmentMode::SubstituteNearest,
+            || &gray,
+        );
+        assert_eq!(ref_list0, [&nearest, &older, &nearest, &nearest]);
+
+        let mut ref_list0 = vec![&nearest, &older];
+        fill_missing_refs(&mut ref_list0, 3, ConcealmentMode::Gray, || &gray);
+        assert_eq!(ref_list0, [&nearest, &older, &gray]);
+
+        let mut ref_list0 = vec![&nearest];
+        fill_missing_refs(&mut ref_list0, 3, ConcealmentMode::Error, || &gray);
+        assert_eq!(ref_list0, [&nearest]);
+
+        // A count past the largest active list is only padded to 32 entries
+        for concealment in [ConcealmentMode::SubstituteNearest, ConcealmentMode::Gray] {
+            let mut ref_list0 = vec![&nearest];
+            fill_missing_refs(&mut ref_list0, u32::MAX as usize, concealment, || &gray);
+            assert_eq!(ref_list0.len(), 32);
+        }
+    }
+
+    #[test]
+    fn test_dispersed_slice_groups_decode_in_map_order() -> Result<()> {
+        let mut pps = BitWriter::new();
+        pps.write_ue(0); // pic_parameter_set_id
+        pps.write_ue(0); // seq_parameter_set_id
+        pps.write_flag(false); // entropy_coding_mode_flag
+        pps.write_flag(false); // bottom_field_pic_order_in_frame_present_flag
+        pps.write_ue(1); // num_slice_groups_minus1
+        pps.write_ue(1); // slice_group_map_type, dispersed
+        pps.write_ue(0); // num_ref_idx_l0_default_active_minus1
+        pps.write_ue(0); // num_ref_idx_l1_default_active_minus1
+        pps.write_flag(false); // weighted_pred_flag
+        pps.write(0, 2); // weighted_bipred_idc
+        pps.write_se(0); // pic_init_qp_minus26
+        pps.write_se(0); // pic_init_qs_minus26
+        pps.write_se(0); // chroma_qp_index_offset
+        pps.write_flag(true); // deblocking_filter_control_present_flag
+        pps.write_flag(false); // constrained_intra_pred_flag
+        pps.write_flag(false); // redundant_pic_cnt_present_flag
+        pps.write_rbsp_trailing_bits();
+
+        // Slice group 0 of a 2x2 picture is macroblocks 0 and 3, slice group 1 is 1 and 2
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 2, 2)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps.into_bytes()))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp_at(0, 2, 0x40)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp_at(1, 2, 0x80)))?;
+        decoder.flush();
+
+        let picture = decoder.next_frame().ok_or_else(|| anyhow!("No picture"))?;
+        let y = &picture.frame.y;
+        assert_eq!(
+            [y.at(0, 0), y.at(16, 0), y.at(0, 16), y.at(16, 16)],
+            [0x40, 0x80, 0x80, 0x40]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parameter_set_change_callback() -> Result<()> {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let mut decoder = Decoder::new();
+        let collected = Arc::clone(&changes);
+        decoder.on_parameter_set_change(move |change| collected.lock().unwrap().push(*change));
+
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 1, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(1, 0x40)))?;
+        // Re-sending identical sets activates nothing new
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 1, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(1, 0x40)))?;
+        let first = |kind, id| ParamSetChange {
+            kind,
+            id,
+            changed: false,
+        };
+        assert_eq!(
+            *changes.lock().unwrap(),
+            [first(ParamSetKind::Sps, 0), first(ParamSetKind::Pps, 0)]
+        );
+        changes.lock().unwrap().clear();
+
+        // A different picture size under the same id
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 2, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(2, 0x40)))?;
+        assert_eq!(
+            *changes.lock().unwrap(),
+            [ParamSetChange {
+                kind: ParamSetKind::Sps,
+                id: 0,
+                changed: true
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_activation_reports_the_change_once() -> Result<()> {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let mut decoder = Decoder::new();
+        let collected = Arc::clone(&changes);
+        decoder.on_parameter_set_change(move |change| collected.lock().unwrap().push(*change));
+
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 1, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(1, 0x40)))?;
+        changes.lock().unwrap().clear();
+
+        // A new picture size can't be activated by non-IDR slices, which fail without touching
+        // the active SPS
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 2, 1)))?;
+        for frame_num in 1..3 {
+            let err = decoder
+                .decode_nalu(&nalu(2, 1, &pcm_slice_rbsp(frame_num, 0x40)))
+                .expect_err("A format change needs an IDR picture");
+            assert!(err.to_string().contains("without an IDR picture"));
+        }
+        assert!(changes.lock().unwrap().is_empty());
+        assert_eq!(
+            decoder
+                .active_sps
+                .as_ref()
+                .map(|sps| sps.pic_width_in_mbs()),
+            Some(1)
+        );
+
+        // The IDR picture activates it, reported once across both its slices
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp_at(0, 1, 0x40)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp_at(1, 1, 0x40)))?;
+        assert_eq!(
+            *changes.lock().unwrap(),
+            [ParamSetChange {
+                kind: ParamSetKind::Sps,
+                id: 0,
+                changed: true
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_slice_diagnostics_for_a_two_slice_picture() -> Result<()> {
+        let slices = Arc::new(Mutex::new(Vec::new()));
+        let mut decoder = Decoder::new();
+        let collected = Arc::clone(&slices);
+        decoder.on_slice(move |slice| collected.lock().unwrap().push(slice.clone()));
+
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 3, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp_at(0, 2, 0x40)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp_at(2, 1, 0x80)))?;
+        decoder.flush();
+        assert_eq!(decoder.decoded_frame_count(), 1);
+
+        let slices = slices.lock().unwrap();
+        let first_mbs: Vec<_> = slices.iter().map(|s| s.first_mb_in_slice).collect();
+        assert_eq!(first_mbs, [0, 2]);
+        assert!(slices.iter().all(|s| s.slice_type == SliceType::I
+            && s.qp == 26
+            && s.num_ref_idx_l0_active == 0
+            && !s.deblocking_filter_enabled));
+        Ok(())
+    }
+
+    #[test]
+    fn test_two_slice_picture_reconstructs_into_one_frame() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 1, 2)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        // The top macroblock in one slice, the bottom one in another
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp_at(0, 1, 0x40)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp_at(1, 1, 0x80)))?;
+        assert_eq!(
+            decoder.decoded_frame_count(),
+            0,
+            "The picture is still open"
+        );
+        decoder.flush();
+
+        let picture = decoder
+            .next_frame()
+            .ok_or_else(|| anyhow!("No picture was output"))?;
+        assert!(decoder.next_frame().is_none());
+        assert_eq!(decoder.decoded_frame_count(), 1);
+        let y = &picture.frame.y;
+        assert_eq!((y.at(0, 0), y.at(15, 15)), (0x40, 0x40));
+        assert_eq!((y.at(0, 16), y.at(15, 31)), (0x80, 0x80));
+        assert_eq!(
+            (picture.frame.cb.at(0, 7), picture.frame.cb.at(0, 8)),
+            (0x40, 0x80)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_picture_detected_without_its_first_slice() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 1, 2)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp_at(0, 1, 0x40)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp_at(1, 1, 0x50)))?;
+        // The next picture's first slice was lost, but its frame_num differs
+        decoder.decode_nalu(&nalu(2, 1, &pcm_slice_rbsp_at(1, 1, 0x80)))?;
+        decoder.flush();
+
+        assert_eq!(decoder.decoded_frame_count(), 2);
+        let first = decoder
+            .next_frame()
+            .ok_or_else(|| anyhow!("No picture was output"))?;
+        assert_eq!(first.frame.y.at(0, 16), 0x50);
+        let second = decoder
+            .next_frame()
+            .ok_or_else(|| anyhow!("No picture was output"))?;
+        assert_eq!((second.frame_num, second.frame.y.at(0, 16)), (1, 0x80));
+        Ok(())
+    }
+
+    #[test]
+    fn test_constrained_intra_prediction_uses_pcm_neighbours() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 2, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &constrained_intra_pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(2, 0x10)))?;
+
+        let mut writer = BitWriter::new();
+        writer.write_ue(0); // first_mb_in_slice
+        writer.write_ue(5); // slice_type, P
+        writer.write_ue(0); // pic_parameter_set_id
+        writer.write(1, 4); // frame_num
+        writer.write_flag(false); // num_ref_idx_active_override_flag
+        writer.write_flag(false); // ref_pic_list_modification_flag_l0
+        writer.write_flag(false); // adaptive_ref_pic_marking_mode_flag
+        writer.write_se(0); // slice_qp_delta
+        writer.write_ue(1); // disable_deblocking_filter_idc
+        writer.write_ue(0); // mb_skip_run
+        writer.write_ue(30); // mb_type, I_PCM
+        while !writer.byte_aligned() {
+            writer.write_flag(false);
+        }
+        for _ in 0..384 {
+            writer.write(0x60, 8);
+        }
+        writer.write_ue(0); // mb_skip_run
+        writer.write_ue(8); // mb_type, I_16x16_2_0_0
+        writer.write_ue(0); // intra_chroma_pred_mode, DC
+        writer.write_se(0); // mb_qp_delta
+        writer.write(0b000011, 6); // coeff_token for nC 16, no coefficients
+        writer.write_rbsp_trailing_bits();
+        decoder.decode_nalu(&nalu(2, 1, &writer.into_bytes()))?;
+        decoder.flush();
+
+        let picture = decoder
+            .output
+            .pop_back()
+            .ok_or_else(|| anyhow!("No P picture"))?;
+        // DC prediction from the I_PCM macroblock on the left rather than the 128 fallback
+        assert_eq!(picture.frame.y.at(31, 15), 0x60);
+        assert_eq!(picture.frame.cb.at(15, 7), 0x60);
+        Ok(())
+    }
+
+    #[test]
+    fn test_recovery_point_seek_hint() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 1, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        assert_eq!(decoder.seek_hint(), SeekHint::default());
+
+        // recovery_frame_cnt 2, exact_match_flag 1
+        decoder.decode_nalu(&nalu(0, 6, &[0x06, 0x01, 0b0111_0001, 0x80]))?;
+        let mut valid = Vec::new();
+        for frame_num in 0..4 {
+            decoder.decode_nalu(&nalu(2, 1, &pcm_slice_rbsp(frame_num, 0x80)))?;
+            decoder.finish_picture();
+            valid.push(decoder.seek_hint().output_valid);
+        }
+
+        assert_eq!(valid, [false, false, true, true]);
+        assert_eq!(
+            decoder.seek_hint(),
+            SeekHint {
+                recovery_frame_cnt: None,
+                first_valid_frame: Some(2),
+                output_valid: true,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_resync_skips_to_the_next_idr() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 1, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(1, 0x10)))?;
+
+        // A seek lands in the middle of the next GOP, whose P pictures have lost their reference
+        decoder.resync();
+        for frame_num in 3..5 {
+            decoder.decode_nalu(&nalu(2, 1, &p_skip_slice_rbsp(frame_num, 1)))?;
+        }
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(1, 0x20)))?;
+        decoder.decode_nalu(&nalu(2, 1, &p_skip_slice_rbsp(1, 1)))?;
+        decoder.flush();
+
+        let samples: Vec<_> = std::iter::from_fn(|| decoder.next_frame())
+            .map(|picture| (picture.idr, picture.frame.y.at(0, 0)))
+            .collect();
+        assert_eq!(samples, [(true, 0x20), (false, 0x20)]);
+        assert_eq!(decoder.skipped_nalu_count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_take_stats() -> Result<()> {
+        let mut decoder = Decoder::new();
+        let nalus = [
+            nalu(3, 7, &sps_rbsp(0, 2, 1)),
+            nalu(3, 8, &pps_rbsp(0, 0)),
+            nalu(3, 5, &pcm_idr_slice_rbsp(2, 0x10)),
+            nalu(2, 1, &p_skip_slice_rbsp(1, 2)),
+        ];
+        for data in &nalus {
+            decoder.decode_nalu(data)?;
+        }
+        decoder.flush();
+
+        let stats = decoder.take_stats();
+        assert_eq!(
+            stats,
+            DecoderStats {
+                nalus: 4,
+                bytes: nalus.iter().map(|data| data.len() as u64).sum(),
+                pictures: 2,
+                intra_mbs: 2,
+                inter_mbs: 0,
+                skipped_mbs: 2,
+            }
+        );
+        assert_eq!(decoder.take_stats(), DecoderStats::default());
+        // The lifetime counters carry on across windows
+        assert_eq!(decoder.decoded_frame_count(), 2);
+
+        let mut stats = stats;
+        stats.reset();
+        assert_eq!(stats, DecoderStats::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_picture_wide_slice_types() -> Result<()> {
+        let p_skip_slice_rbsp_at = |first_mb, frame_num, slice_type| {
+            let mut writer = BitWriter::new();
+            writer.write_ue(first_mb); // first_mb_in_slice
+            writer.write_ue(slice_type);
+            writer.write_ue(0); // pic_parameter_set_id
+            writer.write(frame_num, 4);
+            writer.write_flag(false); // num_ref_idx_active_override_flag
+            writer.write_flag(false); // ref_pic_list_modification_flag_l0
+            writer.write_flag(false); // adaptive_ref_pic_marking_mode_flag
+            writer.write_se(0); // slice_qp_delta
+            writer.write_ue(1); // disable_deblocking_filter_idc
+            writer.write_ue(1); // mb_skip_run
+            writer.write_rbsp_trailing_bits();
+            writer.into_bytes()
+        };
+
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 2, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(2, 0x10)))?;
+        // Two I slices that both signal slice_type 7
+        decoder.decode_nalu(&nalu(2, 1, &pcm_slice_rbsp_at(0, 1, 0x20)))?;
+        decoder.decode_nalu(&nalu(2, 1, &pcm_slice_rbsp_at(1, 1, 0x20)))?;
+        // A P slice after a slice_type 7 slice
+        decoder.decode_nalu(&nalu(2, 1, &pcm_slice_rbsp_at(0, 2, 0x30)))?;
+        let mismatch = decoder.decode_nalu(&nalu(2, 1, &p_skip_slice_rbsp_at(1, 2, 5)));
+        assert!(mismatch.is_err());
+        // slice_type 0 doesn't constrain the other slices, but a later slice_type 7 does
+        decoder.decode_nalu(&nalu(2, 1, &p_skip_slice_rbsp_at(0, 3, 0)))?;
+        let mismatch = decoder.decode_nalu(&nalu(2, 1, &pcm_slice_rbsp_at(1, 3, 0x40)));
+        assert!(mismatch.is_err());
+        decoder.flush();
+
+        let slice_types: Vec<_> = std::iter::from_fn(|| decoder.next_frame())
+            .map(|picture| picture.slice_type)
+            .collect();
+        assert_eq!(
+            slice_types,
+            [
+                Some(SliceType::I),
+                Some(SliceType::I),
+                Some(SliceType::I),
+                Some(SliceType::P)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_resync_resumes_at_a_recovery_point() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 1, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.resync();
+        decoder.decode_nalu(&nalu(2, 1, &pcm_slice_rbsp(5, 0x30)))?;
+        // recovery_frame_cnt 0, exact_match_flag 1
+        decoder.decode_nalu(&nalu(0, 6, &[0x06, 0x01, 0b1100_0100, 0x80]))?;
+        decoder.decode_nalu(&nalu(2, 1, &pcm_slice_rbsp(6, 0x40)))?;
+        decoder.decode_nalu(&nalu(2, 1, &pcm_slice_rbsp(7, 0x50)))?;
+        decoder.flush();
+
+        let samples: Vec<_> = std::iter::from_fn(|| decoder.next_frame())
+            .map(|picture| picture.frame.y.at(0, 0))
+            .collect();
+        assert_eq!(samples, [0x40, 0x50]);
+        assert!(decoder.seek_hint().output_valid);
+        Ok(())
+    }
+
+    #[test]
+    fn test_liveness_counters() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 2, 2)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(4, 0x80)))?;
+        decoder.decode_nalu(&nalu(0, 12, &[0xFF, 0xFF, 0x80]))?;
+        decoder.decode_nalu(&nalu(2, 1, &p_skip_slice_rbsp(1, 4)))?;
+        assert_eq!(
+            decoder.decoded_frame_count(),
+            1,
+            "P picture is still pending"
+        );
+
+        decoder.flush();
+        assert_eq!(decoder.decoded_frame_count(), 2);
+        assert_eq!(decoder.skipped_nalu_count(), 1);
+
+        let first = decoder
+            .next_frame()
+            .ok_or_else(|| anyhow!("No IDR picture"))?;
+        let second = decoder
+            .next_frame()
+            .ok_or_else(|| anyhow!("No P picture"))?;
+        assert!(first.idr);
+        assert_eq!(second.poc, 2);
+        assert_eq!(second.frame, first.frame);
+        assert!(second.frame.y.data.iter().all(|&s| s == 0x80));
+        assert!(decoder.next_frame().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_intra_dc_prediction_and_residual() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 2, 2)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(
+            3,
+            5,
+            &idr_slice_rbsp(|writer| {
+                write_i16x16_dc_mb(writer);
+                write_i4x4_mb(writer);
+                write_i16x16_dc_mb(writer);
+                write_i16x16_dc_mb(writer);
+            }),
+        ))?;
+        decoder.flush();
+
+        let frame = decoder
+            .next_frame()
+            .ok_or_else(|| anyhow!("No picture"))?
+            .frame;
+        // Without neighbours, DC prediction is 128 and the residual adds 1
+        assert_eq!(frame.y.at(0, 0), 129);
+        assert_eq!(frame.y.at(15, 15), 129);
+        // The Intra_4x4 macroblock predicts every block from the 129 column on its left
+        assert!((16..32).all(|x| (0..16).all(|y| frame.y.at(x, y) == 129)));
+        // Predicted from the row above, then from both neighbours: (129 * 16 + 130 * 16 + 16) >> 5 is 130
+        assert_eq!(frame.y.at(0, 16), 130);
+        assert_eq!(frame.y.at(31, 31), 131);
+        assert!(
+            frame
+                .cb
+                .data
+                .iter()
+                .chain(&frame.cr.data)
+                .all(|&s| s == 128)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_decoding_does_not_allocate_per_macroblock() -> Result<()> {
+        fn decode_allocations(width_in_mbs: u32, height_in_mbs: u32) -> Result<usize> {
+            let num_mbs = (width_in_mbs * height_in_mbs) as usize;
+            let nalus = [
+                nalu(3, 7, &sps_rbsp(0, width_in_mbs, height_in_mbs)),
+                nalu(3, 8, &pps_rbsp(0, 0)),
+                nalu(3, 5, &i16x16_idr_slice_rbsp(num_mbs)),
+                nalu(2, 1, &p_skip_slice_rbsp(1, num_mbs as u32)),
+                nalu(2, 1, &p_skip_slice_rbsp(2, num_mbs as u32)),
+            ];
+
+            let mut decoder = Decoder::new();
+            let mut result = Ok(());
+            let count = allocations::count(|| {
+                result = nalus.iter().try_for_each(|nalu| decoder.decode_nalu(nalu));
+                decoder.flush();
+            });
+            result?;
+            assert_eq!(decoder.decoded_frame_count(), 3);
+            Ok(count)
+        }
+
+        // Allocations are per picture and NALU only, so 36 times the macroblocks costs nothing extra
+        assert_eq!(decode_allocations(1, 1)?, decode_allocations(6, 6)?);
+        Ok(())
+    }
+
+    /// An IDR slice for an interlaced SPS: the frame_num is followed by field_pic_flag, and
+    /// `field` is the bottom_field_flag of a field picture
+    fn interlaced_idr_slice_rbsp(num_mbs: usize, field: Option<bool>) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        writer.write_ue(0); // first_mb_in_slice
+        writer.write_ue(7); // slice_type, I
+        writer.write_ue(0); // pic_parameter_set_id
+        writer.write(0, 4); // frame_num
+        writer.write_flag(field.is_some()); // field_pic_flag
+        if let Some(bottom_field_flag) = field {
+            writer.write_flag(bottom_field_flag);
+        }
+        writer.write_ue(0); // idr_pic_id
+        writer.write_flag(false); // no_output_of_prior_pics_flag
+        writer.write_flag(false); // long_term_reference_flag
+        writer.write_se(0); // slice_qp_delta
+        writer.write_ue(1); // disable_deblocking_filter_idc
+        for _ in 0..num_mbs {
+            write_i16x16_dc_mb(&mut writer);
+        }
+        writer.write_rbsp_trailing_bits();
+        writer.into_bytes()
+    }
+
+    #[test]
+    fn test_picture_size_is_checked_at_activation() -> Result<()> {
+        let empty = Sps {
+            pic_width_in_mbs_minus1: u32::MAX,
+            frame_mbs_only_flag: true,
+            ..Default::default()
+        };
+        assert!(check_picture_size(&empty, DEFAULT_MAX_LUMA_SAMPLES).is_err());
+
+        let valid = Sps {
+            pic_width_in_mbs_minus1: 119,
+            pic_height_in_map_units_minus1: 67,
+            frame_mbs_only_flag: true,
+            ..Default::default()
+        };
+        check_picture_size(&valid, DEFAULT_MAX_LUMA_SAMPLES)?;
+
+        let mut decoder = Decoder::new().with_max_luma_samples(4 * 256);
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 4, 2)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        let err = decoder
+            .decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(8, 0x80)))
+            .expect_err("Oversized pictures must be refused");
+        assert!(err.to_string().contains("exceeds the limit"));
+
+        let mut decoder = Decoder::new().with_max_luma_samples(4 * 256);
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 2, 2)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(4, 0x80)))?;
+        decoder.flush();
+        assert_eq!(decoder.decoded_frame_count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mbaff_is_rejected() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &interlaced_sps_rbsp(0, 1, 1, true)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        assert!(
+            decoder
+                .parameter_sets()
+                .sps(0)
+                .is_some_and(|s| s.mb_adaptive_frame_field_flag)
+        );
+
+        let err = decoder
+            .decode_nalu(&nalu(3, 5, &interlaced_idr_slice_rbsp(2, None)))
+            .expect_err("MBAFF slices must be rejected");
+        assert!(err.to_string().contains("MBAFF is not yet supported"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_interlaced_sps_without_mbaff_decodes_frames() -> Result<()> {
+        let mut decoder = Decoder::new();
+        // One map unit of a field pair covers two macroblock rows
+        decoder.decode_nalu(&nalu(3, 7, &interlaced_sps_rbsp(0, 1, 1, false)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &interlaced_idr_slice_rbsp(2, None)))?;
+        decoder.flush();
+
+        let frame = decoder
+            .next_frame()
+            .ok_or_else(|| anyhow!("No picture"))?
+            .frame;
+        assert_eq!((frame.width, frame.height), (16, 32));
+        assert_eq!(frame.y.at(0, 31), 130);
+        Ok(())
+    }
+
+    #[test]
+    fn test_intra_blocks_use_the_intra_default_scaling_list() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &default_scaling_sps_rbsp(0, 1, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &i16x16_idr_slice_rbsp(1)))?;
+        decoder.flush();
+
+        // Default_4x4_Intra weights the DC by 6 instead of 16, too little for the level of 1 to
+        // survive the transform's rounding
+        let frame = decoder
+            .next_frame()
+            .ok_or_else(|| anyhow!("No picture"))?
+            .frame;
+        assert!(frame.y.data.iter().all(|&s| s == 128));
+        Ok(())
+    }
+
+    #[test]
+    fn test_field_pictures_report_their_structure() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &interlaced_sps_rbsp(0, 1, 1, true)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        // Field pictures are decoded even when the SPS allows MBAFF frames
+        decoder.decode_nalu(&nalu(3, 5, &interlaced_idr_slice_rbsp(1, Some(false))))?;
+        decoder.flush();
+
+        let picture = decoder.next_frame().ok_or_else(|| anyhow!("No picture"))?;
+        assert_eq!(picture.structure, PictureStructure::TopField);
+        assert_eq!((picture.frame.width, picture.frame.height), (16, 16));
+        assert_eq!(picture.frame.y.at(0, 15), 129);
+
+        let mut header = SliceHeader {
+            field_pic_flag: true,
+            bottom_field_flag: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            PictureStructure::from_header(&header),
+            PictureStructure::BottomField
+        );
+        header.field_pic_flag = false;
+        assert_eq!(
+            PictureStructure::from_header(&header),
+            PictureStructure::Frame
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe_finds_sps_after_sei() -> Result<()> {
+        let mut stream = Vec::new();
+        for nalu in [
+            nalu(0, 6, &[0x06, 0x01, 0b0111_0001, 0x80]),
+            nalu(0, 6, &[0x05, 0x01, 0x00, 0x80]),
+            nalu(3, 7, &sps_rbsp_with_profile(100, 0, 3, 2)),
+            nalu(3, 8, &pps_rbsp(0, 0)),
+            nalu(3, 5, &pcm_idr_slice_rbsp(6, 0x80)),
+        ] {
+            stream.extend([0, 0, 0, 1]);
+            stream.extend(nalu);
+        }
+
+        assert_eq!(
+            Decoder::probe(&stream)?,
+            StreamInfo {
+                width: 48,
+                height: 32,
+                profile: 100,
+                level: 30,
+                chroma_format: ChromaFormat::Yuv420,
+                bit_depth: 8,
+                frame_rate: None,
+                interlaced: false,
+                mbaff: false,
+            }
+        );
+
+        let sei_only = [0, 0, 0, 1, 0x06, 0x06, 0x01, 0x71, 0x80];
+        assert!(Decoder::probe(&sei_only).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pending_frames() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &poc_sps_rbsp(0, 1, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+
+        // Each picture is complete once the next one starts, and reordering holds them all
+        let pictures = [(true, 0, 0), (false, 1, 8), (false, 2, 4)];
+        for (pending, (idr, frame_num, poc_lsb)) in pictures.into_iter().enumerate() {
+            let slice = pcm_poc_slice_rbsp(idr, frame_num, poc_lsb, false, 10);
+            decoder.decode_nalu(&nalu(3, if idr { 5 } else { 1 }, &slice))?;
+            assert_eq!(decoder.pending_frames(), pending);
+        }
+
+        decoder.flush();
+        assert_eq!(decoder.pending_frames(), 3);
+        for pending in (0..3).rev() {
+            assert!(decoder.next_frame().is_some());
+            assert_eq!(decoder.pending_frames(), pending);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_output_of_prior_pics_discards_pending_pictures() -> Result<()> {
+        let decode = |no_output_of_prior_pics_flag: bool| -> Result<Vec<(i32, u8)>> {
+            let mut decoder = Decoder::new();
+            decoder.decode_nalu(&nalu(3, 7, &poc_sps_rbsp(0, 1, 1)))?;
+            decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+            // An I picture followed by two pictures coded out of output order, all still held
+            // for reordering when the next IDR arrives
+            decoder.decode_nalu(&nalu(3, 5, &pcm_poc_slice_rbsp(true, 0, 0, false, 10)))?;
+            decoder.decode_nalu(&nalu(3, 1, &pcm_poc_slice_rbsp(false, 1, 8, false, 20)))?;
+            decoder.decode_nalu(&nalu(3, 1, &pcm_poc_slice_rbsp(false, 2, 4, false, 30)))?;
+            decoder.decode_nalu(&nalu(
+                3,
+                5,
+                &pcm_poc_slice_rbsp(true, 0, 0, no_output_of_prior_pics_flag, 40),
+            ))?;
+            decoder.flush();
+            Ok(std::iter::from_fn(|| decoder.next_frame())
+                .map(|picture| (picture.poc, picture.frame.y.at(0, 0)))
+                .collect())
+        };
+
+        assert_eq!(decode(false)?, &[(0, 10), (4, 30), (8, 20), (0, 40)]);
+        assert_eq!(decode(true)?, &[(0, 40)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mmco5_restarts_poc_and_references() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &poc_sps_rbsp(0, 1, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_poc_slice_rbsp(true, 0, 0, false, 10)))?;
+        decoder.decode_nalu(&nalu(3, 1, &pcm_poc_slice_rbsp(false, 1, 6, false, 20)))?;
+        decoder.decode_nalu(&nalu(3, 1, &pcm_mmco5_slice_rbsp(2, 12, 30)))?;
+        // The MMCO 5 picture is the only reference left, as frame_num 0
+        decoder.finish_picture();
+        let refs: Vec<_> = decoder.dpb.short_term_refs().collect();
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs[0].picture.frame_num, 0);
+        decoder.decode_nalu(&nalu(3, 1, &pcm_poc_slice_rbsp(false, 1, 2, false, 40)))?;
+        decoder.flush();
+
+        // Without the reset, lsb 2 after 12 would wrap to POC 18, and the MMCO 5 picture would
+        // be output ahead of POC 6
+        let pictures: Vec<_> = std::iter::from_fn(|| decoder.next_frame())
+            .map(|picture| (picture.poc, picture.frame_num, picture.frame.y.at(0, 0)))
+            .collect();
+        assert_eq!(pictures, [(0, 0, 10), (6, 1, 20), (0, 0, 30), (2, 1, 40)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_decodable() -> Result<()> {
+        let probe = |sps: Vec<u8>| {
+            let mut stream = vec![0, 0, 0, 1];
+            stream.extend(nalu(3, 7, &sps));
+            Decoder::probe(&stream)
+        };
+
+        let baseline = probe(sps_rbsp(0, 2, 2))?;
+        assert_eq!(baseline.is_decodable(), Ok(()));
+
+        // Field pictures decode, macroblock-adaptive frames don't
+        let fields = probe(interlaced_sps_rbsp(0, 2, 1, false))?;
+        assert!(fields.interlaced && !fields.mbaff);
+        assert_eq!(fields.is_decodable(), Ok(()));
+
+        let mbaff = probe(interlaced_sps_rbsp(0, 2, 1, true))?;
+        assert!(mbaff.interlaced && mbaff.mbaff);
+        assert_eq!(mbaff.is_decodable(), Err(UnsupportedFeature::Mbaff));
+
+        let high10 = StreamInfo {
+            bit_depth: 10,
+            ..baseline.clone()
+        };
+        assert_eq!(high10.is_decodable(), Err(UnsupportedFeature::BitDepth(10)));
+        let svc = StreamInfo {
+            profile: 83,
+            ..baseline
+        };
+        assert_eq!(svc.is_decodable(), Err(UnsupportedFeature::Profile(83)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_monochrome_intra_skips_chroma() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &monochrome_sps_rbsp(0, 2, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(
+            3,
+            5,
+            &idr_slice_rbsp(|writer| {
+                // No intra_chroma_pred_mode and no chroma residual in either macroblock
+                writer.write_ue(3); // mb_type, I_16x16_2_0_0
+                writer.write_se(0); // mb_qp_delta
+                writer.write(0b01, 2); // coeff_token, TotalCoeff 1 and TrailingOnes 1
+                writer.write_flag(false); // trailing_ones_sign_flag
+                writer.write(0b1, 1); // total_zeros 0
+                writer.write_ue(0); // mb_type, I_NxN
+                for _ in 0..16 {
+                    writer.write_flag(true); // prev_intra4x4_pred_mode_flag
+                }
+                writer.write_ue(1); // coded_block_pattern 0 in the monochrome mapping
+            }),
+        ))?;
+        decoder.flush();
+
+        let frame = decoder
+            .next_frame()
+            .ok_or_else(|| anyhow!("No picture"))?
+            .frame;
+        assert_eq!(frame.chroma_format, ChromaFormat::Monochrome);
+        assert!(frame.cb.data.is_empty() && frame.cr.data.is_empty());
+        assert!(frame.y.data.iter().all(|&s| s == 129));
+
+        // An Intra_16x16 type that codes chroma AC can't appear without chroma
+        let chroma_ac = idr_slice_rbsp(|writer| {
+            writer.write_ue(7); // mb_type, I_16x16_2_1_0
+            writer.write_se(0); // mb_qp_delta
+        });
+        assert!(decoder.decode_nalu(&nalu(3, 5, &chroma_ac)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_access_unit_delimiters() -> Result<()> {
+        let mut stream = Vec::new();
+        for nalu in [
+            nalu(3, 7, &sps_rbsp(0, 2, 1)),
+            nalu(3, 8, &pps_rbsp(0, 0)),
+            // primary_pic_type 0, I slices only
+            nalu(0, 9, &[0b0001_0000]),
+            nalu(3, 5, &pcm_idr_slice_rbsp_at(0, 1, 0x10)),
+            nalu(3, 5, &pcm_idr_slice_rbsp_at(1, 1, 0x20)),
+            // primary_pic_type 1, I and P slices
+            nalu(0, 9, &[0b0011_0000]),
+            nalu(2, 1, &p_skip_slice_rbsp(1, 2)),
+            nalu(0, 9, &[0b0011_0000]),
+            nalu(2, 1, &pcm_slice_rbsp_at(0, 2, 0x30)),
+        ] {
+            stream.extend([0, 0, 0, 1]);
+            stream.extend(nalu);
+        }
+        let mut decoder = Decoder::new();
+        decoder.decode_annexb(&stream)?;
+        decoder.flush();
+
+        let pictures: Vec<_> = std::iter::from_fn(|| decoder.next_frame())
+            .map(|picture| {
+                let y = &picture.frame.y;
+                (picture.primary_pic_type, y.at(0, 0), y.at(16, 0))
+            })
+            .collect();
+        // Both IDR slices land in the first picture, while the last picture's only slice leaves
+        // its second macroblock missing
+        assert_eq!(
+            pictures,
+            [
+                (Some(0), 0x10, 0x20),
+                (Some(1), 0x10, 0x20),
+                (Some(1), 0x30, 0)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_feed_partial_stops_at_the_last_nalu_boundary() -> Result<()> {
+        let mut stream = Vec::new();
+        for nalu in [
+            nalu(3, 7, &sps_rbsp(0, 1, 1)),
+            nalu(3, 8, &pps_rbsp(0, 0)),
+            nalu(3, 5, &pcm_idr_slice_rbsp(1, 0x40)),
+            nalu(0, 9, &[0x10]),
+        ] {
+            stream.extend([0, 0, 0, 1]);
+            stream.extend(nalu);
+        }
+        let boundary = stream.len();
+        stream.extend([0, 0, 0, 1]);
+        stream.extend(nalu(2, 1, &pcm_slice_rbsp(1, 0x50)));
+        let cut = boundary + 40;
+
+        let mut decoder = Decoder::new();
+        let (consumed, frames) = decoder.feed_partial(&stream[..cut])?;
+        assert_eq!(consumed, boundary);
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].y.data.iter().all(|&s| s == 0x40));
+
+        // Re-feeding the tail along with the rest completes the second picture
+        let (consumed, frames) = decoder.feed_partial(&stream[boundary..])?;
+        assert_eq!(consumed, 0);
+        assert!(frames.is_empty());
+        decoder.decode_annexb(&stream[boundary..])?;
+        decoder.flush();
+        let last = decoder
+            .next_frame()
+            .ok_or_else(|| anyhow!("No second picture"))?;
+        assert!(last.frame.y.data.iter().all(|&s| s == 0x50));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitstream_restriction_sizes_the_dpb() {
+        // CIF at level 1 leaves room for a single frame
+        let mut sps = Sps {
+            level_idc: 10,
+            pic_width_in_mbs_minus1: 21,
+            pic_height_in_map_units_minus1: 17,
+            frame_mbs_only_flag: true,
+            pic_order_cnt_type: 0,
+            max_num_ref_frames: 1,
+            ..Default::default()
+        };
+        assert_eq!(dpb_limits(&sps), (1, 1));
+
+        sps.level_idc = 20;
+        assert_eq!(dpb_limits(&sps), (6, 6));
+        // The restriction overrides level 3's 16 frames
+        sps.level_idc = 30;
+        sps.vui_parameters = Some(VuiParameters {
+            bitstream_restriction_flag: true,
+            max_num_reorder_frames: 2,
+            max_dec_frame_buffering: 3,
+            ..Default::default()
+        });
+        let (capacity, max_reorder) = dpb_limits(&sps);
+        assert_eq!((capacity, max_reorder), (3, 2));
+
+        // Two pictures can wait, so POC 2 still comes out ahead of 4 and 6
+        let mut dpb = Dpb::default();
+        dpb.set_limits(capacity, max_reorder);
+        let mut output = VecDeque::new();
+        for poc in [0, 6, 2, 4] {
+            let picture = Picture::new(YuvFrame::new(16, 16, ChromaFormat::Yuv420), 1, 1);
+            let mut picture = picture.into_decoded();
+            picture.poc = poc;
+            dpb.store(picture, Reference::Unused, &mut output);
+        }
+        dpb.flush(&mut output);
+        let pocs: Vec<i32> = output.iter().map(|p| p.poc).collect();
+        assert_eq!(pocs, [0, 2, 4, 6]);
+    }
+
+    #[test]
+    fn test_reordering_without_bitstream_restriction() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &poc_vui_sps_rbsp(0, 1, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        let pictures = [(true, 0, 0), (false, 1, 8), (false, 2, 4), (false, 3, 6)];
+        for (idr, frame_num, poc_lsb) in pictures {
+            let slice = pcm_poc_slice_rbsp(idr, frame_num, poc_lsb, false, 10);
+            decoder.decode_nalu(&nalu(3, if idr { 5 } else { 1 }, &slice))?;
+        }
+        decoder.finish_picture();
+        // The VUI leaves max_num_reorder_frames to be inferred as MaxDpbFrames, so nothing is
+        // bumped while the DPB has room
+        assert!(decoder.next_frame().is_none());
+        decoder.flush();
+
+        let pocs: Vec<_> = std::iter::from_fn(|| decoder.next_frame())
+            .map(|picture| picture.poc)
+            .collect();
+        assert_eq!(pocs, [0, 4, 6, 8]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pictures_report_their_parameter_sets() -> Result<()> {
+        let mut decoder = Decoder::new();
+        for sps_id in [2, 5] {
+            decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(sps_id, 1, 1)))?;
+            // PPS 0 is re-sent to point at the new SPS
+            decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, sps_id)))?;
+            decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(1, 0x10)))?;
+        }
+        decoder.flush();
+
+        let ids: Vec<_> = std::iter::from_fn(|| decoder.next_frame())
+            .map(|picture| (picture.sps_id, picture.pps_id))
+            .collect();
+        assert_eq!(ids, [(2, 0), (5, 0)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_dpb_frames() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.set_max_dpb_frames(2);
+        decoder.decode_nalu(&nalu(3, 7, &poc_vui_sps_rbsp(0, 1, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        let pictures = [(true, 0, 0), (false, 1, 8), (false, 2, 4), (false, 3, 6)];
+        for (idr, frame_num, poc_lsb) in pictures {
+            let slice = pcm_poc_slice_rbsp(idr, frame_num, poc_lsb, false, 10);
+            decoder.decode_nalu(&nalu(3, if idr { 5 } else { 1 }, &slice))?;
+            assert!(decoder.dpb.len() <= 2);
+        }
+        decoder.finish_picture();
+        assert!(decoder.dpb.len() <= 2);
+        // Unlike `test_reordering_without_bitstream_restriction`, the full DPB bumps pictures
+        // before the end of the stream
+        assert_eq!(decoder.next_frame().map(|picture| picture.poc), Some(0));
+        decoder.flush();
+
+        let pocs: Vec<_> = std::iter::from_fn(|| decoder.next_frame())
+            .map(|picture| picture.poc)
+            .collect();
+        assert_eq!(pocs, [4, 6, 8]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_access_unit() -> Result<()> {
+        let sps = nalu(3, 7, &sps_rbsp(0, 1, 1));
+        let pps = nalu(3, 8, &pps_rbsp(0, 0));
+        let idr = nalu(3, 5, &pcm_idr_slice_rbsp(1, 0x60));
+        let mut decoder = Decoder::new();
+
+        let picture = decoder
+            .decode_access_unit(&[&sps, &pps, &idr])?
+            .ok_or_else(|| anyhow!("No picture from the access unit"))?;
+        assert!(picture.idr);
+        assert!(picture.frame.y.data.iter().all(|&s| s == 0x60));
+        assert!(decoder.next_frame().is_none());
+
+        let next = nalu(2, 1, &pcm_slice_rbsp(1, 0x70));
+        let picture = decoder.decode_access_unit(&[&next])?;
+        assert_eq!(picture.map(|p| p.frame_num), Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_feed_nalus() -> Result<()> {
+        let nalus = [
+            nalu(3, 7, &sps_rbsp(0, 1, 1)),
+            nalu(3, 8, &pps_rbsp(0, 0)),
+            nalu(3, 5, &pcm_idr_slice_rbsp(1, 0x60)),
+            nalu(2, 1, &pcm_slice_rbsp(1, 0x70)),
+        ];
+        let mut decoder = Decoder::new();
+
+        // The IDR picture is complete once the next picture's slice arrives
+        let pictures = decoder.feed_nalus(nalus.iter().map(Vec::as_slice))?;
+        assert_eq!(pictures.len(), 1);
+        assert!(pictures[0].idr);
+        assert!(pictures[0].frame.y.data.iter().all(|&s| s == 0x60));
+        assert_eq!(pictures[0].buffer.len(), 16 * 16 * 3 / 2);
+
+        decoder.flush();
+        assert_eq!(decoder.next_frame().map(|p| p.frame_num), Some(1));
+        assert!(decoder.feed_nalus(std::iter::empty())?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolution_change_at_idr() -> Result<()> {
+        let mut decoder = Decoder::new();
+        let vga = sps_rbsp(0, 40, 30);
+        decoder.decode_nalu(&nalu(3, 7, &vga))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(1200, 0x40)))?;
+        decoder.decode_nalu(&nalu(2, 1, &p_skip_slice_rbsp(1, 1200)))?;
+
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 80, 45)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(3600, 0x80)))?;
+        decoder.decode_nalu(&nalu(2, 1, &p_skip_slice_rbsp(1, 3600)))?;
+        decoder.flush();
+
+        let mut sizes = Vec::new();
+        while let Some(picture) = decoder.next_frame() {
+            let frame = picture.frame;
+            sizes.push((frame.width, frame.height, frame.y.at(0, 0)));
+        }
+        assert_eq!(
+            sizes,
+            [
+                (640, 480, 0x40),
+                (640, 480, 0x40),
+                (1280, 720, 0x80),
+                (1280, 720, 0x80)
+            ]
+        );
+
+        // Switching back without an IDR would predict from a 720p reference
+        decoder.decode_nalu(&nalu(3, 7, &vga))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        assert!(
+            decoder
+                .decode_nalu(&nalu(2, 1, &p_skip_slice_rbsp(2, 1200)))
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_trailing_bits() -> Result<()> {
+        let mut strict = Decoder::new().with_strict_trailing_bits(true);
+        strict.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 1, 1)))?;
+        strict.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+
+        // A byte of padding after the stop bit
+        let mut padded = sps_rbsp(1, 1, 1);
+        padded.push(0x80);
+        assert!(strict.decode_nalu(&nalu(3, 7, &padded)).is_err());
+        // A one among the alignment bits
+        let mut corrupted = sps_rbsp(2, 1, 1);
+        *corrupted.last_mut().unwrap() |= 0x01;
+        assert!(strict.decode_nalu(&nalu(3, 7, &corrupted)).is_err());
+        assert!(strict.store.sps(1).is_none() && strict.store.sps(2).is_none());
+
+        let mut lenient = Decoder::new();
+        lenient.decode_nalu(&nalu(3, 7, &padded))?;
+        lenient.decode_nalu(&nalu(3, 7, &corrupted))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_keyframes_only() -> Result<()> {
+        let mut decoder = Decoder::new().with_keyframes_only(true);
+        decoder.decode_nalu(&nalu(3, 7, &sps_rbsp(0, 1, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        for sample in [0x10, 0x20] {
+            decoder.decode_nalu(&nalu(3, 5, &pcm_idr_slice_rbsp(1, sample)))?;
+            for frame_num in 1..3 {
+                decoder.decode_nalu(&nalu(2, 1, &p_skip_slice_rbsp(frame_num, 1)))?;
+            }
+        }
+        decoder.flush();
+
+        let mut samples = Vec::new();
+        while let Some(picture) = decoder.next_frame() {
+            assert!(picture.idr);
+            samples.push(picture.frame.y.at(0, 0));
+        }
+        assert_eq!(samples, [0x10, 0x20]);
+        assert_eq!(decoder.decoded_frame_count(), 2);
+        assert_eq!(decoder.skipped_nalu_count(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_format_buffer_lengths() -> Result<()> {
+        let decode = |format: OutputFormat| -> Result<DecodedPicture> {
+            let mut decoder = Decoder::new();
+            decoder.set_output_format(format);
+            decoder.decode_nalu(&nalu(3, 7, &poc_sps_rbsp(0, 1, 1)))?;
+            decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+            decoder.decode_nalu(&nalu(3, 5, &pcm_poc_slice_rbsp(true, 0, 0, false, 10)))?;
+            decoder.flush();
+            decoder
+                .next_frame()
+                .ok_or_else(|| anyhow!("No picture was output"))
+        };
+
+        let pixels = 16 * 16;
+        assert_eq!(
+            decode(OutputFormat::default())?.buffer.len(),
+            pixels * 3 / 2
+        );
+        assert_eq!(decode(OutputFormat::I420)?.buffer.len(), pixels * 3 / 2);
+        assert_eq!(decode(OutputFormat::Nv12)?.buffer.len(), pixels * 3 / 2);
+        assert_eq!(decode(OutputFormat::Rgb24)?.buffer.len(), pixels * 3);
+        assert_eq!(decode(OutputFormat::Rgba32)?.buffer.len(), pixels * 4);
+
+        let i420 = decode(OutputFormat::I420)?;
+        assert_eq!(i420.buffer, i420.frame.to_i420_packed()?);
+        Ok(())
+    }
+}
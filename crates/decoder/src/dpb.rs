@@ -0,0 +1,281 @@
+use crate::picture::DecodedPicture;
+use std::collections::VecDeque;
+
+/// Reference marking of a picture held in the DPB (8.2.5)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Reference {
+    Unused,
+    ShortTerm,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct DpbEntry {
+    pub picture: DecodedPicture,
+    pub reference: Reference,
+    pub needed_for_output: bool,
+}
+
+/// The decoded picture buffer. It holds pictures that are still used for reference and/or still
+/// waiting to be output, and bumps pictures out in POC order as in C.4.5.3.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Dpb {
+    entries: Vec<DpbEntry>,
+    /// Number of frame buffers
+    capacity: usize,
+    /// Pictures may wait for output until more than this many are pending
+    max_reorder: usize,
+}
+
+impl Dpb {
+    pub fn set_limits(&mut self, capacity: usize, max_reorder: usize) {
+        self.capacity = capacity.max(1);
+        self.max_reorder = max_reorder.min(self.capacity);
+    }
+
+    /// Short-term reference frames, most recently decoded first
+    pub fn short_term_refs(&self) -> impl Iterator<Item = &DpbEntry> {
+        self.entries
+            .iter()
+            .rev()
+            .filter(|e| e.reference == Reference::ShortTerm)
+    }
+
+    /// The initial RefPicList0 of a P slice in a frame (8.2.4.2.1): short-term reference frames
+    /// by descending PicNum, which is FrameNumWrap for frames
+    pub fn build_ref_list_p(&self, current_frame_num: u32, max_frame_num: u32) -> Vec<&DpbEntry> {
+        let mut refs: Vec<_> = self.short_term_refs().collect();
+        refs.sort_by_key(|e| {
+            std::cmp::Reverse(frame_num_wrap(
+                e.picture.frame_num,
+                current_frame_num,
+                max_frame_num,
+            ))
+        });
+        refs
+    }
+
+    /// Pictures held, whether for reference, for output or both
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn num_ref_frames(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|e| e.reference != Reference::Unused)
+            .count()
+    }
+
+    /// Pictures still waiting to be bumped to the output
+    pub fn pending_output(&self) -> usize {
+        self.entries.iter().filter(|e| e.needed_for_output).count()
+    }
+
+    /// 8.2.5.3 Sliding window decoded reference picture marking process: once the DPB holds
+    /// `max_num_ref_frames` references, the oldest short-term reference is dropped.
+    pub fn sliding_window(&mut self, max_num_ref_frames: usize) {
+        if self.num_ref_frames() < max_num_ref_frames.max(1) {
+            return;
+        }
+        // Entries are kept in decoding order, so the first short-term one has the smallest FrameNumWrap
+        if let Some(oldest) = self
+            .entries
+            .iter_mut()
+            .find(|e| e.reference == Reference::ShortTerm)
+        {
+            oldest.reference = Reference::Unused;
+        }
+        self.remove_unused();
+    }
+
+    /// Marks every picture as unused for reference, as an IDR picture does
+    pub fn mark_all_unused(&mut self) {
+        for entry in &mut self.entries {
+            entry.reference = Reference::Unused;
+        }
+        self.remove_unused();
+    }
+
+    /// Stores a decoded picture, bumping pictures into `output` as needed (C.4.5.1, C.4.5.2)
+    pub fn store(
+        &mut self,
+        picture: DecodedPicture,
+        reference: Reference,
+        output: &mut VecDeque<DecodedPicture>,
+    ) {
+        while self.entries.len() >= self.capacity {
+            // A non-reference picture that precedes everything waiting goes straight out
+            if reference == Reference::Unused
+                && self
+                    .entries
+                    .iter()
+                    .filter(|e| e.needed_for_output)
+                    .all(|e| e.picture.poc > picture.poc)
+            {
+                output.push_back(picture);
+                return;
+            }
+            if !self.bump(output) {
+                break;
+            }
+        }
+
+        self.entries.push(DpbEntry {
+            picture,
+            reference,
+            needed_for_output: true,
+        });
+
+        while self.pending_output() > self.max_reorder {
+            self.bump(output);
+        }
+    }
+
+    /// Outputs the picture with the smallest POC. Returns false if nothing was waiting for output.
+    pub fn bump(&mut self, output: &mut VecDeque<DecodedPicture>) -> bool {
+        let Some(index) = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.needed_for_output)
+            .min_by_key(|(_, e)| e.picture.poc)
+            .map(|(i, _)| i)
+        else {
+            return false;
+        };
+
+        let entry = &mut self.entries[index];
+        entry.needed_for_output = false;
+        output.push_back(entry.picture.clone());
+        self.remove_unused();
+        true
+    }
+
+    /// Outputs every pending picture in POC order
+    pub fn flush(&mut self, output: &mut VecDeque<DecodedPicture>) {
+        while self.bump(output) {}
+    }
+
+    /// Empties the buffer without outputting anything, for an IDR picture with
+    /// no_output_of_prior_pics_flag set (C.4.4)
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    fn remove_unused(&mut self) {
+        self.entries
+            .retain(|e| e.needed_for_output || e.reference != Reference::Unused);
+    }
+}
+
+/// FrameNumWrap of a short-term reference (8-27): frame_num values above the current picture's
+/// were decoded before frame_num last wrapped, so they map below zero
+pub(crate) fn frame_num_wrap(frame_num: u32, current_frame_num: u32, max_frame_num: u32) -> i32 {
+    if frame_num > current_frame_num {
+        frame_num as i32 - max_frame_num as i32
+    } else {
+        frame_num as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{ChromaFormat, YuvFrame};
+
+    fn picture(poc: i32) -> DecodedPicture {
+        picture_with_frame_num(poc, 0)
+    }
+
+    fn picture_with_frame_num(poc: i32, frame_num: u32) -> DecodedPicture {
+        DecodedPicture {
+            frame: YuvFrame::new(16, 16, ChromaFormat::Yuv420),
+            poc,
+            frame_num,
+            idr: false,
+            sps_id: 0,
+            pps_id: 0,
+            reference: true,
+            structure: Default::default(),
+            pic_struct: None,
+            primary_pic_type: None,
+            slice_type: None,
+            buffer: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_bumping_outputs_in_poc_order() {
+        let mut dpb = Dpb::default();
+        dpb.set_limits(3, 2);
+        let mut output = VecDeque::new();
+
+        dpb.store(picture(0), Reference::ShortTerm, &mut output);
+        dpb.store(picture(4), Reference::ShortTerm, &mut output);
+        assert!(output.is_empty());
+        dpb.store(picture(2), Reference::Unused, &mut output);
+        assert_eq!(output.pop_front().map(|p| p.poc), Some(0));
+
+        dpb.flush(&mut output);
+        let pocs: Vec<i32> = output.iter().map(|p| p.poc).collect();
+        assert_eq!(pocs, &[2, 4]);
+
+        // References stay behind after output, the non-reference picture is gone
+        assert_eq!(dpb.num_ref_frames(), 2);
+        assert!(!dpb.bump(&mut output));
+    }
+
+    #[test]
+    fn test_sliding_window_drops_oldest() {
+        let mut dpb = Dpb::default();
+        dpb.set_limits(2, 0);
+        let mut output = VecDeque::new();
+
+        dpb.store(picture(0), Reference::ShortTerm, &mut output);
+        dpb.store(picture(2), Reference::ShortTerm, &mut output);
+        dpb.sliding_window(2);
+
+        let refs: Vec<i32> = dpb.short_term_refs().map(|e| e.picture.poc).collect();
+        assert_eq!(refs, &[2]);
+        assert_eq!(output.len(), 2);
+    }
+
+    #[test]
+    fn test_frame_num_wrap() {
+        assert_eq!(frame_num_wrap(5, 7, 16), 5);
+        assert_eq!(frame_num_wrap(7, 7, 16), 7);
+        assert_eq!(frame_num_wrap(15, 1, 16), -1);
+        assert_eq!(frame_num_wrap(14, 0, 16), -2);
+    }
+
+    #[test]
+    fn test_ref_list_p_across_frame_num_wrap() {
+        let mut dpb = Dpb::default();
+        dpb.set_limits(4, 0);
+        let mut output = VecDeque::new();
+
+        // frame_num runs 14, 15, 0 with MaxFrameNum 16, and the current picture has frame_num 1
+        for (poc, frame_num) in [(0, 14), (2, 15), (4, 0)] {
+            dpb.store(
+                picture_with_frame_num(poc, frame_num),
+                Reference::ShortTerm,
+                &mut output,
+            );
+        }
+        let list: Vec<u32> = dpb
+            .build_ref_list_p(1, 16)
+            .iter()
+            .map(|e| e.picture.frame_num)
+            .collect();
+        assert_eq!(list, &[0, 15, 14]);
+
+        // Without wrapping, frame_num order and decoding order agree
+        let list: Vec<u32> = dpb
+            .build_ref_list_p(15, 16)
+            .iter()
+            .map(|e| e.picture.frame_num)
+            .collect();
+        assert_eq!(list, &[15, 14, 0]);
+    }
+}
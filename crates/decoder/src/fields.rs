@@ -0,0 +1,190 @@
+use crate::frame::{PlaneKind, YuvFrame};
+use crate::picture::{DecodedPicture, PictureStructure};
+use anyhow::{Result, anyhow};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// One field waiting to be displayed: the picture it comes from and its parity
+#[derive(Debug, Clone)]
+struct Field {
+    top: bool,
+    source: Arc<YuvFrame>,
+    /// The source holds only this field's lines rather than a whole frame
+    field_picture: bool,
+}
+
+/// Turns decoded pictures into display frames by laying out their fields as `pic_struct` says
+/// (Table D-1) and weaving consecutive top and bottom fields together. 3:2 pulldown content comes
+/// out as five frames for every four coded ones. Pictures without `pic_struct` fall back to their
+/// picture structure.
+#[derive(Debug, Clone, Default)]
+pub struct FieldPairer {
+    fields: VecDeque<Field>,
+}
+
+impl FieldPairer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues the fields a picture is displayed as, in display order
+    pub fn push(&mut self, picture: &DecodedPicture) -> Result<()> {
+        let parities: &[bool] = match (picture.pic_struct, picture.structure) {
+            (None, PictureStructure::Frame) | (Some(0 | 3), _) => &[true, false],
+            (None, PictureStructure::TopField) | (Some(1), _) => &[true],
+            (None, PictureStructure::BottomField) | (Some(2), _) => &[false],
+            (Some(4), _) => &[false, true],
+            (Some(5), _) => &[true, false, true],
+            (Some(6), _) => &[false, true, false],
+            // Frame doubling and tripling
+            (Some(7), _) => &[true, false, true, false],
+            (Some(8), _) => &[true, false, true, false, true, false],
+            (Some(other), _) => return Err(anyhow!("Reserved pic_struct: {}", other)),
+        };
+
+        let source = Arc::new(picture.frame.clone());
+        for &top in parities {
+            self.fields.push_back(Field {
+                top,
+                source: Arc::clone(&source),
+                field_picture: picture.structure.is_field(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Weaves the next pair of opposite parity fields into a frame, if two are queued
+    pub fn next_frame(&mut self) -> Option<YuvFrame> {
+        while self.fields.len() >= 2 {
+            let first = self.fields.pop_front()?;
+            let second = &self.fields[0];
+            // A field without a partner of the other parity can't be shown as a frame
+            if first.top == second.top || frame_height(&first) != frame_height(second) {
+                continue;
+            }
+            let second = self.fields.pop_front()?;
+            let (top, bottom) = if first.top {
+                (first, second)
+            } else {
+                (second, first)
+            };
+            return Some(weave(&top, &bottom));
+        }
+        None
+    }
+}
+
+fn frame_height(field: &Field) -> usize {
+    field.source.height * (1 + field.field_picture as usize)
+}
+
+fn weave(top: &Field, bottom: &Field) -> YuvFrame {
+    let source = &top.source;
+    let mut frame = YuvFrame::new(source.width, frame_height(top), source.chroma_format);
+    frame.range = source.range;
+    frame.crop = source.crop;
+    if top.field_picture {
+        frame.crop.top *= 2;
+        frame.crop.bottom *= 2;
+    }
+
+    for kind in [PlaneKind::Y, PlaneKind::Cb, PlaneKind::Cr] {
+        let plane = frame.plane_mut(kind);
+        for y in 0..plane.height {
+            let field = if y % 2 == 0 { top } else { bottom };
+            let row = if field.field_picture { y / 2 } else { y };
+            let start = y * plane.stride;
+            plane.data[start..start + plane.width]
+                .copy_from_slice(field.source.plane(kind).row(row));
+        }
+    }
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Decoder;
+    use crate::frame::ChromaFormat;
+    use crate::tests::{nalu, pcm_slice_rbsp, pic_struct_sps_rbsp, pps_rbsp};
+
+    fn picture(sample: u8, pic_struct: Option<u8>) -> DecodedPicture {
+        let mut frame = YuvFrame::new(16, 16, ChromaFormat::Yuv420);
+        frame.y.data.fill(sample);
+        frame.cb.data.fill(sample);
+        frame.cr.data.fill(sample);
+        DecodedPicture {
+            frame,
+            poc: 0,
+            frame_num: 0,
+            idr: false,
+            sps_id: 0,
+            pps_id: 0,
+            reference: true,
+            structure: PictureStructure::Frame,
+            pic_struct,
+            primary_pic_type: None,
+            slice_type: None,
+            buffer: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_field_pictures_are_woven() -> Result<()> {
+        let mut pairer = FieldPairer::new();
+        let mut top = picture(0x10, None);
+        top.structure = PictureStructure::TopField;
+        top.frame = YuvFrame::new(16, 8, ChromaFormat::Yuv420);
+        top.frame.y.data.fill(0x10);
+        let mut bottom = top.clone();
+        bottom.structure = PictureStructure::BottomField;
+        bottom.frame.y.data.fill(0x20);
+
+        pairer.push(&top)?;
+        assert!(pairer.next_frame().is_none());
+        pairer.push(&bottom)?;
+        let frame = pairer.next_frame().ok_or_else(|| anyhow!("No frame"))?;
+        assert_eq!(frame.height, 16);
+        assert_eq!(
+            (frame.y.at(0, 0), frame.y.at(0, 1), frame.y.at(0, 14)),
+            (0x10, 0x20, 0x10)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_three_two_pulldown() -> Result<()> {
+        let mut decoder = Decoder::new();
+        decoder.decode_nalu(&nalu(3, 7, &pic_struct_sps_rbsp(0, 1, 1)))?;
+        decoder.decode_nalu(&nalu(3, 8, &pps_rbsp(0, 0)))?;
+        let samples = [0x10, 0x20, 0x30, 0x40];
+        for (frame_num, (&sample, pic_struct)) in samples.iter().zip([5u8, 4, 6, 3]).enumerate() {
+            // pic_timing with only pic_struct and clock_timestamp_flags of 0
+            decoder.decode_nalu(&nalu(0, 6, &[0x01, 0x01, pic_struct << 4, 0x80]))?;
+            decoder.decode_nalu(&nalu(2, 1, &pcm_slice_rbsp(frame_num as u32, sample)))?;
+        }
+        decoder.flush();
+
+        let mut pairer = FieldPairer::new();
+        while let Some(picture) = decoder.next_frame() {
+            pairer.push(&picture)?;
+        }
+        let mut woven = Vec::new();
+        while let Some(frame) = pairer.next_frame() {
+            woven.push((frame.y.at(0, 0), frame.y.at(0, 1)));
+        }
+
+        // T B T | B T | B T B | T B
+        assert_eq!(
+            woven,
+            [
+                (0x10, 0x10),
+                (0x10, 0x20),
+                (0x20, 0x30),
+                (0x30, 0x30),
+                (0x40, 0x40)
+            ]
+        );
+        Ok(())
+    }
+}
@@ -1 +1,725 @@
+use crate::color::{ColorMatrix, ColorRange, yuv_to_rgb};
+use crate::md5::{Md5, to_hex};
+use anyhow::anyhow;
 
+/// Chroma sampling structure of a picture, as signalled by `chroma_format_idc` (Table 6-1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChromaFormat {
+    Monochrome,
+    #[default]
+    Yuv420,
+    Yuv422,
+    Yuv444,
+}
+
+impl ChromaFormat {
+    pub fn from_idc(chroma_format_idc: u32) -> anyhow::Result<Self> {
+        match chroma_format_idc {
+            0 => Ok(ChromaFormat::Monochrome),
+            1 => Ok(ChromaFormat::Yuv420),
+            2 => Ok(ChromaFormat::Yuv422),
+            3 => Ok(ChromaFormat::Yuv444),
+            _ => Err(anyhow!("Invalid chroma_format_idc: {}", chroma_format_idc)),
+        }
+    }
+
+    /// Returns (SubWidthC, SubHeightC). Monochrome has no chroma planes, so it reports (1, 1).
+    pub fn subsampling(self) -> (usize, usize) {
+        match self {
+            ChromaFormat::Monochrome | ChromaFormat::Yuv444 => (1, 1),
+            ChromaFormat::Yuv420 => (2, 2),
+            ChromaFormat::Yuv422 => (2, 1),
+        }
+    }
+
+    /// Size of each chroma plane for a given luma size
+    pub fn chroma_size(self, luma_width: usize, luma_height: usize) -> (usize, usize) {
+        if self == ChromaFormat::Monochrome {
+            return (0, 0);
+        }
+        let (sub_w, sub_h) = self.subsampling();
+        (luma_width.div_ceil(sub_w), luma_height.div_ceil(sub_h))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaneKind {
+    Y,
+    Cb,
+    Cr,
+}
+
+/// A single 8-bit sample plane. Rows are `stride` bytes apart, of which the first `width` are samples.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Plane {
+    pub data: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+}
+
+impl Plane {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            data: vec![0; width * height],
+            width,
+            height,
+            stride: width,
+        }
+    }
+
+    /// Wraps `data` as a `width`x`height` plane, checking it holds every row
+    fn from_data(
+        kind: PlaneKind,
+        data: Vec<u8>,
+        width: usize,
+        height: usize,
+        stride: usize,
+    ) -> anyhow::Result<Self> {
+        if stride < width {
+            return Err(anyhow!(
+                "{:?} plane stride {} is narrower than its width {}",
+                kind,
+                stride,
+                width
+            ));
+        }
+        let needed = if height == 0 {
+            0
+        } else {
+            stride * (height - 1) + width
+        };
+        if data.len() < needed {
+            return Err(anyhow!(
+                "{:?} plane holds {} bytes, but {}x{} samples with stride {} need {}",
+                kind,
+                data.len(),
+                width,
+                height,
+                stride,
+                needed
+            ));
+        }
+        Ok(Self {
+            data,
+            width,
+            height,
+            stride,
+        })
+    }
+
+    pub fn at(&self, x: usize, y: usize) -> u8 {
+        self.data[y * self.stride + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: u8) {
+        self.data[y * self.stride + x] = value;
+    }
+
+    /// The samples of row `y`, without stride padding
+    pub fn row(&self, y: usize) -> &[u8] {
+        let start = y * self.stride;
+        &self.data[start..start + self.width]
+    }
+
+    /// MD5 of the plane's samples row by row, ignoring stride padding
+    pub fn checksum(&self) -> [u8; 16] {
+        let mut md5 = Md5::new();
+        for y in 0..self.height {
+            md5.update(self.row(y));
+        }
+        md5.finalize()
+    }
+}
+
+/// Frame cropping offsets in luma samples, derived from the SPS `frame_crop_*_offset` fields
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CropWindow {
+    pub left: usize,
+    pub right: usize,
+    pub top: usize,
+    pub bottom: usize,
+}
+
+/// Sample layout of the buffers the `Decoder` hands out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Y plane, then the Cb and Cr planes at half resolution in both directions
+    #[default]
+    I420,
+    /// Y plane, then a half resolution plane of interleaved Cb/Cr pairs
+    Nv12,
+    /// Packed 8-bit R, G, B
+    Rgb24,
+    /// Packed 8-bit R, G, B and an opaque alpha
+    Rgba32,
+}
+
+/// A reconstructed picture. `width`/`height` are the coded luma dimensions, `crop` selects the
+/// displayed region.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct YuvFrame {
+    pub width: usize,
+    pub height: usize,
+    pub chroma_format: ChromaFormat,
+    pub y: Plane,
+    pub cb: Plane,
+    pub cr: Plane,
+    pub crop: CropWindow,
+    pub range: ColorRange,
+}
+
+impl YuvFrame {
+    /// Allocates a zeroed frame of the given coded size
+    pub fn new(width: usize, height: usize, chroma_format: ChromaFormat) -> Self {
+        let (chroma_width, chroma_height) = chroma_format.chroma_size(width, height);
+        Self {
+            width,
+            height,
+            chroma_format,
+            y: Plane::new(width, height),
+            cb: Plane::new(chroma_width, chroma_height),
+            cr: Plane::new(chroma_width, chroma_height),
+            crop: CropWindow::default(),
+            range: ColorRange::default(),
+        }
+    }
+
+    /// Wraps planes decoded elsewhere into a frame of the given coded size. Each plane holds
+    /// rows `strides` bytes apart for Y, Cb and Cr, and must be large enough for the dimensions
+    /// the chroma format gives it, although its last row may stop short of the stride. The chroma
+    /// planes of a monochrome frame are empty.
+    pub fn from_planes(
+        width: usize,
+        height: usize,
+        chroma_format: ChromaFormat,
+        y: Vec<u8>,
+        cb: Vec<u8>,
+        cr: Vec<u8>,
+        strides: [usize; 3],
+    ) -> anyhow::Result<Self> {
+        let (chroma_width, chroma_height) = chroma_format.chroma_size(width, height);
+        let [y_stride, cb_stride, cr_stride] = strides;
+        Ok(Self {
+            width,
+            height,
+            chroma_format,
+            y: Plane::from_data(PlaneKind::Y, y, width, height, y_stride)?,
+            cb: Plane::from_data(PlaneKind::Cb, cb, chroma_width, chroma_height, cb_stride)?,
+            cr: Plane::from_data(PlaneKind::Cr, cr, chroma_width, chroma_height, cr_stride)?,
+            crop: CropWindow::default(),
+            range: ColorRange::default(),
+        })
+    }
+
+    pub fn plane(&self, kind: PlaneKind) -> &Plane {
+        match kind {
+            PlaneKind::Y => &self.y,
+            PlaneKind::Cb => &self.cb,
+            PlaneKind::Cr => &self.cr,
+        }
+    }
+
+    pub fn plane_mut(&mut self, kind: PlaneKind) -> &mut Plane {
+        match kind {
+            PlaneKind::Y => &mut self.y,
+            PlaneKind::Cb => &mut self.cb,
+            PlaneKind::Cr => &mut self.cr,
+        }
+    }
+
+    /// Copies a plane's samples row by row, dropping any stride padding
+    pub fn pack_plane(&self, kind: PlaneKind) -> Vec<u8> {
+        let plane = self.plane(kind);
+        let mut packed = Vec::with_capacity(plane.width * plane.height);
+        for y in 0..plane.height {
+            packed.extend_from_slice(plane.row(y));
+        }
+        packed
+    }
+
+    /// The tightly packed Y, Cb and Cr planes of a 4:2:0 frame, one after another (I420)
+    pub fn to_i420_packed(&self) -> anyhow::Result<Vec<u8>> {
+        if self.chroma_format != ChromaFormat::Yuv420 {
+            return Err(anyhow!(
+                "I420 output needs a 4:2:0 frame, not {:?}",
+                self.chroma_format
+            ));
+        }
+        let mut packed = self.pack_plane(PlaneKind::Y);
+        packed.extend(self.pack_plane(PlaneKind::Cb));
+        packed.extend(self.pack_plane(PlaneKind::Cr));
+        Ok(packed)
+    }
+
+    /// Coded width and height of each chroma plane, (0, 0) for monochrome frames
+    pub fn chroma_dimensions(&self) -> (u32, u32) {
+        let (width, height) = self.chroma_format.chroma_size(self.width, self.height);
+        (width as u32, height as u32)
+    }
+
+    /// Width after cropping
+    pub fn display_width(&self) -> usize {
+        self.width - self.crop.left - self.crop.right
+    }
+
+    /// Height after cropping
+    pub fn display_height(&self) -> usize {
+        self.height - self.crop.top - self.crop.bottom
+    }
+
+    /// Converts the cropped picture to packed 8-bit RGB, honoring the frame's sample range
+    pub fn to_rgb(&self, matrix: ColorMatrix) -> Vec<u8> {
+        let (sub_w, sub_h) = self.chroma_format.subsampling();
+        let mut rgb = Vec::with_capacity(self.display_width() * self.display_height() * 3);
+
+        for y in self.crop.top..self.height - self.crop.bottom {
+            for x in self.crop.left..self.width - self.crop.right {
+                let luma = self.y.at(x, y);
+                let (cb, cr) = match self.chroma_format {
+                    ChromaFormat::Monochrome => (128, 128),
+                    _ => (
+                        self.cb.at(x / sub_w, y / sub_h),
+                        self.cr.at(x / sub_w, y / sub_h),
+                    ),
+                };
+                rgb.extend_from_slice(&yuv_to_rgb(luma, cb, cr, matrix, self.range));
+            }
+        }
+
+        rgb
+    }
+
+    /// Converts the cropped picture to `format`. 4:2:2 and 4:4:4 chroma is decimated for the
+    /// 4:2:0 layouts, and monochrome frames get neutral chroma.
+    pub fn to_output_format(&self, format: OutputFormat, matrix: ColorMatrix) -> Vec<u8> {
+        match format {
+            OutputFormat::I420 => {
+                let mut packed = self.pack_cropped_luma();
+                packed.extend(self.cropped_chroma_420(PlaneKind::Cb));
+                packed.extend(self.cropped_chroma_420(PlaneKind::Cr));
+                packed
+            }
+            OutputFormat::Nv12 => {
+                let mut packed = self.pack_cropped_luma();
+                let cb = self.cropped_chroma_420(PlaneKind::Cb);
+                let cr = self.cropped_chroma_420(PlaneKind::Cr);
+                for (cb, cr) in cb.into_iter().zip(cr) {
+                    packed.extend_from_slice(&[cb, cr]);
+                }
+                packed
+            }
+            OutputFormat::Rgb24 => self.to_rgb(matrix),
+            OutputFormat::Rgba32 => self
+                .to_rgb(matrix)
+                .chunks_exact(3)
+                .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 255])
+                .collect(),
+        }
+    }
+
+    fn pack_cropped_luma(&self) -> Vec<u8> {
+        let mut packed = Vec::with_capacity(self.display_width() * self.display_height());
+        for y in self.crop.top..self.height - self.crop.bottom {
+            packed.extend_from_slice(&self.y.row(y)[self.crop.left..self.width - self.crop.right]);
+        }
+        packed
+    }
+
+    /// A chroma plane of the cropped picture resampled to 4:2:0, rounding odd sizes up
+    fn cropped_chroma_420(&self, kind: PlaneKind) -> Vec<u8> {
+        let (sub_w, sub_h) = self.chroma_format.subsampling();
+        let width = self.display_width().div_ceil(2);
+        let height = self.display_height().div_ceil(2);
+        if self.chroma_format == ChromaFormat::Monochrome {
+            return vec![128; width * height];
+        }
+
+        let plane = self.plane(kind);
+        let mut packed = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let luma_y = self.crop.top + y * 2;
+            for x in 0..width {
+                let luma_x = self.crop.left + x * 2;
+                packed.push(plane.at(luma_x / sub_w, luma_y / sub_h));
+            }
+        }
+        packed
+    }
+
+    /// Rescales a limited-range frame's samples to full range (luma 16..=235 and chroma
+    /// 16..=240 onto 0..=255), for consumers that only handle full-range YCbCr. Full-range frames
+    /// are left as they are.
+    pub fn expand_to_full_range(&mut self) {
+        if self.range == ColorRange::Full {
+            return;
+        }
+        for sample in &mut self.y.data {
+            *sample = expand_sample(*sample as i32 - 16, 219, 0);
+        }
+        for sample in self.cb.data.iter_mut().chain(&mut self.cr.data) {
+            *sample = expand_sample(*sample as i32 - 128, 224, 128);
+        }
+        self.range = ColorRange::Full;
+    }
+
+    /// MD5 of the cropped picture's samples: every Y row, then Cb, then Cr, as written to a raw
+    /// planar YUV file. This is the per-frame digest conformance tools compare against.
+    pub fn md5(&self) -> [u8; 16] {
+        let mut md5 = Md5::new();
+        for y in self.crop.top..self.height - self.crop.bottom {
+            md5.update(&self.y.row(y)[self.crop.left..self.width - self.crop.right]);
+        }
+
+        if self.chroma_format != ChromaFormat::Monochrome {
+            let (sub_w, sub_h) = self.chroma_format.subsampling();
+            let (left, right) = (
+                self.crop.left / sub_w,
+                self.cb.width - self.crop.right / sub_w,
+            );
+            let (top, bottom) = (
+                self.crop.top / sub_h,
+                self.cb.height - self.crop.bottom / sub_h,
+            );
+            for plane in [&self.cb, &self.cr] {
+                for y in top..bottom {
+                    md5.update(&plane.row(y)[left..right]);
+                }
+            }
+        }
+
+        md5.finalize()
+    }
+
+    /// The (plane, x, y) coordinates of every sample that differs from `other`, over the whole
+    /// coded picture, in plane order then raster order. A sample that only one of the frames has
+    /// counts as differing.
+    pub fn diff(&self, other: &YuvFrame) -> Vec<(PlaneKind, usize, usize)> {
+        self.diff_bounded(other, usize::MAX)
+    }
+
+    /// `diff`, stopping after the first `max` differences
+    pub fn diff_bounded(&self, other: &YuvFrame, max: usize) -> Vec<(PlaneKind, usize, usize)> {
+        let mut differences = Vec::new();
+        for kind in [PlaneKind::Y, PlaneKind::Cb, PlaneKind::Cr] {
+            let (a, b) = (self.plane(kind), other.plane(kind));
+            for y in 0..a.height.max(b.height) {
+                for x in 0..a.width.max(b.width) {
+                    let sample = |plane: &Plane| {
+                        (x < plane.width && y < plane.height).then(|| plane.at(x, y))
+                    };
+                    if sample(a) != sample(b) {
+                        if differences.len() == max {
+                            return differences;
+                        }
+                        differences.push((kind, x, y));
+                    }
+                }
+            }
+        }
+        differences
+    }
+
+    /// `md5` as a lowercase hexadecimal string
+    pub fn md5_hex(&self) -> String {
+        to_hex(&self.md5())
+    }
+
+    /// Converts the cropped picture into an `image` crate RGB buffer
+    #[cfg(feature = "image")]
+    pub fn to_image(&self, matrix: ColorMatrix) -> image::RgbImage {
+        image::RgbImage::from_raw(
+            self.display_width() as u32,
+            self.display_height() as u32,
+            self.to_rgb(matrix),
+        )
+        .expect("RGB buffer always matches the cropped frame size")
+    }
+}
+
+/// Scales a sample offset from a limited excursion of `span` onto 255, rounding to nearest
+fn expand_sample(offset: i32, span: i32, base: i32) -> u8 {
+    let scaled = (offset * 255 * 2 + offset.signum() * span) / (span * 2);
+    (base + scaled).clamp(0, 255) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 32x16 4:2:0 frame: mid-grey luma on the left half, limited-range white on the right
+    fn split_frame() -> YuvFrame {
+        let mut frame = YuvFrame::new(32, 16, ChromaFormat::Yuv420);
+        for y in 0..16 {
+            for x in 0..32 {
+                frame.y.set(x, y, if x < 16 { 126 } else { 235 });
+            }
+        }
+        frame.cb.data.fill(128);
+        frame.cr.data.fill(128);
+        frame
+    }
+
+    #[test]
+    fn test_chroma_plane_sizes() {
+        let frame = YuvFrame::new(32, 16, ChromaFormat::Yuv420);
+        assert_eq!((frame.cb.width, frame.cb.height), (16, 8));
+
+        let frame = YuvFrame::new(32, 16, ChromaFormat::Yuv422);
+        assert_eq!((frame.cr.width, frame.cr.height), (16, 16));
+
+        let frame = YuvFrame::new(32, 16, ChromaFormat::Monochrome);
+        assert!(frame.cb.data.is_empty());
+    }
+
+    #[test]
+    fn test_chroma_dimensions() {
+        let dimensions = |chroma_format| YuvFrame::new(64, 32, chroma_format).chroma_dimensions();
+        assert_eq!(dimensions(ChromaFormat::Yuv420), (32, 16));
+        assert_eq!(dimensions(ChromaFormat::Yuv422), (32, 32));
+        assert_eq!(dimensions(ChromaFormat::Yuv444), (64, 32));
+        assert_eq!(dimensions(ChromaFormat::Monochrome), (0, 0));
+
+        let frame = YuvFrame::new(64, 32, ChromaFormat::Yuv422);
+        assert_eq!(
+            frame.chroma_dimensions(),
+            (frame.cb.width as u32, frame.cb.height as u32)
+        );
+    }
+
+    #[test]
+    fn test_pack_planes_removes_stride_padding() -> anyhow::Result<()> {
+        let mut frame = YuvFrame::new(4, 2, ChromaFormat::Yuv420);
+        frame.y = Plane {
+            data: vec![1, 2, 3, 4, 0xEE, 0xEE, 5, 6, 7, 8, 0xEE, 0xEE],
+            width: 4,
+            height: 2,
+            stride: 6,
+        };
+
+        frame.cb = Plane {
+            data: vec![9, 9, 0xEE],
+            width: 2,
+            height: 1,
+            stride: 3,
+        };
+        frame.cr.data.fill(10);
+
+        assert_eq!(frame.pack_plane(PlaneKind::Y), [1, 2, 3, 4, 5, 6, 7, 8]);
+        let i420 = frame.to_i420_packed()?;
+        assert_eq!(i420.len(), 4 * 2 + 2 * 2);
+        assert_eq!(i420, [1, 2, 3, 4, 5, 6, 7, 8, 9, 9, 10, 10]);
+
+        assert!(
+            YuvFrame::new(4, 2, ChromaFormat::Yuv444)
+                .to_i420_packed()
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_planes() -> anyhow::Result<()> {
+        let y: Vec<u8> = (0..4 * 2).collect();
+        // Chroma rows padded to a stride of 4, except for the last one
+        let cb = vec![20, 21, 0xEE, 0xEE];
+        let cr = vec![30, 31, 0xEE, 0xEE, 0xEE];
+        let frame = YuvFrame::from_planes(4, 2, ChromaFormat::Yuv422, y.clone(), cb, cr, [4, 4, 4]);
+        assert!(frame.is_err(), "4:2:2 chroma of a 4x2 frame has two rows");
+
+        let frame = YuvFrame::from_planes(
+            4,
+            2,
+            ChromaFormat::Yuv420,
+            y.clone(),
+            vec![20, 21],
+            vec![30, 31, 0xEE],
+            [4, 2, 3],
+        )?;
+        assert_eq!(frame.pack_plane(PlaneKind::Y), y);
+        assert_eq!(frame.y.row(1), [4, 5, 6, 7]);
+        assert_eq!((frame.cb.at(1, 0), frame.cr.at(0, 0)), (21, 30));
+        assert_eq!(
+            frame.to_i420_packed()?,
+            [0, 1, 2, 3, 4, 5, 6, 7, 20, 21, 30, 31]
+        );
+
+        let mono =
+            YuvFrame::from_planes(4, 2, ChromaFormat::Monochrome, y, vec![], vec![], [4, 0, 0])?;
+        assert_eq!(mono.chroma_dimensions(), (0, 0));
+
+        // A stride narrower than the width, and a plane missing its last sample
+        let short = |y: Vec<u8>, stride| {
+            YuvFrame::from_planes(
+                4,
+                2,
+                ChromaFormat::Yuv420,
+                y,
+                vec![0; 2],
+                vec![0; 2],
+                [stride, 2, 2],
+            )
+        };
+        assert!(short(vec![0; 8], 3).is_err());
+        assert!(short(vec![0; 7], 4).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_rgb_respects_crop() {
+        let mut frame = split_frame();
+        frame.crop = CropWindow {
+            left: 0,
+            right: 16,
+            top: 0,
+            bottom: 8,
+        };
+
+        let rgb = frame.to_rgb(ColorMatrix::Bt709);
+        assert_eq!(rgb.len(), 16 * 8 * 3);
+        assert!(
+            rgb.iter().all(|&c| c < 255),
+            "Cropped region shouldn't contain the white half"
+        );
+    }
+
+    #[test]
+    fn test_to_rgb_monochrome() {
+        let mut frame = YuvFrame::new(16, 16, ChromaFormat::Monochrome);
+        frame.y.data.fill(235);
+
+        let rgb = frame.to_rgb(ColorMatrix::Bt601);
+        assert!(rgb.iter().all(|&c| c == 255));
+    }
+
+    #[test]
+    fn test_expand_to_full_range() {
+        let mut frame = split_frame();
+        frame.y.set(0, 0, 16);
+        frame.y.set(1, 0, 0);
+        frame.cb.set(0, 0, 240);
+        frame.cr.set(0, 0, 16);
+        frame.expand_to_full_range();
+
+        assert_eq!(frame.range, ColorRange::Full);
+        assert_eq!(frame.y.at(0, 0), 0);
+        // Footroom below 16 clips to black
+        assert_eq!(frame.y.at(1, 0), 0);
+        assert_eq!(frame.y.at(16, 0), 255);
+        assert_eq!(frame.y.at(0, 1), 128);
+        assert_eq!((frame.cb.at(0, 0), frame.cr.at(0, 0)), (255, 0));
+        assert_eq!(frame.cb.at(1, 0), 128);
+
+        // The converted frame still looks the same
+        let rgb = frame.to_rgb(ColorMatrix::Bt601);
+        assert_eq!(&rgb[16 * 3..17 * 3], &[255, 255, 255]);
+
+        // A second call doesn't expand again
+        let expanded = frame.clone();
+        frame.expand_to_full_range();
+        assert_eq!(frame, expanded);
+    }
+
+    #[test]
+    fn test_diff_finds_altered_sample() {
+        let reference = split_frame();
+        let mut frame = reference.clone();
+        assert!(frame.diff(&reference).is_empty());
+        assert_eq!(frame.y.checksum(), reference.y.checksum());
+
+        frame.cr.set(5, 3, 129);
+        assert_eq!(frame.diff(&reference), &[(PlaneKind::Cr, 5, 3)]);
+        assert_eq!(frame.y.checksum(), reference.y.checksum());
+        assert_ne!(frame.cr.checksum(), reference.cr.checksum());
+
+        frame.y.set(20, 1, 0);
+        frame.y.set(0, 2, 0);
+        assert_eq!(
+            frame.diff(&reference),
+            &[
+                (PlaneKind::Y, 20, 1),
+                (PlaneKind::Y, 0, 2),
+                (PlaneKind::Cr, 5, 3)
+            ]
+        );
+        assert_eq!(frame.diff_bounded(&reference, 1), &[(PlaneKind::Y, 20, 1)]);
+    }
+
+    #[test]
+    fn test_diff_of_different_sizes() {
+        let small = YuvFrame::new(16, 16, ChromaFormat::Monochrome);
+        let large = YuvFrame::new(16, 17, ChromaFormat::Monochrome);
+        let differences = small.diff(&large);
+        assert_eq!(differences.len(), 16);
+        assert!(
+            differences
+                .iter()
+                .all(|&(kind, _, y)| kind == PlaneKind::Y && y == 16)
+        );
+    }
+
+    #[test]
+    fn test_md5_of_known_frames() {
+        let frame = YuvFrame::new(16, 16, ChromaFormat::Yuv420);
+        assert_eq!(frame.md5_hex(), "0fe8b6ff202a2b826cb73fc50d089e9b");
+
+        let mut frame = YuvFrame::new(16, 16, ChromaFormat::Yuv420);
+        for y in 0..16 {
+            for x in 0..16 {
+                frame.y.set(x, y, ((x + y) & 0xff) as u8);
+            }
+        }
+        frame.cb.data.fill(128);
+        frame.cr.data.fill(64);
+        assert_eq!(frame.md5_hex(), "95e25ecbb885b87a7e9c4030d657785d");
+
+        frame.crop.right = 2;
+        assert_eq!(frame.md5_hex(), "ba734a9ee0444adbd4ab4bee57c3d52c");
+    }
+
+    #[test]
+    fn test_output_format_layouts() {
+        let mut frame = YuvFrame::new(16, 16, ChromaFormat::Yuv420);
+        frame.y.data.fill(16);
+        frame.cb.data.fill(100);
+        frame.cr.data.fill(200);
+        frame.crop.right = 2;
+        frame.crop.bottom = 4;
+
+        let i420 = frame.to_output_format(OutputFormat::I420, ColorMatrix::Bt601);
+        assert_eq!(i420.len(), 14 * 12 + 2 * 7 * 6);
+        assert_eq!(&i420[14 * 12..][..2], &[100, 100]);
+        assert_eq!(i420[14 * 12 + 7 * 6], 200);
+
+        let nv12 = frame.to_output_format(OutputFormat::Nv12, ColorMatrix::Bt601);
+        assert_eq!(nv12.len(), i420.len());
+        assert_eq!(&nv12[14 * 12..][..4], &[100, 200, 100, 200]);
+
+        let rgba = frame.to_output_format(OutputFormat::Rgba32, ColorMatrix::Bt601);
+        let rgb = frame.to_rgb(ColorMatrix::Bt601);
+        assert_eq!(rgba.len(), 14 * 12 * 4);
+        assert_eq!(&rgba[..4], &[rgb[0], rgb[1], rgb[2], 255]);
+
+        // Monochrome frames get neutral chroma
+        let mut frame = YuvFrame::new(16, 16, ChromaFormat::Monochrome);
+        frame.y.data.fill(16);
+        let i420 = frame.to_output_format(OutputFormat::I420, ColorMatrix::Bt601);
+        assert_eq!(i420.len(), 16 * 16 * 3 / 2);
+        assert!(i420[256..].iter().all(|&sample| sample == 128));
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn test_to_image_dimensions_and_pixel() {
+        let mut frame = split_frame();
+        frame.crop.bottom = 2;
+
+        let image = frame.to_image(ColorMatrix::Bt601);
+        assert_eq!(image.dimensions(), (32, 14));
+        assert_eq!(image.get_pixel(20, 5).0, [255, 255, 255]);
+
+        frame.range = ColorRange::Full;
+        let image = frame.to_image(ColorMatrix::Bt601);
+        assert_eq!(image.get_pixel(0, 0).0, [126, 126, 126]);
+    }
+}
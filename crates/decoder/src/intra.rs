@@ -1 +1,377 @@
+//! Intra prediction (8.3)
 
+use anyhow::{Result, anyhow};
+
+/// The constructed samples around a block that intra prediction reads from. `top` holds p[x, -1]
+/// (including the top-right samples for 4x4 blocks), `left` holds p[-1, y].
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Edge {
+    pub top: [u8; 16],
+    pub left: [u8; 16],
+    pub top_left: u8,
+    pub has_top: bool,
+    pub has_top_right: bool,
+    pub has_left: bool,
+    pub has_top_left: bool,
+}
+
+impl Edge {
+    fn require(&self, top: bool, left: bool, top_left: bool) -> Result<()> {
+        if (top && !self.has_top) || (left && !self.has_left) || (top_left && !self.has_top_left) {
+            return Err(anyhow!("Intra prediction references unavailable samples"));
+        }
+        Ok(())
+    }
+}
+
+/// 8.3.1.2 Intra_4x4 sample prediction, into a raster ordered 4x4 block
+pub(crate) fn predict_4x4(mode: u8, edge: &Edge, pred: &mut [u8; 16]) -> Result<()> {
+    let mut top = [0i32; 8];
+    for (x, t) in top.iter_mut().enumerate() {
+        // Missing top-right samples are substituted with p[3, -1] (8.3.1.2)
+        *t = if x < 4 || edge.has_top_right {
+            edge.top[x]
+        } else {
+            edge.top[3]
+        } as i32;
+    }
+    let left: [i32; 4] = std::array::from_fn(|y| edge.left[y] as i32);
+    let tl = edge.top_left as i32;
+
+    // p[x, -1] for x in -1..=7 and p[-1, y] for y in -1..=3
+    let pt = |x: i32| if x < 0 { tl } else { top[x as usize] };
+    let pl = |y: i32| if y < 0 { tl } else { left[y as usize] };
+
+    for y in 0..4i32 {
+        for x in 0..4i32 {
+            let value = match mode {
+                0 => {
+                    edge.require(true, false, false)?;
+                    pt(x)
+                }
+                1 => {
+                    edge.require(false, true, false)?;
+                    pl(y)
+                }
+                2 => match (edge.has_top, edge.has_left) {
+                    (true, true) => {
+                        (top[..4].iter().sum::<i32>() + left.iter().sum::<i32>() + 4) >> 3
+                    }
+                    (false, true) => (left.iter().sum::<i32>() + 2) >> 2,
+                    (true, false) => (top[..4].iter().sum::<i32>() + 2) >> 2,
+                    (false, false) => 128,
+                },
+                3 => {
+                    edge.require(true, false, false)?;
+                    if x == 3 && y == 3 {
+                        (pt(6) + 3 * pt(7) + 2) >> 2
+                    } else {
+                        (pt(x + y) + 2 * pt(x + y + 1) + pt(x + y + 2) + 2) >> 2
+                    }
+                }
+                4 => {
+                    edge.require(true, true, true)?;
+                    if x > y {
+                        (pt(x - y - 2) + 2 * pt(x - y - 1) + pt(x - y) + 2) >> 2
+                    } else if x < y {
+                        (pl(y - x - 2) + 2 * pl(y - x - 1) + pl(y - x) + 2) >> 2
+                    } else {
+                        (pt(0) + 2 * tl + pl(0) + 2) >> 2
+                    }
+                }
+                5 => {
+                    edge.require(true, true, true)?;
+                    let z = 2 * x - y;
+                    match z {
+                        0 | 2 | 4 | 6 => (pt(x - (y >> 1) - 1) + pt(x - (y >> 1)) + 1) >> 1,
+                        1 | 3 | 5 => {
+                            (pt(x - (y >> 1) - 2) + 2 * pt(x - (y >> 1) - 1) + pt(x - (y >> 1)) + 2)
+                                >> 2
+                        }
+                        -1 => (pl(0) + 2 * tl + pt(0) + 2) >> 2,
+                        _ => (pl(y - 1) + 2 * pl(y - 2) + pl(y - 3) + 2) >> 2,
+                    }
+                }
+                6 => {
+                    edge.require(true, true, true)?;
+                    let z = 2 * y - x;
+                    match z {
+                        0 | 2 | 4 | 6 => (pl(y - (x >> 1) - 1) + pl(y - (x >> 1)) + 1) >> 1,
+                        1 | 3 | 5 => {
+                            (pl(y - (x >> 1) - 2) + 2 * pl(y - (x >> 1) - 1) + pl(y - (x >> 1)) + 2)
+                                >> 2
+                        }
+                        -1 => (pl(0) + 2 * tl + pt(0) + 2) >> 2,
+                        _ => (pt(x - 1) + 2 * pt(x - 2) + pt(x - 3) + 2) >> 2,
+                    }
+                }
+                7 => {
+                    edge.require(true, false, false)?;
+                    let i = x + (y >> 1);
+                    if y % 2 == 0 {
+                        (pt(i) + pt(i + 1) + 1) >> 1
+                    } else {
+                        (pt(i) + 2 * pt(i + 1) + pt(i + 2) + 2) >> 2
+                    }
+                }
+                8 => {
+                    edge.require(false, true, false)?;
+                    let z = x + 2 * y;
+                    let i = y + (x >> 1);
+                    match z {
+                        0 | 2 | 4 => (pl(i) + pl(i + 1) + 1) >> 1,
+                        1 | 3 => (pl(i) + 2 * pl(i + 1) + pl(i + 2) + 2) >> 2,
+                        5 => (pl(2) + 3 * pl(3) + 2) >> 2,
+                        _ => pl(3),
+                    }
+                }
+                _ => return Err(anyhow!("Invalid Intra4x4PredMode: {}", mode)),
+            };
+            pred[(y * 4 + x) as usize] = value as u8;
+        }
+    }
+
+    Ok(())
+}
+
+/// 8.3.3 Intra_16x16 prediction, into a raster ordered 16x16 block
+pub(crate) fn predict_16x16(mode: u8, edge: &Edge, pred: &mut [u8; 256]) -> Result<()> {
+    match mode {
+        0 => {
+            edge.require(true, false, false)?;
+            for row in pred.chunks_exact_mut(16) {
+                row.copy_from_slice(&edge.top);
+            }
+        }
+        1 => {
+            edge.require(false, true, false)?;
+            for (row, &left) in pred.chunks_exact_mut(16).zip(edge.left.iter()) {
+                row.fill(left);
+            }
+        }
+        2 => {
+            let top: u32 = edge.top.iter().map(|&s| s as u32).sum();
+            let left: u32 = edge.left.iter().map(|&s| s as u32).sum();
+            let dc = match (edge.has_top, edge.has_left) {
+                (true, true) => (top + left + 16) >> 5,
+                (false, true) => (left + 8) >> 4,
+                (true, false) => (top + 8) >> 4,
+                (false, false) => 128,
+            };
+            pred.fill(dc as u8);
+        }
+        3 => {
+            edge.require(true, true, true)?;
+            predict_plane(edge, 16, 16, 5, 5, pred);
+        }
+        _ => return Err(anyhow!("Invalid Intra16x16PredMode: {}", mode)),
+    }
+    Ok(())
+}
+
+/// 8.3.4 Intra prediction for chroma samples, into a raster ordered `width` x `height` block
+pub(crate) fn predict_chroma(
+    mode: u8,
+    edge: &Edge,
+    width: usize,
+    height: usize,
+    pred: &mut [u8],
+) -> Result<()> {
+    match mode {
+        0 => {
+            // DC is derived per 4x4 chroma block (8.3.4.1 to 8.3.4.3)
+            for block_y in (0..height).step_by(4) {
+                for block_x in (0..width).step_by(4) {
+                    let top = edge.top[block_x..block_x + 4]
+                        .iter()
+                        .map(|&s| s as u32)
+                        .sum::<u32>();
+                    let left = edge.left[block_y..block_y + 4]
+                        .iter()
+                        .map(|&s| s as u32)
+                        .sum::<u32>();
+                    let both = (top + left + 4) >> 3;
+                    let top = (top + 2) >> 2;
+                    let left = (left + 2) >> 2;
+
+                    let dc = if (block_x == 0 && block_y == 0) || (block_x > 0 && block_y > 0) {
+                        match (edge.has_top, edge.has_left) {
+                            (true, true) => both,
+                            (false, true) => left,
+                            (true, false) => top,
+                            (false, false) => 128,
+                        }
+                    } else if block_x > 0 {
+                        match (edge.has_top, edge.has_left) {
+                            (true, _) => top,
+                            (false, true) => left,
+                            (false, false) => 128,
+                        }
+                    } else {
+                        match (edge.has_top, edge.has_left) {
+                            (_, true) => left,
+                            (true, false) => top,
+                            (false, false) => 128,
+                        }
+                    };
+
+                    for y in block_y..block_y + 4 {
+                        pred[y * width + block_x..y * width + block_x + 4].fill(dc as u8);
+                    }
+                }
+            }
+        }
+        1 => {
+            edge.require(false, true, false)?;
+            for (row, &left) in pred
+                .chunks_exact_mut(width)
+                .take(height)
+                .zip(edge.left.iter())
+            {
+                row.fill(left);
+            }
+        }
+        2 => {
+            edge.require(true, false, false)?;
+            for row in pred.chunks_exact_mut(width).take(height) {
+                row.copy_from_slice(&edge.top[..width]);
+            }
+        }
+        3 => {
+            edge.require(true, true, true)?;
+            // The b and c weights are 34 - 29 * (chroma_format_idc == 3) and
+            // 34 - 29 * (chroma_format_idc != 1)
+            let b_weight = if width == 16 { 5 } else { 34 };
+            let c_weight = if height == 16 { 5 } else { 34 };
+            predict_plane(edge, width, height, b_weight, c_weight, pred);
+        }
+        _ => return Err(anyhow!("Invalid intra_chroma_pred_mode: {}", mode)),
+    }
+    Ok(())
+}
+
+/// The plane prediction shared by Intra_16x16 (8-138 to 8-141) and chroma (8-147 to 8-150)
+fn predict_plane(
+    edge: &Edge,
+    width: usize,
+    height: usize,
+    b_weight: i32,
+    c_weight: i32,
+    pred: &mut [u8],
+) {
+    let pt = |x: i32| {
+        if x < 0 {
+            edge.top_left as i32
+        } else {
+            edge.top[x as usize] as i32
+        }
+    };
+    let pl = |y: i32| {
+        if y < 0 {
+            edge.top_left as i32
+        } else {
+            edge.left[y as usize] as i32
+        }
+    };
+
+    let (half_w, half_h) = (width as i32 / 2, height as i32 / 2);
+    let h: i32 = (0..half_w)
+        .map(|x| (x + 1) * (pt(half_w + x) - pt(half_w - 2 - x)))
+        .sum();
+    let v: i32 = (0..half_h)
+        .map(|y| (y + 1) * (pl(half_h + y) - pl(half_h - 2 - y)))
+        .sum();
+
+    let a = 16 * (pl(height as i32 - 1) + pt(width as i32 - 1));
+    let b = (b_weight * h + 32) >> 6;
+    let c = (c_weight * v + 32) >> 6;
+
+    for y in 0..height {
+        for x in 0..width {
+            let value =
+                (a + b * (x as i32 - (half_w - 1)) + c * (y as i32 - (half_h - 1)) + 16) >> 5;
+            pred[y * width + x] = value.clamp(0, 255) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edge(top: u8, left: u8) -> Edge {
+        Edge {
+            top: [top; 16],
+            left: [left; 16],
+            top_left: top,
+            has_top: true,
+            has_top_right: true,
+            has_left: true,
+            has_top_left: true,
+        }
+    }
+
+    #[test]
+    fn test_4x4_dc_without_neighbours() -> Result<()> {
+        let mut pred = [0; 16];
+        predict_4x4(2, &Edge::default(), &mut pred)?;
+        assert!(pred.iter().all(|&s| s == 128));
+        assert!(predict_4x4(0, &Edge::default(), &mut pred).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_4x4_directional_modes() -> Result<()> {
+        let mut edge = edge(0, 0);
+        edge.top[..8].copy_from_slice(&[10, 20, 30, 40, 50, 60, 70, 80]);
+        edge.left[..4].copy_from_slice(&[1, 2, 3, 4]);
+        edge.top_left = 5;
+        let mut pred = [0; 16];
+
+        predict_4x4(0, &edge, &mut pred)?;
+        assert_eq!(&pred[12..], &[10, 20, 30, 40]);
+        predict_4x4(1, &edge, &mut pred)?;
+        assert_eq!(&pred[4..8], &[2, 2, 2, 2]);
+
+        // Diagonal down left: p[3, 3] = (p[6, -1] + 3 * p[7, -1] + 2) >> 2
+        predict_4x4(3, &edge, &mut pred)?;
+        assert_eq!(pred[15], 78);
+
+        // Without top-right samples, p[3, -1] is repeated
+        edge.has_top_right = false;
+        predict_4x4(3, &edge, &mut pred)?;
+        assert_eq!(pred[15], 40);
+
+        // Horizontal up: zHU > 5 is p[-1, 3]
+        predict_4x4(8, &edge, &mut pred)?;
+        assert_eq!(pred[15], 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_16x16_plane_of_flat_edges_is_flat() -> Result<()> {
+        let mut pred = [0; 256];
+        predict_16x16(3, &edge(77, 77), &mut pred)?;
+        assert!(pred.iter().all(|&s| s == 77));
+        Ok(())
+    }
+
+    #[test]
+    fn test_chroma_dc_per_block() -> Result<()> {
+        let mut edge = edge(100, 20);
+        edge.has_left = false;
+        let mut pred = [0; 64];
+        predict_chroma(0, &edge, 8, 8, &mut pred)?;
+        assert!(pred.iter().all(|&s| s == 100));
+
+        let mut edge = self::edge(100, 20);
+        edge.top[4..8].fill(200);
+        predict_chroma(0, &edge, 8, 8, &mut pred)?;
+        // Top-left averages both edges, top-right only uses the samples above it
+        assert_eq!(pred[0], 60);
+        assert_eq!(pred[4], 200);
+        assert_eq!(pred[32], 20);
+        assert_eq!(pred[36], 110);
+        Ok(())
+    }
+}
@@ -1,6 +1,29 @@
+mod cabac;
 mod cavlc;
-mod frame;
+pub mod color;
+mod deblock;
+pub mod decoder;
+mod dpb;
+pub mod fields;
+pub mod frame;
 mod intra;
+mod macroblock;
+mod md5;
 mod motion;
+mod picture;
+mod poc;
+mod scratch;
+mod slice_data;
+mod slice_group;
+#[cfg(test)]
 mod tests;
 mod transform;
+
+pub use color::{ColorMatrix, ColorRange};
+pub use decoder::{
+    ConcealmentMode, Decoder, DecoderStats, ParamSetChange, ParamSetKind, SeekHint,
+    SliceDiagnostics, StreamInfo, UnsupportedFeature,
+};
+pub use fields::FieldPairer;
+pub use frame::{ChromaFormat, CropWindow, OutputFormat, Plane, PlaneKind, YuvFrame};
+pub use picture::{DecodedPicture, PictureStructure};
@@ -0,0 +1,1732 @@
+use crate::cabac::{
+    CTX_MB_SKIP_P, CTX_MB_TYPE_I, CTX_MB_TYPE_P_PREFIX, CTX_MB_TYPE_P_SUFFIX, CabacContext,
+    CabacDecoder,
+};
+use crate::cavlc::{read_coded_block_pattern, read_residual_block};
+use crate::frame::{ChromaFormat, Plane};
+use crate::intra::{Edge, predict_4x4, predict_16x16, predict_chroma};
+use crate::motion::{clamp_luma_mv, derive_chroma_mv, interpolate_chroma, predict_luma_block};
+use crate::picture::{MbInfo, Picture};
+use crate::scratch::DecodeScratch;
+use crate::slice_data::SliceContext;
+use crate::transform::{
+    ZIGZAG_4X4, chroma_qp, dequantize_4x4, inverse_chroma_dc_420, inverse_luma_dc,
+    inverse_transform_4x4,
+};
+use anyhow::{Result, anyhow};
+use bitstream::BitReader;
+use parser::SliceType;
+
+/// Macroblock types, as in Tables 7-11 to 7-14
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum MbType {
+    #[default]
+    INxN,
+    I16x16 {
+        pred_mode: u8,
+        cbp_chroma: u8,
+        cbp_luma: u8,
+    },
+    IPcm,
+    SI,
+    PL016x16,
+    PL0L016x8,
+    PL0L08x16,
+    P8x8,
+    P8x8Ref0,
+    PSkip,
+    BDirect16x16,
+    B16x16(PredList),
+    B16x8(PredList, PredList),
+    B8x16(PredList, PredList),
+    B8x8,
+}
+
+/// Reference picture lists a macroblock partition predicts from, the Pred_L0, Pred_L1 and
+/// BiPred values of MbPartPredMode()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PredList {
+    L0,
+    L1,
+    Bi,
+}
+
+/// A macroblock partition, in luma samples relative to the macroblock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct MbPartition {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    pub list: PredList,
+}
+
+impl MbType {
+    /// Maps a parsed `mb_type` onto its type, given the slice it was read in
+    pub fn from_mb_type(slice_type: SliceType, mb_type: u32) -> Result<Self> {
+        match slice_type {
+            SliceType::I => Self::intra(mb_type),
+            SliceType::SI if mb_type == 0 => Ok(MbType::SI),
+            SliceType::SI => Self::intra(mb_type - 1),
+            SliceType::P | SliceType::SP => match mb_type {
+                0 => Ok(MbType::PL016x16),
+                1 => Ok(MbType::PL0L016x8),
+                2 => Ok(MbType::PL0L08x16),
+                3 => Ok(MbType::P8x8),
+                4 => Ok(MbType::P8x8Ref0),
+                _ => Self::intra(mb_type - 5),
+            },
+            SliceType::B => Self::bipredictive(mb_type),
+        }
+    }
+
+    /// Table 7-14 Macroblock types for B slices
+    fn bipredictive(mb_type: u32) -> Result<Self> {
+        use PredList::{Bi, L0, L1};
+        const PAIRS: [(PredList, PredList); 9] = [
+            (L0, L0),
+            (L1, L1),
+            (L0, L1),
+            (L1, L0),
+            (L0, Bi),
+            (L1, Bi),
+            (Bi, L0),
+            (Bi, L1),
+            (Bi, Bi),
+        ];
+        match mb_type {
+            0 => Ok(MbType::BDirect16x16),
+            1 => Ok(MbType::B16x16(L0)),
+            2 => Ok(MbType::B16x16(L1)),
+            3 => Ok(MbType::B16x16(Bi)),
+            4..=21 => {
+                let index = (mb_type - 4) as usize;
+                let (first, second) = PAIRS[index / 2];
+                Ok(if index.is_multiple_of(2) {
+                    MbType::B16x8(first, second)
+                } else {
+                    MbType::B8x16(first, second)
+                })
+            }
+            22 => Ok(MbType::B8x8),
+            _ => Self::intra(mb_type - 23),
+        }
+    }
+
+    /// Partitions of an inter macroblock with their MbPartPredMode() (Tables 7-13 and 7-14), in
+    /// decoding order. Intra macroblocks, B_Direct_16x16 and the 8x8 types, whose prediction
+    /// comes from direct prediction or sub_mb_type, have none.
+    pub fn partitions(self) -> impl Iterator<Item = MbPartition> {
+        let part = |x, y, width, height, list| MbPartition {
+            x,
+            y,
+            width,
+            height,
+            list,
+        };
+        let (first, second) = match self {
+            MbType::PL016x16 | MbType::PSkip => (Some(part(0, 0, 16, 16, PredList::L0)), None),
+            MbType::PL0L016x8 => (
+                Some(part(0, 0, 16, 8, PredList::L0)),
+                Some(part(0, 8, 16, 8, PredList::L0)),
+            ),
+            MbType::PL0L08x16 => (
+                Some(part(0, 0, 8, 16, PredList::L0)),
+                Some(part(8, 0, 8, 16, PredList::L0)),
+            ),
+            MbType::B16x16(list) => (Some(part(0, 0, 16, 16, list)), None),
+            MbType::B16x8(first, second) => (
+                Some(part(0, 0, 16, 8, first)),
+                Some(part(0, 8, 16, 8, second)),
+            ),
+            MbType::B8x16(first, second) => (
+                Some(part(0, 0, 8, 16, first)),
+                Some(part(8, 0, 8, 16, second)),
+            ),
+            _ => (None, None),
+        };
+        // Without the allocation of a Vec, as this runs for every inter macroblock
+        first.into_iter().chain(second)
+    }
+
+    pub fn is_intra(self) -> bool {
+        matches!(
+            self,
+            MbType::INxN | MbType::I16x16 { .. } | MbType::IPcm | MbType::SI
+        )
+    }
+
+    /// Table 7-11 Macroblock types for I slices
+    fn intra(mb_type: u32) -> Result<Self> {
+        match mb_type {
+            0 => Ok(MbType::INxN),
+            1..=24 => {
+                let index = mb_type - 1;
+                Ok(MbType::I16x16 {
+                    pred_mode: (index % 4) as u8,
+                    cbp_chroma: ((index / 4) % 3) as u8,
+                    cbp_luma: if index >= 12 { 15 } else { 0 },
+                })
+            }
+            25 => Ok(MbType::IPcm),
+            _ => Err(anyhow!("Invalid intra mb_type: {}", mb_type)),
+        }
+    }
+}
+
+/// Reads and reconstructs one macroblock_layer() (7.3.5) at `mb_addr`
+pub(crate) fn decode_macroblock(
+    reader: &mut BitReader,
+    ctx: &mut SliceContext,
+    picture: &mut Picture,
+    scratch: &mut DecodeScratch,
+    mb_addr: usize,
+) -> Result<()> {
+    let mb_type = MbType::from_mb_type(ctx.slice_type, reader.read_ue()?)?;
+    let mut info = MbInfo {
+        decoded: true,
+        slice_num: ctx.slice_num,
+        mb_type,
+        qp: ctx.qp,
+        ..Default::default()
+    };
+
+    match mb_type {
+        MbType::IPcm => {
+            decode_pcm(reader, ctx, picture, mb_addr)?;
+            // QPY,PRED for the next macroblock stays SliceQPY's running value in `ctx`
+            info.qp = 0;
+            // nC treats every block of an I_PCM macroblock as holding 16 coefficients
+            info.total_coeff = [16; 16];
+            info.total_coeff_chroma = [[16; 8]; 2];
+        }
+        MbType::INxN | MbType::I16x16 { .. } => {
+            decode_intra(reader, ctx, picture, scratch, mb_addr, &mut info)?
+        }
+        MbType::PL016x16 | MbType::PL0L016x8 | MbType::PL0L08x16 => {
+            decode_inter(reader, ctx, picture, scratch, mb_addr, &mut info)?
+        }
+        other => return Err(anyhow!("Macroblock type {:?} is not supported", other)),
+    }
+
+    picture.mb_info[mb_addr] = info;
+    Ok(())
+}
+
+/// Reads mb_skip_flag in a P slice. ctxIdxInc counts the available neighbours A and B that
+/// aren't skipped (9.3.3.1.1.1).
+pub(crate) fn read_mb_skip_flag(
+    cabac: &mut CabacDecoder,
+    contexts: &mut [CabacContext],
+    ctx: &SliceContext,
+    picture: &Picture,
+    mb_addr: usize,
+) -> Result<bool> {
+    if !matches!(ctx.slice_type, SliceType::P | SliceType::SP) {
+        return Err(anyhow!(
+            "CABAC {:?} slices are not supported",
+            ctx.slice_type
+        ));
+    }
+    let n = MbNeighbours::new(picture, ctx.slice_num, mb_addr);
+    let coded = |addr: Option<usize>| {
+        addr.is_some_and(|a| picture.mb_info[a].mb_type != MbType::PSkip) as usize
+    };
+    cabac.decode_decision(&mut contexts[CTX_MB_SKIP_P + coded(n.a) + coded(n.b)])
+}
+
+/// Reads and reconstructs one CABAC coded macroblock_layer() (7.3.5) at `mb_addr`. Only the
+/// mb_type bins are decoded so far, so I_PCM is the one supported macroblock type.
+pub(crate) fn decode_cabac_macroblock(
+    cabac: &mut CabacDecoder,
+    contexts: &mut [CabacContext],
+    ctx: &SliceContext,
+    picture: &mut Picture,
+    mb_addr: usize,
+) -> Result<()> {
+    let mb_type = read_cabac_mb_type(cabac, contexts, ctx, picture, mb_addr)?;
+    if mb_type != MbType::IPcm {
+        return Err(anyhow!(
+            "CABAC macroblock type {:?} is not supported",
+            mb_type
+        ));
+    }
+
+    decode_pcm(cabac.reader(), ctx, picture, mb_addr)?;
+    cabac.restart()?;
+    picture.mb_info[mb_addr] = MbInfo {
+        decoded: true,
+        slice_num: ctx.slice_num,
+        mb_type,
+        qp: 0,
+        total_coeff: [16; 16],
+        total_coeff_chroma: [[16; 8]; 2],
+        ..Default::default()
+    };
+    Ok(())
+}
+
+/// The mb_type bins of I and P slices (9.3.2.5), as far as telling I_NxN and I_PCM apart
+fn read_cabac_mb_type(
+    cabac: &mut CabacDecoder,
+    contexts: &mut [CabacContext],
+    ctx: &SliceContext,
+    picture: &Picture,
+    mb_addr: usize,
+) -> Result<MbType> {
+    let first_bin = match ctx.slice_type {
+        SliceType::I => {
+            // ctxIdxInc counts the available neighbours that aren't I_NxN (9.3.3.1.1.3)
+            let n = MbNeighbours::new(picture, ctx.slice_num, mb_addr);
+            let not_nxn = |addr: Option<usize>| {
+                addr.is_some_and(|a| picture.mb_info[a].mb_type != MbType::INxN) as usize
+            };
+            &mut contexts[CTX_MB_TYPE_I + not_nxn(n.a) + not_nxn(n.b)]
+        }
+        SliceType::P | SliceType::SP => {
+            if !cabac.decode_decision(&mut contexts[CTX_MB_TYPE_P_PREFIX])? {
+                return Err(anyhow!("CABAC inter macroblocks are not supported"));
+            }
+            &mut contexts[CTX_MB_TYPE_P_SUFFIX]
+        }
+        other => return Err(anyhow!("CABAC {:?} slices are not supported", other)),
+    };
+
+    if !cabac.decode_decision(first_bin)? {
+        return Ok(MbType::INxN);
+    }
+    if !cabac.decode_terminate()? {
+        return Err(anyhow!("CABAC I_16x16 macroblocks are not supported"));
+    }
+    Ok(MbType::IPcm)
+}
+
+/// Reconstructs a P_Skip macroblock, predicted from RefPicList0[0] with the motion vector
+/// derived from its neighbours (8.4.1.1)
+pub(crate) fn decode_skip(
+    ctx: &SliceContext,
+    picture: &mut Picture,
+    scratch: &mut DecodeScratch,
+    mb_addr: usize,
+) -> Result<()> {
+    // B_Skip is B_Direct_16x16 without a residual, which needs direct prediction
+    if !matches!(ctx.slice_type, SliceType::P | SliceType::SP) {
+        return Err(anyhow!(
+            "Skipped macroblock {} in a {:?} slice is not supported",
+            mb_addr,
+            ctx.slice_type
+        ));
+    }
+    if ctx.ref_list0.is_empty() {
+        return Err(anyhow!(
+            "P_Skip macroblock {} has no reference picture",
+            mb_addr
+        ));
+    }
+
+    let neighbours = MbNeighbours::new(picture, ctx.slice_num, mb_addr);
+    let mut info = MbInfo {
+        decoded: true,
+        slice_num: ctx.slice_num,
+        mb_type: MbType::PSkip,
+        qp: ctx.qp,
+        ..Default::default()
+    };
+    info.mv_l0 = [p_skip_mv(picture, &neighbours, &info); 16];
+    set_ref_pics(ctx, &mut info);
+    let (mb_x, mb_y) = picture.mb_position(mb_addr);
+    reconstruct_inter(ctx, picture, scratch, &info, mb_x, mb_y, false);
+
+    picture.mb_info[mb_addr] = info;
+    Ok(())
+}
+
+/// MbWidthC and MbHeightC for a chroma format (6.2)
+pub(crate) fn mb_chroma_size(chroma_format: ChromaFormat) -> (usize, usize) {
+    match chroma_format {
+        ChromaFormat::Monochrome => (0, 0),
+        ChromaFormat::Yuv420 => (8, 8),
+        ChromaFormat::Yuv422 => (8, 16),
+        ChromaFormat::Yuv444 => (16, 16),
+    }
+}
+
+/// Reads the raw samples of an I_PCM macroblock (7.3.5) straight into the picture
+fn decode_pcm(
+    reader: &mut BitReader,
+    ctx: &SliceContext,
+    picture: &mut Picture,
+    mb_addr: usize,
+) -> Result<()> {
+    while !reader.byte_aligned() {
+        if reader.read_flag()? {
+            return Err(anyhow!("pcm_alignment_zero_bit must be 0"));
+        }
+    }
+
+    let luma_bits = ctx.sps.bit_depth_luma_minus8 as usize + 8;
+    let chroma_bits = ctx.sps.bit_depth_chroma_minus8 as usize + 8;
+    let (mb_x, mb_y) = picture.mb_position(mb_addr);
+
+    for y in 0..16 {
+        for x in 0..16 {
+            let sample = reader.read(luma_bits)?;
+            picture
+                .frame
+                .y
+                .set(mb_x * 16 + x, mb_y * 16 + y, sample as u8);
+        }
+    }
+
+    let (mb_width_c, mb_height_c) = mb_chroma_size(picture.frame.chroma_format);
+    for plane in [&mut picture.frame.cb, &mut picture.frame.cr] {
+        for y in 0..mb_height_c {
+            for x in 0..mb_width_c {
+                let sample = reader.read(chroma_bits)?;
+                plane.set(mb_x * mb_width_c + x, mb_y * mb_height_c + y, sample as u8);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Position of each luma4x4BlkIdx in 4x4 block units (6.4.3)
+const BLOCK_X: [usize; 16] = [0, 1, 0, 1, 2, 3, 2, 3, 0, 1, 0, 1, 2, 3, 2, 3];
+const BLOCK_Y: [usize; 16] = [0, 0, 1, 1, 0, 0, 1, 1, 2, 2, 3, 3, 2, 2, 3, 3];
+
+/// luma4x4BlkIdx of the 4x4 block at (`bx`, `by`) within a macroblock
+fn block_index(bx: usize, by: usize) -> usize {
+    (by / 2) * 8 + (bx / 2) * 4 + (by % 2) * 2 + bx % 2
+}
+
+/// The neighbouring macroblocks A (left), B (above), C (above right) and D (above left) of 6.4.9
+/// that are available, i.e. already decoded in the same slice
+#[derive(Debug, Clone, Copy)]
+struct MbNeighbours {
+    a: Option<usize>,
+    b: Option<usize>,
+    c: Option<usize>,
+    d: Option<usize>,
+}
+
+impl MbNeighbours {
+    fn new(picture: &Picture, slice_num: u32, mb_addr: usize) -> Self {
+        let width = picture.width_in_mbs;
+        let (mb_x, mb_y) = picture.mb_position(mb_addr);
+        let available = |addr: usize| {
+            let info = &picture.mb_info[addr];
+            (info.decoded && info.slice_num == slice_num).then_some(addr)
+        };
+
+        Self {
+            a: (mb_x > 0).then(|| mb_addr - 1).and_then(available),
+            b: (mb_y > 0).then(|| mb_addr - width).and_then(available),
+            c: (mb_y > 0 && mb_x + 1 < width)
+                .then(|| mb_addr - width + 1)
+                .and_then(available),
+            d: (mb_y > 0 && mb_x > 0)
+                .then(|| mb_addr - width - 1)
+                .and_then(available),
+        }
+    }
+
+    /// With constrained_intra_pred_flag set, inter macroblocks can't be used for intra prediction.
+    /// I_PCM macroblocks aren't inter coded, so they stay available.
+    fn for_intra(self, picture: &Picture, constrained_intra_pred: bool) -> Self {
+        let intra = |addr: Option<usize>| {
+            addr.filter(|&a| !constrained_intra_pred || picture.mb_info[a].mb_type.is_intra())
+        };
+        Self {
+            a: intra(self.a),
+            b: intra(self.b),
+            c: intra(self.c),
+            d: intra(self.d),
+        }
+    }
+}
+
+/// mb_field_decoding_flag of the macroblock at `mb_addr` in an MBAFF frame, where macroblocks
+/// come in vertical pairs that share the flag (7.4.4). `coded` is the flag read with either
+/// macroblock of the pair: when only the bottom one carries it, it applies to the top one as well.
+/// Without it, the bottom macroblock follows the top one, and a pair takes the flag of the pair to
+/// its left or else above it in the same slice, or frame decoding when neither is available.
+// MBAFF frames are refused before their slice data, so nothing calls this yet
+#[allow(dead_code)]
+pub(crate) fn mb_field_decoding_flag(
+    picture: &Picture,
+    slice_num: u32,
+    mb_addr: usize,
+    coded: Option<bool>,
+) -> bool {
+    if let Some(flag) = coded {
+        return flag;
+    }
+    let available = |addr: usize| {
+        let info = &picture.mb_info[addr];
+        (info.decoded && info.slice_num == slice_num).then_some(info.field)
+    };
+
+    let top = mb_addr & !1;
+    if mb_addr != top
+        && let Some(flag) = available(top)
+    {
+        return flag;
+    }
+    // Pairs are addressed like the macroblocks of a picture half as tall (6.4.10)
+    let width = picture.width_in_mbs;
+    let (pair_x, pair_y) = (top / 2 % width, top / 2 / width);
+    let left = (pair_x > 0).then(|| top - 2).and_then(available);
+    let above = (pair_y > 0).then(|| top - 2 * width).and_then(available);
+    left.or(above).unwrap_or(false)
+}
+
+/// nC from the TotalCoeff of the left and upper blocks (9.2.1)
+fn predict_nc(left: Option<u8>, top: Option<u8>) -> i32 {
+    match (left, top) {
+        (Some(a), Some(b)) => (a as i32 + b as i32 + 1) >> 1,
+        (Some(n), None) | (None, Some(n)) => n as i32,
+        (None, None) => 0,
+    }
+}
+
+fn luma_nc(picture: &Picture, n: &MbNeighbours, info: &MbInfo, bx: usize, by: usize) -> i32 {
+    let left = if bx > 0 {
+        Some(info.total_coeff[by * 4 + bx - 1])
+    } else {
+        n.a.map(|a| picture.mb_info[a].total_coeff[by * 4 + 3])
+    };
+    let top = if by > 0 {
+        Some(info.total_coeff[(by - 1) * 4 + bx])
+    } else {
+        n.b.map(|b| picture.mb_info[b].total_coeff[12 + bx])
+    };
+    predict_nc(left, top)
+}
+
+/// nC of a 4:2:0 chroma AC block, whose blocks form a 2x2 grid per component
+fn chroma_nc(
+    picture: &Picture,
+    n: &MbNeighbours,
+    info: &MbInfo,
+    component: usize,
+    bx: usize,
+    by: usize,
+) -> i32 {
+    let left = if bx > 0 {
+        Some(info.total_coeff_chroma[component][by * 2 + bx - 1])
+    } else {
+        n.a.map(|a| picture.mb_info[a].total_coeff_chroma[component][by * 2 + 1])
+    };
+    let top = if by > 0 {
+        Some(info.total_coeff_chroma[component][(by - 1) * 2 + bx])
+    } else {
+        n.b.map(|b| picture.mb_info[b].total_coeff_chroma[component][2 + bx])
+    };
+    predict_nc(left, top)
+}
+
+/// 8.3.1.1 Derivation process for Intra4x4PredMode
+fn intra4x4_pred_mode(
+    reader: &mut BitReader,
+    picture: &Picture,
+    n: &MbNeighbours,
+    info: &MbInfo,
+    bx: usize,
+    by: usize,
+) -> Result<u8> {
+    let neighbour_mode = |addr: usize, index: usize| {
+        let neighbour = &picture.mb_info[addr];
+        if neighbour.mb_type == MbType::INxN {
+            neighbour.intra4x4_modes[index]
+        } else {
+            2
+        }
+    };
+    let mode_a = if bx > 0 {
+        Some(info.intra4x4_modes[by * 4 + bx - 1])
+    } else {
+        n.a.map(|a| neighbour_mode(a, by * 4 + 3))
+    };
+    let mode_b = if by > 0 {
+        Some(info.intra4x4_modes[(by - 1) * 4 + bx])
+    } else {
+        n.b.map(|b| neighbour_mode(b, 12 + bx))
+    };
+    let predicted = match (mode_a, mode_b) {
+        (Some(a), Some(b)) => a.min(b),
+        _ => 2,
+    };
+
+    if reader.read_flag()? {
+        return Ok(predicted);
+    }
+    let rem = reader.read(3)? as u8;
+    Ok(if rem < predicted { rem } else { rem + 1 })
+}
+
+/// Decodes an Intra_4x4 or Intra_16x16 macroblock: mb_pred() (7.3.5.1), coded_block_pattern,
+/// mb_qp_delta and residual() (7.3.5.3), then reconstruction (8.3, 8.5)
+fn decode_intra(
+    reader: &mut BitReader,
+    ctx: &mut SliceContext,
+    picture: &mut Picture,
+    scratch: &mut DecodeScratch,
+    mb_addr: usize,
+    info: &mut MbInfo,
+) -> Result<()> {
+    // ChromaArrayType 0 carries no chroma syntax, prediction or residual at all
+    let chroma = match picture.frame.chroma_format {
+        ChromaFormat::Yuv420 => true,
+        ChromaFormat::Monochrome => false,
+        _ => {
+            return Err(anyhow!(
+                "Intra macroblocks are only supported for 4:2:0 and monochrome video"
+            ));
+        }
+    };
+
+    let neighbours = MbNeighbours::new(picture, ctx.slice_num, mb_addr);
+    let intra_neighbours = neighbours.for_intra(picture, ctx.pps.constrained_intra_pred_flag);
+
+    if info.mb_type == MbType::INxN {
+        if ctx.pps.transform_8x8_mode_flag && reader.read_flag()? {
+            return Err(anyhow!("The 8x8 transform is not supported"));
+        }
+        for blk in 0..16 {
+            let (bx, by) = (BLOCK_X[blk], BLOCK_Y[blk]);
+            info.intra4x4_modes[by * 4 + bx] =
+                intra4x4_pred_mode(reader, picture, &intra_neighbours, info, bx, by)?;
+        }
+    }
+    let chroma_pred_mode = if chroma { reader.read_ue()? } else { 0 };
+    if chroma_pred_mode > 3 {
+        return Err(anyhow!(
+            "Invalid intra_chroma_pred_mode: {}",
+            chroma_pred_mode
+        ));
+    }
+
+    let (cbp_luma, cbp_chroma) = match info.mb_type {
+        MbType::I16x16 {
+            cbp_luma,
+            cbp_chroma,
+            ..
+        } => (cbp_luma, cbp_chroma),
+        _ => {
+            let cbp = read_coded_block_pattern(reader, true, ctx.sps.chroma_array_type())?;
+            (cbp & 15, cbp >> 4)
+        }
+    };
+    if !chroma && cbp_chroma != 0 {
+        return Err(anyhow!(
+            "mb_type {:?} codes a chroma residual in a monochrome picture",
+            info.mb_type
+        ));
+    }
+
+    if cbp_luma > 0 || cbp_chroma > 0 || matches!(info.mb_type, MbType::I16x16 { .. }) {
+        read_mb_qp_delta(reader, ctx)?;
+    }
+    info.qp = ctx.qp;
+
+    // nC counts the coefficients of inter neighbours even under constrained_intra_pred_flag.
+    // 9.2.1 only leaves them out of slice data partitions B and C, which aren't decoded.
+    read_residual(
+        reader,
+        picture,
+        &neighbours,
+        scratch,
+        info,
+        cbp_luma,
+        cbp_chroma,
+    )?;
+
+    let (mb_x, mb_y) = picture.mb_position(mb_addr);
+    let luma_weights = ctx.weight_scales(true, 0);
+    match info.mb_type {
+        MbType::I16x16 { pred_mode, .. } => reconstruct_intra_16x16(
+            picture,
+            &intra_neighbours,
+            scratch,
+            pred_mode,
+            ctx.qp,
+            luma_weights,
+            mb_x,
+            mb_y,
+        )?,
+        _ => reconstruct_intra_4x4(
+            picture,
+            &intra_neighbours,
+            scratch,
+            info,
+            luma_weights,
+            mb_x,
+            mb_y,
+        )?,
+    }
+
+    if !chroma {
+        return Ok(());
+    }
+    for (component, offset) in [
+        (0, ctx.pps.chroma_qp_index_offset),
+        (1, ctx.pps.second_chroma_qp_index_offset),
+    ] {
+        let qp = chroma_qp(ctx.qp, offset);
+        reconstruct_chroma(
+            picture,
+            &intra_neighbours,
+            scratch,
+            component,
+            chroma_pred_mode as u8,
+            qp,
+            ctx.weight_scales(true, component + 1),
+            mb_x,
+            mb_y,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Reads mb_qp_delta and applies it to QPY (7.4.5)
+fn read_mb_qp_delta(reader: &mut BitReader, ctx: &mut SliceContext) -> Result<()> {
+    let mb_qp_delta = reader.read_se()?;
+    if !(-26..=25).contains(&mb_qp_delta) {
+        return Err(anyhow!("Invalid mb_qp_delta: {}", mb_qp_delta));
+    }
+    ctx.qp = (ctx.qp + mb_qp_delta + 52) % 52;
+    Ok(())
+}
+
+/// Decodes a P macroblock of one or two partitions: mb_pred() (7.3.5.1), coded_block_pattern,
+/// mb_qp_delta and residual() (7.3.5.3), then inter prediction and reconstruction (8.4, 8.5)
+fn decode_inter(
+    reader: &mut BitReader,
+    ctx: &mut SliceContext,
+    picture: &mut Picture,
+    scratch: &mut DecodeScratch,
+    mb_addr: usize,
+    info: &mut MbInfo,
+) -> Result<()> {
+    if !matches!(
+        picture.frame.chroma_format,
+        ChromaFormat::Yuv420 | ChromaFormat::Monochrome
+    ) {
+        return Err(anyhow!(
+            "Inter macroblocks are only supported for 4:2:0 and monochrome video"
+        ));
+    }
+
+    // Every ref_idx_l0 comes before the first mvd_l0
+    let mut ref_idx = [0; 2];
+    for (ref_idx, _) in ref_idx.iter_mut().zip(info.mb_type.partitions()) {
+        *ref_idx = read_ref_idx(reader, ctx.header.num_ref_idx_l0_active_minus1)?;
+        if *ref_idx as usize >= ctx.ref_list0.len() {
+            return Err(anyhow!(
+                "Macroblock {} has no reference picture for ref_idx_l0 {}",
+                mb_addr,
+                ref_idx
+            ));
+        }
+    }
+    let mut mvd = [[0; 2]; 2];
+    for (mvd, _) in mvd.iter_mut().zip(info.mb_type.partitions()) {
+        *mvd = [reader.read_se()?, reader.read_se()?];
+    }
+
+    // Each partition's prediction can use the vector of the one before it
+    let neighbours = MbNeighbours::new(picture, ctx.slice_num, mb_addr);
+    for (i, part) in info.mb_type.partitions().enumerate() {
+        let mvp = predict_mv(picture, &neighbours, info, &part, ref_idx[i]);
+        // The vector is clamped before it's used, so a corrupt mvd only has to not overflow here
+        let mv = [
+            mvp[0].saturating_add(mvd[i][0]),
+            mvp[1].saturating_add(mvd[i][1]),
+        ];
+        set_motion(info, &part, mv, ref_idx[i]);
+    }
+    set_ref_pics(ctx, info);
+
+    let cbp = read_coded_block_pattern(reader, false, ctx.sps.chroma_array_type())?;
+    let (cbp_luma, cbp_chroma) = (cbp & 15, cbp >> 4);
+    if cbp_luma > 0 && ctx.pps.transform_8x8_mode_flag && reader.read_flag()? {
+        return Err(anyhow!("The 8x8 transform is not supported"));
+    }
+    if cbp > 0 {
+        read_mb_qp_delta(reader, ctx)?;
+    }
+    info.qp = ctx.qp;
+    read_residual(
+        reader,
+        picture,
+        &neighbours,
+        scratch,
+        info,
+        cbp_luma,
+        cbp_chroma,
+    )?;
+
+    let (mb_x, mb_y) = picture.mb_position(mb_addr);
+    reconstruct_inter(ctx, picture, scratch, info, mb_x, mb_y, cbp > 0);
+    Ok(())
+}
+
+/// Reads ref_idx_l0, coded as te(v) with num_ref_idx_l0_active_minus1 as its range (9.1): absent
+/// with a single reference, an inverted bit with two
+fn read_ref_idx(reader: &mut BitReader, max: u32) -> Result<u32> {
+    let ref_idx = match max {
+        0 => 0,
+        1 => !reader.read_flag()? as u32,
+        _ => reader.read_ue()?,
+    };
+    if ref_idx > max {
+        return Err(anyhow!(
+            "Invalid ref_idx_l0: {}, num_ref_idx_l0_active_minus1 is {}",
+            ref_idx,
+            max
+        ));
+    }
+    Ok(ref_idx)
+}
+
+/// Records the motion of partition `part` in `info`
+fn set_motion(info: &mut MbInfo, part: &MbPartition, mv: [i32; 2], ref_idx: u32) {
+    for y in (part.y..part.y + part.height).step_by(4) {
+        for x in (part.x..part.x + part.width).step_by(4) {
+            info.mv_l0[y / 4 * 4 + x / 4] = mv;
+            info.ref_idx_l0[y / 8 * 2 + x / 8] = ref_idx;
+        }
+    }
+}
+
+/// Records the picture each quadrant's refIdxL0 selects, for the deblocking filter
+fn set_ref_pics(ctx: &SliceContext, info: &mut MbInfo) {
+    for (ref_pic, &ref_idx) in info.ref_pic_l0.iter_mut().zip(&info.ref_idx_l0) {
+        *ref_pic = ctx.ref_list0[ref_idx as usize].poc;
+    }
+}
+
+/// The vector and reference of partition `part` of an inter macroblock
+fn partition_motion(info: &MbInfo, part: &MbPartition) -> ([i32; 2], u32) {
+    (
+        info.mv_l0[part.y / 4 * 4 + part.x / 4],
+        info.ref_idx_l0[part.y / 8 * 2 + part.x / 8],
+    )
+}
+
+/// mvL0 and refIdxL0 of a neighbouring partition, refIdxL0 being -1 for an intra one (8.4.1.3.2)
+type Motion = ([i32; 2], i32);
+
+/// Motion of the block covering luma location (`x`, `y`) relative to the current macroblock,
+/// whose earlier partitions are in `current`, or None when it's unavailable (6.4.12). Inside
+/// the current macroblock only locations of earlier partitions are asked for, except to the
+/// right of it, which is never available.
+fn neighbour_motion(
+    picture: &Picture,
+    n: &MbNeighbours,
+    current: &MbInfo,
+    x: i32,
+    y: i32,
+) -> Option<Motion> {
+    let (info, x, y) = match (x < 0, y < 0, x > 15) {
+        (true, true, _) => (&picture.mb_info[n.d?], x + 16, y + 16),
+        (true, false, _) => (&picture.mb_info[n.a?], x + 16, y),
+        (false, true, false) => (&picture.mb_info[n.b?], x, y + 16),
+        (false, true, true) => (&picture.mb_info[n.c?], x - 16, y + 16),
+        (false, false, false) => (current, x, y),
+        (false, false, true) => return None,
+    };
+    if info.mb_type.is_intra() {
+        return Some(([0, 0], -1));
+    }
+    let (x, y) = (x as usize, y as usize);
+    Some((
+        info.mv_l0[y / 4 * 4 + x / 4],
+        info.ref_idx_l0[y / 8 * 2 + x / 8] as i32,
+    ))
+}
+
+/// mvpL0 of partition `part` predicting from `ref_idx` (8.4.1.3), from neighbours A, B and C,
+/// where D stands in for an unavailable C
+fn predict_mv(
+    picture: &Picture,
+    n: &MbNeighbours,
+    current: &MbInfo,
+    part: &MbPartition,
+    ref_idx: u32,
+) -> [i32; 2] {
+    let (x, y, width) = (part.x as i32, part.y as i32, part.width as i32);
+    let at = |x, y| neighbour_motion(picture, n, current, x, y);
+    let (a, b) = (at(x - 1, y), at(x, y - 1));
+    let c = at(x + width, y - 1).or_else(|| at(x - 1, y - 1));
+    let only_a = a.is_some() && b.is_none() && c.is_none();
+    let [a, b, c] = [a, b, c].map(|m| m.unwrap_or(([0, 0], -1)));
+    let ref_idx = ref_idx as i32;
+
+    // 16x8 and 8x16 partitions first look in the direction of the neighbouring partition
+    let directional = match (part.width, part.height) {
+        (16, 8) if part.y == 0 => Some(b),
+        (16, 8) => Some(a),
+        (8, 16) if part.x == 0 => Some(a),
+        (8, 16) => Some(c),
+        _ => None,
+    };
+    if let Some((mv, r)) = directional
+        && r == ref_idx
+    {
+        return mv;
+    }
+
+    // 8.4.1.3.1: A stands in for both B and C when neither of them is available, and a single
+    // neighbour on the same reference gives the prediction on its own
+    let (b, c) = if only_a { (a, a) } else { (b, c) };
+    match [a, b, c].map(|(_, r)| r == ref_idx) {
+        [true, false, false] => a.0,
+        [false, true, false] => b.0,
+        [false, false, true] => c.0,
+        _ => {
+            let median = |i: usize| a.0[i].min(b.0[i]).max(a.0[i].max(b.0[i]).min(c.0[i]));
+            [median(0), median(1)]
+        }
+    }
+}
+
+/// mvL0 of a P_Skip macroblock (8.4.1.1): zero at the edge of the picture or slice and next to a
+/// neighbour A or B that stays still on RefPicList0[0], the 16x16 prediction otherwise
+fn p_skip_mv(picture: &Picture, n: &MbNeighbours, current: &MbInfo) -> [i32; 2] {
+    let still = |motion: Option<Motion>| motion.is_none_or(|motion| motion == ([0, 0], 0));
+    if still(neighbour_motion(picture, n, current, -1, 0))
+        || still(neighbour_motion(picture, n, current, 0, -1))
+    {
+        return [0, 0];
+    }
+    let part = current
+        .mb_type
+        .partitions()
+        .next()
+        .expect("P_Skip has a single 16x16 partition");
+    predict_mv(picture, n, current, &part, 0)
+}
+
+/// Parses residual_luma() and the 4:2:0 chroma residual of 7.3.5.3 into `scratch`, with every
+/// block's coefficients moved from scanning order to raster order
+fn read_residual(
+    reader: &mut BitReader,
+    picture: &Picture,
+    n: &MbNeighbours,
+    scratch: &mut DecodeScratch,
+    info: &mut MbInfo,
+    cbp_luma: u8,
+    cbp_chroma: u8,
+) -> Result<()> {
+    let intra_16x16 = matches!(info.mb_type, MbType::I16x16 { .. });
+
+    if intra_16x16 {
+        let nc = luma_nc(picture, n, info, 0, 0);
+        read_residual_block(reader, &mut scratch.levels, 0, 15, 16, nc)?;
+        for (k, &level) in scratch.levels.iter().enumerate() {
+            scratch.luma_dc[ZIGZAG_4X4[k]] = level;
+        }
+    }
+
+    for blk in 0..16 {
+        let (bx, by) = (BLOCK_X[blk], BLOCK_Y[blk]);
+        scratch.luma[blk] = [0; 16];
+        if cbp_luma & (1 << (blk / 4)) == 0 {
+            info.total_coeff[by * 4 + bx] = 0;
+            continue;
+        }
+
+        let nc = luma_nc(picture, n, info, bx, by);
+        let (total_coeff, first) = if intra_16x16 {
+            (
+                read_residual_block(reader, &mut scratch.levels, 0, 14, 15, nc)?,
+                1,
+            )
+        } else {
+            (
+                read_residual_block(reader, &mut scratch.levels, 0, 15, 16, nc)?,
+                0,
+            )
+        };
+        for k in 0..16 - first {
+            scratch.luma[blk][ZIGZAG_4X4[k + first]] = scratch.levels[k];
+        }
+        info.total_coeff[by * 4 + bx] = total_coeff;
+    }
+
+    if picture.frame.chroma_format == ChromaFormat::Monochrome {
+        return Ok(());
+    }
+    for component in 0..2 {
+        scratch.chroma_dc[component] = [0; 4];
+        if cbp_chroma & 3 != 0 {
+            read_residual_block(reader, &mut scratch.levels, 0, 3, 4, -1)?;
+            scratch.chroma_dc[component].copy_from_slice(&scratch.levels[..4]);
+        }
+    }
+
+    for component in 0..2 {
+        for blk in 0..4 {
+            let (bx, by) = (blk % 2, blk / 2);
+            scratch.chroma[component][blk] = [0; 16];
+            if cbp_chroma & 2 == 0 {
+                info.total_coeff_chroma[component][blk] = 0;
+                continue;
+            }
+
+            let nc = chroma_nc(picture, n, info, component, bx, by);
+            let total_coeff = read_residual_block(reader, &mut scratch.levels, 0, 14, 15, nc)?;
+            for k in 0..15 {
+                scratch.chroma[component][blk][ZIGZAG_4X4[k + 1]] = scratch.levels[k];
+            }
+            info.total_coeff_chroma[component][blk] = total_coeff;
+        }
+    }
+
+    Ok(())
+}
+
+/// Gathers the constructed samples above and left of the `width` x `height` block at (`x`, `y`).
+/// For 4x4 luma blocks, `width` more samples are read to the top right when available.
+#[allow(clippy::too_many_arguments)]
+fn fill_edge(
+    plane: &Plane,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    has_top: bool,
+    has_left: bool,
+    has_top_left: bool,
+    has_top_right: bool,
+) -> Edge {
+    let mut edge = Edge {
+        has_top,
+        has_left,
+        has_top_left,
+        has_top_right,
+        ..Default::default()
+    };
+    if has_top {
+        let row = plane.row(y - 1);
+        let top_width = if has_top_right { 2 * width } else { width };
+        edge.top[..top_width].copy_from_slice(&row[x..x + top_width]);
+    }
+    if has_left {
+        for (j, sample) in edge.left.iter_mut().take(height).enumerate() {
+            *sample = plane.at(x - 1, y + j);
+        }
+    }
+    if has_top_left {
+        edge.top_left = plane.at(x - 1, y - 1);
+    }
+    edge
+}
+
+/// Adds a residual block to the prediction and stores the clipped result (8.5.14)
+fn store_block(
+    plane: &mut Plane,
+    x: usize,
+    y: usize,
+    pred: &[u8],
+    pred_stride: usize,
+    residual: &[i32; 16],
+) {
+    for j in 0..4 {
+        for i in 0..4 {
+            let value = pred[j * pred_stride + i] as i32 + residual[j * 4 + i];
+            plane.set(x + i, y + j, value.clamp(0, 255) as u8);
+        }
+    }
+}
+
+fn reconstruct_intra_4x4(
+    picture: &mut Picture,
+    n: &MbNeighbours,
+    scratch: &mut DecodeScratch,
+    info: &MbInfo,
+    weights: &[i32; 16],
+    mb_x: usize,
+    mb_y: usize,
+) -> Result<()> {
+    for blk in 0..16 {
+        let (bx, by) = (BLOCK_X[blk], BLOCK_Y[blk]);
+        let has_top = by > 0 || n.b.is_some();
+        let has_left = bx > 0 || n.a.is_some();
+        let has_top_left = match (bx, by) {
+            (0, 0) => n.d.is_some(),
+            (0, _) => n.a.is_some(),
+            (_, 0) => n.b.is_some(),
+            _ => true,
+        };
+        let has_top_right = match (bx, by) {
+            (3, 0) => n.c.is_some(),
+            (_, 0) => n.b.is_some(),
+            (3, _) => false,
+            _ => block_index(bx + 1, by - 1) < blk,
+        };
+
+        let (x, y) = (mb_x * 16 + bx * 4, mb_y * 16 + by * 4);
+        let edge = fill_edge(
+            &picture.frame.y,
+            x,
+            y,
+            4,
+            4,
+            has_top,
+            has_left,
+            has_top_left,
+            has_top_right,
+        );
+
+        let mut pred = [0u8; 16];
+        predict_4x4(info.intra4x4_modes[by * 4 + bx], &edge, &mut pred)?;
+
+        let block = &mut scratch.luma[blk];
+        dequantize_4x4(block, info.qp, true, weights);
+        inverse_transform_4x4(block);
+        store_block(&mut picture.frame.y, x, y, &pred, 4, block);
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn reconstruct_intra_16x16(
+    picture: &mut Picture,
+    n: &MbNeighbours,
+    scratch: &mut DecodeScratch,
+    pred_mode: u8,
+    qp: i32,
+    weights: &[i32; 16],
+    mb_x: usize,
+    mb_y: usize,
+) -> Result<()> {
+    let (x, y) = (mb_x * 16, mb_y * 16);
+    let edge = fill_edge(
+        &picture.frame.y,
+        x,
+        y,
+        16,
+        16,
+        n.b.is_some(),
+        n.a.is_some(),
+        n.d.is_some(),
+        false,
+    );
+    predict_16x16(pred_mode, &edge, &mut scratch.pred)?;
+
+    inverse_luma_dc(&mut scratch.luma_dc, qp, weights);
+    for blk in 0..16 {
+        let (bx, by) = (BLOCK_X[blk], BLOCK_Y[blk]);
+        let block = &mut scratch.luma[blk];
+        block[0] = scratch.luma_dc[by * 4 + bx];
+        dequantize_4x4(block, qp, false, weights);
+        inverse_transform_4x4(block);
+
+        let pred = &scratch.pred[by * 4 * 16 + bx * 4..];
+        store_block(
+            &mut picture.frame.y,
+            x + bx * 4,
+            y + by * 4,
+            pred,
+            16,
+            block,
+        );
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn reconstruct_chroma(
+    picture: &mut Picture,
+    n: &MbNeighbours,
+    scratch: &mut DecodeScratch,
+    component: usize,
+    pred_mode: u8,
+    qp: i32,
+    weights: &[i32; 16],
+    mb_x: usize,
+    mb_y: usize,
+) -> Result<()> {
+    let plane = match component {
+        0 => &mut picture.frame.cb,
+        _ => &mut picture.frame.cr,
+    };
+    let (x, y) = (mb_x * 8, mb_y * 8);
+    let edge = fill_edge(
+        plane,
+        x,
+        y,
+        8,
+        8,
+        n.b.is_some(),
+        n.a.is_some(),
+        n.d.is_some(),
+        false,
+    );
+    predict_chroma(pred_mode, &edge, 8, 8, &mut scratch.pred)?;
+    store_chroma(plane, scratch, component, qp, weights, x, y);
+    Ok(())
+}
+
+/// Adds the 4:2:0 residual of `component` in `scratch` to its 8x8 prediction in `scratch.pred`
+/// and stores the block at (`x`, `y`)
+fn store_chroma(
+    plane: &mut Plane,
+    scratch: &mut DecodeScratch,
+    component: usize,
+    qp: i32,
+    weights: &[i32; 16],
+    x: usize,
+    y: usize,
+) {
+    let dc = &mut scratch.chroma_dc[component];
+    inverse_chroma_dc_420(dc, qp, weights);
+    for (blk, &dc) in dc.iter().enumerate() {
+        let (bx, by) = (blk % 2, blk / 2);
+        let block = &mut scratch.chroma[component][blk];
+        block[0] = dc;
+        dequantize_4x4(block, qp, false, weights);
+        inverse_transform_4x4(block);
+
+        let pred = &scratch.pred[by * 4 * 8 + bx * 4..];
+        store_block(plane, x + bx * 4, y + by * 4, pred, 8, block);
+    }
+}
+
+/// Inter prediction of the macroblock at (`mb_x`, `mb_y`), each partition from the reference
+/// and vector recorded in `info` (8.4.2), with the residual in `scratch` added when `residual`
+/// is set (8.5). Only 4:2:0 and monochrome pictures carry a residual.
+fn reconstruct_inter(
+    ctx: &SliceContext,
+    picture: &mut Picture,
+    scratch: &mut DecodeScratch,
+    info: &MbInfo,
+    mb_x: usize,
+    mb_y: usize,
+    residual: bool,
+) {
+    // Vectors are clamped once up front, so a corrupt one can't overflow the chroma vector
+    let motion = |part: &MbPartition| {
+        let (mv, ref_idx) = partition_motion(info, part);
+        let reference = ctx.ref_list0[ref_idx as usize];
+        let position = (mb_x * 16 + part.x, mb_y * 16 + part.y);
+        let size = (part.width, part.height);
+        (
+            reference,
+            clamp_luma_mv(mv, position, size, &reference.frame.y),
+        )
+    };
+
+    let (x, y) = (mb_x * 16, mb_y * 16);
+    for part in info.mb_type.partitions() {
+        let (reference, mv) = motion(&part);
+        predict_luma_block(
+            &reference.frame.y,
+            (x + part.x, y + part.y),
+            (part.width, part.height),
+            mv,
+            &mut scratch.pred[part.y * 16 + part.x..],
+            16,
+        );
+    }
+    if residual {
+        let weights = ctx.weight_scales(false, 0);
+        for blk in 0..16 {
+            let (bx, by) = (BLOCK_X[blk], BLOCK_Y[blk]);
+            let block = &mut scratch.luma[blk];
+            dequantize_4x4(block, ctx.qp, true, weights);
+            inverse_transform_4x4(block);
+
+            let pred = &scratch.pred[by * 4 * 16 + bx * 4..];
+            store_block(
+                &mut picture.frame.y,
+                x + bx * 4,
+                y + by * 4,
+                pred,
+                16,
+                block,
+            );
+        }
+    } else {
+        write_block(&mut picture.frame.y, (x, y), 16, &scratch.pred);
+    }
+
+    let chroma_format = picture.frame.chroma_format;
+    if chroma_format == ChromaFormat::Monochrome {
+        return;
+    }
+    let (mb_width_c, mb_height_c) = mb_chroma_size(chroma_format);
+    let (x, y) = (mb_x * mb_width_c, mb_y * mb_height_c);
+    for (component, offset) in [
+        (0, ctx.pps.chroma_qp_index_offset),
+        (1, ctx.pps.second_chroma_qp_index_offset),
+    ] {
+        for part in info.mb_type.partitions() {
+            let (reference, mv) = motion(&part);
+            let mv_c =
+                derive_chroma_mv(mv, chroma_format, (picture.structure, reference.structure));
+            let src = match component {
+                0 => &reference.frame.cb,
+                _ => &reference.frame.cr,
+            };
+            let (part_x, part_y) = (part.x * mb_width_c / 16, part.y * mb_height_c / 16);
+            interpolate_chroma(
+                src,
+                (x + part_x, y + part_y),
+                (part.width * mb_width_c / 16, part.height * mb_height_c / 16),
+                mv_c,
+                &mut scratch.pred[part_y * mb_width_c + part_x..],
+                mb_width_c,
+            );
+        }
+
+        let dst = match component {
+            0 => &mut picture.frame.cb,
+            _ => &mut picture.frame.cr,
+        };
+        if residual {
+            let qp = chroma_qp(ctx.qp, offset);
+            let weights = ctx.weight_scales(false, component + 1);
+            store_chroma(dst, scratch, component, qp, weights, x, y);
+        } else {
+            write_block(
+                dst,
+                (x, y),
+                mb_width_c,
+                &scratch.pred[..mb_width_c * mb_height_c],
+            );
+        }
+    }
+}
+
+/// Stores prediction samples `pred`, `width` to a row, as the block at (`x`, `y`)
+fn write_block(plane: &mut Plane, (x, y): (usize, usize), width: usize, pred: &[u8]) {
+    for (row, line) in pred.chunks_exact(width).enumerate() {
+        let start = (y + row) * plane.stride + x;
+        plane.data[start..start + width].copy_from_slice(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::YuvFrame;
+    use bitstream::BitWriter;
+    use parser::{Pps, SliceHeader, Sps};
+
+    #[test]
+    fn test_i_slice_mb_types() -> Result<()> {
+        assert_eq!(MbType::from_mb_type(SliceType::I, 0)?, MbType::INxN);
+        assert_eq!(MbType::from_mb_type(SliceType::I, 25)?, MbType::IPcm);
+        assert_eq!(
+            MbType::from_mb_type(SliceType::I, 24)?,
+            MbType::I16x16 {
+                pred_mode: 3,
+                cbp_chroma: 2,
+                cbp_luma: 15
+            }
+        );
+        assert!(MbType::from_mb_type(SliceType::I, 26).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_p_slice_mb_types() -> Result<()> {
+        assert_eq!(MbType::from_mb_type(SliceType::P, 0)?, MbType::PL016x16);
+        assert_eq!(MbType::from_mb_type(SliceType::P, 4)?, MbType::P8x8Ref0);
+        assert_eq!(MbType::from_mb_type(SliceType::P, 30)?, MbType::IPcm);
+        assert!(MbType::from_mb_type(SliceType::P, 5)?.is_intra());
+        Ok(())
+    }
+
+    #[test]
+    fn test_b_slice_mb_types() -> Result<()> {
+        assert_eq!(MbType::from_mb_type(SliceType::B, 0)?, MbType::BDirect16x16);
+        assert_eq!(
+            MbType::from_mb_type(SliceType::B, 3)?,
+            MbType::B16x16(PredList::Bi)
+        );
+        assert_eq!(
+            MbType::from_mb_type(SliceType::B, 13)?,
+            MbType::B8x16(PredList::L0, PredList::Bi)
+        );
+        assert_eq!(
+            MbType::from_mb_type(SliceType::B, 21)?,
+            MbType::B8x16(PredList::Bi, PredList::Bi)
+        );
+        assert_eq!(MbType::from_mb_type(SliceType::B, 22)?, MbType::B8x8);
+        assert_eq!(MbType::from_mb_type(SliceType::B, 48)?, MbType::IPcm);
+        assert!(MbType::from_mb_type(SliceType::B, 23)?.is_intra());
+        assert!(MbType::from_mb_type(SliceType::B, 49).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_b_l0_l1_16x8_partitions() -> Result<()> {
+        // B_L0_L1_16x8: the top partition predicts from list 0, the bottom one from list 1
+        let mb_type = MbType::from_mb_type(SliceType::B, 8)?;
+        assert_eq!(mb_type, MbType::B16x8(PredList::L0, PredList::L1));
+        let partitions: Vec<_> = mb_type.partitions().collect();
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(
+            (partitions[0].y, partitions[0].height, partitions[0].list),
+            (0, 8, PredList::L0)
+        );
+        assert_eq!(
+            (partitions[1].y, partitions[1].height, partitions[1].list),
+            (8, 8, PredList::L1)
+        );
+        assert!(partitions.iter().all(|p| p.x == 0 && p.width == 16));
+
+        assert_eq!(MbType::BDirect16x16.partitions().count(), 0);
+        assert_eq!(
+            MbType::PL0L08x16.partitions().nth(1).unwrap(),
+            MbPartition {
+                x: 8,
+                y: 0,
+                width: 8,
+                height: 16,
+                list: PredList::L0,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_motion_vector_prediction() {
+        // Macroblock 4 has A = 3, B = 1, C = 2 and D = 0
+        let frame = YuvFrame::new(48, 32, ChromaFormat::Yuv420);
+        let mut picture = Picture::new(frame, 3, 2);
+        let inter = |mv, ref_idx| {
+            let mut info = MbInfo {
+                decoded: true,
+                mb_type: MbType::PL016x16,
+                ..Default::default()
+            };
+            set_motion(&mut info, &part(0, 0, 16, 16), mv, ref_idx);
+            info
+        };
+        picture.mb_info[0] = inter([-40, 40], 0);
+        picture.mb_info[1] = inter([4, -8], 0);
+        picture.mb_info[2] = inter([12, 0], 1);
+        picture.mb_info[3] = inter([-4, 20], 0);
+        let n = MbNeighbours::new(&picture, 0, 4);
+        let skip = MbInfo {
+            mb_type: MbType::PSkip,
+            ..Default::default()
+        };
+        let whole = part(0, 0, 16, 16);
+
+        // The median of each component
+        assert_eq!(predict_mv(&picture, &n, &skip, &whole, 0), [4, 0]);
+        assert_eq!(p_skip_mv(&picture, &n, &skip), [4, 0]);
+        // C is the only neighbour on reference 1
+        assert_eq!(predict_mv(&picture, &n, &skip, &whole, 1), [12, 0]);
+
+        // The upper 16x8 partition takes B's vector on the same reference, and the lower one
+        // A's, with the upper partition of its own macroblock as B
+        let mut current = MbInfo {
+            mb_type: MbType::PL0L016x8,
+            ..Default::default()
+        };
+        assert_eq!(
+            predict_mv(&picture, &n, &current, &part(0, 0, 16, 8), 0),
+            [4, -8]
+        );
+        set_motion(&mut current, &part(0, 0, 16, 8), [100, 100], 1);
+        assert_eq!(
+            predict_mv(&picture, &n, &current, &part(0, 8, 16, 8), 0),
+            [-4, 20]
+        );
+        // On reference 1 only the upper partition matches, as C to the right is never available
+        // and D is A
+        assert_eq!(
+            predict_mv(&picture, &n, &current, &part(0, 8, 16, 8), 1),
+            [100, 100]
+        );
+        // The right 8x16 partition takes C's vector
+        assert_eq!(
+            predict_mv(&picture, &n, &current, &part(8, 0, 8, 16), 1),
+            [12, 0]
+        );
+
+        // An intra C counts as a zero vector on no reference
+        picture.mb_info[2].mb_type = MbType::INxN;
+        assert_eq!(predict_mv(&picture, &n, &skip, &whole, 1), [0, 0]);
+        // An unavailable C is replaced by D
+        picture.mb_info[2].decoded = false;
+        let n = MbNeighbours::new(&picture, 0, 4);
+        assert_eq!(predict_mv(&picture, &n, &skip, &whole, 0), [-4, 20]);
+
+        // P_Skip stays still next to a neighbour that does
+        picture.mb_info[1] = inter([0, 0], 0);
+        assert_eq!(p_skip_mv(&picture, &n, &skip), [0, 0]);
+        let n = MbNeighbours::new(&picture, 0, 3);
+        assert_eq!(p_skip_mv(&picture, &n, &skip), [0, 0]);
+    }
+
+    fn part(x: usize, y: usize, width: usize, height: usize) -> MbPartition {
+        MbPartition {
+            x,
+            y,
+            width,
+            height,
+            list: PredList::L0,
+        }
+    }
+
+    #[test]
+    fn test_mb_field_decoding_flag_inference() {
+        // Two columns and two rows of macroblock pairs
+        let frame = YuvFrame::new(32, 64, ChromaFormat::Yuv420);
+        let mut picture = Picture::new(frame, 2, 4);
+        let field_mb = MbInfo {
+            decoded: true,
+            field: true,
+            ..Default::default()
+        };
+        picture.mb_info[0] = field_mb;
+        picture.mb_info[1] = field_mb;
+
+        // A skipped top macroblock whose bottom one codes the flag takes that flag, rather than
+        // inferring it from the left neighbour
+        assert!(!mb_field_decoding_flag(&picture, 0, 2, Some(false)));
+        assert!(!mb_field_decoding_flag(&picture, 0, 3, Some(false)));
+
+        // A pair whose top macroblock is skipped without the flag takes its left neighbour's,
+        // and the bottom macroblock then follows the top one
+        assert!(mb_field_decoding_flag(&picture, 0, 2, None));
+        picture.mb_info[2] = field_mb;
+        assert!(mb_field_decoding_flag(&picture, 0, 3, None));
+        // The flag coded with the bottom macroblock wins
+        assert!(!mb_field_decoding_flag(&picture, 0, 3, Some(false)));
+
+        // The first pair of the second row has no left neighbour, so it looks above
+        assert!(mb_field_decoding_flag(&picture, 0, 4, None));
+        // Pairs of another slice aren't available, leaving frame decoding
+        assert!(!mb_field_decoding_flag(&picture, 1, 4, None));
+        assert!(!mb_field_decoding_flag(&picture, 1, 5, None));
+    }
+
+    #[test]
+    fn test_pcm_samples_land_at_the_macroblock() -> Result<()> {
+        let (sps, pps, header) = (Sps::default(), Pps::default(), SliceHeader::default());
+        let mut ctx = SliceContext {
+            sps: &sps,
+            pps: &pps,
+            header: &header,
+            slice_type: SliceType::I,
+            slice_num: 0,
+            qp: 30,
+            ref_list0: Vec::new(),
+            weight_scales: [[16; 16]; 6],
+            slice_group_map: Vec::new(),
+        };
+        let frame = YuvFrame::new(32, 32, ChromaFormat::Yuv420);
+        let mut picture = Picture::new(frame, 2, 2);
+        let mut scratch = DecodeScratch::default();
+
+        let mut writer = BitWriter::new();
+        writer.write_ue(25); // mb_type, I_PCM
+        while !writer.byte_aligned() {
+            writer.write_flag(false);
+        }
+        // Every sample holds its own index within its plane's block
+        for i in 0..256 {
+            writer.write(i, 8);
+        }
+        for i in 0..128 {
+            writer.write(i % 64 + 100, 8);
+        }
+        let bytes = writer.into_bytes();
+        decode_macroblock(
+            &mut BitReader::from_bytes(&bytes),
+            &mut ctx,
+            &mut picture,
+            &mut scratch,
+            3,
+        )?;
+
+        // Macroblock 3 is the bottom right one
+        let frame = &picture.frame;
+        assert_eq!(frame.y.at(16, 16), 0);
+        assert_eq!(frame.y.at(31, 16), 15);
+        assert_eq!(frame.y.at(16, 17), 16);
+        assert_eq!(frame.y.at(31, 31), 255);
+        assert_eq!(frame.y.at(15, 15), 0, "Other macroblocks are untouched");
+        assert_eq!((frame.cb.at(8, 8), frame.cb.at(15, 15)), (100, 163));
+        assert_eq!((frame.cr.at(8, 8), frame.cr.at(9, 8)), (100, 101));
+
+        assert_eq!(picture.mb_info[3].qp, 0);
+        assert_eq!(ctx.qp, 30);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chroma_dc_and_ac_recombine() -> Result<()> {
+        let mut picture = Picture::new(YuvFrame::new(16, 16, ChromaFormat::Yuv420), 1, 1);
+        let neighbours = MbNeighbours::new(&picture, 0, 0);
+        let mut scratch = DecodeScratch::default();
+        // At QP 28 LevelScale4x4 is 256 for the DC and 320 at (0, 1), so a DC level of 1 in
+        // the first position becomes dcC = 128 in all four blocks
+        scratch.chroma_dc[0] = [1, 0, 0, 0];
+        scratch.chroma[0][0][1] = 1;
+        // The last DC position alternates in sign across the blocks
+        scratch.chroma_dc[1] = [0, 0, 0, 1];
+        for component in 0..2 {
+            reconstruct_chroma(
+                &mut picture,
+                &neighbours,
+                &mut scratch,
+                component,
+                0,
+                28,
+                &[16; 16],
+                0,
+                0,
+            )?;
+        }
+
+        // DC prediction of 128 without neighbours, plus (128 + 32) >> 6 from the DC alone
+        let cb = &picture.frame.cb;
+        for y in 0..4 {
+            assert_eq!(&cb.row(y)[..4], &[135, 133, 128, 125]);
+            assert_eq!(&cb.row(y)[4..], &[130; 4]);
+            assert_eq!(cb.row(y + 4), &[130; 8]);
+        }
+        let cr = &picture.frame.cr;
+        assert_eq!((cr.at(0, 0), cr.at(4, 0)), (130, 126));
+        assert_eq!((cr.at(3, 7), cr.at(7, 7)), (126, 130));
+        Ok(())
+    }
+
+    #[test]
+    fn test_mb_qp_delta_only_with_coded_residual() -> Result<()> {
+        let sps = Sps {
+            chroma_format_idc: 1,
+            ..Default::default()
+        };
+        let (pps, header) = (Pps::default(), SliceHeader::default());
+        let mut ctx = SliceContext {
+            sps: &sps,
+            pps: &pps,
+            header: &header,
+            slice_type: SliceType::I,
+            slice_num: 0,
+            qp: 26,
+            ref_list0: Vec::new(),
+            weight_scales: [[16; 16]; 6],
+            slice_group_map: Vec::new(),
+        };
+        let frame = YuvFrame::new(32, 16, ChromaFormat::Yuv420);
+        let mut picture = Picture::new(frame, 2, 1);
+        let mut scratch = DecodeScratch::default();
+
+        let mut writer = BitWriter::new();
+        // I_16x16 always carries mb_qp_delta, even with both CBPs 0
+        writer.write_ue(3); // mb_type, I_16x16_2_0_0
+        writer.write_ue(0); // intra_chroma_pred_mode, DC
+        writer.write_se(3); // mb_qp_delta
+        writer.write(0b1, 1); // coeff_token of Intra16x16DCLevel, TotalCoeff 0
+        let second_mb = writer.position();
+        // I_NxN with coded_block_pattern 0 has no mb_qp_delta
+        writer.write_ue(0); // mb_type, I_NxN
+        for _ in 0..16 {
+            writer.write_flag(true); // prev_intra4x4_pred_mode_flag
+        }
+        writer.write_ue(0); // intra_chroma_pred_mode, DC
+        writer.write_ue(3); // coded_block_pattern 0
+        let end = writer.position();
+        writer.write_se(-5); // would be read as mb_qp_delta if it weren't skipped
+        let bytes = writer.into_bytes();
+
+        let mut reader = BitReader::from_bytes(&bytes);
+        decode_macroblock(&mut reader, &mut ctx, &mut picture, &mut scratch, 0)?;
+        assert_eq!(reader.position(), second_mb);
+        assert_eq!((ctx.qp, picture.mb_info[0].qp), (29, 29));
+
+        decode_macroblock(&mut reader, &mut ctx, &mut picture, &mut scratch, 1)?;
+        assert_eq!(reader.position(), end);
+        assert_eq!((ctx.qp, picture.mb_info[1].qp), (29, 29));
+        Ok(())
+    }
+
+    #[test]
+    fn test_constrained_intra_nc_counts_inter_neighbours() -> Result<()> {
+        let sps = Sps {
+            chroma_format_idc: 1,
+            ..Default::default()
+        };
+        let pps = Pps {
+            constrained_intra_pred_flag: true,
+            ..Default::default()
+        };
+        let header = SliceHeader::default();
+        let mut ctx = SliceContext {
+            sps: &sps,
+            pps: &pps,
+            header: &header,
+            slice_type: SliceType::P,
+            slice_num: 0,
+            qp: 26,
+            ref_list0: Vec::new(),
+            weight_scales: [[16; 16]; 6],
+            slice_group_map: Vec::new(),
+        };
+        let frame = YuvFrame::new(32, 16, ChromaFormat::Yuv420);
+        let mut picture = Picture::new(frame, 2, 1);
+        picture.mb_info[0] = MbInfo {
+            decoded: true,
+            mb_type: MbType::PSkip,
+            total_coeff: [4; 16],
+            ..Default::default()
+        };
+        let mut scratch = DecodeScratch::default();
+
+        let mut writer = BitWriter::new();
+        writer.write_ue(8); // mb_type, I_16x16_2_0_0 in a P slice
+        writer.write_ue(0); // intra_chroma_pred_mode, DC
+        writer.write_se(0); // mb_qp_delta
+        // The inter macroblock on the left can't be used for prediction, but its TotalCoeff of 4
+        // still selects the 4 <= nC < 8 table, where TotalCoeff 0 is 1111 rather than 1
+        writer.write(0b1111, 4);
+        let end = writer.position();
+        writer.write_ue(0);
+        let bytes = writer.into_bytes();
+
+        let mut reader = BitReader::from_bytes(&bytes);
+        decode_macroblock(&mut reader, &mut ctx, &mut picture, &mut scratch, 1)?;
+        assert_eq!(reader.position(), end);
+        assert_eq!(picture.mb_info[1].total_coeff, [0; 16]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_outside_p_slices_is_rejected() {
+        let (sps, pps, header) = (Sps::default(), Pps::default(), SliceHeader::default());
+        let ctx = SliceContext {
+            sps: &sps,
+            pps: &pps,
+            header: &header,
+            slice_type: SliceType::B,
+            slice_num: 0,
+            qp: 26,
+            ref_list0: Vec::new(),
+            weight_scales: [[16; 16]; 6],
+            slice_group_map: Vec::new(),
+        };
+        let frame = YuvFrame::new(16, 16, ChromaFormat::Yuv420);
+        let mut picture = Picture::new(frame, 1, 1);
+
+        let err = decode_skip(&ctx, &mut picture, &mut DecodeScratch::default(), 0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Skipped macroblock 0 in a B slice is not supported"
+        );
+        assert!(!picture.mb_info[0].decoded);
+    }
+}
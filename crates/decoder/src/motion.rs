@@ -1 +1,319 @@
+//! Inter prediction (8.4)
 
+use crate::frame::{ChromaFormat, Plane};
+use crate::picture::PictureStructure;
+
+/// Samples of edge extension a reference plane is treated as having around it. The
+/// interpolation filter reaches 3 samples past a block, so a block further out than this only
+/// reads copies of the edge and moving it further doesn't change its prediction.
+pub(crate) const REF_BORDER: i32 = 16;
+
+/// Clamps a luma motion vector, in quarter samples, so the `width`x`height` block at (`x`, `y`)
+/// stays within the reference plane plus `REF_BORDER`. The prediction is unchanged, but the
+/// sample positions of a corrupt vector can't run arbitrarily far outside the plane.
+pub(crate) fn clamp_luma_mv(
+    mv: [i32; 2],
+    (x, y): (usize, usize),
+    (width, height): (usize, usize),
+    plane: &Plane,
+) -> [i32; 2] {
+    let clamp = |component: i32, pos: usize, size: usize, extent: usize| {
+        let min = -(pos as i32 + size as i32 + REF_BORDER);
+        let max = extent as i32 - pos as i32 + REF_BORDER;
+        // Keep the fractional part, so only the whole-sample offset moves
+        let frac = component & 3;
+        ((component >> 2).clamp(min, max) << 2) | frac
+    };
+    [
+        clamp(mv[0], x, width, plane.width),
+        clamp(mv[1], y, height, plane.height),
+    ]
+}
+
+/// A reference sample at a position that may be outside the plane, whose edge samples repeat
+/// beyond it (8-228, 8-229)
+pub(crate) fn reference_sample(plane: &Plane, x: i32, y: i32) -> u8 {
+    let x = x.clamp(0, plane.width as i32 - 1) as usize;
+    let y = y.clamp(0, plane.height as i32 - 1) as usize;
+    plane.at(x, y)
+}
+
+/// Luma prediction of the `width`x`height` block at (`x`, `y`) moved by `mv`, into `pred` with
+/// rows `stride` samples apart. The vector is clamped first.
+pub(crate) fn predict_luma_block(
+    reference: &Plane,
+    (x, y): (usize, usize),
+    (width, height): (usize, usize),
+    mv: [i32; 2],
+    pred: &mut [u8],
+    stride: usize,
+) {
+    let [mv_x, mv_y] = clamp_luma_mv(mv, (x, y), (width, height), reference);
+    let (x0, y0) = (x as i32 + (mv_x >> 2), y as i32 + (mv_y >> 2));
+    let frac = (mv_x & 3, mv_y & 3);
+    for (row, line) in pred.chunks_mut(stride).take(height).enumerate() {
+        for (col, sample) in line[..width].iter_mut().enumerate() {
+            *sample = luma_sample(reference, x0 + col as i32, y0 + row as i32, frac);
+        }
+    }
+}
+
+/// The 6-tap filter of the luma half-sample positions, before rounding (8-241)
+fn tap6(p: [i32; 6]) -> i32 {
+    p[0] - 5 * p[1] + 20 * p[2] + 20 * p[3] - 5 * p[4] + p[5]
+}
+
+/// 8.4.2.2.1 luma sample interpolation at the fractional offset `frac`, in quarter samples,
+/// from the full sample G at (`x`, `y`). Samples are named as in Figure 8-4.
+fn luma_sample(plane: &Plane, x: i32, y: i32, frac: (i32, i32)) -> u8 {
+    let full = |x: i32, y: i32| reference_sample(plane, x, y) as i32;
+    // b1 and h1: half-sample positions right of and below the full sample at (x, y)
+    let b1 = |x: i32, y: i32| tap6(std::array::from_fn(|i| full(x + i as i32 - 2, y)));
+    let h1 = |x: i32, y: i32| tap6(std::array::from_fn(|i| full(x, y + i as i32 - 2)));
+    let clip = |value: i32| value.clamp(0, 255);
+    let b = |x: i32, y: i32| clip((b1(x, y) + 16) >> 5);
+    let h = |x: i32, y: i32| clip((h1(x, y) + 16) >> 5);
+    // j, the centre, filters the intermediate b1 values vertically (8-245)
+    let j = || clip((tap6(std::array::from_fn(|i| b1(x, y + i as i32 - 2))) + 512) >> 10);
+    let avg = |p: i32, q: i32| (p + q + 1) >> 1;
+
+    // Table 8-12, with s = b(x, y + 1) and m = h(x + 1, y)
+    let value = match frac {
+        (0, 0) => full(x, y),
+        (0, 1) => avg(full(x, y), h(x, y)),
+        (0, 2) => h(x, y),
+        (0, 3) => avg(full(x, y + 1), h(x, y)),
+        (1, 0) => avg(full(x, y), b(x, y)),
+        (2, 0) => b(x, y),
+        (3, 0) => avg(full(x + 1, y), b(x, y)),
+        (1, 1) => avg(b(x, y), h(x, y)),
+        (3, 1) => avg(b(x, y), h(x + 1, y)),
+        (1, 3) => avg(h(x, y), b(x, y + 1)),
+        (3, 3) => avg(h(x + 1, y), b(x, y + 1)),
+        (2, 1) => avg(b(x, y), j()),
+        (2, 3) => avg(j(), b(x, y + 1)),
+        (1, 2) => avg(h(x, y), j()),
+        (3, 2) => avg(j(), h(x + 1, y)),
+        _ => j(),
+    };
+    value as u8
+}
+
+/// Chroma vector of a luma vector `mv` in quarter luma samples (8.4.1.4), in eighths of a chroma
+/// sample in both directions. 4:2:0 vectors are reused as is, except that a field predicted from
+/// the field of opposite parity moves by a quarter of a chroma sample, to account for where the
+/// chroma lines of the two fields sit (Table 8-9). Where chroma isn't subsampled, a quarter luma
+/// sample is a quarter chroma sample, so that component is doubled: the vertical one for 4:2:2,
+/// both for 4:4:4. Monochrome has no chroma, so its vector is returned unchanged.
+pub(crate) fn derive_chroma_mv(
+    mv: [i32; 2],
+    chroma_format: ChromaFormat,
+    (current, reference): (PictureStructure, PictureStructure),
+) -> [i32; 2] {
+    match chroma_format {
+        ChromaFormat::Yuv420 => {
+            let offset = match (current, reference) {
+                (PictureStructure::TopField, PictureStructure::BottomField) => -2,
+                (PictureStructure::BottomField, PictureStructure::TopField) => 2,
+                _ => 0,
+            };
+            [mv[0], mv[1] + offset]
+        }
+        ChromaFormat::Yuv422 => [mv[0], mv[1] * 2],
+        ChromaFormat::Yuv444 => [mv[0] * 2, mv[1] * 2],
+        ChromaFormat::Monochrome => mv,
+    }
+}
+
+/// Chroma prediction of the `width`x`height` block at (`x`, `y`) moved by `mv`, in eighths of a
+/// chroma sample as `derive_chroma_mv` gives it, into `pred` with rows `stride` samples apart.
+/// Each sample is the bilinear interpolation of the four reference samples around it (8-266).
+pub(crate) fn interpolate_chroma(
+    reference: &Plane,
+    (x, y): (usize, usize),
+    (width, height): (usize, usize),
+    mv: [i32; 2],
+    pred: &mut [u8],
+    stride: usize,
+) {
+    let (x_frac, y_frac) = (mv[0] & 7, mv[1] & 7);
+    let (x0, y0) = (x as i32 + (mv[0] >> 3), y as i32 + (mv[1] >> 3));
+    for (row, line) in pred.chunks_mut(stride).take(height).enumerate() {
+        let y_int = y0 + row as i32;
+        for (col, sample) in line[..width].iter_mut().enumerate() {
+            let x_int = x0 + col as i32;
+            let a = reference_sample(reference, x_int, y_int) as i32;
+            let b = reference_sample(reference, x_int + 1, y_int) as i32;
+            let c = reference_sample(reference, x_int, y_int + 1) as i32;
+            let d = reference_sample(reference, x_int + 1, y_int + 1) as i32;
+            *sample = (((8 - x_frac) * (8 - y_frac) * a
+                + x_frac * (8 - y_frac) * b
+                + (8 - x_frac) * y_frac * c
+                + x_frac * y_frac * d
+                + 32)
+                >> 6) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derive_chroma_mv() {
+        use PictureStructure::{BottomField, Frame, TopField};
+
+        // 4:2:0 frames: a quarter luma sample is an eighth of a chroma sample
+        assert_eq!(
+            derive_chroma_mv([5, -7], ChromaFormat::Yuv420, (Frame, Frame)),
+            [5, -7]
+        );
+        // Full-height chroma: vertical quarter samples become eighths
+        assert_eq!(
+            derive_chroma_mv([5, -7], ChromaFormat::Yuv422, (Frame, Frame)),
+            [5, -14]
+        );
+        assert_eq!(
+            derive_chroma_mv([5, -7], ChromaFormat::Yuv444, (Frame, Frame)),
+            [10, -14]
+        );
+
+        // Fields of opposite parity shift by a quarter of a chroma sample
+        let mv = [4, 8];
+        assert_eq!(
+            derive_chroma_mv(mv, ChromaFormat::Yuv420, (TopField, BottomField)),
+            [4, 6]
+        );
+        assert_eq!(
+            derive_chroma_mv(mv, ChromaFormat::Yuv420, (BottomField, TopField)),
+            [4, 10]
+        );
+        assert_eq!(
+            derive_chroma_mv(mv, ChromaFormat::Yuv420, (TopField, TopField)),
+            mv
+        );
+        // Only 4:2:0 has the offset
+        assert_eq!(
+            derive_chroma_mv(mv, ChromaFormat::Yuv422, (TopField, BottomField)),
+            [4, 16]
+        );
+    }
+
+    fn gradient_plane() -> Plane {
+        let mut plane = Plane::new(32, 32);
+        for y in 0..32 {
+            for x in 0..32 {
+                plane.set(x, y, (x + 4 * y) as u8);
+            }
+        }
+        plane
+    }
+
+    #[test]
+    fn test_interpolate_chroma() {
+        let plane = gradient_plane();
+        let mut pred = [0; 4];
+
+        // Whole chroma samples copy the reference
+        interpolate_chroma(&plane, (4, 4), (2, 2), [8, 16], &mut pred, 2);
+        assert_eq!(
+            pred,
+            [
+                plane.at(5, 6),
+                plane.at(6, 6),
+                plane.at(5, 7),
+                plane.at(6, 7)
+            ]
+        );
+
+        // Halfway between 0, 1, 4 and 5: (16 * (0 + 1 + 4 + 5) + 32) >> 6
+        interpolate_chroma(&plane, (0, 0), (2, 2), [4, 4], &mut pred, 2);
+        assert_eq!(pred[0], 3);
+        // An eighth across from 4 to 5: (56 * 4 + 8 * 5 + 32) >> 6
+        interpolate_chroma(&plane, (1, 1), (2, 2), [-7, 0], &mut pred, 2);
+        assert_eq!(pred[0], 4);
+
+        // Outside the plane, the edge samples repeat
+        interpolate_chroma(&plane, (0, 0), (2, 2), [-800, -800], &mut pred, 2);
+        assert_eq!(pred, [plane.at(0, 0); 4]);
+    }
+
+    #[test]
+    fn test_clamp_luma_mv() {
+        let plane = gradient_plane();
+        // Vectors that keep the block near the plane are untouched
+        assert_eq!(clamp_luma_mv([-13, 22], (8, 8), (8, 8), &plane), [-13, 22]);
+        // Far ones stop once the block is REF_BORDER samples outside, keeping their fraction
+        assert_eq!(
+            clamp_luma_mv([-4000, 4001], (8, 8), (8, 8), &plane),
+            [-32 * 4, 40 * 4 + 1]
+        );
+        assert_eq!(
+            clamp_luma_mv([i32::MAX, i32::MIN], (0, 0), (16, 16), &plane),
+            [48 * 4 + 3, -32 * 4]
+        );
+    }
+
+    #[test]
+    fn test_out_of_range_mv_predicts_edge_samples() {
+        let plane = gradient_plane();
+        let mut pred = [0; 64];
+        predict_luma_block(
+            &plane,
+            (8, 8),
+            (8, 8),
+            [i32::MIN & !3, i32::MAX & !3],
+            &mut pred,
+            8,
+        );
+        // Below and left of the plane, every sample is the bottom left corner
+        assert!(pred.iter().all(|&sample| sample == plane.at(0, 31)));
+
+        // Clamping doesn't change the prediction of a vector that's just further out
+        let mut near = [0; 64];
+        predict_luma_block(&plane, (8, 8), (8, 8), [-4 * 40, 4 * 3], &mut near, 8);
+        let mut far = [0; 64];
+        predict_luma_block(&plane, (8, 8), (8, 8), [-4 * 4000, 4 * 3], &mut far, 8);
+        assert_eq!(near, far);
+        assert_eq!(&near[..8], &[plane.at(0, 11); 8]);
+
+        // A fractional vector far outside still only sees the edge
+        predict_luma_block(&plane, (8, 8), (8, 8), [i32::MIN, i32::MAX], &mut pred, 8);
+        assert!(pred.iter().all(|&sample| sample == plane.at(0, 31)));
+    }
+
+    #[test]
+    fn test_luma_sample_interpolation() {
+        // x + 4 * y is linear, so the 6-tap filter reproduces it up to rounding
+        let plane = gradient_plane();
+        let at = |mv| {
+            let mut pred = [0; 1];
+            predict_luma_block(&plane, (8, 8), (1, 1), mv, &mut pred, 1);
+            pred[0]
+        };
+        // G at (8, 8) is 40
+        assert_eq!(at([0, 0]), 40);
+        // b and h, half a sample right of and below G
+        assert_eq!(at([2, 0]), 41);
+        assert_eq!(at([0, 2]), 42);
+        // j in the centre, and the quarter samples a, d and e averaging around them
+        assert_eq!(at([2, 2]), 43);
+        assert_eq!(at([1, 0]), 41);
+        assert_eq!(at([0, 1]), 41);
+        assert_eq!(at([1, 1]), 42);
+        // r averages m and s, both right of or below G
+        assert_eq!(at([3, 3]), 44);
+
+        // The filter overshoots next to a step, and the result clips
+        let mut step = Plane::new(8, 8);
+        for y in 0..8 {
+            for x in 4..8 {
+                step.set(x, y, 255);
+            }
+        }
+        let mut pred = [0; 2];
+        predict_luma_block(&step, (2, 0), (2, 1), [2, 0], &mut pred, 2);
+        assert_eq!(pred, [0, 128]);
+    }
+}
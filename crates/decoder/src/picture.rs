@@ -0,0 +1,177 @@
+use crate::deblock::SliceFilter;
+use crate::frame::YuvFrame;
+use crate::macroblock::MbType;
+use anyhow::{Result, anyhow};
+use parser::{SliceHeader, SliceType};
+
+/// Whether a picture covers a whole frame or a single field of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PictureStructure {
+    #[default]
+    Frame,
+    TopField,
+    BottomField,
+}
+
+impl PictureStructure {
+    /// Derived from `field_pic_flag` and `bottom_field_flag` of the picture's slices
+    pub fn from_header(header: &SliceHeader) -> Self {
+        match (header.field_pic_flag, header.bottom_field_flag) {
+            (false, _) => PictureStructure::Frame,
+            (true, false) => PictureStructure::TopField,
+            (true, true) => PictureStructure::BottomField,
+        }
+    }
+
+    pub fn is_field(self) -> bool {
+        self != PictureStructure::Frame
+    }
+}
+
+/// A decoded picture, as handed out by the `Decoder` in output order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedPicture {
+    pub frame: YuvFrame,
+    /// PicOrderCnt of the picture, which defines output order within a coded video sequence
+    pub poc: i32,
+    pub frame_num: u32,
+    pub idr: bool,
+    /// seq_parameter_set_id and pic_parameter_set_id of the sets active for the picture
+    pub sps_id: u32,
+    pub pps_id: u32,
+    /// Whether the picture was coded with nal_ref_idc != 0
+    pub reference: bool,
+    /// A field picture's frame holds only the lines of that field
+    pub structure: PictureStructure,
+    /// `pic_struct` of the picture timing SEI, telling how the picture's fields are displayed
+    pub pic_struct: Option<u8>,
+    /// `primary_pic_type` of the access unit delimiter in front of the picture, listing the
+    /// slice types it may contain (Table 7-5)
+    pub primary_pic_type: Option<u8>,
+    /// Type shared by every slice of the picture, or None when they differ
+    pub slice_type: Option<SliceType>,
+    /// The cropped frame in the decoder's `OutputFormat`, filled in as the picture is handed out
+    pub buffer: Vec<u8>,
+}
+
+/// What the rest of the picture needs to know about an already decoded macroblock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct MbInfo {
+    /// False until the macroblock has been decoded in the current picture
+    pub decoded: bool,
+    /// Index of the slice the macroblock belongs to, used for neighbour availability
+    pub slice_num: u32,
+    pub mb_type: MbType,
+    /// QPY, except for I_PCM macroblocks where it's 0 as the deblocking filter takes it (8.7.2.2)
+    pub qp: i32,
+    /// TotalCoeff of each luma 4x4 block in raster order, used to predict nC (9.2.1)
+    pub total_coeff: [u8; 16],
+    /// TotalCoeff of each chroma AC block, per component in raster order
+    pub total_coeff_chroma: [[u8; 8]; 2],
+    /// Intra4x4PredMode of each 4x4 block in raster order, for Intra_4x4 macroblocks
+    pub intra4x4_modes: [u8; 16],
+    /// mb_field_decoding_flag of the macroblock's pair, in MBAFF frames
+    pub field: bool,
+    /// mvL0 of each 4x4 luma block in raster order, for motion vector prediction (8.4.1.3)
+    pub mv_l0: [[i32; 2]; 16],
+    /// refIdxL0 of each 8x8 quadrant in raster order
+    pub ref_idx_l0: [u32; 4],
+    /// PicOrderCnt of the picture each 8x8 quadrant predicts from, which the deblocking filter
+    /// compares instead of the indices (8.7.2.1)
+    pub ref_pic_l0: [i32; 4],
+}
+
+/// The picture currently being reconstructed
+#[derive(Debug, Clone)]
+pub(crate) struct Picture {
+    pub frame: YuvFrame,
+    pub mb_info: Vec<MbInfo>,
+    pub width_in_mbs: usize,
+    pub poc: i32,
+    pub frame_num: u32,
+    pub idr: bool,
+    pub sps_id: u32,
+    pub pps_id: u32,
+    pub reference: bool,
+    pub structure: PictureStructure,
+    pub pic_struct: Option<u8>,
+    pub primary_pic_type: Option<u8>,
+    /// Type shared by the slices decoded so far, or None once they differ
+    pub slice_type: Option<SliceType>,
+    /// A slice signalled slice_type 5 to 9, so every slice must have the same type
+    pub uniform_slice_type: bool,
+    /// Number of slices decoded into the picture so far
+    pub slice_count: u32,
+    /// The deblocking filter fields of each slice, indexed by `MbInfo::slice_num`
+    pub slice_filters: Vec<SliceFilter>,
+    /// The picture carries memory_management_control_operation 5, so every other reference is
+    /// dropped once it's decoded
+    pub mmco5: bool,
+}
+
+impl Picture {
+    pub fn new(frame: YuvFrame, width_in_mbs: usize, height_in_mbs: usize) -> Self {
+        Self {
+            frame,
+            mb_info: vec![MbInfo::default(); width_in_mbs * height_in_mbs],
+            width_in_mbs,
+            poc: 0,
+            frame_num: 0,
+            idr: false,
+            sps_id: 0,
+            pps_id: 0,
+            reference: false,
+            structure: PictureStructure::Frame,
+            pic_struct: None,
+            primary_pic_type: None,
+            slice_type: None,
+            uniform_slice_type: false,
+            slice_count: 0,
+            slice_filters: Vec::new(),
+            mmco5: false,
+        }
+    }
+
+    /// Records the type of the next slice of the picture. Once any slice has signalled a
+    /// slice_type of 5 to 9, all of them must have that type (7.4.3), so a mismatch means the
+    /// stream is corrupt.
+    pub fn add_slice_type(&mut self, header: &SliceHeader) -> Result<()> {
+        let kind = header.kind();
+        let uniform = header.slice_type >= 5;
+        if self.slice_count == 0 {
+            self.slice_type = Some(kind);
+        } else if self.slice_type != Some(kind) {
+            if uniform || self.uniform_slice_type {
+                return Err(anyhow!(
+                    "{:?} slice in a picture whose slices must all have the same type",
+                    kind
+                ));
+            }
+            self.slice_type = None;
+        }
+        self.uniform_slice_type |= uniform;
+        Ok(())
+    }
+
+    /// Macroblock column and row of a macroblock address
+    pub fn mb_position(&self, mb_addr: usize) -> (usize, usize) {
+        (mb_addr % self.width_in_mbs, mb_addr / self.width_in_mbs)
+    }
+
+    pub fn into_decoded(self) -> DecodedPicture {
+        DecodedPicture {
+            frame: self.frame,
+            poc: self.poc,
+            frame_num: self.frame_num,
+            idr: self.idr,
+            sps_id: self.sps_id,
+            pps_id: self.pps_id,
+            reference: self.reference,
+            structure: self.structure,
+            pic_struct: self.pic_struct,
+            primary_pic_type: self.primary_pic_type,
+            slice_type: self.slice_type,
+            buffer: Vec::new(),
+        }
+    }
+}
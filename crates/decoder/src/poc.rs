@@ -0,0 +1,396 @@
+use anyhow::{Result, anyhow};
+use bitstream::{NalUnitType, NaluHeader};
+use parser::{SliceHeader, Sps};
+
+/// TopFieldOrderCnt and BottomFieldOrderCnt of a picture. A frame has both, a field only its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FieldOrderCnts {
+    pub top: Option<i32>,
+    pub bottom: Option<i32>,
+}
+
+impl FieldOrderCnts {
+    /// Keeps the counts the picture of `header` has
+    fn new(header: &SliceHeader, top: i32, bottom: i32) -> Self {
+        Self {
+            top: (!header.field_pic_flag || !header.bottom_field_flag).then_some(top),
+            bottom: (!header.field_pic_flag || header.bottom_field_flag).then_some(bottom),
+        }
+    }
+
+    /// PicOrderCnt (8-1): the smaller of a frame's two counts, or a field's own
+    pub fn pic_order_cnt(self) -> i32 {
+        match (self.top, self.bottom) {
+            (Some(top), Some(bottom)) => top.min(bottom),
+            (top, bottom) => top.or(bottom).unwrap_or_default(),
+        }
+    }
+}
+
+/// State carried from picture to picture for picture order count derivation (8.2.1)
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PocState {
+    prev_pic_order_cnt_msb: i32,
+    prev_pic_order_cnt_lsb: i32,
+    prev_frame_num_offset: i32,
+    prev_frame_num: u32,
+}
+
+impl PocState {
+    /// Derives the picture order count of the picture whose first slice is `header`, and updates
+    /// the state used by the pictures that follow it
+    pub fn compute(&mut self, sps: &Sps, header: &SliceHeader, nalu: &NaluHeader) -> Result<i32> {
+        Ok(self.compute_fields(sps, header, nalu)?.pic_order_cnt())
+    }
+
+    /// `compute`, keeping the top and bottom field counts apart
+    pub fn compute_fields(
+        &mut self,
+        sps: &Sps,
+        header: &SliceHeader,
+        nalu: &NaluHeader,
+    ) -> Result<FieldOrderCnts> {
+        let idr = nalu.kind() == NalUnitType::IdrSlice;
+        let poc = match sps.pic_order_cnt_type {
+            0 => self.compute_type0(sps, header, nalu, idr),
+            1 => self.compute_type1(sps, header, nalu, idr),
+            2 => self.compute_type2(sps, header, nalu, idr),
+            other => return Err(anyhow!("pic_order_cnt_type {} is not supported", other)),
+        };
+        self.prev_frame_num = header.frame_num;
+
+        Ok(poc)
+    }
+
+    /// Resets the state after a picture with memory_management_control_operation 5, whose POC
+    /// then becomes 0 (8.2.1). The pictures that follow count from it as from an IDR picture,
+    /// with prevFrameNumOffset and its frame_num 0. For type 0, prevPicOrderCntLsb is the
+    /// picture's TopFieldOrderCnt less tempPicOrderCnt, which is only non-zero for a frame whose
+    /// bottom field comes first.
+    pub fn reset_after_mmco5(&mut self, header: &SliceHeader) {
+        self.prev_pic_order_cnt_msb = 0;
+        self.prev_pic_order_cnt_lsb = if header.field_pic_flag {
+            0
+        } else {
+            (-header.delta_pic_order_cnt_bottom).max(0)
+        };
+        self.prev_frame_num_offset = 0;
+        self.prev_frame_num = 0;
+    }
+
+    /// 8.2.1.1 Decoding process for picture order count type 0. A frame's bottom field is
+    /// delta_pic_order_cnt_bottom away from its top field (8-4).
+    fn compute_type0(
+        &mut self,
+        sps: &Sps,
+        header: &SliceHeader,
+        nalu: &NaluHeader,
+        idr: bool,
+    ) -> FieldOrderCnts {
+        if idr {
+            self.prev_pic_order_cnt_msb = 0;
+            self.prev_pic_order_cnt_lsb = 0;
+        }
+
+        let max_lsb = 1i32 << (sps.log2_max_pic_order_cnt_lsb_minus4 + 4);
+        let lsb = header.pic_order_cnt_lsb as i32;
+        let prev_lsb = self.prev_pic_order_cnt_lsb;
+
+        let msb = if lsb < prev_lsb && prev_lsb - lsb >= max_lsb / 2 {
+            self.prev_pic_order_cnt_msb + max_lsb
+        } else if lsb > prev_lsb && lsb - prev_lsb > max_lsb / 2 {
+            self.prev_pic_order_cnt_msb - max_lsb
+        } else {
+            self.prev_pic_order_cnt_msb
+        };
+
+        if nalu.nal_ref_idc != 0 {
+            self.prev_pic_order_cnt_msb = msb;
+            self.prev_pic_order_cnt_lsb = lsb;
+        }
+
+        let top = msb + lsb;
+        // A bottom field picture's count is msb + lsb like a top field's
+        let bottom = if header.field_pic_flag {
+            top
+        } else {
+            top + header.delta_pic_order_cnt_bottom
+        };
+        FieldOrderCnts::new(header, top, bottom)
+    }
+
+    /// 8.2.1.2 Decoding process for picture order count type 1
+    fn compute_type1(
+        &mut self,
+        sps: &Sps,
+        header: &SliceHeader,
+        nalu: &NaluHeader,
+        idr: bool,
+    ) -> FieldOrderCnts {
+        let frame_num_offset = self.frame_num_offset(sps, header, idr) as i64;
+        let cycle = &sps.offset_for_ref_frame;
+        let mut abs_frame_num = if cycle.is_empty() {
+            0
+        } else {
+            frame_num_offset + header.frame_num as i64
+        };
+        if nalu.nal_ref_idc == 0 && abs_frame_num > 0 {
+            abs_frame_num -= 1;
+        }
+
+        let mut expected_poc = if abs_frame_num > 0 {
+            let cycle_len = cycle.len() as i64;
+            let cycle_cnt = (abs_frame_num - 1) / cycle_len;
+            let frame_num_in_cycle = ((abs_frame_num - 1) % cycle_len) as usize;
+            // ExpectedDeltaPerPicOrderCntCycle (7-12)
+            let expected_delta: i64 = cycle.iter().map(|&offset| offset as i64).sum();
+            cycle_cnt * expected_delta
+                + cycle[..=frame_num_in_cycle]
+                    .iter()
+                    .map(|&offset| offset as i64)
+                    .sum::<i64>()
+        } else {
+            0
+        };
+        if nalu.nal_ref_idc == 0 {
+            expected_poc += sps.offset_for_non_ref_pic as i64;
+        }
+
+        let [delta0, delta1] = header.delta_pic_order_cnt.map(i64::from);
+        let top = expected_poc + delta0;
+        let bottom = if header.field_pic_flag {
+            expected_poc + sps.offset_for_top_to_bottom_field as i64 + delta0
+        } else {
+            top + sps.offset_for_top_to_bottom_field as i64 + delta1
+        };
+        FieldOrderCnts::new(header, top as i32, bottom as i32)
+    }
+
+    /// 8.2.1.3 Decoding process for picture order count type 2. A non-reference picture comes
+    /// just before the reference picture that shares its frame_num (8-12). Both fields of a frame
+    /// share its count.
+    fn compute_type2(
+        &mut self,
+        sps: &Sps,
+        header: &SliceHeader,
+        nalu: &NaluHeader,
+        idr: bool,
+    ) -> FieldOrderCnts {
+        let frame_num_offset = self.frame_num_offset(sps, header, idr);
+
+        let poc = if idr {
+            0
+        } else if nalu.nal_ref_idc == 0 {
+            2 * (frame_num_offset + header.frame_num as i32) - 1
+        } else {
+            2 * (frame_num_offset + header.frame_num as i32)
+        };
+        FieldOrderCnts::new(header, poc, poc)
+    }
+
+    /// FrameNumOffset (8-6, 8-11), which grows by MaxFrameNum each time frame_num wraps
+    fn frame_num_offset(&mut self, sps: &Sps, header: &SliceHeader, idr: bool) -> i32 {
+        let frame_num_offset = if idr {
+            0
+        } else if self.prev_frame_num > header.frame_num {
+            self.prev_frame_num_offset + sps.max_frame_num() as i32
+        } else {
+            self.prev_frame_num_offset
+        };
+        self.prev_frame_num_offset = frame_num_offset;
+        frame_num_offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poc_type0_sps() -> Sps {
+        Sps {
+            pic_order_cnt_type: 0,
+            log2_max_pic_order_cnt_lsb_minus4: 0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_type0_msb_wraps_forward() -> Result<()> {
+        let sps = poc_type0_sps();
+        let idr = NaluHeader::new(0x65)?;
+        let non_idr = NaluHeader::new(0x41)?;
+        let mut state = PocState::default();
+
+        let mut header = SliceHeader::default();
+        assert_eq!(state.compute(&sps, &header, &idr)?, 0);
+
+        // MaxPicOrderCntLsb is 16, so 12 -> 2 wraps into the next MSB period
+        header.pic_order_cnt_lsb = 6;
+        assert_eq!(state.compute(&sps, &header, &non_idr)?, 6);
+        header.pic_order_cnt_lsb = 12;
+        assert_eq!(state.compute(&sps, &header, &non_idr)?, 12);
+        header.pic_order_cnt_lsb = 2;
+        assert_eq!(state.compute(&sps, &header, &non_idr)?, 18);
+        Ok(())
+    }
+
+    #[test]
+    fn test_type0_bottom_field_delta() -> Result<()> {
+        let sps = poc_type0_sps();
+        let idr = NaluHeader::new(0x65)?;
+        let non_idr = NaluHeader::new(0x41)?;
+        let mut state = PocState::default();
+        state.compute(&sps, &SliceHeader::default(), &idr)?;
+
+        // A frame whose bottom field is displayed first
+        let mut header = SliceHeader {
+            pic_order_cnt_lsb: 6,
+            delta_pic_order_cnt_bottom: -1,
+            ..Default::default()
+        };
+        let counts = state.compute_fields(&sps, &header, &non_idr)?;
+        assert_eq!((counts.top, counts.bottom), (Some(6), Some(5)));
+        assert_eq!(counts.pic_order_cnt(), 5);
+
+        header.delta_pic_order_cnt_bottom = 1;
+        header.pic_order_cnt_lsb = 8;
+        let counts = state.compute_fields(&sps, &header, &non_idr)?;
+        assert_eq!((counts.top, counts.bottom), (Some(8), Some(9)));
+        assert_eq!(counts.pic_order_cnt(), 8);
+
+        // A field picture has its own count only, whatever the delta
+        header.field_pic_flag = true;
+        header.bottom_field_flag = true;
+        header.pic_order_cnt_lsb = 9;
+        let counts = state.compute_fields(&sps, &header, &non_idr)?;
+        assert_eq!((counts.top, counts.bottom), (None, Some(9)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_type2_follows_frame_num() -> Result<()> {
+        let sps = Sps {
+            pic_order_cnt_type: 2,
+            ..Default::default()
+        };
+        let idr = NaluHeader::new(0x65)?;
+        let non_idr = NaluHeader::new(0x41)?;
+        let mut state = PocState::default();
+
+        let mut header = SliceHeader::default();
+        assert_eq!(state.compute(&sps, &header, &idr)?, 0);
+        header.frame_num = 1;
+        assert_eq!(state.compute(&sps, &header, &non_idr)?, 2);
+        header.frame_num = 15;
+        assert_eq!(state.compute(&sps, &header, &non_idr)?, 30);
+
+        // frame_num wraps at MaxFrameNum (16), FrameNumOffset carries the lost period
+        header.frame_num = 0;
+        assert_eq!(state.compute(&sps, &header, &non_idr)?, 32);
+        Ok(())
+    }
+
+    #[test]
+    fn test_type2_non_reference_pictures_come_first() -> Result<()> {
+        let sps = Sps {
+            pic_order_cnt_type: 2,
+            ..Default::default()
+        };
+        let idr = NaluHeader::new(0x65)?;
+        let reference = NaluHeader::new(0x41)?;
+        let non_ref = NaluHeader::new(0x01)?;
+        let mut state = PocState::default();
+
+        let mut header = SliceHeader::default();
+        assert_eq!(state.compute(&sps, &header, &idr)?, 0);
+        header.frame_num = 1;
+        assert_eq!(state.compute(&sps, &header, &non_ref)?, 1);
+        // The next picture reuses frame_num 1 since the last one wasn't a reference
+        assert_eq!(state.compute(&sps, &header, &reference)?, 2);
+        header.frame_num = 2;
+        assert_eq!(state.compute(&sps, &header, &non_ref)?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_type1_offset_cycle() -> Result<()> {
+        let sps = Sps {
+            pic_order_cnt_type: 1,
+            offset_for_non_ref_pic: -2,
+            offset_for_top_to_bottom_field: 1,
+            offset_for_ref_frame: vec![2, 4],
+            ..Default::default()
+        };
+        let idr = NaluHeader::new(0x65)?;
+        let reference = NaluHeader::new(0x41)?;
+        let non_ref = NaluHeader::new(0x01)?;
+        let mut state = PocState::default();
+
+        // (nalu, frame_num, delta_pic_order_cnt, bottom field)
+        let pictures = [
+            (idr, 0, [0, 0], false),
+            (reference, 1, [0, 0], false),
+            (non_ref, 2, [3, 0], false),
+            (reference, 2, [0, 0], false),
+            // The bottom field comes out earlier than the top
+            (reference, 3, [0, -5], false),
+            (reference, 4, [0, 0], true),
+        ];
+        let mut pocs = Vec::new();
+        for (nalu, frame_num, delta_pic_order_cnt, bottom) in pictures {
+            let header = SliceHeader {
+                frame_num,
+                delta_pic_order_cnt,
+                field_pic_flag: bottom,
+                bottom_field_flag: bottom,
+                ..Default::default()
+            };
+            pocs.push(state.compute(&sps, &header, &nalu)?);
+        }
+
+        // ExpectedDeltaPerPicOrderCntCycle is 6: 0, 2, 2 - 2 + 3, 2 + 4, 6 + 2 - 5 + 1, 8 + 4 + 1
+        assert_eq!(pocs, [0, 2, 3, 6, 4, 13]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mmco5_resets_poc_state() -> Result<()> {
+        let non_idr = NaluHeader::new(0x41)?;
+
+        // Type 0: after the reset, lsb 2 is 2 rather than a wrap past 12
+        let sps = poc_type0_sps();
+        let mut state = PocState::default();
+        let mut header = SliceHeader::default();
+        for lsb in [6, 12] {
+            header.pic_order_cnt_lsb = lsb;
+            state.compute(&sps, &header, &non_idr)?;
+        }
+        state.reset_after_mmco5(&header);
+        header.pic_order_cnt_lsb = 2;
+        assert_eq!(state.compute(&sps, &header, &non_idr)?, 2);
+
+        // A frame whose bottom field came first leaves prevPicOrderCntLsb at 3, so 11 is less
+        // than half of MaxPicOrderCntLsb ahead rather than a step back
+        header.delta_pic_order_cnt_bottom = -3;
+        state.reset_after_mmco5(&header);
+        header.pic_order_cnt_lsb = 11;
+        let counts = state.compute_fields(&sps, &header, &non_idr)?;
+        assert_eq!((counts.top, counts.bottom), (Some(11), Some(8)));
+
+        // Type 2: frame_num restarts from 0, so a lower frame_num isn't a wrap
+        let sps = Sps {
+            pic_order_cnt_type: 2,
+            ..Default::default()
+        };
+        let mut state = PocState::default();
+        let mut header = SliceHeader {
+            frame_num: 5,
+            ..Default::default()
+        };
+        state.compute(&sps, &header, &non_idr)?;
+        state.reset_after_mmco5(&header);
+        header.frame_num = 1;
+        assert_eq!(state.compute(&sps, &header, &non_idr)?, 2);
+        Ok(())
+    }
+}
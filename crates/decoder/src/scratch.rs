@@ -0,0 +1,30 @@
+/// Per-macroblock temporaries. The `Decoder` owns one and hands it to every macroblock it
+/// decodes, so reconstructing a macroblock never touches the heap.
+#[derive(Debug, Clone)]
+pub(crate) struct DecodeScratch {
+    /// Levels of the residual block being parsed, in scanning order
+    pub levels: [i32; 16],
+    /// Intra16x16DCLevel in raster order, replaced by dcY once transformed
+    pub luma_dc: [i32; 16],
+    /// Coefficients of each luma 4x4 block, indexed by luma4x4BlkIdx, in raster order
+    pub luma: [[i32; 16]; 16],
+    /// ChromaDCLevel per component in raster order, replaced by dcC once transformed
+    pub chroma_dc: [[i32; 4]; 2],
+    /// Coefficients of each chroma 4x4 block, per component and chroma4x4BlkIdx
+    pub chroma: [[[i32; 16]; 4]; 2],
+    /// Prediction samples of the block being reconstructed, in raster order
+    pub pred: [u8; 256],
+}
+
+impl Default for DecodeScratch {
+    fn default() -> Self {
+        Self {
+            levels: [0; 16],
+            luma_dc: [0; 16],
+            luma: [[0; 16]; 16],
+            chroma_dc: [[0; 4]; 2],
+            chroma: [[[0; 16]; 4]; 2],
+            pred: [0; 256],
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use crate::cabac::{CabacDecoder, init_contexts};
+use crate::macroblock::{
+    decode_cabac_macroblock, decode_macroblock, decode_skip, read_mb_skip_flag,
+};
+use crate::picture::{DecodedPicture, Picture};
+use crate::scratch::DecodeScratch;
+use crate::slice_group::next_mb_address;
+use anyhow::{Result, anyhow};
+use bitstream::BitReader;
+use parser::{Pps, SliceHeader, SliceType, Sps};
+
+/// Everything the macroblock layer needs to know about the slice being decoded
+pub(crate) struct SliceContext<'a> {
+    pub sps: &'a Sps,
+    pub pps: &'a Pps,
+    pub header: &'a SliceHeader,
+    pub slice_type: SliceType,
+    /// Index of the slice within its picture
+    pub slice_num: u32,
+    /// SliceQPY, updated by each mb_qp_delta
+    pub qp: i32,
+    pub ref_list0: Vec<&'a DecodedPicture>,
+    /// Raster ordered weightScale4x4 of each 4x4 scaling list
+    pub weight_scales: [[i32; 16]; 6],
+    /// MbToSliceGroupMap, left empty when the picture is a single slice group
+    pub slice_group_map: Vec<u8>,
+}
+
+impl SliceContext<'_> {
+    /// The weightScale4x4 matrix for a block, selected by its macroblock's prediction type and
+    /// its colour component (0 = Y, 1 = Cb, 2 = Cr)
+    pub fn weight_scales(&self, intra: bool, component: usize) -> &[i32; 16] {
+        &self.weight_scales[if intra { 0 } else { 3 } + component]
+    }
+}
+
+/// Implemented as in 7.3.4 Slice data syntax, for slices in progressive frames
+pub(crate) fn decode_slice_data(
+    reader: &mut BitReader,
+    ctx: &mut SliceContext,
+    picture: &mut Picture,
+    scratch: &mut DecodeScratch,
+) -> Result<()> {
+    if ctx.pps.entropy_coding_mode_flag {
+        return decode_cabac_slice_data(reader, ctx, picture, scratch);
+    }
+
+    let pic_size_in_mbs = picture.mb_info.len();
+    let mut mb_addr = ctx.header.first_mb_in_slice as usize;
+    let mut more_data = true;
+
+    while more_data {
+        if !ctx.slice_type.is_intra() {
+            let mb_skip_run = reader.read_ue()?;
+            for _ in 0..mb_skip_run {
+                if mb_addr >= pic_size_in_mbs {
+                    return Err(anyhow!("mb_skip_run runs past the end of the picture"));
+                }
+                decode_skip(ctx, picture, scratch, mb_addr)?;
+                mb_addr = next_mb_address(mb_addr, &ctx.slice_group_map);
+            }
+            if mb_skip_run > 0 {
+                more_data = reader.more_rbsp_data();
+            }
+        }
+
+        if more_data {
+            if mb_addr >= pic_size_in_mbs {
+                return Err(anyhow!("Slice data runs past the end of the picture"));
+            }
+            decode_macroblock(reader, ctx, picture, scratch, mb_addr)?;
+            more_data = reader.more_rbsp_data();
+            mb_addr = next_mb_address(mb_addr, &ctx.slice_group_map);
+        }
+    }
+
+    Ok(())
+}
+
+/// The CABAC branch of 7.3.4, where every macroblock ends with an end_of_slice_flag
+fn decode_cabac_slice_data(
+    reader: &mut BitReader,
+    ctx: &mut SliceContext,
+    picture: &mut Picture,
+    scratch: &mut DecodeScratch,
+) -> Result<()> {
+    if ctx.sps.mb_adaptive_frame_field_flag && !ctx.header.field_pic_flag {
+        return Err(anyhow!(
+            "mb_field_decoding_flag of MBAFF frames is not supported"
+        ));
+    }
+    read_cabac_alignment(reader)?;
+    let mut contexts = init_contexts(ctx.slice_type, ctx.header.cabac_init_idc, ctx.qp)?;
+    let mut cabac = CabacDecoder::new(reader)?;
+
+    let pic_size_in_mbs = picture.mb_info.len();
+    let mut mb_addr = ctx.header.first_mb_in_slice as usize;
+    loop {
+        if mb_addr >= pic_size_in_mbs {
+            return Err(anyhow!("Slice data runs past the end of the picture"));
+        }
+        let skipped = !ctx.slice_type.is_intra()
+            && read_mb_skip_flag(&mut cabac, &mut contexts, ctx, picture, mb_addr)?;
+        if skipped {
+            decode_skip(ctx, picture, scratch, mb_addr)?;
+        } else {
+            decode_cabac_macroblock(&mut cabac, &mut contexts, ctx, picture, mb_addr)?;
+        }
+
+        let end_of_slice_flag = cabac.decode_terminate()?;
+        mb_addr = next_mb_address(mb_addr, &ctx.slice_group_map);
+        if end_of_slice_flag {
+            break;
+        }
+    }
+
+    cabac.finish_slice()
+}
+
+/// Consumes the `cabac_alignment_one_bit`s between the slice header and CABAC slice data (7.3.4)
+fn read_cabac_alignment(reader: &mut BitReader) -> Result<()> {
+    while !reader.byte_aligned() {
+        if !reader.read_flag()? {
+            return Err(anyhow!("cabac_alignment_one_bit must be 1"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cabac::{CTX_MB_SKIP_P, CTX_MB_TYPE_I};
+    use crate::frame::{ChromaFormat, YuvFrame};
+    use crate::macroblock::MbType;
+    use crate::scratch::DecodeScratch;
+    use crate::tests::CabacWriter;
+    use bitstream::BitWriter;
+
+    /// Decodes CABAC slice data into a 3x1 macroblock picture, returning the picture
+    fn decode_cabac(slice_type: SliceType, data: &[u8], reference: &YuvFrame) -> Result<Picture> {
+        decode(true, slice_type, data, reference)
+    }
+
+    /// `decode_cabac` for either entropy coding mode
+    fn decode(
+        entropy_coding_mode_flag: bool,
+        slice_type: SliceType,
+        data: &[u8],
+        reference: &YuvFrame,
+    ) -> Result<Picture> {
+        let sps = Sps {
+            chroma_format_idc: 1,
+            ..Default::default()
+        };
+        let pps = Pps {
+            entropy_coding_mode_flag,
+            ..Default::default()
+        };
+        let header = SliceHeader::default();
+        let reference = Picture::new(reference.clone(), 3, 1).into_decoded();
+        let mut ctx = SliceContext {
+            sps: &sps,
+            pps: &pps,
+            header: &header,
+            slice_type,
+            slice_num: 0,
+            qp: 26,
+            ref_list0: vec![&reference],
+            weight_scales: [[16; 16]; 6],
+            slice_group_map: Vec::new(),
+        };
+        let mut picture = Picture::new(YuvFrame::new(48, 16, ChromaFormat::Yuv420), 3, 1);
+        let mut reader = BitReader::from_bytes(data);
+        decode_slice_data(
+            &mut reader,
+            &mut ctx,
+            &mut picture,
+            &mut DecodeScratch::default(),
+        )?;
+        Ok(picture)
+    }
+
+    #[test]
+    fn test_cabac_slice_ends_at_end_of_slice_flag() -> Result<()> {
+        let mut contexts = init_contexts(SliceType::P, 0, 26)?;
+        let mut writer = BitWriter::new();
+        let mut encoder = CabacWriter::new(&mut writer);
+        // Two skipped macroblocks, neither with a coded neighbour
+        for end_of_slice_flag in [false, true] {
+            encoder.encode_decision(&mut contexts[CTX_MB_SKIP_P], true);
+            encoder.encode_terminate(end_of_slice_flag);
+        }
+        while !writer.byte_aligned() {
+            writer.write_flag(false);
+        }
+
+        let mut reference = YuvFrame::new(48, 16, ChromaFormat::Yuv420);
+        reference.y.data.fill(77);
+        let picture = decode_cabac(SliceType::P, &writer.into_bytes(), &reference)?;
+
+        let decoded: Vec<bool> = picture.mb_info.iter().map(|info| info.decoded).collect();
+        assert_eq!(decoded, [true, true, false]);
+        assert_eq!(
+            (picture.frame.y.at(31, 15), picture.frame.y.at(32, 0)),
+            (77, 0)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cabac_pcm_macroblocks_restart_the_engine() -> Result<()> {
+        let mut contexts = init_contexts(SliceType::I, 0, 26)?;
+        let mut writer = BitWriter::new();
+        let write_pcm = |writer: &mut BitWriter, sample: u32| {
+            while !writer.byte_aligned() {
+                writer.write_flag(false); // pcm_alignment_zero_bit
+            }
+            for _ in 0..384 {
+                writer.write(sample, 8);
+            }
+        };
+
+        let mut encoder = CabacWriter::new(&mut writer);
+        encoder.encode_decision(&mut contexts[CTX_MB_TYPE_I], true);
+        encoder.encode_terminate(true); // I_PCM
+        write_pcm(&mut writer, 10);
+        // The engine restarts after the samples
+        let mut encoder = CabacWriter::new(&mut writer);
+        encoder.encode_terminate(false); // end_of_slice_flag
+        // The left neighbour is I_PCM rather than I_NxN
+        encoder.encode_decision(&mut contexts[CTX_MB_TYPE_I + 1], true);
+        encoder.encode_terminate(true); // I_PCM
+        write_pcm(&mut writer, 20);
+        let mut encoder = CabacWriter::new(&mut writer);
+        encoder.encode_terminate(true); // end_of_slice_flag
+        while !writer.byte_aligned() {
+            writer.write_flag(false);
+        }
+
+        let reference = YuvFrame::new(48, 16, ChromaFormat::Yuv420);
+        let picture = decode_cabac(SliceType::I, &writer.into_bytes(), &reference)?;
+        assert_eq!(picture.mb_info[1].mb_type, MbType::IPcm);
+        assert!(!picture.mb_info[2].decoded);
+        assert_eq!(
+            (picture.frame.y.at(0, 0), picture.frame.cr.at(15, 7)),
+            (10, 20)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cavlc_slice_of_pcm_macroblocks() -> Result<()> {
+        // The first macroblock's samples end in what looks like rbsp_trailing_bits, and the
+        // second one starts right after them without any mb_skip_run or alignment bits
+        let mut writer = BitWriter::new();
+        for sample in [0x80, 0x00] {
+            writer.write_ue(25); // mb_type, I_PCM
+            while !writer.byte_aligned() {
+                writer.write_flag(false); // pcm_alignment_zero_bit
+            }
+            for _ in 0..384 {
+                writer.write(sample, 8);
+            }
+        }
+        writer.write_rbsp_trailing_bits();
+
+        let reference = YuvFrame::new(48, 16, ChromaFormat::Yuv420);
+        let picture = decode(false, SliceType::I, &writer.into_bytes(), &reference)?;
+        let mb_types: Vec<_> = picture.mb_info.iter().map(|info| info.mb_type).collect();
+        assert_eq!(mb_types[..2], [MbType::IPcm, MbType::IPcm]);
+        assert!(!picture.mb_info[2].decoded);
+        assert_eq!(
+            (picture.frame.y.at(15, 15), picture.frame.cb.at(8, 0)),
+            (0x80, 0x00)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_slice_data_stops_at_the_end_of_the_picture() -> Result<()> {
+        let reference = YuvFrame::new(48, 16, ChromaFormat::Yuv420);
+        let past_the_end = |result: Result<Picture>| {
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("past the end of the picture")
+        };
+
+        // A fourth macroblock in the 3 macroblock picture
+        let mut writer = BitWriter::new();
+        for _ in 0..4 {
+            writer.write_ue(25); // mb_type, I_PCM
+            while !writer.byte_aligned() {
+                writer.write_flag(false);
+            }
+            for _ in 0..384 {
+                writer.write(0x80, 8);
+            }
+        }
+        writer.write_rbsp_trailing_bits();
+        assert!(past_the_end(decode(
+            false,
+            SliceType::I,
+            &writer.into_bytes(),
+            &reference
+        )));
+
+        // A skip run that would take billions of iterations
+        let mut writer = BitWriter::new();
+        writer.write_ue(u32::MAX - 1); // mb_skip_run
+        writer.write_rbsp_trailing_bits();
+        assert!(past_the_end(decode(
+            false,
+            SliceType::P,
+            &writer.into_bytes(),
+            &reference
+        )));
+
+        // CABAC data whose end_of_slice_flag never comes
+        let mut contexts = init_contexts(SliceType::P, 0, 26)?;
+        let mut writer = BitWriter::new();
+        let mut encoder = CabacWriter::new(&mut writer);
+        for _ in 0..3 {
+            encoder.encode_decision(&mut contexts[CTX_MB_SKIP_P], true);
+            encoder.encode_terminate(false);
+        }
+        encoder.encode_terminate(true);
+        while !writer.byte_aligned() {
+            writer.write_flag(false);
+        }
+        assert!(past_the_end(decode_cabac(
+            SliceType::P,
+            &writer.into_bytes(),
+            &reference
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cabac_alignment_from_mid_byte() -> Result<()> {
+        let mut reader = BitReader::from_bytes(&[0b1010_0111, 0xAB]);
+        reader.read(5)?;
+        read_cabac_alignment(&mut reader)?;
+        assert_eq!(reader.position(), 8);
+        assert_eq!(reader.read(8)?, 0xAB);
+
+        // Nothing to consume at the end of the data when already aligned
+        read_cabac_alignment(&mut reader)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_cabac_alignment_rejects_zero_bits() -> Result<()> {
+        let mut reader = BitReader::from_bytes(&[0b1010_0101]);
+        reader.read(5)?;
+        let err = read_cabac_alignment(&mut reader).unwrap_err();
+        assert!(err.to_string().contains("cabac_alignment_one_bit"));
+        Ok(())
+    }
+}
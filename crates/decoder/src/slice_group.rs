@@ -0,0 +1,323 @@
+use anyhow::{Result, anyhow};
+use parser::{Pps, SliceHeader, Sps};
+
+/// Derives MbToSliceGroupMap (8.2.2), the slice group of every macroblock of the picture `header`
+/// belongs to. Only meaningful when the PPS has more than one slice group.
+pub(crate) fn derive_slice_group_map(
+    pps: &Pps,
+    sps: &Sps,
+    header: &SliceHeader,
+) -> Result<Vec<u8>> {
+    let width = sps.pic_width_in_mbs() as usize;
+    let height = sps.pic_height_in_map_units_minus1 as usize + 1;
+    let map_units =
+        map_unit_to_slice_group_map(pps, width, height, header.slice_group_change_cycle)?;
+
+    // 8.2.2.8: a map unit of a frame that may hold fields covers a vertical macroblock pair
+    if sps.frame_mbs_only_flag || header.field_pic_flag {
+        return Ok(map_units);
+    }
+    Ok((0..map_units.len() * 2)
+        .map(|i| map_units[(i / (2 * width)) * width + i % width])
+        .collect())
+}
+
+/// NextMbAddress (8-16): the macroblock after `current` in the same slice group, or the picture
+/// size when there is none. An empty map means the picture is a single slice group.
+pub(crate) fn next_mb_address(current: usize, map: &[u8]) -> usize {
+    let Some(&group) = map.get(current) else {
+        return current + 1;
+    };
+    map.iter()
+        .skip(current + 1)
+        .position(|&g| g == group)
+        .map_or(map.len(), |offset| current + 1 + offset)
+}
+
+/// mapUnitToSliceGroupMap, as in 8.2.2.1 to 8.2.2.7
+fn map_unit_to_slice_group_map(
+    pps: &Pps,
+    width: usize,
+    height: usize,
+    slice_group_change_cycle: u32,
+) -> Result<Vec<u8>> {
+    let size = width * height;
+    let num_groups = pps.num_slice_groups_minus1 as usize + 1;
+    let direction = pps.slice_group_change_direction_flag as u8;
+    // MapUnitsInSliceGroup0 (7-36)
+    let units_in_group0 = (slice_group_change_cycle as usize
+        * (pps.slice_group_change_rate_minus1 as usize + 1))
+        .min(size);
+    // sizeOfUpperLeftGroup (8-17)
+    let upper_left = if direction == 1 {
+        size - units_in_group0
+    } else {
+        units_in_group0
+    };
+
+    let mut map = vec![0u8; size];
+    match pps.slice_group_map_type {
+        // 8.2.2.1 Interleaved
+        0 => {
+            if pps.run_length_minus1.len() != num_groups {
+                return Err(anyhow!("PPS is missing slice group run lengths"));
+            }
+            let mut i = 0;
+            while i < size {
+                for (group, &run_length_minus1) in pps.run_length_minus1.iter().enumerate() {
+                    let run = (run_length_minus1 as usize + 1).min(size.saturating_sub(i));
+                    map[i..i + run].fill(group as u8);
+                    i += run;
+                }
+            }
+        }
+        // 8.2.2.2 Dispersed
+        1 => {
+            for (i, group) in map.iter_mut().enumerate() {
+                *group = ((i % width + (i / width * num_groups) / 2) % num_groups) as u8;
+            }
+        }
+        // 8.2.2.3 Foreground with left-over
+        2 => {
+            map.fill(pps.num_slice_groups_minus1 as u8);
+            for group in (0..pps.num_slice_groups_minus1 as usize).rev() {
+                let (top_left, bottom_right) = pps
+                    .top_left
+                    .get(group)
+                    .zip(pps.bottom_right.get(group))
+                    .ok_or_else(|| anyhow!("PPS is missing slice group {} bounds", group))?;
+                let (top_left, bottom_right) = (*top_left as usize, *bottom_right as usize);
+                if top_left > bottom_right
+                    || bottom_right >= size
+                    || top_left % width > bottom_right % width
+                {
+                    return Err(anyhow!("Invalid bounds for slice group {}", group));
+                }
+                for y in top_left / width..=bottom_right / width {
+                    map[y * width + top_left % width..=y * width + bottom_right % width]
+                        .fill(group as u8);
+                }
+            }
+        }
+        // 8.2.2.4 Box-out
+        3 => box_out(&mut map, width, height, direction == 1, units_in_group0),
+        // 8.2.2.5 Raster scan
+        4 => {
+            for (i, group) in map.iter_mut().enumerate() {
+                *group = if i < upper_left {
+                    direction
+                } else {
+                    1 - direction
+                };
+            }
+        }
+        // 8.2.2.6 Wipe, column by column
+        5 => {
+            let mut k = 0;
+            for x in 0..width {
+                for y in 0..height {
+                    map[y * width + x] = if k < upper_left {
+                        direction
+                    } else {
+                        1 - direction
+                    };
+                    k += 1;
+                }
+            }
+        }
+        // 8.2.2.7 Explicit
+        6 => {
+            if pps.slice_group_id.len() != size {
+                return Err(anyhow!(
+                    "PPS slice_group_id covers {} map units, but the picture has {}",
+                    pps.slice_group_id.len(),
+                    size
+                ));
+            }
+            for (group, &id) in map.iter_mut().zip(&pps.slice_group_id) {
+                *group = id as u8;
+            }
+        }
+        other => return Err(anyhow!("Invalid slice_group_map_type: {}", other)),
+    }
+
+    Ok(map)
+}
+
+/// 8.2.2.4: slice group 0 spirals out from the picture centre, clockwise unless `counter_clockwise`
+fn box_out(map: &mut [u8], width: usize, height: usize, counter_clockwise: bool, units: usize) {
+    map.fill(1);
+    let dir = counter_clockwise as isize;
+    let (width, height) = (width as isize, height as isize);
+    let mut x = (width - dir) / 2;
+    let mut y = (height - dir) / 2;
+    let (mut left, mut top, mut right, mut bottom) = (x, y, x, y);
+    let (mut x_dir, mut y_dir) = (dir - 1, dir);
+
+    let mut k = 0;
+    while k < units {
+        let index = (y * width + x) as usize;
+        let vacant = map[index] == 1;
+        if vacant {
+            map[index] = 0;
+        }
+
+        if x_dir == -1 && x == left {
+            left = (left - 1).max(0);
+            x = left;
+            x_dir = 0;
+            y_dir = 2 * dir - 1;
+        } else if x_dir == 1 && x == right {
+            right = (right + 1).min(width - 1);
+            x = right;
+            x_dir = 0;
+            y_dir = 1 - 2 * dir;
+        } else if y_dir == -1 && y == top {
+            top = (top - 1).max(0);
+            y = top;
+            x_dir = 1 - 2 * dir;
+            y_dir = 0;
+        } else if y_dir == 1 && y == bottom {
+            bottom = (bottom + 1).min(height - 1);
+            y = bottom;
+            x_dir = 2 * dir - 1;
+            y_dir = 0;
+        } else {
+            x += x_dir;
+            y += y_dir;
+        }
+        k += vacant as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sps(width_in_mbs: u32, height_in_mbs: u32) -> Sps {
+        Sps {
+            pic_width_in_mbs_minus1: width_in_mbs - 1,
+            pic_height_in_map_units_minus1: height_in_mbs - 1,
+            frame_mbs_only_flag: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_dispersed_map() -> Result<()> {
+        let pps = Pps {
+            num_slice_groups_minus1: 1,
+            slice_group_map_type: 1,
+            ..Default::default()
+        };
+        let map = derive_slice_group_map(&pps, &sps(4, 2), &SliceHeader::default())?;
+        assert_eq!(map, [0, 1, 0, 1, 1, 0, 1, 0]);
+
+        let pps = Pps {
+            num_slice_groups_minus1: 2,
+            ..pps
+        };
+        let map = derive_slice_group_map(&pps, &sps(3, 3), &SliceHeader::default())?;
+        assert_eq!(map, [0, 1, 2, 1, 2, 0, 0, 1, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_explicit_map() -> Result<()> {
+        let pps = Pps {
+            num_slice_groups_minus1: 2,
+            slice_group_map_type: 6,
+            pic_size_in_map_units_minus1: 5,
+            slice_group_id: vec![2, 2, 0, 1, 0, 1],
+            ..Default::default()
+        };
+        let map = derive_slice_group_map(&pps, &sps(3, 2), &SliceHeader::default())?;
+        assert_eq!(map, [2, 2, 0, 1, 0, 1]);
+
+        assert!(derive_slice_group_map(&pps, &sps(3, 3), &SliceHeader::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_interleaved_and_foreground_maps() -> Result<()> {
+        let pps = Pps {
+            num_slice_groups_minus1: 1,
+            slice_group_map_type: 0,
+            run_length_minus1: vec![2, 0],
+            ..Default::default()
+        };
+        let map = derive_slice_group_map(&pps, &sps(3, 2), &SliceHeader::default())?;
+        assert_eq!(map, [0, 0, 0, 1, 0, 0]);
+
+        let pps = Pps {
+            num_slice_groups_minus1: 1,
+            slice_group_map_type: 2,
+            top_left: vec![4],
+            bottom_right: vec![8],
+            ..Default::default()
+        };
+        let map = derive_slice_group_map(&pps, &sps(3, 3), &SliceHeader::default())?;
+        assert_eq!(map, [1, 1, 1, 1, 0, 0, 1, 0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_box_out_map_evolves_with_change_cycle() -> Result<()> {
+        let pps = Pps {
+            num_slice_groups_minus1: 1,
+            slice_group_map_type: 3,
+            ..Default::default()
+        };
+        let map = |slice_group_change_cycle: u32| {
+            let header = SliceHeader {
+                slice_group_change_cycle,
+                ..Default::default()
+            };
+            derive_slice_group_map(&pps, &sps(3, 3), &header)
+        };
+
+        // Clockwise from the centre: left, then up, then along the top row
+        assert_eq!(map(0)?, [1, 1, 1, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(map(1)?, [1, 1, 1, 1, 0, 1, 1, 1, 1]);
+        assert_eq!(map(2)?, [1, 1, 1, 0, 0, 1, 1, 1, 1]);
+        assert_eq!(map(4)?, [0, 0, 1, 0, 0, 1, 1, 1, 1]);
+        assert_eq!(map(9)?, [0; 9]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_map_units_cover_macroblock_pairs_in_interlaced_frames() -> Result<()> {
+        let pps = Pps {
+            num_slice_groups_minus1: 1,
+            slice_group_map_type: 1,
+            ..Default::default()
+        };
+        let sps = Sps {
+            frame_mbs_only_flag: false,
+            ..sps(2, 1)
+        };
+        let map = derive_slice_group_map(&pps, &sps, &SliceHeader::default())?;
+        assert_eq!(map, [0, 1, 0, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_next_mb_address_follows_a_dispersed_map() -> Result<()> {
+        let pps = Pps {
+            num_slice_groups_minus1: 1,
+            slice_group_map_type: 1,
+            ..Default::default()
+        };
+        let map = derive_slice_group_map(&pps, &sps(4, 2), &SliceHeader::default())?;
+
+        let scan = |first: usize| {
+            std::iter::successors(Some(first), |&addr| Some(next_mb_address(addr, &map)))
+                .take_while(|&addr| addr < map.len())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(scan(0), [0, 2, 5, 7]);
+        assert_eq!(scan(1), [1, 3, 4, 6]);
+        assert_eq!(next_mb_address(4, &[]), 5);
+        Ok(())
+    }
+}
@@ -1 +1,539 @@
+//! Helpers for synthesizing H.264 streams in tests
 
+use crate::cabac::{CabacContext, RANGE_TAB_LPS, TRANS_IDX_LPS, trans_idx_mps};
+use bitstream::BitWriter;
+use bitstream::rbsp::rbsp_to_ebsp;
+
+/// The arithmetic encoder of 9.3.4.2, for writing CABAC slice data
+pub(crate) struct CabacWriter<'a> {
+    writer: &'a mut BitWriter,
+    low: u32,
+    range: u32,
+    first_bit: bool,
+    bits_outstanding: u32,
+}
+
+impl<'a> CabacWriter<'a> {
+    /// 9.3.4.1 InitEncoder. The writer must be byte aligned.
+    pub(crate) fn new(writer: &'a mut BitWriter) -> Self {
+        Self {
+            writer,
+            low: 0,
+            range: 510,
+            first_bit: true,
+            bits_outstanding: 0,
+        }
+    }
+
+    /// 9.3.4.2 EncodeDecision
+    pub(crate) fn encode_decision(&mut self, ctx: &mut CabacContext, bin: bool) {
+        let range_lps = RANGE_TAB_LPS[ctx.p_state_idx as usize][((self.range >> 6) & 3) as usize];
+        self.range -= range_lps as u32;
+        if bin != ctx.val_mps {
+            self.low += self.range;
+            self.range = range_lps as u32;
+            if ctx.p_state_idx == 0 {
+                ctx.val_mps = !ctx.val_mps;
+            }
+            ctx.p_state_idx = TRANS_IDX_LPS[ctx.p_state_idx as usize];
+        } else {
+            ctx.p_state_idx = trans_idx_mps(ctx.p_state_idx);
+        }
+        self.renorm();
+    }
+
+    /// 9.3.4.4 EncodeBypass
+    pub(crate) fn encode_bypass(&mut self, bin: bool) {
+        self.low <<= 1;
+        if bin {
+            self.low += self.range;
+        }
+        if self.low >= 1024 {
+            self.put_bit(true);
+            self.low -= 1024;
+        } else if self.low < 512 {
+            self.put_bit(false);
+        } else {
+            self.low -= 512;
+            self.bits_outstanding += 1;
+        }
+    }
+
+    /// 9.3.4.5 EncodeTerminate. A 1 flushes the encoder (EncodeFlush), whose last bit is the
+    /// rbsp_stop_one_bit.
+    pub(crate) fn encode_terminate(&mut self, bin: bool) {
+        self.range -= 2;
+        if !bin {
+            self.renorm();
+            return;
+        }
+        self.low += self.range;
+        self.range = 2;
+        self.renorm();
+        self.put_bit((self.low >> 9) & 1 == 1);
+        self.writer.write(((self.low >> 7) & 3) | 1, 2);
+    }
+
+    /// 9.3.4.3 RenormE
+    fn renorm(&mut self) {
+        while self.range < 256 {
+            if self.low < 256 {
+                self.put_bit(false);
+            } else if self.low >= 512 {
+                self.low -= 512;
+                self.put_bit(true);
+            } else {
+                self.low -= 256;
+                self.bits_outstanding += 1;
+            }
+            self.range <<= 1;
+            self.low <<= 1;
+        }
+    }
+
+    fn put_bit(&mut self, bit: bool) {
+        if self.first_bit {
+            self.first_bit = false;
+        } else {
+            self.writer.write_flag(bit);
+        }
+        while self.bits_outstanding > 0 {
+            self.writer.write_flag(!bit);
+            self.bits_outstanding -= 1;
+        }
+    }
+}
+
+/// Wraps an RBSP into a NALU: header byte followed by the emulation-prevented payload
+pub(crate) fn nalu(nal_ref_idc: u8, nal_unit_type: u8, rbsp: &[u8]) -> Vec<u8> {
+    let mut nalu = vec![(nal_ref_idc << 5) | nal_unit_type];
+    nalu.extend(rbsp_to_ebsp(rbsp));
+    nalu
+}
+
+/// A Baseline profile, 4:2:0, progressive SPS RBSP with POC type 2 and no VUI
+pub(crate) fn sps_rbsp(sps_id: u32, width_in_mbs: u32, height_in_mbs: u32) -> Vec<u8> {
+    sps_rbsp_with_profile(66, sps_id, width_in_mbs, height_in_mbs)
+}
+
+pub(crate) fn sps_rbsp_with_profile(
+    profile_idc: u8,
+    sps_id: u32,
+    width_in_mbs: u32,
+    height_in_mbs: u32,
+) -> Vec<u8> {
+    TestSps::new(profile_idc, sps_id, width_in_mbs, height_in_mbs).write()
+}
+
+/// A High profile SPS RBSP that signals a scaling matrix without any list, so every list takes
+/// its default by fall-back rule A
+pub(crate) fn default_scaling_sps_rbsp(
+    sps_id: u32,
+    width_in_mbs: u32,
+    height_in_mbs: u32,
+) -> Vec<u8> {
+    TestSps {
+        seq_scaling_matrix_present_flag: true,
+        ..TestSps::new(100, sps_id, width_in_mbs, height_in_mbs)
+    }
+    .write()
+}
+
+/// A Main profile SPS RBSP for interlaced video, with `mb_adaptive_frame_field_flag` as given
+pub(crate) fn interlaced_sps_rbsp(
+    sps_id: u32,
+    width_in_mbs: u32,
+    height_in_map_units: u32,
+    mb_adaptive_frame_field_flag: bool,
+) -> Vec<u8> {
+    TestSps {
+        mbaff: Some(mb_adaptive_frame_field_flag),
+        ..TestSps::new(77, sps_id, width_in_mbs, height_in_map_units)
+    }
+    .write()
+}
+
+/// `sps_rbsp` with a VUI whose only set flag is `pic_struct_present_flag`, so pic timing SEIs
+/// carry `pic_struct`
+pub(crate) fn pic_struct_sps_rbsp(sps_id: u32, width_in_mbs: u32, height_in_mbs: u32) -> Vec<u8> {
+    TestSps {
+        pic_struct_present_flag: true,
+        ..TestSps::new(66, sps_id, width_in_mbs, height_in_mbs)
+    }
+    .write()
+}
+
+/// `sps_rbsp` with POC type 0, so pictures can be coded out of output order
+pub(crate) fn poc_sps_rbsp(sps_id: u32, width_in_mbs: u32, height_in_mbs: u32) -> Vec<u8> {
+    TestSps {
+        pic_order_cnt_type: 0,
+        ..TestSps::new(66, sps_id, width_in_mbs, height_in_mbs)
+    }
+    .write()
+}
+
+/// `poc_sps_rbsp` with a VUI that has no bitstream restriction
+pub(crate) fn poc_vui_sps_rbsp(sps_id: u32, width_in_mbs: u32, height_in_mbs: u32) -> Vec<u8> {
+    TestSps {
+        pic_order_cnt_type: 0,
+        pic_struct_present_flag: true,
+        ..TestSps::new(66, sps_id, width_in_mbs, height_in_mbs)
+    }
+    .write()
+}
+
+/// A High profile monochrome (chroma_format_idc 0) SPS RBSP
+pub(crate) fn monochrome_sps_rbsp(sps_id: u32, width_in_mbs: u32, height_in_mbs: u32) -> Vec<u8> {
+    TestSps {
+        chroma_format_idc: 0,
+        ..TestSps::new(100, sps_id, width_in_mbs, height_in_mbs)
+    }
+    .write()
+}
+
+/// The SPS fields the test streams vary; everything else is fixed
+struct TestSps {
+    profile_idc: u8,
+    sps_id: u32,
+    width_in_mbs: u32,
+    height_in_map_units: u32,
+    /// Only written for profiles other than Baseline, Main and Extended
+    chroma_format_idc: u32,
+    seq_scaling_matrix_present_flag: bool,
+    /// None for frame_mbs_only_flag == 1, otherwise the mb_adaptive_frame_field_flag
+    mbaff: Option<bool>,
+    /// Writes a VUI that sets only this flag
+    pic_struct_present_flag: bool,
+    /// 0 or 2. Type 0 uses a 4-bit pic_order_cnt_lsb.
+    pic_order_cnt_type: u32,
+}
+
+impl TestSps {
+    /// A 4:2:0 progressive SPS with POC type 2 and no VUI
+    fn new(profile_idc: u8, sps_id: u32, width_in_mbs: u32, height_in_map_units: u32) -> Self {
+        Self {
+            profile_idc,
+            sps_id,
+            width_in_mbs,
+            height_in_map_units,
+            chroma_format_idc: 1,
+            seq_scaling_matrix_present_flag: false,
+            mbaff: None,
+            pic_struct_present_flag: false,
+            pic_order_cnt_type: 2,
+        }
+    }
+
+    fn write(&self) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        writer.write(self.profile_idc as u32, 8);
+        writer.write(0, 8); // constraint flags
+        writer.write(30, 8); // level_idc
+        writer.write_ue(self.sps_id);
+        if ![66, 77, 88].contains(&self.profile_idc) {
+            writer.write_ue(self.chroma_format_idc);
+            writer.write_ue(0); // bit_depth_luma_minus8
+            writer.write_ue(0); // bit_depth_chroma_minus8
+            writer.write_flag(false); // qpprime_y_zero_transform_bypass_flag
+            writer.write_flag(self.seq_scaling_matrix_present_flag);
+            if self.seq_scaling_matrix_present_flag {
+                for _ in 0..8 {
+                    writer.write_flag(false); // seq_scaling_list_present_flag
+                }
+            }
+        }
+        writer.write_ue(0); // log2_max_frame_num_minus4
+        writer.write_ue(self.pic_order_cnt_type);
+        if self.pic_order_cnt_type == 0 {
+            writer.write_ue(0); // log2_max_pic_order_cnt_lsb_minus4
+        }
+        writer.write_ue(1); // max_num_ref_frames
+        writer.write_flag(false); // gaps_in_frame_num_value_allowed_flag
+        writer.write_ue(self.width_in_mbs - 1);
+        writer.write_ue(self.height_in_map_units - 1);
+        writer.write_flag(self.mbaff.is_none()); // frame_mbs_only_flag
+        if let Some(mbaff) = self.mbaff {
+            writer.write_flag(mbaff);
+        }
+        writer.write_flag(true); // direct_8x8_inference_flag
+        writer.write_flag(false); // frame_cropping_flag
+        writer.write_flag(self.pic_struct_present_flag); // vui_parameters_present_flag
+        if self.pic_struct_present_flag {
+            // aspect_ratio_info, overscan_info, video_signal_type, chroma_loc_info, timing_info,
+            // nal_hrd_parameters and vcl_hrd_parameters present flags
+            for _ in 0..7 {
+                writer.write_flag(false);
+            }
+            writer.write_flag(true); // pic_struct_present_flag
+            writer.write_flag(false); // bitstream_restriction_flag
+        }
+        writer.write_rbsp_trailing_bits();
+        writer.into_bytes()
+    }
+}
+
+/// A CAVLC PPS RBSP with a single slice group and every optional tool disabled
+pub(crate) fn pps_rbsp(pps_id: u32, sps_id: u32) -> Vec<u8> {
+    write_pps(pps_id, sps_id, false)
+}
+
+/// `pps_rbsp` with `constrained_intra_pred_flag` set
+pub(crate) fn constrained_intra_pps_rbsp(pps_id: u32, sps_id: u32) -> Vec<u8> {
+    write_pps(pps_id, sps_id, true)
+}
+
+fn write_pps(pps_id: u32, sps_id: u32, constrained_intra_pred_flag: bool) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    writer.write_ue(pps_id);
+    writer.write_ue(sps_id);
+    writer.write_flag(false); // entropy_coding_mode_flag
+    writer.write_flag(false); // bottom_field_pic_order_in_frame_present_flag
+    writer.write_ue(0); // num_slice_groups_minus1
+    writer.write_ue(0); // num_ref_idx_l0_default_active_minus1
+    writer.write_ue(0); // num_ref_idx_l1_default_active_minus1
+    writer.write_flag(false); // weighted_pred_flag
+    writer.write(0, 2); // weighted_bipred_idc
+    writer.write_se(0); // pic_init_qp_minus26
+    writer.write_se(0); // pic_init_qs_minus26
+    writer.write_se(0); // chroma_qp_index_offset
+    writer.write_flag(true); // deblocking_filter_control_present_flag
+    writer.write_flag(constrained_intra_pred_flag);
+    writer.write_flag(false); // redundant_pic_cnt_present_flag
+    writer.write_rbsp_trailing_bits();
+    writer.into_bytes()
+}
+
+/// An IDR I slice RBSP for `pps_rbsp(0, 0)`, with `write_mbs` writing its macroblocks
+pub(crate) fn idr_slice_rbsp(write_mbs: impl FnOnce(&mut BitWriter)) -> Vec<u8> {
+    idr_slice_rbsp_at(0, write_mbs)
+}
+
+/// `idr_slice_rbsp` for a slice that starts at macroblock `first_mb`
+pub(crate) fn idr_slice_rbsp_at(first_mb: u32, write_mbs: impl FnOnce(&mut BitWriter)) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    writer.write_ue(first_mb); // first_mb_in_slice
+    writer.write_ue(7); // slice_type, I
+    writer.write_ue(0); // pic_parameter_set_id
+    writer.write(0, 4); // frame_num
+    writer.write_ue(0); // idr_pic_id
+    writer.write_flag(false); // no_output_of_prior_pics_flag
+    writer.write_flag(false); // long_term_reference_flag
+    writer.write_se(0); // slice_qp_delta
+    writer.write_ue(1); // disable_deblocking_filter_idc
+    write_mbs(&mut writer);
+    writer.write_rbsp_trailing_bits();
+    writer.into_bytes()
+}
+
+/// An IDR slice whose `num_mbs` macroblocks are all I_PCM with every sample set to `sample`
+pub(crate) fn pcm_idr_slice_rbsp(num_mbs: usize, sample: u8) -> Vec<u8> {
+    pcm_idr_slice_rbsp_at(0, num_mbs, sample)
+}
+
+/// `pcm_idr_slice_rbsp` for a slice that starts at macroblock `first_mb`
+pub(crate) fn pcm_idr_slice_rbsp_at(first_mb: u32, num_mbs: usize, sample: u8) -> Vec<u8> {
+    idr_slice_rbsp_at(first_mb, |writer| {
+        for _ in 0..num_mbs {
+            writer.write_ue(25); // mb_type, I_PCM
+            while !writer.byte_aligned() {
+                writer.write_flag(false);
+            }
+            for _ in 0..384 {
+                writer.write(sample as u32, 8);
+            }
+        }
+    })
+}
+
+/// A non-IDR reference I slice RBSP for `pps_rbsp(0, 0)`, holding a single I_PCM macroblock with
+/// every sample set to `sample`
+pub(crate) fn pcm_slice_rbsp(frame_num: u32, sample: u8) -> Vec<u8> {
+    pcm_slice_rbsp_at(0, frame_num, sample)
+}
+
+/// `pcm_slice_rbsp` for a slice that starts at macroblock `first_mb`
+pub(crate) fn pcm_slice_rbsp_at(first_mb: u32, frame_num: u32, sample: u8) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    writer.write_ue(first_mb); // first_mb_in_slice
+    writer.write_ue(7); // slice_type, I
+    writer.write_ue(0); // pic_parameter_set_id
+    writer.write(frame_num, 4);
+    writer.write_flag(false); // adaptive_ref_pic_marking_mode_flag
+    writer.write_se(0); // slice_qp_delta
+    writer.write_ue(1); // disable_deblocking_filter_idc
+    writer.write_ue(25); // mb_type, I_PCM
+    while !writer.byte_aligned() {
+        writer.write_flag(false);
+    }
+    for _ in 0..384 {
+        writer.write(sample as u32, 8);
+    }
+    writer.write_rbsp_trailing_bits();
+    writer.into_bytes()
+}
+
+/// A reference I slice RBSP for `pps_rbsp(0, 0)` under `poc_sps_rbsp`, holding a single I_PCM
+/// macroblock with every sample set to `sample`. `no_output_of_prior_pics_flag` is only written
+/// for IDR slices.
+pub(crate) fn pcm_poc_slice_rbsp(
+    idr: bool,
+    frame_num: u32,
+    pic_order_cnt_lsb: u32,
+    no_output_of_prior_pics_flag: bool,
+    sample: u8,
+) -> Vec<u8> {
+    write_pcm_poc_slice(
+        idr,
+        frame_num,
+        pic_order_cnt_lsb,
+        no_output_of_prior_pics_flag,
+        false,
+        sample,
+    )
+}
+
+/// A non-IDR `pcm_poc_slice_rbsp` whose dec_ref_pic_marking holds
+/// memory_management_control_operation 5
+pub(crate) fn pcm_mmco5_slice_rbsp(frame_num: u32, pic_order_cnt_lsb: u32, sample: u8) -> Vec<u8> {
+    write_pcm_poc_slice(false, frame_num, pic_order_cnt_lsb, false, true, sample)
+}
+
+fn write_pcm_poc_slice(
+    idr: bool,
+    frame_num: u32,
+    pic_order_cnt_lsb: u32,
+    no_output_of_prior_pics_flag: bool,
+    mmco5: bool,
+    sample: u8,
+) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    writer.write_ue(0); // first_mb_in_slice
+    writer.write_ue(7); // slice_type, I
+    writer.write_ue(0); // pic_parameter_set_id
+    writer.write(frame_num, 4);
+    if idr {
+        writer.write_ue(0); // idr_pic_id
+    }
+    writer.write(pic_order_cnt_lsb, 4);
+    if idr {
+        writer.write_flag(no_output_of_prior_pics_flag);
+        writer.write_flag(false); // long_term_reference_flag
+    } else if mmco5 {
+        writer.write_flag(true); // adaptive_ref_pic_marking_mode_flag
+        writer.write_ue(5); // memory_management_control_operation
+        writer.write_ue(0); // memory_management_control_operation, end of the list
+    } else {
+        writer.write_flag(false); // adaptive_ref_pic_marking_mode_flag
+    }
+    writer.write_se(0); // slice_qp_delta
+    writer.write_ue(1); // disable_deblocking_filter_idc
+    writer.write_ue(25); // mb_type, I_PCM
+    while !writer.byte_aligned() {
+        writer.write_flag(false);
+    }
+    for _ in 0..384 {
+        writer.write(sample as u32, 8);
+    }
+    writer.write_rbsp_trailing_bits();
+    writer.into_bytes()
+}
+
+/// Writes an Intra_16x16 DC predicted macroblock whose only coefficient is a luma DC level of 1,
+/// which adds 1 to every luma sample at QP 26. Assumes nC is 0 for the DC block.
+pub(crate) fn write_i16x16_dc_mb(writer: &mut BitWriter) {
+    writer.write_ue(3); // mb_type, I_16x16_2_0_0
+    writer.write_ue(0); // intra_chroma_pred_mode, DC
+    writer.write_se(0); // mb_qp_delta
+    writer.write(0b01, 2); // coeff_token, TotalCoeff 1 and TrailingOnes 1
+    writer.write_flag(false); // trailing_ones_sign_flag
+    writer.write(0b1, 1); // total_zeros 0
+}
+
+/// Writes an Intra_4x4 macroblock that keeps every predicted mode and has no residual
+pub(crate) fn write_i4x4_mb(writer: &mut BitWriter) {
+    writer.write_ue(0); // mb_type, I_NxN
+    for _ in 0..16 {
+        writer.write_flag(true); // prev_intra4x4_pred_mode_flag
+    }
+    writer.write_ue(0); // intra_chroma_pred_mode, DC
+    writer.write_ue(3); // coded_block_pattern 0
+}
+
+/// An IDR slice of `num_mbs` macroblocks written by `write_i16x16_dc_mb`
+pub(crate) fn i16x16_idr_slice_rbsp(num_mbs: usize) -> Vec<u8> {
+    idr_slice_rbsp(|writer| {
+        for _ in 0..num_mbs {
+            write_i16x16_dc_mb(writer);
+        }
+    })
+}
+
+/// A P slice RBSP for `pps_rbsp(0, 0)`, with `write_mbs` writing its slice data
+pub(crate) fn p_slice_rbsp(frame_num: u32, write_mbs: impl FnOnce(&mut BitWriter)) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    writer.write_ue(0); // first_mb_in_slice
+    writer.write_ue(5); // slice_type, P
+    writer.write_ue(0); // pic_parameter_set_id
+    writer.write(frame_num, 4);
+    writer.write_flag(false); // num_ref_idx_active_override_flag
+    writer.write_flag(false); // ref_pic_list_modification_flag_l0
+    writer.write_flag(false); // adaptive_ref_pic_marking_mode_flag
+    writer.write_se(0); // slice_qp_delta
+    writer.write_ue(1); // disable_deblocking_filter_idc
+    write_mbs(&mut writer);
+    writer.write_rbsp_trailing_bits();
+    writer.into_bytes()
+}
+
+/// A P slice RBSP for `pps_rbsp(0, 0)` that skips all `num_mbs` macroblocks
+pub(crate) fn p_skip_slice_rbsp(frame_num: u32, num_mbs: u32) -> Vec<u8> {
+    p_slice_rbsp(frame_num, |writer| writer.write_ue(num_mbs)) // mb_skip_run
+}
+
+/// Counts the heap allocations made by the current thread while `counting` is set, so tests can
+/// check that a code path doesn't allocate
+pub(crate) mod allocations {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    struct CountingAllocator;
+
+    thread_local! {
+        static COUNTING: Cell<bool> = const { Cell::new(false) };
+        static COUNT: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn record() {
+        if COUNTING.try_with(Cell::get).unwrap_or(false) {
+            let _ = COUNT.try_with(|c| c.set(c.get() + 1));
+        }
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            record();
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) }
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            record();
+            unsafe { System.realloc(ptr, layout, new_size) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Runs `f` and returns how many allocations it made on this thread
+    pub(crate) fn count(f: impl FnOnce()) -> usize {
+        COUNT.with(|c| c.set(0));
+        COUNTING.with(|c| c.set(true));
+        f();
+        COUNTING.with(|c| c.set(false));
+        COUNT.with(Cell::get)
+    }
+}
@@ -1 +1,201 @@
+//! Scaling and transform decoding of residual blocks (8.5)
 
+use parser::ScalingMatrix;
+
+/// Raster position of each coefficient of a 4x4 block in frame zig-zag scanning order (Table 8-13)
+pub(crate) const ZIGZAG_4X4: [usize; 16] = [0, 1, 4, 8, 5, 2, 3, 6, 9, 12, 13, 10, 7, 11, 14, 15];
+
+/// normAdjust4x4 values for each qP % 6 (8-315)
+const NORM_ADJUST_4X4: [[i32; 3]; 6] = [
+    [10, 16, 13],
+    [11, 18, 14],
+    [13, 20, 16],
+    [14, 23, 18],
+    [16, 25, 20],
+    [18, 29, 23],
+];
+
+/// QPC as a function of qPI, for qPI >= 30 (Table 8-15)
+const CHROMA_QP: [i32; 22] = [
+    29, 30, 31, 32, 32, 33, 34, 34, 35, 35, 36, 36, 37, 37, 37, 38, 38, 38, 39, 39, 39, 39,
+];
+
+/// Derives QPC from QPY and the PPS chroma offset, as in 8.5.8 for 8-bit video
+pub(crate) fn chroma_qp(qp: i32, chroma_qp_index_offset: i32) -> i32 {
+    let qpi = (qp + chroma_qp_index_offset).clamp(0, 51);
+    if qpi < 30 {
+        qpi
+    } else {
+        CHROMA_QP[qpi as usize - 30]
+    }
+}
+
+/// Converts the zig-zag ordered 4x4 lists of a scaling matrix to raster ordered weightScale4x4
+/// matrices (8.5.6)
+pub(crate) fn weight_scales_4x4(matrix: &ScalingMatrix) -> [[i32; 16]; 6] {
+    let mut weights = [[0; 16]; 6];
+    for (weights, list) in weights.iter_mut().zip(&matrix.lists_4x4) {
+        for (k, &weight) in list.iter().enumerate() {
+            weights[ZIGZAG_4X4[k]] = weight as i32;
+        }
+    }
+    weights
+}
+
+/// LevelScale4x4 (8-315) at raster position `index`, given a raster ordered weightScale4x4
+fn level_scale(qp_rem: usize, index: usize, weights: &[i32; 16]) -> i32 {
+    let (row, col) = (index / 4, index % 4);
+    let norm = match (row % 2, col % 2) {
+        (0, 0) => NORM_ADJUST_4X4[qp_rem][0],
+        (1, 1) => NORM_ADJUST_4X4[qp_rem][1],
+        _ => NORM_ADJUST_4X4[qp_rem][2],
+    };
+    weights[index] * norm
+}
+
+/// 8.5.12.1 Scaling process for residual 4x4 blocks. With `has_dc` unset, the DC coefficient has
+/// already been scaled by the Intra16x16 or chroma DC transform and is left untouched.
+pub(crate) fn dequantize_4x4(coeffs: &mut [i32; 16], qp: i32, has_dc: bool, weights: &[i32; 16]) {
+    let (qp_div, qp_rem) = ((qp / 6) as u32, (qp % 6) as usize);
+    let start = if has_dc { 0 } else { 1 };
+
+    for (i, c) in coeffs.iter_mut().enumerate().skip(start) {
+        if *c == 0 {
+            continue;
+        }
+        let scaled = *c * level_scale(qp_rem, i, weights);
+        *c = if qp_div >= 4 {
+            scaled << (qp_div - 4)
+        } else {
+            (scaled + (1 << (3 - qp_div))) >> (4 - qp_div)
+        };
+    }
+}
+
+/// 8.5.12.2 Transformation process for residual 4x4 blocks. `block` holds raster ordered scaled
+/// coefficients and is replaced by the residual samples.
+pub(crate) fn inverse_transform_4x4(block: &mut [i32; 16]) {
+    for row in block.chunks_exact_mut(4) {
+        let e0 = row[0] + row[2];
+        let e1 = row[0] - row[2];
+        let e2 = (row[1] >> 1) - row[3];
+        let e3 = row[1] + (row[3] >> 1);
+        row[0] = e0 + e3;
+        row[1] = e1 + e2;
+        row[2] = e1 - e2;
+        row[3] = e0 - e3;
+    }
+
+    for col in 0..4 {
+        let e0 = block[col] + block[8 + col];
+        let e1 = block[col] - block[8 + col];
+        let e2 = (block[4 + col] >> 1) - block[12 + col];
+        let e3 = block[4 + col] + (block[12 + col] >> 1);
+        block[col] = (e0 + e3 + 32) >> 6;
+        block[4 + col] = (e1 + e2 + 32) >> 6;
+        block[8 + col] = (e1 - e2 + 32) >> 6;
+        block[12 + col] = (e0 - e3 + 32) >> 6;
+    }
+}
+
+/// 8.5.10 Scaling and transformation process for DC transform coefficients for Intra_16x16
+/// macroblock types. `dc` is the raster ordered 4x4 matrix c, replaced by dcY.
+pub(crate) fn inverse_luma_dc(dc: &mut [i32; 16], qp: i32, weights: &[i32; 16]) {
+    let mut f = [0i32; 16];
+    for i in 0..4 {
+        let (a, b, c, d) = (dc[i * 4], dc[i * 4 + 1], dc[i * 4 + 2], dc[i * 4 + 3]);
+        f[i * 4] = a + b + c + d;
+        f[i * 4 + 1] = a + b - c - d;
+        f[i * 4 + 2] = a - b - c + d;
+        f[i * 4 + 3] = a - b + c - d;
+    }
+    for j in 0..4 {
+        let (a, b, c, d) = (f[j], f[4 + j], f[8 + j], f[12 + j]);
+        f[j] = a + b + c + d;
+        f[4 + j] = a + b - c - d;
+        f[8 + j] = a - b - c + d;
+        f[12 + j] = a - b + c - d;
+    }
+
+    let scale = level_scale((qp % 6) as usize, 0, weights);
+    let qp_div = (qp / 6) as u32;
+    for (out, f) in dc.iter_mut().zip(f) {
+        *out = if qp >= 36 {
+            (f * scale) << (qp_div - 6)
+        } else {
+            (f * scale + (1 << (5 - qp_div))) >> (6 - qp_div)
+        };
+    }
+}
+
+/// 8.5.11.2 for 4:2:0 chroma DC: the 2x2 transform of `dc` (raster ordered c), replaced by dcC
+pub(crate) fn inverse_chroma_dc_420(dc: &mut [i32; 4], qp: i32, weights: &[i32; 16]) {
+    let (a, b, c, d) = (dc[0], dc[1], dc[2], dc[3]);
+    let f = [a + b + c + d, a - b + c - d, a + b - c - d, a - b - c + d];
+
+    let scale = level_scale((qp % 6) as usize, 0, weights);
+    for (out, f) in dc.iter_mut().zip(f) {
+        *out = ((f * scale) << (qp / 6)) >> 5;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLAT: [i32; 16] = [16; 16];
+
+    #[test]
+    fn test_dc_only_block_is_flat() {
+        let mut block = [0; 16];
+        block[0] = 64;
+        inverse_transform_4x4(&mut block);
+        assert!(block.iter().all(|&r| r == 1));
+    }
+
+    #[test]
+    fn test_dequantize_4x4() {
+        let mut coeffs = [0; 16];
+        coeffs[0] = 1;
+        coeffs[1] = 1;
+        coeffs[5] = -2;
+        // qP 28: qP / 6 = 4, so the scaled values are not shifted
+        dequantize_4x4(&mut coeffs, 28, true, &FLAT);
+        assert_eq!(coeffs[0], 16 * 16);
+        assert_eq!(coeffs[1], 16 * 20);
+        assert_eq!(coeffs[5], -2 * 16 * 25);
+
+        let mut coeffs = [7; 16];
+        dequantize_4x4(&mut coeffs, 28, false, &FLAT);
+        assert_eq!(coeffs[0], 7);
+    }
+
+    #[test]
+    fn test_chroma_qp_mapping() {
+        assert_eq!(chroma_qp(20, 0), 20);
+        assert_eq!(chroma_qp(30, 0), 29);
+        assert_eq!(chroma_qp(51, 0), 39);
+        assert_eq!(chroma_qp(49, 4), 39);
+        assert_eq!(chroma_qp(2, -6), 0);
+    }
+
+    #[test]
+    fn test_luma_dc_round_trip_of_single_coefficient() {
+        let mut dc = [0; 16];
+        dc[0] = 1;
+        inverse_luma_dc(&mut dc, 36, &FLAT);
+        // f is 1 everywhere, scaled by LevelScale4x4(0, 0, 0) = 160
+        assert!(dc.iter().all(|&v| v == 160));
+    }
+
+    #[test]
+    fn test_weight_scales_are_raster_ordered() {
+        let mut matrix = ScalingMatrix::default();
+        matrix.lists_4x4[3] = parser::scaling::default_4x4(false);
+        let weights = weight_scales_4x4(&matrix);
+
+        assert_eq!(weights[0], FLAT);
+        // Zig-zag index 2 is raster position 4
+        assert_eq!(&weights[3][..5], &[10, 14, 20, 24, 14]);
+    }
+}
@@ -0,0 +1,55 @@
+use crate::slice::SliceType;
+use anyhow::Result;
+use bitstream::BitReader;
+
+/// Implemented as in 7.3.2.4 Access unit delimiter RBSP syntax
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AccessUnitDelimiter {
+    pub primary_pic_type: u8,
+}
+
+impl AccessUnitDelimiter {
+    pub fn parse(reader: &mut BitReader) -> Result<Self> {
+        Ok(Self {
+            primary_pic_type: reader.read(3)? as u8,
+        })
+    }
+
+    /// The slice types the primary picture of the access unit may contain (Table 7-5)
+    pub fn slice_types(&self) -> &'static [SliceType] {
+        use SliceType::*;
+        match self.primary_pic_type {
+            0 => &[I],
+            1 => &[I, P],
+            2 => &[I, P, B],
+            3 => &[SI],
+            4 => &[SI, SP],
+            5 => &[I, SI],
+            6 => &[I, SI, P, SP],
+            _ => &[I, SI, P, SP, B],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_access_unit_delimiter() -> Result<()> {
+        // primary_pic_type 2, then rbsp_trailing_bits
+        let aud = AccessUnitDelimiter::parse(&mut BitReader::from_bytes(&[0b0101_0000]))?;
+        assert_eq!(aud.primary_pic_type, 2);
+        assert_eq!(
+            aud.slice_types(),
+            [SliceType::I, SliceType::P, SliceType::B]
+        );
+
+        let aud = AccessUnitDelimiter {
+            primary_pic_type: 7,
+        };
+        assert!(aud.slice_types().contains(&SliceType::SP));
+        assert!(AccessUnitDelimiter::parse(&mut BitReader::from_bytes(&[])).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+use anyhow::Result;
+use bitstream::{BitReader, BitWriter};
+
+/// Bits a parameter set carries between its last known syntax element and its
+/// rbsp_trailing_bits, such as vendor extensions. They're kept so the set can be written back
+/// unchanged.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ExtensionData {
+    /// The bits, packed from the most significant bit of the first byte
+    pub data: Vec<u8>,
+    /// Number of bits, as the last byte may only be partly used
+    pub bits: usize,
+}
+
+impl ExtensionData {
+    /// Reads everything up to the rbsp_stop_one_bit
+    pub(crate) fn read(reader: &mut BitReader) -> Result<Self> {
+        let mut extension = Self::default();
+        while reader.more_rbsp_data() {
+            let bit = reader.read_flag()?;
+            if extension.bits % 8 == 0 {
+                extension.data.push(0);
+            }
+            if let Some(last) = extension.data.last_mut() {
+                *last |= (bit as u8) << (7 - extension.bits % 8);
+            }
+            extension.bits += 1;
+        }
+        Ok(extension)
+    }
+
+    pub(crate) fn write(&self, writer: &mut BitWriter) {
+        for (i, &byte) in self.data.iter().enumerate() {
+            let bits = (self.bits - i * 8).min(8);
+            writer.write(byte as u32 >> (8 - bits), bits);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_stops_at_the_stop_bit() -> Result<()> {
+        // 3 bits of the last field, 10 extension bits, then the stop bit and alignment
+        let data = [0b1011_0110, 0b0110_0100];
+        let mut reader = BitReader::from_bytes(&data);
+        reader.read(3)?;
+        let extension = ExtensionData::read(&mut reader)?;
+        assert_eq!(extension.bits, 10);
+        assert_eq!(extension.data, [0b1011_0011, 0b0000_0000]);
+        reader.read_rbsp_trailing_bits()?;
+
+        let mut writer = BitWriter::new();
+        writer.write(0b101, 3);
+        extension.write(&mut writer);
+        writer.write_rbsp_trailing_bits();
+        assert_eq!(writer.into_bytes(), data);
+        Ok(())
+    }
+}
@@ -1,5 +1,57 @@
-mod nalu;
-mod pps;
-mod slice;
-mod sps;
+pub mod aud;
+pub mod extension;
+pub mod nalu;
+pub mod pps;
+pub mod scaling;
+pub mod sei;
+pub mod slice;
+pub mod sps;
+pub mod store;
+#[cfg(test)]
 mod tests;
+pub mod vui;
+
+pub use aud::AccessUnitDelimiter;
+pub use extension::ExtensionData;
+pub use nalu::{Nalu, NaluExtension, parse_nalu};
+pub use pps::Pps;
+pub use scaling::ScalingMatrix;
+pub use sei::{PicTiming, RecoveryPoint, SeiMessage, parse_sei};
+pub use slice::{SliceHeader, SliceType, is_new_picture};
+pub use sps::Sps;
+pub use store::{ActiveParams, ParameterSetStore, collect_parameter_sets};
+
+use anyhow::{Result, anyhow};
+use bitstream::{BitReader, BitReaderError};
+
+/// `BitReader::read_ue_max`, naming `field` when the value is out of range
+pub(crate) fn read_ue_max(reader: &mut BitReader, max: u32, field: &str) -> Result<u32> {
+    match reader.read_ue_max(max) {
+        Err(BitReaderError::AboveMaximum { value, max }) => Err(anyhow!(
+            "Invalid {}: {}, expected at most {}",
+            field,
+            value,
+            max
+        )),
+        result => Ok(result?),
+    }
+}
+
+/// `BitReader::read_se_bounded`, naming `field` when the value is out of range
+pub(crate) fn read_se_bounded(
+    reader: &mut BitReader,
+    min: i32,
+    max: i32,
+    field: &str,
+) -> Result<i32> {
+    match reader.read_se_bounded(min, max) {
+        Err(BitReaderError::OutOfRange { value, min, max }) => Err(anyhow!(
+            "Invalid {}: {}, expected {}..={}",
+            field,
+            value,
+            min,
+            max
+        )),
+        result => Ok(result?),
+    }
+}
@@ -1 +1,203 @@
+use anyhow::{Result, anyhow};
+use bitstream::rbsp::ebsp_to_rbsp;
+use bitstream::{BitReader, NalUnitType, NaluHeader};
 
+/// A NALU split into its header and its RBSP payload (emulation prevention bytes removed)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nalu {
+    pub header: NaluHeader,
+    /// Present for prefix NALUs and slice extensions (types 14, 20 and 21)
+    pub extension: Option<NaluExtension>,
+    pub rbsp: Vec<u8>,
+}
+
+/// The extra NALU header bytes of the scalable, multiview and 3D-AVC extensions (7.3.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NaluExtension {
+    Svc(SvcExtension),
+    Mvc(MvcExtension),
+    Avc3d(Avc3dExtension),
+}
+
+/// Implemented as in G.7.3.1.1 NAL unit header SVC extension syntax
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SvcExtension {
+    pub idr_flag: bool,
+    pub priority_id: u8,
+    pub no_inter_layer_pred_flag: bool,
+    pub dependency_id: u8,
+    pub quality_id: u8,
+    pub temporal_id: u8,
+    pub use_ref_base_pic_flag: bool,
+    pub discardable_flag: bool,
+    pub output_flag: bool,
+}
+
+/// Implemented as in H.7.3.1.1 NAL unit header MVC extension syntax
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MvcExtension {
+    pub non_idr_flag: bool,
+    pub priority_id: u8,
+    pub view_id: u16,
+    pub temporal_id: u8,
+    pub anchor_pic_flag: bool,
+    pub inter_view_flag: bool,
+}
+
+/// Implemented as in J.7.3.1.1 NAL unit header 3D-AVC extension syntax
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Avc3dExtension {
+    pub view_idx: u8,
+    pub depth_flag: bool,
+    pub non_idr_flag: bool,
+    pub temporal_id: u8,
+    pub anchor_pic_flag: bool,
+    pub inter_view_flag: bool,
+}
+
+impl NaluExtension {
+    /// Parses the extension header, returning it along with the number of bytes it took
+    fn parse(kind: NalUnitType, data: &[u8]) -> Result<(Self, usize)> {
+        let mut reader = BitReader::from_bytes(data);
+        let flag = reader.read_flag()?;
+
+        if kind == NalUnitType::SliceExtensionDepth && flag {
+            let extension = Avc3dExtension {
+                view_idx: reader.read(8)? as u8,
+                depth_flag: reader.read_flag()?,
+                non_idr_flag: reader.read_flag()?,
+                temporal_id: reader.read(3)? as u8,
+                anchor_pic_flag: reader.read_flag()?,
+                inter_view_flag: reader.read_flag()?,
+            };
+            return Ok((NaluExtension::Avc3d(extension), 2));
+        }
+
+        // svc_extension_flag for types 14 and 20; type 21 without the 3D-AVC flag uses MVC
+        let extension = if flag && kind != NalUnitType::SliceExtensionDepth {
+            let svc = SvcExtension {
+                idr_flag: reader.read_flag()?,
+                priority_id: reader.read(6)? as u8,
+                no_inter_layer_pred_flag: reader.read_flag()?,
+                dependency_id: reader.read(3)? as u8,
+                quality_id: reader.read(4)? as u8,
+                temporal_id: reader.read(3)? as u8,
+                use_ref_base_pic_flag: reader.read_flag()?,
+                discardable_flag: reader.read_flag()?,
+                output_flag: reader.read_flag()?,
+            };
+            reader.read(2)?; // reserved_three_2bits
+            NaluExtension::Svc(svc)
+        } else {
+            let mvc = MvcExtension {
+                non_idr_flag: reader.read_flag()?,
+                priority_id: reader.read(6)? as u8,
+                view_id: reader.read(10)? as u16,
+                temporal_id: reader.read(3)? as u8,
+                anchor_pic_flag: reader.read_flag()?,
+                inter_view_flag: reader.read_flag()?,
+            };
+            reader.read(1)?; // reserved_one_bit
+            NaluExtension::Mvc(mvc)
+        };
+        Ok((extension, 3))
+    }
+}
+
+/// Parses a single NALU, without its start code or length prefix
+pub fn parse_nalu(data: &[u8]) -> Result<Nalu> {
+    let (&first, mut payload) = data
+        .split_first()
+        .ok_or_else(|| anyhow!("Cannot parse an empty NALU"))?;
+    let header = NaluHeader::new(first)?;
+
+    // The extension bytes count towards the NALU header, so they aren't emulation prevented
+    let extension = match header.kind() {
+        kind @ (NalUnitType::PrefixNalu
+        | NalUnitType::SliceExtension
+        | NalUnitType::SliceExtensionDepth) => {
+            let (extension, len) = NaluExtension::parse(kind, payload).map_err(|_| {
+                anyhow!(
+                    "NALU type {} is missing its extension header",
+                    header.nal_unit_type
+                )
+            })?;
+            payload = &payload[len..];
+            Some(extension)
+        }
+        _ => None,
+    };
+
+    Ok(Nalu {
+        header,
+        extension,
+        rbsp: ebsp_to_rbsp(payload),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitstream::NalUnitType;
+
+    #[test]
+    fn test_parse_nalu_strips_emulation() -> Result<()> {
+        let nalu = parse_nalu(&[0x68, 0xCE, 0x00, 0x00, 0x03, 0x01])?;
+        assert_eq!(nalu.header.kind(), NalUnitType::Pps);
+        assert_eq!(nalu.header.nal_ref_idc, 3);
+        assert_eq!(nalu.rbsp, &[0xCE, 0x00, 0x00, 0x01]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_empty_and_header_only_nalus() -> Result<()> {
+        assert!(parse_nalu(&[]).is_err());
+
+        let nalu = parse_nalu(&[0x65])?;
+        assert_eq!(nalu.header.kind(), NalUnitType::IdrSlice);
+        assert!(nalu.rbsp.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_prefix_nalu_svc_extension() -> Result<()> {
+        // svc_extension_flag = 1, idr_flag = 1, priority_id = 5, no_inter_layer_pred_flag = 1,
+        // dependency_id = 2, quality_id = 3, temporal_id = 4, use_ref_base_pic_flag = 0,
+        // discardable_flag = 1, output_flag = 1, reserved_three_2bits = 3
+        let nalu = parse_nalu(&[0x6E, 0xC5, 0xA3, 0x8F, 0xAB])?;
+        assert_eq!(nalu.header.kind(), NalUnitType::PrefixNalu);
+        assert_eq!(
+            nalu.extension,
+            Some(NaluExtension::Svc(SvcExtension {
+                idr_flag: true,
+                priority_id: 5,
+                no_inter_layer_pred_flag: true,
+                dependency_id: 2,
+                quality_id: 3,
+                temporal_id: 4,
+                use_ref_base_pic_flag: false,
+                discardable_flag: true,
+                output_flag: true,
+            }))
+        );
+        assert_eq!(nalu.rbsp, &[0xAB]);
+
+        assert!(parse_nalu(&[0x6E, 0xC5]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_slice_extension_mvc_header() -> Result<()> {
+        // svc_extension_flag = 0, non_idr_flag = 1, priority_id = 0, view_id = 1,
+        // temporal_id = 0, anchor_pic_flag = 1, inter_view_flag = 1, reserved_one_bit = 1
+        let nalu = parse_nalu(&[0x74, 0x40, 0x00, 0x47])?;
+        let Some(NaluExtension::Mvc(mvc)) = nalu.extension else {
+            panic!("Expected an MVC extension, got {:?}", nalu.extension);
+        };
+        assert!(mvc.non_idr_flag);
+        assert_eq!(mvc.view_id, 1);
+        assert!(mvc.anchor_pic_flag && mvc.inter_view_flag);
+        assert!(nalu.rbsp.is_empty());
+        Ok(())
+    }
+}
@@ -1 +1,542 @@
+use crate::extension::ExtensionData;
+use crate::read_ue_max;
+use crate::scaling::{ScalingList, parse_scaling_lists, write_scaling_lists};
+use crate::sps::Sps;
+use crate::store::ParameterSetStore;
+use anyhow::{Result, anyhow};
+use bitstream::{BitReader, BitWriter};
 
+/// Implemented as in 7.3.2.2 Picture parameter set RBSP syntax in Rec. ITU-T H.264 (04/2013)
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Pps {
+    pub pic_parameter_set_id: u32,
+    pub seq_parameter_set_id: u32,
+    pub entropy_coding_mode_flag: bool,
+    pub bottom_field_pic_order_in_frame_present_flag: bool,
+    pub num_slice_groups_minus1: u32,
+    pub slice_group_map_type: u32,
+    /// Per slice group, map type 0
+    pub run_length_minus1: Vec<u32>,
+    /// Per slice group except the last, map type 2
+    pub top_left: Vec<u32>,
+    pub bottom_right: Vec<u32>,
+    /// Map types 3 to 5
+    pub slice_group_change_direction_flag: bool,
+    pub slice_group_change_rate_minus1: u32,
+    /// Map type 6
+    pub pic_size_in_map_units_minus1: u32,
+    pub slice_group_id: Vec<u32>,
+    pub num_ref_idx_l0_default_active_minus1: u32,
+    pub num_ref_idx_l1_default_active_minus1: u32,
+    pub weighted_pred_flag: bool,
+    pub weighted_bipred_idc: u32,
+    pub pic_init_qp_minus26: i32,
+    pub pic_init_qs_minus26: i32,
+    pub chroma_qp_index_offset: i32,
+    pub deblocking_filter_control_present_flag: bool,
+    pub constrained_intra_pred_flag: bool,
+    pub redundant_pic_cnt_present_flag: bool,
+    pub transform_8x8_mode_flag: bool,
+    pub pic_scaling_matrix_present_flag: bool,
+    /// Empty unless `pic_scaling_matrix_present_flag` is set
+    pub pic_scaling_lists: Vec<ScalingList>,
+    /// Equal to `chroma_qp_index_offset` when not present
+    pub second_chroma_qp_index_offset: i32,
+    /// Anything between second_chroma_qp_index_offset and rbsp_trailing_bits, kept for `write`
+    pub extension_data: ExtensionData,
+}
+
+impl Pps {
+    /// Parses a PPS from its RBSP, positioned just after the NALU header. The referenced SPS is only
+    /// looked up in `store` when the PPS carries a scaling matrix, which depends on its chroma format.
+    pub fn parse(reader: &mut BitReader, store: &ParameterSetStore) -> Result<Self> {
+        // The mandatory fields up to redundant_pic_cnt_present_flag take at least 16 bits
+        if !reader.has_bits(16) {
+            return Err(anyhow!(
+                "PPS is truncated: too short for its mandatory fields, from pic_parameter_set_id \
+                 to redundant_pic_cnt_present_flag"
+            ));
+        }
+
+        let mut pps = Pps {
+            pic_parameter_set_id: read_ue_max(reader, 255, "pic_parameter_set_id in PPS")?,
+            seq_parameter_set_id: read_ue_max(reader, 31, "seq_parameter_set_id in PPS")?,
+            entropy_coding_mode_flag: reader.read_flag()?,
+            bottom_field_pic_order_in_frame_present_flag: reader.read_flag()?,
+            num_slice_groups_minus1: reader.read_ue()?,
+            ..Default::default()
+        };
+
+        if pps.num_slice_groups_minus1 > 7 {
+            return Err(anyhow!(
+                "Invalid num_slice_groups_minus1 in PPS: {}",
+                pps.num_slice_groups_minus1
+            ));
+        }
+
+        if pps.num_slice_groups_minus1 > 0 {
+            pps.parse_slice_groups(reader)?;
+        }
+
+        pps.num_ref_idx_l0_default_active_minus1 =
+            read_ue_max(reader, 31, "num_ref_idx_l0_default_active_minus1 in PPS")?;
+        pps.num_ref_idx_l1_default_active_minus1 =
+            read_ue_max(reader, 31, "num_ref_idx_l1_default_active_minus1 in PPS")?;
+        pps.weighted_pred_flag = reader.read_flag()?;
+        pps.weighted_bipred_idc = reader.read(2)?;
+        if pps.weighted_bipred_idc == 3 {
+            return Err(anyhow!(
+                "Invalid weighted_bipred_idc in PPS: 3, the value is reserved"
+            ));
+        }
+        // Without its SPS the bit depth isn't known yet, so allow the range of the deepest one
+        // (14 bits); the slice header checks SliceQPY against the actual QpBdOffsetY
+        let qp_bd_offset_y = store
+            .sps(pps.seq_parameter_set_id)
+            .map_or(36, Sps::qp_bd_offset_y);
+        pps.pic_init_qp_minus26 = reader.read_se_bounded(-(26 + qp_bd_offset_y), 25)?;
+        pps.pic_init_qs_minus26 = reader.read_se_bounded(-26, 25)?;
+        pps.chroma_qp_index_offset = reader.read_se_bounded(-12, 12)?;
+        pps.deblocking_filter_control_present_flag = reader.read_flag()?;
+        pps.constrained_intra_pred_flag = reader.read_flag()?;
+        pps.redundant_pic_cnt_present_flag = reader.read_flag()?;
+        pps.second_chroma_qp_index_offset = pps.chroma_qp_index_offset;
+
+        if reader.more_rbsp_data() {
+            pps.transform_8x8_mode_flag = reader.read_flag()?;
+            pps.pic_scaling_matrix_present_flag = reader.read_flag()?;
+            if pps.pic_scaling_matrix_present_flag {
+                let sps = store.sps(pps.seq_parameter_set_id).ok_or_else(|| {
+                    anyhow!(
+                        "PPS {} references missing SPS {}",
+                        pps.pic_parameter_set_id,
+                        pps.seq_parameter_set_id
+                    )
+                })?;
+                let lists_8x8 = if sps.chroma_format_idc != 3 { 2 } else { 6 };
+                let count = 6 + lists_8x8 * pps.transform_8x8_mode_flag as usize;
+                pps.pic_scaling_lists = parse_scaling_lists(reader, count)?;
+            }
+            pps.second_chroma_qp_index_offset = reader.read_se_bounded(-12, 12)?;
+            pps.extension_data = ExtensionData::read(reader)?;
+        }
+
+        Ok(pps)
+    }
+
+    fn parse_slice_groups(&mut self, reader: &mut BitReader) -> Result<()> {
+        self.slice_group_map_type = reader.read_ue()?;
+        let num_slice_groups = self.num_slice_groups_minus1 + 1;
+
+        match self.slice_group_map_type {
+            0 => {
+                for _ in 0..num_slice_groups {
+                    self.run_length_minus1.push(reader.read_ue()?);
+                }
+            }
+            2 => {
+                for _ in 0..self.num_slice_groups_minus1 {
+                    self.top_left.push(reader.read_ue()?);
+                    self.bottom_right.push(reader.read_ue()?);
+                }
+            }
+            3..=5 => {
+                self.slice_group_change_direction_flag = reader.read_flag()?;
+                self.slice_group_change_rate_minus1 = reader.read_ue()?;
+            }
+            6 => {
+                self.pic_size_in_map_units_minus1 = reader.read_ue()?;
+                // Ceil( Log2( num_slice_groups_minus1 + 1 ) ) bits per map unit
+                let bits = (u32::BITS - self.num_slice_groups_minus1.leading_zeros()) as usize;
+                for _ in 0..=self.pic_size_in_map_units_minus1 {
+                    self.slice_group_id.push(reader.read_uv(bits)?);
+                }
+            }
+            1 => {}
+            _ => {
+                return Err(anyhow!(
+                    "Invalid slice_group_map_type in PPS: {}",
+                    self.slice_group_map_type
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the PPS RBSP, trailing bits included, so that `parse` reads back the same struct.
+    /// The fields after redundant_pic_cnt_present_flag are only written when one differs from
+    /// what their absence implies, or when extension data has to follow them.
+    pub fn write(&self, writer: &mut BitWriter) {
+        writer.write_ue(self.pic_parameter_set_id);
+        writer.write_ue(self.seq_parameter_set_id);
+        writer.write_flag(self.entropy_coding_mode_flag);
+        writer.write_flag(self.bottom_field_pic_order_in_frame_present_flag);
+        writer.write_ue(self.num_slice_groups_minus1);
+        if self.num_slice_groups_minus1 > 0 {
+            self.write_slice_groups(writer);
+        }
+
+        writer.write_ue(self.num_ref_idx_l0_default_active_minus1);
+        writer.write_ue(self.num_ref_idx_l1_default_active_minus1);
+        writer.write_flag(self.weighted_pred_flag);
+        writer.write(self.weighted_bipred_idc, 2);
+        writer.write_se(self.pic_init_qp_minus26);
+        writer.write_se(self.pic_init_qs_minus26);
+        writer.write_se(self.chroma_qp_index_offset);
+        writer.write_flag(self.deblocking_filter_control_present_flag);
+        writer.write_flag(self.constrained_intra_pred_flag);
+        writer.write_flag(self.redundant_pic_cnt_present_flag);
+
+        if self.transform_8x8_mode_flag
+            || self.pic_scaling_matrix_present_flag
+            || self.second_chroma_qp_index_offset != self.chroma_qp_index_offset
+            || !self.extension_data.is_empty()
+        {
+            writer.write_flag(self.transform_8x8_mode_flag);
+            writer.write_flag(self.pic_scaling_matrix_present_flag);
+            if self.pic_scaling_matrix_present_flag {
+                write_scaling_lists(writer, &self.pic_scaling_lists);
+            }
+            writer.write_se(self.second_chroma_qp_index_offset);
+            self.extension_data.write(writer);
+        }
+        writer.write_rbsp_trailing_bits();
+    }
+
+    fn write_slice_groups(&self, writer: &mut BitWriter) {
+        writer.write_ue(self.slice_group_map_type);
+        match self.slice_group_map_type {
+            0 => {
+                for &run_length in &self.run_length_minus1 {
+                    writer.write_ue(run_length);
+                }
+            }
+            2 => {
+                for (&top_left, &bottom_right) in self.top_left.iter().zip(&self.bottom_right) {
+                    writer.write_ue(top_left);
+                    writer.write_ue(bottom_right);
+                }
+            }
+            3..=5 => {
+                writer.write_flag(self.slice_group_change_direction_flag);
+                writer.write_ue(self.slice_group_change_rate_minus1);
+            }
+            6 => {
+                writer.write_ue(self.pic_size_in_map_units_minus1);
+                let bits = (u32::BITS - self.num_slice_groups_minus1.leading_zeros()) as usize;
+                for &id in &self.slice_group_id {
+                    writer.write(id, bits);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.pic_parameter_set_id
+    }
+
+    /// The SPS this PPS refers to
+    pub fn sps_id(&self) -> u32 {
+        self.seq_parameter_set_id
+    }
+
+    /// SliceQPY at the start of each slice is 26 + pic_init_qp_minus26 + slice_qp_delta
+    pub fn pic_init_qp(&self) -> i32 {
+        26 + self.pic_init_qp_minus26
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{baseline_pps_rbsp, baseline_sps_rbsp};
+    use crate::{ParameterSetStore, Sps};
+
+    #[test]
+    fn test_parse_baseline_pps() -> Result<()> {
+        let rbsp = baseline_pps_rbsp(2, 1);
+        let pps = Pps::parse(&mut BitReader::from_bytes(&rbsp), &ParameterSetStore::new())?;
+
+        assert_eq!(pps.pic_parameter_set_id, 2);
+        assert_eq!(pps.seq_parameter_set_id, 1);
+        assert!(!pps.entropy_coding_mode_flag);
+        assert_eq!(pps.num_slice_groups_minus1, 0);
+        assert_eq!(pps.pic_init_qp(), 26);
+        assert!(!pps.transform_8x8_mode_flag);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pps_without_its_mandatory_fields() {
+        let rbsp = &baseline_pps_rbsp(2, 1)[..1];
+        let err =
+            Pps::parse(&mut BitReader::from_bytes(rbsp), &ParameterSetStore::new()).unwrap_err();
+        assert!(err.to_string().contains("PPS is truncated"));
+    }
+
+    #[test]
+    fn test_out_of_range_ids() {
+        let store = ParameterSetStore::new();
+        let rbsp = baseline_pps_rbsp(256, 0);
+        let err = Pps::parse(&mut BitReader::from_bytes(&rbsp), &store).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid pic_parameter_set_id in PPS: 256, expected at most 255"
+        );
+
+        let rbsp = baseline_pps_rbsp(255, 32);
+        let err = Pps::parse(&mut BitReader::from_bytes(&rbsp), &store).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid seq_parameter_set_id in PPS: 32, expected at most 31"
+        );
+    }
+
+    #[test]
+    fn test_out_of_range_ref_idx_defaults_and_bipred_idc() {
+        let store = ParameterSetStore::new();
+        let parse = |pps: &Pps| {
+            let mut writer = BitWriter::new();
+            pps.write(&mut writer);
+            Pps::parse(&mut BitReader::from_bytes(&writer.into_bytes()), &store)
+        };
+
+        let pps = Pps {
+            num_ref_idx_l0_default_active_minus1: 31,
+            num_ref_idx_l1_default_active_minus1: 31,
+            ..Default::default()
+        };
+        assert!(parse(&pps).is_ok());
+
+        let err = parse(&Pps {
+            num_ref_idx_l0_default_active_minus1: 32,
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid num_ref_idx_l0_default_active_minus1 in PPS: 32, expected at most 31"
+        );
+        let err = parse(&Pps {
+            num_ref_idx_l1_default_active_minus1: 1000,
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid num_ref_idx_l1_default_active_minus1 in PPS: 1000, expected at most 31"
+        );
+
+        let err = parse(&Pps {
+            weighted_bipred_idc: 3,
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid weighted_bipred_idc in PPS: 3, the value is reserved"
+        );
+    }
+
+    #[test]
+    fn test_pic_init_qp_range() -> Result<()> {
+        let mut store = ParameterSetStore::new();
+        store.insert_sps(Sps::parse(&mut BitReader::from_bytes(&baseline_sps_rbsp(
+            0, 4, 4,
+        )))?);
+        let mut pps = Pps::parse(&mut BitReader::from_bytes(&baseline_pps_rbsp(0, 0)), &store)?;
+
+        // An 8-bit SPS has QpBdOffsetY 0, so -26 is the lowest pic_init_qp_minus26
+        pps.pic_init_qp_minus26 = -26;
+        let mut writer = BitWriter::new();
+        pps.write(&mut writer);
+        let parsed = Pps::parse(&mut BitReader::from_bytes(&writer.into_bytes()), &store)?;
+        assert_eq!(parsed.pic_init_qp(), 0);
+
+        for pic_init_qp_minus26 in [-27, 26, -100] {
+            pps.pic_init_qp_minus26 = pic_init_qp_minus26;
+            let mut writer = BitWriter::new();
+            pps.write(&mut writer);
+            let rbsp = writer.into_bytes();
+            assert!(Pps::parse(&mut BitReader::from_bytes(&rbsp), &store).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_pps_with_explicit_slice_groups() -> Result<()> {
+        let mut writer = BitWriter::new();
+        writer.write_ue(0);
+        writer.write_ue(0);
+        writer.write_flag(false);
+        writer.write_flag(false);
+        writer.write_ue(2); // num_slice_groups_minus1
+        writer.write_ue(6); // slice_group_map_type
+        writer.write_ue(3); // pic_size_in_map_units_minus1
+        for id in [0, 1, 2, 1] {
+            writer.write(id, 2);
+        }
+        writer.write_ue(0);
+        writer.write_ue(0);
+        writer.write_flag(false);
+        writer.write(0, 2);
+        writer.write_se(-3);
+        writer.write_se(0);
+        writer.write_se(2);
+        writer.write_flag(true);
+        writer.write_flag(false);
+        writer.write_flag(false);
+        writer.write_rbsp_trailing_bits();
+        let rbsp = writer.into_bytes();
+
+        let pps = Pps::parse(&mut BitReader::from_bytes(&rbsp), &ParameterSetStore::new())?;
+        assert_eq!(pps.slice_group_id, &[0, 1, 2, 1]);
+        assert_eq!(pps.pic_init_qp(), 23);
+        assert_eq!(pps.chroma_qp_index_offset, 2);
+        assert_eq!(pps.second_chroma_qp_index_offset, 2);
+        assert!(pps.deblocking_filter_control_present_flag);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_pps_with_transform_8x8_and_scaling_matrix() -> Result<()> {
+        let mut store = ParameterSetStore::new();
+        store.insert_sps(Sps::parse(&mut BitReader::from_bytes(&baseline_sps_rbsp(
+            0, 4, 4,
+        )))?);
+
+        let mut writer = BitWriter::new();
+        writer.write_ue(0);
+        writer.write_ue(0);
+        writer.write_flag(true); // entropy_coding_mode_flag
+        writer.write_flag(false);
+        writer.write_ue(0);
+        writer.write_ue(0);
+        writer.write_ue(0);
+        writer.write_flag(false);
+        writer.write(0, 2);
+        writer.write_se(0);
+        writer.write_se(0);
+        writer.write_se(0);
+        writer.write_flag(false);
+        writer.write_flag(false);
+        writer.write_flag(false);
+        writer.write_flag(true); // transform_8x8_mode_flag
+        writer.write_flag(true); // pic_scaling_matrix_present_flag
+        for _ in 0..8 {
+            writer.write_flag(false);
+        }
+        writer.write_se(-1); // second_chroma_qp_index_offset
+        writer.write_rbsp_trailing_bits();
+        let rbsp = writer.into_bytes();
+
+        let pps = Pps::parse(&mut BitReader::from_bytes(&rbsp), &store)?;
+        assert!(pps.entropy_coding_mode_flag);
+        assert!(pps.transform_8x8_mode_flag);
+        assert_eq!(pps.pic_scaling_lists.len(), 8);
+        assert_eq!(pps.second_chroma_qp_index_offset, -1);
+
+        // Without the SPS, the scaling list count can't be derived
+        assert!(Pps::parse(&mut BitReader::from_bytes(&rbsp), &ParameterSetStore::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_extension_data_round_trip() -> Result<()> {
+        let store = ParameterSetStore::new();
+        let mut writer = BitWriter::new();
+        writer.write_ue(0); // pic_parameter_set_id
+        writer.write_ue(0); // seq_parameter_set_id
+        writer.write(0, 2); // entropy_coding_mode_flag, bottom_field_pic_order_in_frame_present_flag
+        writer.write_ue(0); // num_slice_groups_minus1
+        writer.write_ue(0); // num_ref_idx_l0_default_active_minus1
+        writer.write_ue(0); // num_ref_idx_l1_default_active_minus1
+        writer.write(0, 3); // weighted_pred_flag, weighted_bipred_idc
+        writer.write_se(0); // pic_init_qp_minus26
+        writer.write_se(0); // pic_init_qs_minus26
+        writer.write_se(0); // chroma_qp_index_offset
+        writer.write(0, 3); // deblocking, constrained intra and redundant_pic_cnt flags
+        writer.write(0, 2); // transform_8x8_mode_flag, pic_scaling_matrix_present_flag
+        writer.write_se(1); // second_chroma_qp_index_offset
+        // A vendor extension
+        writer.write(0xdead, 16);
+        writer.write(0b101, 3);
+        writer.write_rbsp_trailing_bits();
+        let rbsp = writer.into_bytes();
+
+        let pps = Pps::parse(&mut BitReader::from_bytes(&rbsp), &store)?;
+        assert_eq!(pps.second_chroma_qp_index_offset, 1);
+        assert_eq!(pps.extension_data.bits, 19);
+        assert_eq!(pps.extension_data.data, [0xde, 0xad, 0b1010_0000]);
+
+        let mut writer = BitWriter::new();
+        pps.write(&mut writer);
+        assert_eq!(writer.into_bytes(), rbsp);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_round_trip() -> Result<()> {
+        let mut store = ParameterSetStore::new();
+        store.insert_sps(Sps::parse(&mut BitReader::from_bytes(&baseline_sps_rbsp(
+            0, 4, 4,
+        )))?);
+        let round_trip = |pps: &Pps| -> Result<(Pps, Vec<u8>)> {
+            let mut writer = BitWriter::new();
+            pps.write(&mut writer);
+            let rbsp = writer.into_bytes();
+            Ok((Pps::parse(&mut BitReader::from_bytes(&rbsp), &store)?, rbsp))
+        };
+
+        // A parsed PPS is written back bit for bit
+        let rbsp = baseline_pps_rbsp(2, 1);
+        let pps = Pps::parse(&mut BitReader::from_bytes(&rbsp), &store)?;
+        assert_eq!(round_trip(&pps)?, (pps, rbsp));
+
+        let slice_groups = Pps {
+            num_slice_groups_minus1: 2,
+            slice_group_map_type: 6,
+            pic_size_in_map_units_minus1: 3,
+            slice_group_id: vec![0, 1, 2, 1],
+            pic_init_qp_minus26: -3,
+            chroma_qp_index_offset: 2,
+            second_chroma_qp_index_offset: 2,
+            ..Default::default()
+        };
+        assert_eq!(round_trip(&slice_groups)?.0, slice_groups);
+
+        let boxes = Pps {
+            num_slice_groups_minus1: 1,
+            slice_group_map_type: 2,
+            top_left: vec![0],
+            bottom_right: vec![5],
+            ..Default::default()
+        };
+        assert_eq!(round_trip(&boxes)?.0, boxes);
+
+        let high = Pps {
+            entropy_coding_mode_flag: true,
+            weighted_pred_flag: true,
+            weighted_bipred_idc: 2,
+            transform_8x8_mode_flag: true,
+            pic_scaling_matrix_present_flag: true,
+            pic_scaling_lists: vec![
+                ScalingList::UseDefault,
+                ScalingList::NotPresent,
+                ScalingList::NotPresent,
+                ScalingList::Explicit(vec![20; 16]),
+                ScalingList::NotPresent,
+                ScalingList::NotPresent,
+                ScalingList::NotPresent,
+                ScalingList::Explicit((1..=64).collect()),
+            ],
+            chroma_qp_index_offset: 3,
+            second_chroma_qp_index_offset: -2,
+            ..Default::default()
+        };
+        assert_eq!(round_trip(&high)?.0, high);
+        Ok(())
+    }
+}
@@ -0,0 +1,493 @@
+use crate::pps::Pps;
+use crate::sps::Sps;
+use anyhow::Result;
+use bitstream::{BitReader, BitWriter};
+
+/// Default_4x4_Intra and Default_4x4_Inter (Table 7-3), in zig-zag scan order
+const DEFAULT_4X4_INTRA: [u8; 16] = [
+    6, 13, 13, 20, 20, 20, 28, 28, 28, 28, 32, 32, 32, 37, 37, 42,
+];
+const DEFAULT_4X4_INTER: [u8; 16] = [
+    10, 14, 14, 20, 20, 20, 24, 24, 24, 24, 27, 27, 27, 30, 30, 34,
+];
+
+/// Default_8x8_Intra and Default_8x8_Inter (Table 7-4), in zig-zag scan order
+#[rustfmt::skip]
+const DEFAULT_8X8_INTRA: [u8; 64] = [
+    6, 10, 10, 13, 11, 13, 16, 16, 16, 16, 18, 18, 18, 18, 18, 23,
+    23, 23, 23, 23, 23, 25, 25, 25, 25, 25, 25, 25, 27, 27, 27, 27,
+    27, 27, 27, 27, 29, 29, 29, 29, 29, 29, 29, 31, 31, 31, 31, 31,
+    31, 33, 33, 33, 33, 33, 36, 36, 36, 36, 38, 38, 38, 40, 40, 42,
+];
+
+#[rustfmt::skip]
+const DEFAULT_8X8_INTER: [u8; 64] = [
+    9, 13, 13, 15, 13, 15, 17, 17, 17, 17, 19, 19, 19, 19, 19, 21,
+    21, 21, 21, 21, 21, 22, 22, 22, 22, 22, 22, 22, 24, 24, 24, 24,
+    24, 24, 24, 24, 25, 25, 25, 25, 25, 25, 25, 27, 27, 27, 27, 27,
+    27, 28, 28, 28, 28, 28, 30, 30, 30, 30, 32, 32, 32, 33, 33, 35,
+];
+
+/// The default 4x4 scaling list for intra or inter blocks
+pub fn default_4x4(intra: bool) -> [u8; 16] {
+    if intra {
+        DEFAULT_4X4_INTRA
+    } else {
+        DEFAULT_4X4_INTER
+    }
+}
+
+/// The default 8x8 scaling list for intra or inter blocks
+pub fn default_8x8(intra: bool) -> [u8; 64] {
+    if intra {
+        DEFAULT_8X8_INTRA
+    } else {
+        DEFAULT_8X8_INTER
+    }
+}
+
+/// A scaling list as signalled in an SPS or PPS (7.3.2.1.1.1), before any fall-back rule is applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScalingList {
+    /// `scaling_list_present_flag` was 0
+    NotPresent,
+    /// The list signalled `useDefaultScalingMatrixFlag`
+    UseDefault,
+    /// Explicit weights, in zig-zag scan order
+    Explicit(Vec<u8>),
+}
+
+/// Reads `count` scaling lists, each guarded by its own present flag.
+/// The first six lists are 4x4, the remainder 8x8.
+pub fn parse_scaling_lists(reader: &mut BitReader, count: usize) -> Result<Vec<ScalingList>> {
+    let mut lists = Vec::with_capacity(count);
+    for i in 0..count {
+        if reader.read_flag()? {
+            let size = if i < 6 { 16 } else { 64 };
+            lists.push(parse_scaling_list(reader, size)?);
+        } else {
+            lists.push(ScalingList::NotPresent);
+        }
+    }
+    Ok(lists)
+}
+
+/// Writes lists as `parse_scaling_lists` reads them, each preceded by its present flag
+pub fn write_scaling_lists(writer: &mut BitWriter, lists: &[ScalingList]) {
+    for list in lists {
+        writer.write_flag(*list != ScalingList::NotPresent);
+        match list {
+            ScalingList::NotPresent => {}
+            // A first nextScale of 0 selects the default list
+            ScalingList::UseDefault => writer.write_se(-8),
+            ScalingList::Explicit(values) => {
+                let mut last_scale = 8;
+                for &value in values {
+                    // delta_scale is in -128..=127, and nextScale wraps modulo 256
+                    let delta = (value as i32 - last_scale + 128).rem_euclid(256) - 128;
+                    writer.write_se(delta);
+                    last_scale = value as i32;
+                }
+            }
+        }
+    }
+}
+
+/// Implemented as in 7.3.2.1.1.1 scaling_list( scalingList, sizeOfScalingList, useDefaultScalingMatrixFlag )
+pub fn parse_scaling_list(reader: &mut BitReader, size: usize) -> Result<ScalingList> {
+    let mut list = Vec::with_capacity(size);
+    let mut last_scale: i32 = 8;
+    let mut next_scale: i32 = 8;
+
+    for j in 0..size {
+        if next_scale != 0 {
+            let delta_scale = reader.read_se()?;
+            next_scale = (last_scale + delta_scale).rem_euclid(256);
+            if j == 0 && next_scale == 0 {
+                return Ok(ScalingList::UseDefault);
+            }
+        }
+        let value = if next_scale == 0 {
+            last_scale
+        } else {
+            next_scale
+        };
+        list.push(value as u8);
+        last_scale = value;
+    }
+
+    Ok(ScalingList::Explicit(list))
+}
+
+/// The scaling lists in effect for a picture, with every fall-back rule applied. Lists are in
+/// zig-zag scan order. 4x4 lists 0 to 2 are Y, Cb, Cr of intra blocks and 3 to 5 of inter blocks;
+/// 8x8 lists alternate intra and inter for Y, Cb and Cr.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScalingMatrix {
+    pub lists_4x4: [[u8; 16]; 6],
+    pub lists_8x8: [[u8; 64]; 6],
+}
+
+impl Default for ScalingMatrix {
+    /// Flat_4x4_16 and Flat_8x8_16
+    fn default() -> Self {
+        Self {
+            lists_4x4: [[16; 16]; 6],
+            lists_8x8: [[16; 64]; 6],
+        }
+    }
+}
+
+impl ScalingMatrix {
+    /// Derives the scaling lists from the SPS and PPS, using fall-back rule A for lists absent
+    /// from the SPS and fall-back rule B for lists absent from the PPS (Table 7-2)
+    pub fn resolve(sps: &Sps, pps: &Pps) -> Self {
+        let mut matrix = Self::default();
+        if sps.seq_scaling_matrix_present_flag {
+            matrix.apply(&sps.seq_scaling_lists, None);
+        }
+        if pps.pic_scaling_matrix_present_flag {
+            let sequence = sps.seq_scaling_matrix_present_flag.then(|| matrix.clone());
+            matrix.apply(&pps.pic_scaling_lists, sequence.as_ref());
+        }
+        matrix
+    }
+
+    /// The 4x4 list for a block of the given prediction type and colour component (0 = Y, 1 = Cb,
+    /// 2 = Cr)
+    pub fn list_4x4(&self, intra: bool, component: usize) -> &[u8; 16] {
+        &self.lists_4x4[if intra { 0 } else { 3 } + component]
+    }
+
+    /// The 8x8 list for a block of the given prediction type and colour component
+    pub fn list_8x8(&self, intra: bool, component: usize) -> &[u8; 64] {
+        &self.lists_8x8[component * 2 + if intra { 0 } else { 1 }]
+    }
+
+    /// Applies signalled lists in order. Lists that start a fall-back chain (4x4 lists 0 and 3,
+    /// 8x8 lists 0 and 1) fall back to `sequence` under rule B and to the defaults under rule A;
+    /// every other absent list copies the previous list of its chain (Table 7-2). Lists past the
+    /// end of `lists` (the 8x8 lists of a PPS without transform_8x8_mode_flag, or the chroma 8x8
+    /// lists outside 4:4:4) are treated as absent, so the chains are resolved for all twelve.
+    fn apply(&mut self, lists: &[ScalingList], sequence: Option<&ScalingMatrix>) {
+        let lists = (0..12).map(|i| lists.get(i).unwrap_or(&ScalingList::NotPresent));
+        for (i, list) in lists.clone().enumerate().take(6) {
+            let intra = i < 3;
+            self.lists_4x4[i] = match list {
+                ScalingList::Explicit(values) => values.as_slice().try_into().unwrap_or([16; 16]),
+                ScalingList::UseDefault => default_4x4(intra),
+                ScalingList::NotPresent if i == 0 || i == 3 => match sequence {
+                    Some(sequence) => sequence.lists_4x4[i],
+                    None => default_4x4(intra),
+                },
+                ScalingList::NotPresent => self.lists_4x4[i - 1],
+            };
+        }
+
+        for (i, list) in lists.skip(6).enumerate() {
+            let intra = i % 2 == 0;
+            self.lists_8x8[i] = match list {
+                ScalingList::Explicit(values) => values.as_slice().try_into().unwrap_or([16; 64]),
+                ScalingList::UseDefault => default_8x8(intra),
+                ScalingList::NotPresent if i < 2 => match sequence {
+                    Some(sequence) => sequence.lists_8x8[i],
+                    None => default_8x8(intra),
+                },
+                ScalingList::NotPresent => self.lists_8x8[i - 2],
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_use_default_flag() -> Result<()> {
+        // delta_scale = -8 on the first entry makes nextScale 0
+        let mut writer = BitWriter::new();
+        writer.write_se(-8);
+        let bytes = writer.into_bytes();
+
+        let list = parse_scaling_list(&mut BitReader::from_bytes(&bytes), 16)?;
+        assert_eq!(list, ScalingList::UseDefault);
+        Ok(())
+    }
+
+    #[test]
+    fn test_repeat_last_after_zero() -> Result<()> {
+        // 8 -> 10 -> 12, then nextScale 0 repeats 12 for the rest of the list
+        let mut writer = BitWriter::new();
+        writer.write_se(2);
+        writer.write_se(2);
+        writer.write_se(-12);
+        let bytes = writer.into_bytes();
+
+        let list = parse_scaling_list(&mut BitReader::from_bytes(&bytes), 16)?;
+        let mut expected = vec![10, 12];
+        expected.extend([12; 14]);
+        assert_eq!(list, ScalingList::Explicit(expected));
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_scaling_lists_round_trip() -> Result<()> {
+        let mut explicit = vec![4, 255, 1, 128];
+        explicit.extend([16; 12]);
+        let lists = vec![
+            ScalingList::Explicit(explicit),
+            ScalingList::NotPresent,
+            ScalingList::UseDefault,
+            ScalingList::Explicit(vec![8; 16]),
+        ];
+        let mut writer = BitWriter::new();
+        write_scaling_lists(&mut writer, &lists);
+        let bytes = writer.into_bytes();
+
+        assert_eq!(
+            parse_scaling_lists(&mut BitReader::from_bytes(&bytes), 4)?,
+            lists
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_present_flags() -> Result<()> {
+        let mut writer = BitWriter::new();
+        writer.write_flag(false);
+        writer.write_flag(true);
+        writer.write_se(-8);
+        for _ in 2..8 {
+            writer.write_flag(false);
+        }
+        let bytes = writer.into_bytes();
+
+        let lists = parse_scaling_lists(&mut BitReader::from_bytes(&bytes), 8)?;
+        assert_eq!(lists.len(), 8);
+        assert_eq!(lists[0], ScalingList::NotPresent);
+        assert_eq!(lists[1], ScalingList::UseDefault);
+        assert!(lists[2..].iter().all(|l| *l == ScalingList::NotPresent));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fall_back_rule_a_uses_intra_and_inter_defaults() {
+        let sps = Sps {
+            seq_scaling_matrix_present_flag: true,
+            seq_scaling_lists: vec![ScalingList::NotPresent; 8],
+            ..Default::default()
+        };
+        let matrix = ScalingMatrix::resolve(&sps, &Pps::default());
+
+        for component in 0..3 {
+            assert_eq!(*matrix.list_4x4(true, component), default_4x4(true));
+            assert_eq!(*matrix.list_4x4(false, component), default_4x4(false));
+        }
+        assert_eq!(*matrix.list_8x8(true, 0), default_8x8(true));
+        assert_eq!(*matrix.list_8x8(false, 0), default_8x8(false));
+    }
+
+    #[test]
+    fn test_fall_back_rule_a_propagates_first_list() -> Result<()> {
+        // Only the first 4x4 list and the first 8x8 list are present in a 4:4:4 SPS's twelve
+        let mut writer = BitWriter::new();
+        for i in 0..12 {
+            writer.write_flag(i == 0 || i == 6);
+            if i == 0 {
+                writer.write_se(4); // 12, then nextScale 0 repeats it
+                writer.write_se(-12);
+            } else if i == 6 {
+                writer.write_se(2); // 10 for the whole list
+                writer.write_se(-10);
+            }
+        }
+        let bytes = writer.into_bytes();
+        let sps = Sps {
+            seq_scaling_matrix_present_flag: true,
+            seq_scaling_lists: parse_scaling_lists(&mut BitReader::from_bytes(&bytes), 12)?,
+            ..Default::default()
+        };
+        let matrix = ScalingMatrix::resolve(&sps, &Pps::default());
+
+        // Intra Cb and Cr inherit list 0, the inter chain starts over from Default_4x4_Inter
+        assert_eq!(matrix.lists_4x4[..3], [[12; 16]; 3]);
+        assert_eq!(matrix.lists_4x4[3..], [default_4x4(false); 3]);
+        // The 8x8 chains step over the interleaved lists of the other prediction type
+        for component in 0..3 {
+            assert_eq!(*matrix.list_8x8(true, component), [10; 64]);
+            assert_eq!(*matrix.list_8x8(false, component), default_8x8(false));
+        }
+
+        // With only the eight lists of a 4:2:0 SPS, the chroma 8x8 lists still follow the chain
+        let sps = Sps {
+            seq_scaling_matrix_present_flag: true,
+            seq_scaling_lists: vec![ScalingList::NotPresent; 8],
+            ..Default::default()
+        };
+        let matrix = ScalingMatrix::resolve(&sps, &Pps::default());
+        assert_eq!(*matrix.list_8x8(false, 2), default_8x8(false));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fall_back_rule_b_uses_sequence_lists() {
+        let explicit = ScalingList::Explicit(vec![20; 16]);
+        let mut seq_lists = vec![ScalingList::NotPresent; 8];
+        seq_lists[0] = explicit.clone();
+        let sps = Sps {
+            seq_scaling_matrix_present_flag: true,
+            seq_scaling_lists: seq_lists,
+            ..Default::default()
+        };
+        let mut pic_lists = vec![ScalingList::NotPresent; 6];
+        pic_lists[4] = ScalingList::UseDefault;
+        let pps = Pps {
+            pic_scaling_matrix_present_flag: true,
+            pic_scaling_lists: pic_lists,
+            ..Default::default()
+        };
+
+        let matrix = ScalingMatrix::resolve(&sps, &pps);
+        assert_eq!(matrix.lists_4x4[0], [20; 16]);
+        assert_eq!(matrix.lists_4x4[2], [20; 16]);
+        assert_eq!(matrix.lists_4x4[3], default_4x4(false));
+        assert_eq!(matrix.lists_4x4[4], default_4x4(false));
+
+        // Without scaling matrices everything is flat
+        assert_eq!(
+            ScalingMatrix::resolve(&Sps::default(), &Pps::default()),
+            ScalingMatrix::default()
+        );
+    }
+
+    /// xorshift64, so the randomized cases are the same on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    /// `count` lists, each absent, use-default or explicit with weights in 1..=255
+    fn random_lists(rng: &mut Rng, count: usize) -> Vec<ScalingList> {
+        (0..count)
+            .map(|i| match rng.below(3) {
+                0 => ScalingList::NotPresent,
+                1 => ScalingList::UseDefault,
+                _ => {
+                    let size = if i < 6 { 16 } else { 64 };
+                    ScalingList::Explicit((0..size).map(|_| rng.below(255) as u8 + 1).collect())
+                }
+            })
+            .collect()
+    }
+
+    /// Table 7-2, spelled out list by list: the twelve resolved lists for `lists`, with
+    /// `sequence` holding the SPS lists under fall-back rule B
+    fn expected_lists(lists: &[ScalingList], sequence: Option<&[Vec<u8>]>) -> Vec<Vec<u8>> {
+        let mut resolved: Vec<Vec<u8>> = Vec::with_capacity(12);
+        for i in 0..12 {
+            let intra = if i < 6 { i < 3 } else { i % 2 == 0 };
+            let default = if i < 6 {
+                default_4x4(intra).to_vec()
+            } else {
+                default_8x8(intra).to_vec()
+            };
+            let list = match lists.get(i).unwrap_or(&ScalingList::NotPresent) {
+                ScalingList::Explicit(values) => values.clone(),
+                ScalingList::UseDefault => default,
+                ScalingList::NotPresent => match i {
+                    0 | 3 | 6 | 7 => sequence.map_or(default, |sequence| sequence[i].clone()),
+                    1 | 2 | 4 | 5 => resolved[i - 1].clone(),
+                    _ => resolved[i - 2].clone(),
+                },
+            };
+            resolved.push(list);
+        }
+        resolved
+    }
+
+    fn matrix_lists(matrix: &ScalingMatrix) -> Vec<Vec<u8>> {
+        let lists_4x4 = matrix.lists_4x4.iter().map(|list| list.to_vec());
+        lists_4x4
+            .chain(matrix.lists_8x8.iter().map(|list| list.to_vec()))
+            .collect()
+    }
+
+    /// Writes `lists`, parses them back and checks nothing changed on the way
+    fn round_trip(lists: &[ScalingList]) -> Result<Vec<ScalingList>> {
+        let mut writer = BitWriter::new();
+        write_scaling_lists(&mut writer, lists);
+        writer.write_rbsp_trailing_bits();
+        let bytes = writer.into_bytes();
+        let mut reader = BitReader::from_bytes(&bytes);
+        let parsed = parse_scaling_lists(&mut reader, lists.len())?;
+        reader.read_rbsp_trailing_bits()?;
+        assert_eq!(parsed, lists);
+        Ok(parsed)
+    }
+
+    #[test]
+    fn test_randomized_scaling_lists_resolve_per_table_7_2() -> Result<()> {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..32 {
+            // 4:2:0 or 4:4:4 SPSs, and PPSs with or without transform_8x8_mode_flag
+            let seq_count = [8, 12][rng.below(2) as usize];
+            let pic_count = [6, 8, 12][rng.below(3) as usize];
+            let sps = Sps {
+                seq_scaling_matrix_present_flag: true,
+                seq_scaling_lists: round_trip(&random_lists(&mut rng, seq_count))?,
+                ..Default::default()
+            };
+            let pps = Pps {
+                pic_scaling_matrix_present_flag: true,
+                pic_scaling_lists: round_trip(&random_lists(&mut rng, pic_count))?,
+                ..Default::default()
+            };
+
+            // Rule A for the SPS on its own
+            let sequence = expected_lists(&sps.seq_scaling_lists, None);
+            let matrix = ScalingMatrix::resolve(&sps, &Pps::default());
+            assert_eq!(matrix_lists(&matrix), sequence);
+
+            // Rule B for the PPS on top of it, or rule A without an SPS matrix
+            let matrix = ScalingMatrix::resolve(&sps, &pps);
+            let expected = expected_lists(&pps.pic_scaling_lists, Some(&sequence));
+            assert_eq!(matrix_lists(&matrix), expected);
+
+            let flat_sps = Sps::default();
+            let matrix = ScalingMatrix::resolve(&flat_sps, &pps);
+            assert_eq!(
+                matrix_lists(&matrix),
+                expected_lists(&pps.pic_scaling_lists, None)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_use_default_lists_resolve_to_the_defaults() -> Result<()> {
+        let sps = Sps {
+            seq_scaling_matrix_present_flag: true,
+            seq_scaling_lists: round_trip(&vec![ScalingList::UseDefault; 12])?,
+            ..Default::default()
+        };
+        let matrix = ScalingMatrix::resolve(&sps, &Pps::default());
+        for component in 0..3 {
+            for intra in [true, false] {
+                assert_eq!(*matrix.list_4x4(intra, component), default_4x4(intra));
+                assert_eq!(*matrix.list_8x8(intra, component), default_8x8(intra));
+            }
+        }
+        Ok(())
+    }
+}
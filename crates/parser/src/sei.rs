@@ -0,0 +1,238 @@
+use crate::sps::Sps;
+use anyhow::{Result, anyhow};
+use bitstream::BitReader;
+
+/// One SEI message (7.3.2.3.1). Payloads the parser doesn't interpret are kept as their type and
+/// size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SeiMessage {
+    PicTiming(PicTiming),
+    RecoveryPoint(RecoveryPoint),
+    Unknown {
+        payload_type: u32,
+        payload_size: u32,
+    },
+}
+
+/// Implemented as in D.1.3 Picture timing SEI message syntax, up to `pic_struct`. The clock
+/// timestamps after it are skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PicTiming {
+    /// Present with the delays when the SPS carries NAL or VCL HRD parameters
+    pub cpb_removal_delay: Option<u32>,
+    pub dpb_output_delay: Option<u32>,
+    /// Present when the SPS sets `pic_struct_present_flag`; see Table D-1
+    pub pic_struct: Option<u8>,
+}
+
+impl PicTiming {
+    fn parse(reader: &mut BitReader, sps: &Sps) -> Result<Self> {
+        let vui = sps.vui_parameters.as_ref();
+        let mut timing = PicTiming::default();
+
+        // CpbDpbDelaysPresentFlag
+        if let Some(hrd) = vui.and_then(|v| {
+            v.nal_hrd_parameters
+                .as_ref()
+                .or(v.vcl_hrd_parameters.as_ref())
+        }) {
+            timing.cpb_removal_delay =
+                Some(reader.read_uv(hrd.cpb_removal_delay_length_minus1 as usize + 1)?);
+            timing.dpb_output_delay =
+                Some(reader.read_uv(hrd.dpb_output_delay_length_minus1 as usize + 1)?);
+        }
+
+        if vui.is_some_and(|v| v.pic_struct_present_flag) {
+            let pic_struct = reader.read(4)? as u8;
+            if pic_struct > 8 {
+                return Err(anyhow!("Reserved pic_struct: {}", pic_struct));
+            }
+            timing.pic_struct = Some(pic_struct);
+        }
+
+        Ok(timing)
+    }
+}
+
+/// Implemented as in D.1.7 Recovery point SEI message syntax
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RecoveryPoint {
+    /// Number of frames, in decoding order, until output is correct again
+    pub recovery_frame_cnt: u32,
+    pub exact_match_flag: bool,
+    pub broken_link_flag: bool,
+    pub changing_slice_group_idc: u8,
+}
+
+impl RecoveryPoint {
+    fn parse(reader: &mut BitReader) -> Result<Self> {
+        Ok(Self {
+            recovery_frame_cnt: reader.read_ue()?,
+            exact_match_flag: reader.read_flag()?,
+            broken_link_flag: reader.read_flag()?,
+            changing_slice_group_idc: reader.read(2)? as u8,
+        })
+    }
+}
+
+/// Parses every message of an SEI RBSP (7.3.2.3). Picture timing depends on the active SPS, so
+/// it's only interpreted when `sps` is given.
+pub fn parse_sei(rbsp: &[u8], sps: Option<&Sps>) -> Result<Vec<SeiMessage>> {
+    let mut reader = BitReader::from_bytes(rbsp);
+    let mut messages = Vec::new();
+
+    while reader.more_rbsp_data() {
+        let payload_type = read_ff_coded(&mut reader)?;
+        let payload_size = read_ff_coded(&mut reader)?;
+        let payload_bits = payload_size as usize * 8;
+        if !reader.has_bits(payload_bits) {
+            return Err(anyhow!(
+                "SEI payload of type {} overruns the NALU: {} bytes",
+                payload_type,
+                payload_size
+            ));
+        }
+        let payload_end = reader.position() + payload_bits;
+
+        messages.push(match (payload_type, sps) {
+            (1, Some(sps)) => SeiMessage::PicTiming(PicTiming::parse(&mut reader, sps)?),
+            (6, _) => SeiMessage::RecoveryPoint(RecoveryPoint::parse(&mut reader)?),
+            _ => SeiMessage::Unknown {
+                payload_type,
+                payload_size,
+            },
+        });
+        // Steps over reserved extension data and the payload's alignment bits (7.3.2.3.1)
+        reader.skip_to(payload_end).map_err(|_| {
+            anyhow!(
+                "SEI payload of type {} is larger than its {} bytes",
+                payload_type,
+                payload_size
+            )
+        })?;
+    }
+
+    Ok(messages)
+}
+
+/// payloadType and payloadSize: a run of 0xFF bytes, each adding 255, then a final byte
+fn read_ff_coded(reader: &mut BitReader) -> Result<u32> {
+    let mut value = 0u32;
+    loop {
+        let byte = reader
+            .read(8)
+            .map_err(|_| anyhow!("SEI message header is truncated"))?;
+        value = value
+            .checked_add(byte)
+            .ok_or_else(|| anyhow!("SEI payload type or size overflows"))?;
+        if byte != 0xFF {
+            return Ok(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vui::{HrdParameters, VuiParameters};
+
+    #[test]
+    fn test_parse_recovery_point() -> Result<()> {
+        // recovery_frame_cnt 2 ("011"), exact_match_flag 1, broken_link_flag 0,
+        // changing_slice_group_idc 0, then payload alignment bits
+        let rbsp = [0x06, 0x01, 0b0111_0001, 0x80];
+        assert_eq!(
+            parse_sei(&rbsp, None)?,
+            [SeiMessage::RecoveryPoint(RecoveryPoint {
+                recovery_frame_cnt: 2,
+                exact_match_flag: true,
+                broken_link_flag: false,
+                changing_slice_group_idc: 0,
+            })]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_multiple_and_extended_messages() -> Result<()> {
+        // user_data_unregistered (type 5) with a 256 byte payload, coded as 0xFF 0x01
+        let mut rbsp = vec![0x05, 0xFF, 0x01];
+        rbsp.extend([0xAB; 256]);
+        rbsp.extend([0x06, 0x01, 0b1100_0100, 0x80]);
+
+        let messages = parse_sei(&rbsp, None)?;
+        assert_eq!(
+            messages[0],
+            SeiMessage::Unknown {
+                payload_type: 5,
+                payload_size: 256
+            }
+        );
+        assert!(matches!(
+            messages[1],
+            SeiMessage::RecoveryPoint(RecoveryPoint {
+                recovery_frame_cnt: 0,
+                exact_match_flag: true,
+                ..
+            })
+        ));
+
+        assert!(parse_sei(&[0x06, 0x05, 0x00], None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_pic_timing() -> Result<()> {
+        let sps = Sps {
+            vui_parameters: Some(VuiParameters {
+                nal_hrd_parameters: Some(HrdParameters {
+                    cpb_removal_delay_length_minus1: 3,
+                    dpb_output_delay_length_minus1: 1,
+                    ..Default::default()
+                }),
+                pic_struct_present_flag: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        // cpb_removal_delay 5 (4 bits), dpb_output_delay 2 (2 bits), pic_struct 5, then a
+        // clock_timestamp_flag of 0 for each of its three timestamps
+        let rbsp = [0x01, 0x02, 0b0101_1001, 0b0100_0100, 0x80];
+
+        assert_eq!(
+            parse_sei(&rbsp, Some(&sps))?,
+            [SeiMessage::PicTiming(PicTiming {
+                cpb_removal_delay: Some(5),
+                dpb_output_delay: Some(2),
+                pic_struct: Some(5),
+            })]
+        );
+        assert_eq!(
+            parse_sei(&rbsp, None)?,
+            [SeiMessage::Unknown {
+                payload_type: 1,
+                payload_size: 2
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_payload_extension_bits_are_skipped() -> Result<()> {
+        // A recovery point payload with a trailing byte of extension data, then a second message
+        let rbsp = [0x06, 0x02, 0b0111_0001, 0x5A, 0x06, 0x01, 0b1100_0100, 0x80];
+        let messages = parse_sei(&rbsp, None)?;
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(
+            messages[1],
+            SeiMessage::RecoveryPoint(RecoveryPoint {
+                recovery_frame_cnt: 0,
+                ..
+            })
+        ));
+
+        // recovery_frame_cnt runs past the one byte payload
+        assert!(parse_sei(&[0x06, 0x01, 0b0000_0001, 0xFF, 0x80], None).is_err());
+        Ok(())
+    }
+}
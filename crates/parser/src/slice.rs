@@ -1 +1,1063 @@
+use crate::store::{ActiveParams, ParameterSetStore};
+use crate::{read_se_bounded, read_ue_max};
+use anyhow::{Result, anyhow};
+use bitstream::{BitReader, NalUnitType, NaluHeader};
 
+/// Slice types, as in Table 7-6. Values 5 to 9 map onto the same types as 0 to 4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SliceType {
+    P,
+    B,
+    I,
+    SP,
+    SI,
+}
+
+impl SliceType {
+    pub fn from_slice_type(slice_type: u32) -> Result<Self> {
+        if slice_type > 9 {
+            return Err(anyhow!("Invalid slice_type: {}", slice_type));
+        }
+        match slice_type % 5 {
+            0 => Ok(SliceType::P),
+            1 => Ok(SliceType::B),
+            2 => Ok(SliceType::I),
+            3 => Ok(SliceType::SP),
+            _ => Ok(SliceType::SI),
+        }
+    }
+
+    /// I and SI slices carry no inter prediction
+    pub fn is_intra(self) -> bool {
+        matches!(self, SliceType::I | SliceType::SI)
+    }
+}
+
+/// One `modification_of_pic_nums_idc` operation and its argument (7.3.3.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RefPicListModificationOp {
+    pub modification_of_pic_nums_idc: u32,
+    /// abs_diff_pic_num_minus1 for idc 0/1, long_term_pic_num for idc 2
+    pub value: u32,
+}
+
+/// One `memory_management_control_operation` and its arguments (7.3.3.3)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryManagementOp {
+    pub memory_management_control_operation: u32,
+    pub difference_of_pic_nums_minus1: u32,
+    pub long_term_pic_num: u32,
+    pub long_term_frame_idx: u32,
+    pub max_long_term_frame_idx_plus1: u32,
+}
+
+/// Implemented as in 7.3.3.3 Decoded reference picture marking syntax
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DecRefPicMarking {
+    pub no_output_of_prior_pics_flag: bool,
+    pub long_term_reference_flag: bool,
+    pub adaptive_ref_pic_marking_mode_flag: bool,
+    pub operations: Vec<MemoryManagementOp>,
+}
+
+/// Explicit weights and offsets for one reference picture and one colour component
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Weight {
+    pub weight: i32,
+    pub offset: i32,
+}
+
+/// Implemented as in 7.3.3.2 Prediction weight table syntax. Entries whose flag is 0 hold the
+/// inferred defaults (2^denom weight, 0 offset).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PredWeightTable {
+    pub luma_log2_weight_denom: u32,
+    pub chroma_log2_weight_denom: u32,
+    pub luma_l0: Vec<Weight>,
+    /// [Cb, Cr] per reference
+    pub chroma_l0: Vec<[Weight; 2]>,
+    pub luma_l1: Vec<Weight>,
+    pub chroma_l1: Vec<[Weight; 2]>,
+}
+
+/// Implemented as in 7.3.3 Slice header syntax in Rec. ITU-T H.264 (04/2013)
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SliceHeader {
+    pub first_mb_in_slice: u32,
+    /// The raw value, 0 to 9
+    pub slice_type: u32,
+    pub pic_parameter_set_id: u32,
+    pub colour_plane_id: u8,
+    pub frame_num: u32,
+    pub field_pic_flag: bool,
+    pub bottom_field_flag: bool,
+    pub idr_pic_id: u32,
+    pub pic_order_cnt_lsb: u32,
+    pub delta_pic_order_cnt_bottom: i32,
+    pub delta_pic_order_cnt: [i32; 2],
+    /// Only present when the PPS sets `redundant_pic_cnt_present_flag`
+    pub redundant_pic_cnt: Option<u32>,
+    pub direct_spatial_mv_pred_flag: bool,
+    /// Either overridden in the header, or the PPS default
+    pub num_ref_idx_l0_active_minus1: u32,
+    pub num_ref_idx_l1_active_minus1: u32,
+    pub ref_pic_list_modification_l0: Vec<RefPicListModificationOp>,
+    pub ref_pic_list_modification_l1: Vec<RefPicListModificationOp>,
+    pub pred_weight_table: Option<PredWeightTable>,
+    /// Only present for reference pictures (nal_ref_idc != 0)
+    pub dec_ref_pic_marking: Option<DecRefPicMarking>,
+    pub cabac_init_idc: u32,
+    pub slice_qp_delta: i32,
+    pub sp_for_switch_flag: bool,
+    pub slice_qs_delta: i32,
+    pub disable_deblocking_filter_idc: u32,
+    pub slice_alpha_c0_offset_div2: i32,
+    pub slice_beta_offset_div2: i32,
+    /// Grows slice group 0 of the evolving slice group map types 3 to 5, in units of
+    /// SliceGroupChangeRate map units (7-36)
+    pub slice_group_change_cycle: u32,
+}
+
+impl SliceHeader {
+    /// Parses a slice header from the start of a slice RBSP. The PPS it references (and that PPS's
+    /// SPS) must already be in `store`, since most of the header's layout depends on them.
+    pub fn parse(
+        reader: &mut BitReader,
+        nalu: &NaluHeader,
+        store: &ParameterSetStore,
+    ) -> Result<Self> {
+        // first_mb_in_slice comes before the PPS is known, so it's checked against PicSizeInMbs
+        // further down
+        let mut header = SliceHeader {
+            first_mb_in_slice: reader.read_ue()?,
+            slice_type: read_ue_max(reader, 9, "slice_type in slice header")?,
+            pic_parameter_set_id: read_ue_max(reader, 255, "pic_parameter_set_id in slice header")?,
+            ..Default::default()
+        };
+        let slice_type = SliceType::from_slice_type(header.slice_type)?;
+
+        let ActiveParams { sps, pps } = store.activate(header.pic_parameter_set_id)?;
+        let idr_pic_flag = nalu.kind() == NalUnitType::IdrSlice;
+
+        if sps.separate_colour_plane_flag {
+            header.colour_plane_id = reader.read(2)? as u8;
+        }
+        header.frame_num = reader.read_uv(sps.log2_max_frame_num_minus4 as usize + 4)?;
+        if !sps.frame_mbs_only_flag {
+            header.field_pic_flag = reader.read_flag()?;
+            if header.field_pic_flag {
+                header.bottom_field_flag = reader.read_flag()?;
+            }
+        }
+        // PicSizeInMbs (7-26) bounds first_mb_in_slice, which counts macroblock pairs in MBAFF
+        // frames (7-30). The SPS sizes are bounded when it's parsed, but one built by hand isn't,
+        // so the product is taken in 64 bits.
+        let pic_size_in_mbs = sps.pic_width_in_mbs() as u64 * sps.frame_height_in_mbs() as u64
+            / (1 + header.field_pic_flag as u64);
+        let mbaff_frame = sps.mb_adaptive_frame_field_flag && !header.field_pic_flag;
+        if header.first_mb_in_slice as u64 * (1 + mbaff_frame as u64) >= pic_size_in_mbs {
+            return Err(anyhow!(
+                "Invalid first_mb_in_slice: {}, the picture has {} macroblocks",
+                header.first_mb_in_slice,
+                pic_size_in_mbs
+            ));
+        }
+        if idr_pic_flag {
+            header.idr_pic_id = read_ue_max(reader, 65535, "idr_pic_id in slice header")?;
+        }
+
+        let bottom_field_delta_present =
+            pps.bottom_field_pic_order_in_frame_present_flag && !header.field_pic_flag;
+        if sps.pic_order_cnt_type == 0 {
+            header.pic_order_cnt_lsb =
+                reader.read_uv(sps.log2_max_pic_order_cnt_lsb_minus4 as usize + 4)?;
+            if bottom_field_delta_present {
+                header.delta_pic_order_cnt_bottom = reader.read_se()?;
+            }
+        }
+        if sps.pic_order_cnt_type == 1 && !sps.delta_pic_order_always_zero_flag {
+            header.delta_pic_order_cnt[0] = reader.read_se()?;
+            if bottom_field_delta_present {
+                header.delta_pic_order_cnt[1] = reader.read_se()?;
+            }
+        }
+
+        if pps.redundant_pic_cnt_present_flag {
+            header.redundant_pic_cnt = Some(reader.read_ue()?);
+        }
+
+        if slice_type == SliceType::B {
+            header.direct_spatial_mv_pred_flag = reader.read_flag()?;
+        }
+
+        header.num_ref_idx_l0_active_minus1 = pps.num_ref_idx_l0_default_active_minus1;
+        header.num_ref_idx_l1_active_minus1 = pps.num_ref_idx_l1_default_active_minus1;
+        if matches!(slice_type, SliceType::P | SliceType::SP | SliceType::B)
+            && reader.read_flag()?
+        {
+            header.num_ref_idx_l0_active_minus1 =
+                read_ue_max(reader, 31, "num_ref_idx_l0_active_minus1 in slice header")?;
+            if slice_type == SliceType::B {
+                header.num_ref_idx_l1_active_minus1 =
+                    read_ue_max(reader, 31, "num_ref_idx_l1_active_minus1 in slice header")?;
+            }
+        }
+        if !slice_type.is_intra() {
+            header.ref_pic_list_modification_l0 =
+                parse_ref_pic_list_modification(reader, header.num_ref_idx_l0_active_minus1)?;
+        }
+        if slice_type == SliceType::B {
+            header.ref_pic_list_modification_l1 =
+                parse_ref_pic_list_modification(reader, header.num_ref_idx_l1_active_minus1)?;
+        }
+
+        if (pps.weighted_pred_flag && matches!(slice_type, SliceType::P | SliceType::SP))
+            || (pps.weighted_bipred_idc == 1 && slice_type == SliceType::B)
+        {
+            header.pred_weight_table = Some(header.parse_pred_weight_table(
+                reader,
+                slice_type,
+                sps.chroma_array_type(),
+            )?);
+        }
+
+        if nalu.nal_ref_idc != 0 {
+            header.dec_ref_pic_marking = Some(parse_dec_ref_pic_marking(reader, idr_pic_flag)?);
+        }
+
+        if pps.entropy_coding_mode_flag && !slice_type.is_intra() {
+            header.cabac_init_idc = reader.read_ue()?;
+        }
+        header.slice_qp_delta = reader.read_se()?;
+        // SliceQPY must be within -QpBdOffsetY..=51 (7.4.3)
+        let slice_qp = pps.pic_init_qp() as i64 + header.slice_qp_delta as i64;
+        if !(-sps.qp_bd_offset_y() as i64..=51).contains(&slice_qp) {
+            return Err(anyhow!(
+                "Invalid slice_qp_delta: {}, SliceQPY {} is outside {}..=51",
+                header.slice_qp_delta,
+                slice_qp,
+                -sps.qp_bd_offset_y()
+            ));
+        }
+        if matches!(slice_type, SliceType::SP | SliceType::SI) {
+            if slice_type == SliceType::SP {
+                header.sp_for_switch_flag = reader.read_flag()?;
+            }
+            header.slice_qs_delta = reader.read_se()?;
+        }
+
+        if pps.deblocking_filter_control_present_flag {
+            header.disable_deblocking_filter_idc =
+                read_ue_max(reader, 2, "disable_deblocking_filter_idc in slice header")?;
+            if header.disable_deblocking_filter_idc != 1 {
+                header.slice_alpha_c0_offset_div2 = reader.read_se_bounded(-6, 6)?;
+                header.slice_beta_offset_div2 = reader.read_se_bounded(-6, 6)?;
+            }
+        }
+
+        if pps.num_slice_groups_minus1 > 0 && (3..=5).contains(&pps.slice_group_map_type) {
+            // In 64 bits, like PicSizeInMbs above
+            let pic_size_in_map_units =
+                sps.pic_width_in_mbs() as u64 * (sps.pic_height_in_map_units_minus1 as u64 + 1);
+            let max_cycle =
+                pic_size_in_map_units.div_ceil(pps.slice_group_change_rate_minus1 as u64 + 1);
+            // Ceil(Log2(PicSizeInMapUnits ÷ SliceGroupChangeRate + 1)) bits (7.4.3)
+            let bits = (max_cycle + 1).next_power_of_two().trailing_zeros();
+            header.slice_group_change_cycle = reader.read_uv(bits as usize)?;
+            if header.slice_group_change_cycle as u64 > max_cycle {
+                return Err(anyhow!(
+                    "slice_group_change_cycle {} exceeds {}",
+                    header.slice_group_change_cycle,
+                    max_cycle
+                ));
+            }
+        }
+
+        Ok(header)
+    }
+
+    pub fn kind(&self) -> SliceType {
+        // The raw value is range checked while parsing
+        SliceType::from_slice_type(self.slice_type).unwrap_or(SliceType::I)
+    }
+
+    /// Whether the slice's dec_ref_pic_marking holds memory_management_control_operation 5,
+    /// which resets frame_num and POC like an IDR picture without being one
+    pub fn has_mmco5(&self) -> bool {
+        self.dec_ref_pic_marking.as_ref().is_some_and(|marking| {
+            marking
+                .operations
+                .iter()
+                .any(|op| op.memory_management_control_operation == 5)
+        })
+    }
+
+    fn parse_pred_weight_table(
+        &self,
+        reader: &mut BitReader,
+        slice_type: SliceType,
+        chroma_array_type: u32,
+    ) -> Result<PredWeightTable> {
+        let mut table = PredWeightTable {
+            luma_log2_weight_denom: read_ue_max(reader, 7, "luma_log2_weight_denom")?,
+            ..Default::default()
+        };
+        if chroma_array_type != 0 {
+            table.chroma_log2_weight_denom = read_ue_max(reader, 7, "chroma_log2_weight_denom")?;
+        }
+
+        let (luma_l0, chroma_l0) = parse_weights(
+            reader,
+            self.num_ref_idx_l0_active_minus1,
+            &table,
+            chroma_array_type,
+        )?;
+        table.luma_l0 = luma_l0;
+        table.chroma_l0 = chroma_l0;
+
+        if slice_type == SliceType::B {
+            let (luma_l1, chroma_l1) = parse_weights(
+                reader,
+                self.num_ref_idx_l1_active_minus1,
+                &table,
+                chroma_array_type,
+            )?;
+            table.luma_l1 = luma_l1;
+            table.chroma_l1 = chroma_l1;
+        }
+
+        Ok(table)
+    }
+}
+
+/// Whether the slice `cur` starts a new primary coded picture after the slice `prev`
+/// (7.4.1.2.4). The POC syntax elements are left at 0 when the SPS doesn't send them, so they're
+/// compared whatever pic_order_cnt_type is.
+pub fn is_new_picture(
+    prev: &SliceHeader,
+    cur: &SliceHeader,
+    prev_nal: &NaluHeader,
+    cur_nal: &NaluHeader,
+) -> bool {
+    let prev_idr = prev_nal.kind() == NalUnitType::IdrSlice;
+    let cur_idr = cur_nal.kind() == NalUnitType::IdrSlice;
+
+    prev.frame_num != cur.frame_num
+        || prev.pic_parameter_set_id != cur.pic_parameter_set_id
+        || prev.field_pic_flag != cur.field_pic_flag
+        || (prev.field_pic_flag && prev.bottom_field_flag != cur.bottom_field_flag)
+        || (prev_nal.nal_ref_idc != cur_nal.nal_ref_idc
+            && (prev_nal.nal_ref_idc == 0 || cur_nal.nal_ref_idc == 0))
+        || prev.pic_order_cnt_lsb != cur.pic_order_cnt_lsb
+        || prev.delta_pic_order_cnt_bottom != cur.delta_pic_order_cnt_bottom
+        || prev.delta_pic_order_cnt != cur.delta_pic_order_cnt
+        || prev_idr != cur_idr
+        || (prev_idr && cur_idr && prev.idr_pic_id != cur.idr_pic_id)
+}
+
+/// Reads the weights of one reference list in a pred_weight_table
+fn parse_weights(
+    reader: &mut BitReader,
+    num_ref_idx_active_minus1: u32,
+    table: &PredWeightTable,
+    chroma_array_type: u32,
+) -> Result<(Vec<Weight>, Vec<[Weight; 2]>)> {
+    let default_luma = Weight {
+        weight: 1 << table.luma_log2_weight_denom,
+        offset: 0,
+    };
+    let default_chroma = Weight {
+        weight: 1 << table.chroma_log2_weight_denom,
+        offset: 0,
+    };
+
+    let mut luma = Vec::new();
+    let mut chroma = Vec::new();
+    // Every explicit weight and offset is within -128..=127 (7.4.3.2)
+    for _ in 0..=num_ref_idx_active_minus1 {
+        if reader.read_flag()? {
+            luma.push(Weight {
+                weight: read_se_bounded(reader, -128, 127, "luma_weight")?,
+                offset: read_se_bounded(reader, -128, 127, "luma_offset")?,
+            });
+        } else {
+            luma.push(default_luma);
+        }
+
+        let mut cb_cr = [default_chroma; 2];
+        if chroma_array_type != 0 && reader.read_flag()? {
+            for weight in cb_cr.iter_mut() {
+                weight.weight = read_se_bounded(reader, -128, 127, "chroma_weight")?;
+                weight.offset = read_se_bounded(reader, -128, 127, "chroma_offset")?;
+            }
+        }
+        chroma.push(cb_cr);
+    }
+
+    Ok((luma, chroma))
+}
+
+/// Implemented as in 7.3.3.1 Reference picture list modification syntax, for a single list
+fn parse_ref_pic_list_modification(
+    reader: &mut BitReader,
+    num_ref_idx_active_minus1: u32,
+) -> Result<Vec<RefPicListModificationOp>> {
+    let mut ops = Vec::new();
+    if !reader.read_flag()? {
+        return Ok(ops);
+    }
+
+    loop {
+        let modification_of_pic_nums_idc = reader.read_ue()?;
+        let value = match modification_of_pic_nums_idc {
+            0..=2 => reader.read_ue()?,
+            3 => break,
+            _ => {
+                return Err(anyhow!(
+                    "Invalid modification_of_pic_nums_idc: {}",
+                    modification_of_pic_nums_idc
+                ));
+            }
+        };
+        // Each operation places one entry, so there can't be more than the list holds
+        if ops.len() > num_ref_idx_active_minus1 as usize {
+            return Err(anyhow!(
+                "Too many reference picture list modifications, expected at most {}",
+                num_ref_idx_active_minus1 + 1
+            ));
+        }
+        ops.push(RefPicListModificationOp {
+            modification_of_pic_nums_idc,
+            value,
+        });
+    }
+
+    Ok(ops)
+}
+
+/// Implemented as in 7.3.3.3 Decoded reference picture marking syntax
+fn parse_dec_ref_pic_marking(
+    reader: &mut BitReader,
+    idr_pic_flag: bool,
+) -> Result<DecRefPicMarking> {
+    let mut marking = DecRefPicMarking::default();
+    if idr_pic_flag {
+        marking.no_output_of_prior_pics_flag = reader.read_flag()?;
+        marking.long_term_reference_flag = reader.read_flag()?;
+        return Ok(marking);
+    }
+
+    marking.adaptive_ref_pic_marking_mode_flag = reader.read_flag()?;
+    if !marking.adaptive_ref_pic_marking_mode_flag {
+        return Ok(marking);
+    }
+
+    loop {
+        let mut op = MemoryManagementOp {
+            memory_management_control_operation: reader.read_ue()?,
+            ..Default::default()
+        };
+        match op.memory_management_control_operation {
+            0 => break,
+            1 => op.difference_of_pic_nums_minus1 = reader.read_ue()?,
+            2 => op.long_term_pic_num = reader.read_ue()?,
+            3 => {
+                op.difference_of_pic_nums_minus1 = reader.read_ue()?;
+                op.long_term_frame_idx = reader.read_ue()?;
+            }
+            4 => op.max_long_term_frame_idx_plus1 = reader.read_ue()?,
+            5 => {}
+            6 => op.long_term_frame_idx = reader.read_ue()?,
+            other => {
+                return Err(anyhow!(
+                    "Invalid memory_management_control_operation: {}",
+                    other
+                ));
+            }
+        }
+        if marking.operations.len() > 66 {
+            return Err(anyhow!("Too many memory management control operations"));
+        }
+        marking.operations.push(op);
+    }
+
+    Ok(marking)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{baseline_pps_rbsp, baseline_sps_rbsp};
+    use crate::{Pps, Sps};
+    use bitstream::BitWriter;
+
+    fn store_with(redundant_pic_cnt_present_flag: bool) -> Result<ParameterSetStore> {
+        let mut store = ParameterSetStore::new();
+        store.insert_sps(Sps::parse(&mut BitReader::from_bytes(&baseline_sps_rbsp(
+            0, 4, 4,
+        )))?);
+        let mut pps = Pps::parse(&mut BitReader::from_bytes(&baseline_pps_rbsp(0, 0)), &store)?;
+        pps.redundant_pic_cnt_present_flag = redundant_pic_cnt_present_flag;
+        store.insert_pps(pps);
+        Ok(store)
+    }
+
+    /// An IDR I-slice header, with redundant_pic_cnt written when requested
+    fn idr_slice_rbsp(redundant_pic_cnt: Option<u32>) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        writer.write_ue(0); // first_mb_in_slice
+        writer.write_ue(7); // slice_type (I, picture-wide)
+        writer.write_ue(0); // pic_parameter_set_id
+        writer.write(0, 4); // frame_num
+        writer.write_ue(1); // idr_pic_id
+        if let Some(count) = redundant_pic_cnt {
+            writer.write_ue(count);
+        }
+        writer.write_flag(false); // no_output_of_prior_pics_flag
+        writer.write_flag(false); // long_term_reference_flag
+        writer.write_se(-4); // slice_qp_delta
+        writer.write_ue(1); // disable_deblocking_filter_idc
+        writer.write_rbsp_trailing_bits();
+        writer.into_bytes()
+    }
+
+    #[test]
+    fn test_redundant_pic_cnt_present() -> Result<()> {
+        let store = store_with(true)?;
+        let rbsp = idr_slice_rbsp(Some(2));
+        let nalu = NaluHeader::new(0x65)?;
+
+        let header = SliceHeader::parse(&mut BitReader::from_bytes(&rbsp), &nalu, &store)?;
+        assert_eq!(header.redundant_pic_cnt, Some(2));
+        assert_eq!(header.slice_qp_delta, -4);
+        assert_eq!(header.disable_deblocking_filter_idc, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_slice_group_change_cycle() -> Result<()> {
+        let mut store = store_with(false)?;
+        let mut pps = store
+            .pps(0)
+            .cloned()
+            .ok_or_else(|| anyhow!("PPS 0 is missing"))?;
+        pps.num_slice_groups_minus1 = 1;
+        pps.slice_group_map_type = 3;
+        // 16 map units at 3 per cycle: up to 6 cycles, in 3 bits
+        pps.slice_group_change_rate_minus1 = 2;
+        store.insert_pps(pps);
+
+        let parse = |store: &ParameterSetStore, cycle: u32| {
+            let mut writer = BitWriter::new();
+            writer.write_ue(0); // first_mb_in_slice
+            writer.write_ue(7); // slice_type
+            writer.write_ue(0); // pic_parameter_set_id
+            writer.write(0, 4); // frame_num
+            writer.write_ue(0); // idr_pic_id
+            writer.write_flag(false); // no_output_of_prior_pics_flag
+            writer.write_flag(false); // long_term_reference_flag
+            writer.write_se(0); // slice_qp_delta
+            writer.write_ue(1); // disable_deblocking_filter_idc
+            writer.write(cycle, 3);
+            writer.write_rbsp_trailing_bits();
+            let nalu = NaluHeader::new(0x65)?;
+            SliceHeader::parse(
+                &mut BitReader::from_bytes(&writer.into_bytes()),
+                &nalu,
+                store,
+            )
+        };
+
+        assert_eq!(parse(&store, 5)?.slice_group_change_cycle, 5);
+        assert!(parse(&store, 7).is_err());
+
+        // PicSizeInMapUnits of an SPS built by hand can pass 32 bits, and so does the cycle's
+        // width, which is an error rather than an overflow
+        let mut sps = store
+            .sps(0)
+            .cloned()
+            .ok_or_else(|| anyhow!("SPS 0 is missing"))?;
+        sps.pic_width_in_mbs_minus1 = 69999;
+        sps.pic_height_in_map_units_minus1 = 69999;
+        store.insert_sps(sps);
+        let mut pps = store
+            .pps(0)
+            .cloned()
+            .ok_or_else(|| anyhow!("PPS 0 is missing"))?;
+        pps.slice_group_change_rate_minus1 = 0;
+        store.insert_pps(pps);
+        let err = parse(&store, 0).unwrap_err();
+        assert_eq!(err.to_string(), "u(v) fields are at most 32 bits, not 33");
+        Ok(())
+    }
+
+    #[test]
+    fn test_redundant_pic_cnt_absent() -> Result<()> {
+        let store = store_with(false)?;
+        let rbsp = idr_slice_rbsp(None);
+        let nalu = NaluHeader::new(0x65)?;
+
+        let header = SliceHeader::parse(&mut BitReader::from_bytes(&rbsp), &nalu, &store)?;
+        assert_eq!(header.redundant_pic_cnt, None);
+        assert_eq!(header.kind(), SliceType::I);
+        assert_eq!(header.idr_pic_id, 1);
+        assert_eq!(header.slice_qp_delta, -4);
+        assert_eq!(header.disable_deblocking_filter_idc, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_new_picture_for_each_field() -> Result<()> {
+        let idr = NaluHeader::new(0x65)?;
+        let reference = NaluHeader::new(0x41)?;
+        let non_reference = NaluHeader::new(0x01)?;
+        let base = SliceHeader {
+            first_mb_in_slice: 5,
+            frame_num: 3,
+            ..Default::default()
+        };
+
+        // Another slice of the same picture
+        let next_slice = SliceHeader {
+            first_mb_in_slice: 10,
+            slice_qp_delta: 2,
+            ..base.clone()
+        };
+        assert!(!is_new_picture(&base, &next_slice, &reference, &reference));
+        // nal_ref_idc only matters when one of them is 0
+        let other_reference = NaluHeader::new(0x61)?;
+        assert!(!is_new_picture(&base, &base, &reference, &other_reference));
+
+        let with = |change: fn(&mut SliceHeader)| {
+            let mut header = base.clone();
+            change(&mut header);
+            header
+        };
+        let changed = [
+            ("frame_num", with(|h| h.frame_num = 4)),
+            ("pic_parameter_set_id", with(|h| h.pic_parameter_set_id = 1)),
+            ("field_pic_flag", with(|h| h.field_pic_flag = true)),
+            ("pic_order_cnt_lsb", with(|h| h.pic_order_cnt_lsb = 2)),
+            (
+                "delta_pic_order_cnt_bottom",
+                with(|h| h.delta_pic_order_cnt_bottom = -1),
+            ),
+            (
+                "delta_pic_order_cnt[0]",
+                with(|h| h.delta_pic_order_cnt[0] = 1),
+            ),
+            (
+                "delta_pic_order_cnt[1]",
+                with(|h| h.delta_pic_order_cnt[1] = 1),
+            ),
+        ];
+        for (field, cur) in &changed {
+            assert!(
+                is_new_picture(&base, cur, &reference, &reference),
+                "{} changed",
+                field
+            );
+        }
+
+        // idr_pic_id is only compared between two IDR pictures
+        let next_idr = with(|h| h.idr_pic_id = 1);
+        assert!(is_new_picture(&base, &next_idr, &idr, &idr));
+        assert!(!is_new_picture(&base, &base, &idr, &idr));
+
+        // bottom_field_flag is only compared between fields
+        let top = SliceHeader {
+            field_pic_flag: true,
+            ..base.clone()
+        };
+        let bottom = SliceHeader {
+            bottom_field_flag: true,
+            ..top.clone()
+        };
+        assert!(is_new_picture(&top, &bottom, &reference, &reference));
+
+        // nal_ref_idc becoming 0, and IdrPicFlag changing
+        assert!(is_new_picture(&base, &base, &reference, &non_reference));
+        assert!(is_new_picture(&base, &base, &reference, &idr));
+        Ok(())
+    }
+
+    #[test]
+    fn test_p_slice_with_marking_and_modification() -> Result<()> {
+        let store = store_with(false)?;
+        let mut writer = BitWriter::new();
+        writer.write_ue(5); // first_mb_in_slice
+        writer.write_ue(0); // slice_type P
+        writer.write_ue(0);
+        writer.write(3, 4); // frame_num
+        writer.write_flag(true); // num_ref_idx_active_override_flag
+        writer.write_ue(1);
+        writer.write_flag(true); // ref_pic_list_modification_flag_l0
+        writer.write_ue(0);
+        writer.write_ue(1);
+        writer.write_ue(3);
+        writer.write_flag(true); // adaptive_ref_pic_marking_mode_flag
+        writer.write_ue(1);
+        writer.write_ue(0);
+        writer.write_ue(0);
+        writer.write_se(2); // slice_qp_delta
+        writer.write_ue(0); // disable_deblocking_filter_idc
+        writer.write_se(-1);
+        writer.write_se(1);
+        writer.write_rbsp_trailing_bits();
+        let rbsp = writer.into_bytes();
+        let nalu = NaluHeader::new(0x41)?;
+
+        let header = SliceHeader::parse(&mut BitReader::from_bytes(&rbsp), &nalu, &store)?;
+        assert_eq!(header.kind(), SliceType::P);
+        assert_eq!(header.first_mb_in_slice, 5);
+        assert_eq!(header.frame_num, 3);
+        assert_eq!(header.num_ref_idx_l0_active_minus1, 1);
+        assert_eq!(
+            header.ref_pic_list_modification_l0,
+            &[RefPicListModificationOp {
+                modification_of_pic_nums_idc: 0,
+                value: 1
+            }]
+        );
+        let marking = header
+            .dec_ref_pic_marking
+            .expect("Reference slice has marking");
+        assert_eq!(marking.operations.len(), 1);
+        assert_eq!(marking.operations[0].memory_management_control_operation, 1);
+        assert_eq!(header.slice_qp_delta, 2);
+        assert_eq!(header.slice_alpha_c0_offset_div2, -1);
+        assert_eq!(header.slice_beta_offset_div2, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_modifications_limited_to_the_active_reference_count() -> Result<()> {
+        let store = store_with(false)?;
+        let nalu = NaluHeader::new(0x01)?;
+        // The PPS default of one active reference, so a single operation fits
+        let slice = |operations: u32| {
+            let mut writer = BitWriter::new();
+            writer.write_ue(0); // first_mb_in_slice
+            writer.write_ue(0); // slice_type P
+            writer.write_ue(0); // pic_parameter_set_id
+            writer.write(1, 4); // frame_num
+            writer.write_flag(false); // num_ref_idx_active_override_flag
+            writer.write_flag(true); // ref_pic_list_modification_flag_l0
+            for _ in 0..operations {
+                writer.write_ue(0); // modification_of_pic_nums_idc
+                writer.write_ue(0); // abs_diff_pic_num_minus1
+            }
+            writer.write_ue(3);
+            writer.write_se(0); // slice_qp_delta
+            writer.write_ue(1); // disable_deblocking_filter_idc
+            writer.write_rbsp_trailing_bits();
+            writer.into_bytes()
+        };
+
+        let header = SliceHeader::parse(&mut BitReader::from_bytes(&slice(1)), &nalu, &store)?;
+        assert_eq!(header.ref_pic_list_modification_l0.len(), 1);
+        let err =
+            SliceHeader::parse(&mut BitReader::from_bytes(&slice(2)), &nalu, &store).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Too many reference picture list modifications, expected at most 1"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pred_weight_table_ranges() -> Result<()> {
+        let mut store = store_with(false)?;
+        let mut pps = Pps::parse(&mut BitReader::from_bytes(&baseline_pps_rbsp(0, 0)), &store)?;
+        pps.weighted_pred_flag = true;
+        store.insert_pps(pps);
+        let nalu = NaluHeader::new(0x01)?;
+        let slice = |luma_log2_weight_denom: u32, luma: (i32, i32), cb: (i32, i32)| {
+            let mut writer = BitWriter::new();
+            writer.write_ue(0); // first_mb_in_slice
+            writer.write_ue(0); // slice_type P
+            writer.write_ue(0); // pic_parameter_set_id
+            writer.write(1, 4); // frame_num
+            writer.write_flag(false); // num_ref_idx_active_override_flag
+            writer.write_flag(false); // ref_pic_list_modification_flag_l0
+            writer.write_ue(luma_log2_weight_denom);
+            writer.write_ue(2); // chroma_log2_weight_denom
+            writer.write_flag(true); // luma_weight_l0_flag
+            writer.write_se(luma.0);
+            writer.write_se(luma.1);
+            writer.write_flag(true); // chroma_weight_l0_flag
+            writer.write_se(cb.0);
+            writer.write_se(cb.1);
+            writer.write_se(4); // Cr weight
+            writer.write_se(0); // Cr offset
+            writer.write_se(0); // slice_qp_delta
+            writer.write_ue(1); // disable_deblocking_filter_idc
+            writer.write_rbsp_trailing_bits();
+            writer.into_bytes()
+        };
+        let parse =
+            |rbsp: Vec<u8>| SliceHeader::parse(&mut BitReader::from_bytes(&rbsp), &nalu, &store);
+
+        let header = parse(slice(7, (-128, 127), (127, -128)))?;
+        let table = header.pred_weight_table.expect("weighted_pred_flag is set");
+        assert_eq!(
+            (table.luma_log2_weight_denom, table.chroma_log2_weight_denom),
+            (7, 2)
+        );
+        assert_eq!(
+            table.luma_l0,
+            &[Weight {
+                weight: -128,
+                offset: 127
+            }]
+        );
+        assert_eq!(
+            table.chroma_l0,
+            &[[
+                Weight {
+                    weight: 127,
+                    offset: -128
+                },
+                Weight {
+                    weight: 4,
+                    offset: 0
+                }
+            ]]
+        );
+
+        // A denominator past 7 would also overflow the default weight's shift
+        for (rbsp, message) in [
+            (
+                slice(40, (0, 0), (0, 0)),
+                "Invalid luma_log2_weight_denom: 40, expected at most 7",
+            ),
+            (
+                slice(0, (128, 0), (0, 0)),
+                "Invalid luma_weight: 128, expected -128..=127",
+            ),
+            (
+                slice(0, (0, -129), (0, 0)),
+                "Invalid luma_offset: -129, expected -128..=127",
+            ),
+            (
+                slice(0, (0, 0), (-129, 0)),
+                "Invalid chroma_weight: -129, expected -128..=127",
+            ),
+            (
+                slice(0, (0, 0), (0, 128)),
+                "Invalid chroma_offset: 128, expected -128..=127",
+            ),
+        ] {
+            assert_eq!(parse(rbsp).unwrap_err().to_string(), message);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_poc_type1_deltas() -> Result<()> {
+        let mut store = store_with(false)?;
+        let mut sps = store.sps(0).cloned().expect("SPS 0 is stored");
+        sps.pic_order_cnt_type = 1;
+        sps.delta_pic_order_always_zero_flag = false;
+        store.insert_sps(sps);
+        let mut pps = store.pps(0).cloned().expect("PPS 0 is stored");
+        pps.bottom_field_pic_order_in_frame_present_flag = true;
+        store.insert_pps(pps);
+
+        let mut writer = BitWriter::new();
+        writer.write_ue(0); // first_mb_in_slice
+        writer.write_ue(7); // slice_type
+        writer.write_ue(0); // pic_parameter_set_id
+        writer.write(0, 4); // frame_num
+        writer.write_ue(0); // idr_pic_id
+        writer.write_se(-3); // delta_pic_order_cnt[0]
+        writer.write_se(5); // delta_pic_order_cnt[1]
+        writer.write_flag(false); // no_output_of_prior_pics_flag
+        writer.write_flag(false); // long_term_reference_flag
+        writer.write_se(0); // slice_qp_delta
+        writer.write_ue(1); // disable_deblocking_filter_idc
+        writer.write_rbsp_trailing_bits();
+        let rbsp = writer.into_bytes();
+        let nalu = NaluHeader::new(0x65)?;
+
+        let header = SliceHeader::parse(&mut BitReader::from_bytes(&rbsp), &nalu, &store)?;
+        assert_eq!(header.delta_pic_order_cnt, [-3, 5]);
+        assert_eq!(header.slice_qp_delta, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_first_mb_in_slice_past_the_picture() -> Result<()> {
+        let store = store_with(false)?;
+        let nalu = NaluHeader::new(0x65)?;
+        let mut writer = BitWriter::new();
+        writer.write_ue(16); // first_mb_in_slice, one past the 4x4 picture
+        writer.write_ue(7); // slice_type
+        writer.write_ue(0); // pic_parameter_set_id
+        writer.write(0, 4); // frame_num
+        writer.write_rbsp_trailing_bits();
+
+        let err = SliceHeader::parse(
+            &mut BitReader::from_bytes(&writer.into_bytes()),
+            &nalu,
+            &store,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid first_mb_in_slice: 16, the picture has 16 macroblocks"
+        );
+
+        // An SPS that wasn't parsed can hold sizes whose PicSizeInMbs doesn't fit in 32 bits
+        let mut store = store_with(false)?;
+        let mut sps = store
+            .sps(0)
+            .cloned()
+            .ok_or_else(|| anyhow!("SPS 0 is missing"))?;
+        sps.pic_width_in_mbs_minus1 = 69999;
+        sps.pic_height_in_map_units_minus1 = 69999;
+        store.insert_sps(sps);
+        let mut writer = BitWriter::new();
+        writer.write_ue(1 << 31); // first_mb_in_slice
+        writer.write_ue(7); // slice_type
+        writer.write_ue(0); // pic_parameter_set_id
+        writer.write(0, 4); // frame_num
+        writer.write_ue(0); // idr_pic_id
+        writer.write_flag(false); // no_output_of_prior_pics_flag
+        writer.write_flag(false); // long_term_reference_flag
+        writer.write_se(0); // slice_qp_delta
+        writer.write_ue(1); // disable_deblocking_filter_idc
+        writer.write_rbsp_trailing_bits();
+        let header = SliceHeader::parse(
+            &mut BitReader::from_bytes(&writer.into_bytes()),
+            &nalu,
+            &store,
+        )?;
+        assert_eq!(header.first_mb_in_slice, 1 << 31);
+        Ok(())
+    }
+
+    #[test]
+    fn test_out_of_range_fields_are_named() -> Result<()> {
+        let store = store_with(false)?;
+        let parse = |nalu_header: u8, write: &dyn Fn(&mut BitWriter)| -> Result<SliceHeader> {
+            let mut writer = BitWriter::new();
+            writer.write_ue(0); // first_mb_in_slice
+            write(&mut writer);
+            writer.write_rbsp_trailing_bits();
+            let nalu = NaluHeader::new(nalu_header)?;
+            SliceHeader::parse(
+                &mut BitReader::from_bytes(&writer.into_bytes()),
+                &nalu,
+                &store,
+            )
+        };
+        let idr = |idr_pic_id, disable_deblocking_filter_idc| {
+            move |writer: &mut BitWriter| {
+                writer.write_ue(7); // slice_type
+                writer.write_ue(0); // pic_parameter_set_id
+                writer.write(0, 4); // frame_num
+                writer.write_ue(idr_pic_id);
+                writer.write_flag(false); // no_output_of_prior_pics_flag
+                writer.write_flag(false); // long_term_reference_flag
+                writer.write_se(0); // slice_qp_delta
+                writer.write_ue(disable_deblocking_filter_idc);
+                writer.write_se(0); // slice_alpha_c0_offset_div2
+                writer.write_se(0); // slice_beta_offset_div2
+            }
+        };
+
+        let header = parse(0x65, &idr(65535, 2))?;
+        assert_eq!(
+            (header.idr_pic_id, header.disable_deblocking_filter_idc),
+            (65535, 2)
+        );
+        let err = parse(0x65, &idr(65536, 1)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid idr_pic_id in slice header: 65536, expected at most 65535"
+        );
+        let err = parse(0x65, &idr(0, 3)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid disable_deblocking_filter_idc in slice header: 3, expected at most 2"
+        );
+
+        let err = parse(0x65, &|writer| writer.write_ue(10)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid slice_type in slice header: 10, expected at most 9"
+        );
+
+        let err = parse(0x41, &|writer| {
+            writer.write_ue(5); // slice_type, P
+            writer.write_ue(0); // pic_parameter_set_id
+            writer.write(1, 4); // frame_num
+            writer.write_flag(true); // num_ref_idx_active_override_flag
+            writer.write_ue(32); // num_ref_idx_l0_active_minus1
+        })
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid num_ref_idx_l0_active_minus1 in slice header: 32, expected at most 31"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_slice_qp_out_of_range() -> Result<()> {
+        let nalu = NaluHeader::new(0x65)?;
+        let slice = |slice_qp_delta| {
+            let mut writer = BitWriter::new();
+            writer.write_ue(0); // first_mb_in_slice
+            writer.write_ue(7); // slice_type
+            writer.write_ue(0); // pic_parameter_set_id
+            writer.write(0, 4); // frame_num
+            writer.write_ue(0); // idr_pic_id
+            writer.write_flag(false); // no_output_of_prior_pics_flag
+            writer.write_flag(false); // long_term_reference_flag
+            writer.write_se(slice_qp_delta);
+            writer.write_ue(1); // disable_deblocking_filter_idc
+            writer.write_rbsp_trailing_bits();
+            writer.into_bytes()
+        };
+
+        // pic_init_qp is 26, so SliceQPY spans 0..=51 at 8 bits
+        let store = store_with(false)?;
+        for slice_qp_delta in [-26, 25] {
+            let header = SliceHeader::parse(
+                &mut BitReader::from_bytes(&slice(slice_qp_delta)),
+                &nalu,
+                &store,
+            )?;
+            assert_eq!(header.slice_qp_delta, slice_qp_delta);
+        }
+
+        let err =
+            SliceHeader::parse(&mut BitReader::from_bytes(&slice(-27)), &nalu, &store).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid slice_qp_delta: -27, SliceQPY -1 is outside 0..=51"
+        );
+        let err =
+            SliceHeader::parse(&mut BitReader::from_bytes(&slice(26)), &nalu, &store).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid slice_qp_delta: 26, SliceQPY 52 is outside 0..=51"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_pps() {
+        let rbsp = idr_slice_rbsp(None);
+        let nalu = NaluHeader::new(0x65).unwrap();
+        let err = SliceHeader::parse(
+            &mut BitReader::from_bytes(&rbsp),
+            &nalu,
+            &ParameterSetStore::new(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("missing PPS"));
+    }
+}
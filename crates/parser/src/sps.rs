@@ -1 +1,944 @@
+use crate::extension::ExtensionData;
+use crate::read_ue_max;
+use crate::scaling::{ScalingList, parse_scaling_lists, write_scaling_lists};
+use crate::vui::VuiParameters;
+use anyhow::{Result, anyhow};
+use bitstream::{BitReader, BitWriter};
 
+/// Profiles whose SPS carries the chroma format, bit depth and scaling matrix fields
+const HIGH_PROFILE_IDCS: [u8; 13] = [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135];
+
+/// Sqrt(MaxFS * 8) for the largest MaxFS in Table A-1, 139264 macroblocks. PicWidthInMbs and
+/// FrameHeightInMbs can't exceed it at any level (A.3.1).
+const MAX_PIC_DIMENSION_IN_MBS: u32 = 1055;
+
+/// Implemented as in 7.3.2.1.1 Sequence parameter set data syntax in Rec. ITU-T H.264 (04/2013)
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Sps {
+    pub profile_idc: u8,
+    /// constraint_set0_flag..constraint_set5_flag in the top six bits, followed by reserved_zero_2bits
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    pub seq_parameter_set_id: u32,
+    pub chroma_format_idc: u32,
+    pub separate_colour_plane_flag: bool,
+    pub bit_depth_luma_minus8: u32,
+    pub bit_depth_chroma_minus8: u32,
+    pub qpprime_y_zero_transform_bypass_flag: bool,
+    pub seq_scaling_matrix_present_flag: bool,
+    /// Empty unless `seq_scaling_matrix_present_flag` is set
+    pub seq_scaling_lists: Vec<ScalingList>,
+    pub log2_max_frame_num_minus4: u32,
+    pub pic_order_cnt_type: u32,
+    pub log2_max_pic_order_cnt_lsb_minus4: u32,
+    pub delta_pic_order_always_zero_flag: bool,
+    pub offset_for_non_ref_pic: i32,
+    pub offset_for_top_to_bottom_field: i32,
+    /// One entry per frame in the POC cycle (`num_ref_frames_in_pic_order_cnt_cycle`)
+    pub offset_for_ref_frame: Vec<i32>,
+    pub max_num_ref_frames: u32,
+    pub gaps_in_frame_num_value_allowed_flag: bool,
+    pub pic_width_in_mbs_minus1: u32,
+    pub pic_height_in_map_units_minus1: u32,
+    pub frame_mbs_only_flag: bool,
+    pub mb_adaptive_frame_field_flag: bool,
+    pub direct_8x8_inference_flag: bool,
+    pub frame_cropping_flag: bool,
+    pub frame_crop_left_offset: u32,
+    pub frame_crop_right_offset: u32,
+    pub frame_crop_top_offset: u32,
+    pub frame_crop_bottom_offset: u32,
+    pub vui_parameters: Option<VuiParameters>,
+    /// Anything between the VUI and rbsp_trailing_bits, kept for `write`
+    pub extension_data: ExtensionData,
+}
+
+impl Sps {
+    /// Parses an SPS from its RBSP, positioned just after the NALU header
+    pub fn parse(reader: &mut BitReader) -> Result<Self> {
+        let mut sps = Self::parse_data(reader)?;
+        sps.extension_data = ExtensionData::read(reader)?;
+        Ok(sps)
+    }
+
+    /// 7.3.2.1.1 seq_parameter_set_data(), which both kinds of SPS start with
+    fn parse_data(reader: &mut BitReader) -> Result<Self> {
+        // The three fixed bytes and the shortest seq_parameter_set_id
+        if !reader.has_bits(25) {
+            return Err(anyhow!(
+                "SPS is truncated: too short for profile_idc, constraint flags, level_idc and \
+                 seq_parameter_set_id"
+            ));
+        }
+
+        let mut sps = Sps {
+            profile_idc: reader.read(8)? as u8,
+            constraint_flags: reader.read(8)? as u8,
+            level_idc: reader.read(8)? as u8,
+            seq_parameter_set_id: read_ue_max(reader, 31, "seq_parameter_set_id in SPS")?,
+            chroma_format_idc: 1,
+            ..Default::default()
+        };
+
+        if HIGH_PROFILE_IDCS.contains(&sps.profile_idc) {
+            sps.chroma_format_idc = reader.read_ue()?;
+            if sps.chroma_format_idc > 3 {
+                return Err(anyhow!(
+                    "Invalid chroma_format_idc in SPS: {}",
+                    sps.chroma_format_idc
+                ));
+            }
+            if sps.chroma_format_idc == 3 {
+                sps.separate_colour_plane_flag = reader.read_flag()?;
+            }
+            sps.bit_depth_luma_minus8 = read_ue_max(reader, 6, "bit_depth_luma_minus8 in SPS")?;
+            sps.bit_depth_chroma_minus8 = read_ue_max(reader, 6, "bit_depth_chroma_minus8 in SPS")?;
+            sps.qpprime_y_zero_transform_bypass_flag = reader.read_flag()?;
+            sps.seq_scaling_matrix_present_flag = reader.read_flag()?;
+            if sps.seq_scaling_matrix_present_flag {
+                let count = if sps.chroma_format_idc != 3 { 8 } else { 12 };
+                sps.seq_scaling_lists = parse_scaling_lists(reader, count)?;
+            }
+        }
+
+        sps.log2_max_frame_num_minus4 =
+            read_ue_max(reader, 12, "log2_max_frame_num_minus4 in SPS")?;
+        sps.pic_order_cnt_type = reader.read_ue()?;
+        match sps.pic_order_cnt_type {
+            0 => {
+                sps.log2_max_pic_order_cnt_lsb_minus4 =
+                    read_ue_max(reader, 12, "log2_max_pic_order_cnt_lsb_minus4 in SPS")?
+            }
+            1 => {
+                sps.delta_pic_order_always_zero_flag = reader.read_flag()?;
+                sps.offset_for_non_ref_pic = reader.read_se()?;
+                sps.offset_for_top_to_bottom_field = reader.read_se()?;
+                let num_ref_frames_in_pic_order_cnt_cycle = reader.read_ue()?;
+                if num_ref_frames_in_pic_order_cnt_cycle > 255 {
+                    return Err(anyhow!(
+                        "Invalid num_ref_frames_in_pic_order_cnt_cycle in SPS: {}",
+                        num_ref_frames_in_pic_order_cnt_cycle
+                    ));
+                }
+                // Each offset_for_ref_frame takes at least one bit
+                let count = num_ref_frames_in_pic_order_cnt_cycle as usize;
+                if !reader.has_bits(count) {
+                    return Err(anyhow!(
+                        "SPS is truncated: too short for its {} offset_for_ref_frame values",
+                        count
+                    ));
+                }
+                sps.offset_for_ref_frame.reserve(count);
+                for i in 0..count {
+                    let offset = reader.read_se().map_err(|_| {
+                        anyhow!(
+                            "SPS is truncated: offset_for_ref_frame[{}] of {} is missing",
+                            i,
+                            count
+                        )
+                    })?;
+                    sps.offset_for_ref_frame.push(offset);
+                }
+            }
+            2 => {}
+            _ => {
+                return Err(anyhow!(
+                    "Invalid pic_order_cnt_type in SPS: {}",
+                    sps.pic_order_cnt_type
+                ));
+            }
+        }
+
+        sps.max_num_ref_frames = reader.read_ue()?;
+        sps.gaps_in_frame_num_value_allowed_flag = reader.read_flag()?;
+        sps.pic_width_in_mbs_minus1 = read_ue_max(
+            reader,
+            MAX_PIC_DIMENSION_IN_MBS - 1,
+            "pic_width_in_mbs_minus1 in SPS",
+        )?;
+        sps.pic_height_in_map_units_minus1 = read_ue_max(
+            reader,
+            MAX_PIC_DIMENSION_IN_MBS - 1,
+            "pic_height_in_map_units_minus1 in SPS",
+        )?;
+        sps.frame_mbs_only_flag = reader.read_flag()?;
+        if !sps.frame_mbs_only_flag {
+            sps.mb_adaptive_frame_field_flag = reader.read_flag()?;
+        }
+        sps.direct_8x8_inference_flag = reader.read_flag()?;
+
+        sps.frame_cropping_flag = reader.read_flag()?;
+        if sps.frame_cropping_flag {
+            sps.frame_crop_left_offset = reader.read_ue()?;
+            sps.frame_crop_right_offset = reader.read_ue()?;
+            sps.frame_crop_top_offset = reader.read_ue()?;
+            sps.frame_crop_bottom_offset = reader.read_ue()?;
+        }
+
+        if reader.read_flag()? {
+            sps.vui_parameters = Some(VuiParameters::parse(reader)?);
+        }
+
+        Ok(sps)
+    }
+
+    /// Writes the SPS RBSP, trailing bits included, so that `parse` reads back the same struct.
+    /// Fields the syntax doesn't carry for the profile or the flags set are skipped.
+    pub fn write(&self, writer: &mut BitWriter) {
+        writer.write(self.profile_idc as u32, 8);
+        writer.write(self.constraint_flags as u32, 8);
+        writer.write(self.level_idc as u32, 8);
+        writer.write_ue(self.seq_parameter_set_id);
+
+        if HIGH_PROFILE_IDCS.contains(&self.profile_idc) {
+            writer.write_ue(self.chroma_format_idc);
+            if self.chroma_format_idc == 3 {
+                writer.write_flag(self.separate_colour_plane_flag);
+            }
+            writer.write_ue(self.bit_depth_luma_minus8);
+            writer.write_ue(self.bit_depth_chroma_minus8);
+            writer.write_flag(self.qpprime_y_zero_transform_bypass_flag);
+            writer.write_flag(self.seq_scaling_matrix_present_flag);
+            if self.seq_scaling_matrix_present_flag {
+                write_scaling_lists(writer, &self.seq_scaling_lists);
+            }
+        }
+
+        writer.write_ue(self.log2_max_frame_num_minus4);
+        writer.write_ue(self.pic_order_cnt_type);
+        match self.pic_order_cnt_type {
+            0 => writer.write_ue(self.log2_max_pic_order_cnt_lsb_minus4),
+            1 => {
+                writer.write_flag(self.delta_pic_order_always_zero_flag);
+                writer.write_se(self.offset_for_non_ref_pic);
+                writer.write_se(self.offset_for_top_to_bottom_field);
+                writer.write_ue(self.offset_for_ref_frame.len() as u32);
+                for &offset in &self.offset_for_ref_frame {
+                    writer.write_se(offset);
+                }
+            }
+            _ => {}
+        }
+
+        writer.write_ue(self.max_num_ref_frames);
+        writer.write_flag(self.gaps_in_frame_num_value_allowed_flag);
+        writer.write_ue(self.pic_width_in_mbs_minus1);
+        writer.write_ue(self.pic_height_in_map_units_minus1);
+        writer.write_flag(self.frame_mbs_only_flag);
+        if !self.frame_mbs_only_flag {
+            writer.write_flag(self.mb_adaptive_frame_field_flag);
+        }
+        writer.write_flag(self.direct_8x8_inference_flag);
+
+        writer.write_flag(self.frame_cropping_flag);
+        if self.frame_cropping_flag {
+            writer.write_ue(self.frame_crop_left_offset);
+            writer.write_ue(self.frame_crop_right_offset);
+            writer.write_ue(self.frame_crop_top_offset);
+            writer.write_ue(self.frame_crop_bottom_offset);
+        }
+
+        writer.write_flag(self.vui_parameters.is_some());
+        if let Some(vui) = &self.vui_parameters {
+            vui.write(writer);
+        }
+        self.extension_data.write(writer);
+        writer.write_rbsp_trailing_bits();
+    }
+
+    /// Parses a subset SPS (7.3.2.1.3). Only the base `seq_parameter_set_data` is kept; the SVC/MVC
+    /// extension that follows it is left unread.
+    pub fn parse_subset(reader: &mut BitReader) -> Result<Self> {
+        Self::parse_data(reader)
+    }
+
+    pub fn id(&self) -> u32 {
+        self.seq_parameter_set_id
+    }
+
+    /// ChromaArrayType, as derived from `separate_colour_plane_flag` in 7.4.2.1.1
+    pub fn chroma_array_type(&self) -> u32 {
+        if self.separate_colour_plane_flag {
+            0
+        } else {
+            self.chroma_format_idc
+        }
+    }
+
+    /// The VUI sample aspect ratio, if the SPS specifies one
+    pub fn sample_aspect_ratio(&self) -> Option<(u16, u16)> {
+        self.vui_parameters
+            .as_ref()
+            .and_then(VuiParameters::sample_aspect_ratio)
+    }
+
+    /// MaxFrameNum = 2^(log2_max_frame_num_minus4 + 4)
+    pub fn max_frame_num(&self) -> u32 {
+        1 << (self.log2_max_frame_num_minus4 + 4)
+    }
+
+    pub fn pic_width_in_mbs(&self) -> u32 {
+        self.pic_width_in_mbs_minus1.saturating_add(1)
+    }
+
+    /// Whether the profile allows the 8x8 transform, which a PPS can then enable with
+    /// `transform_8x8_mode_flag`. Baseline, Main and Extended streams only use 4x4 transforms.
+    pub fn supports_8x8_transform(&self) -> bool {
+        HIGH_PROFILE_IDCS.contains(&self.profile_idc)
+    }
+
+    /// Whether the SPS carries scaling lists, which only High profile family SPSs can
+    pub fn has_scaling_matrix(&self) -> bool {
+        self.seq_scaling_matrix_present_flag
+    }
+
+    /// FrameHeightInMbs (7-18). A map unit is a macroblock pair unless every picture is a frame.
+    pub fn frame_height_in_mbs(&self) -> u32 {
+        (2 - self.frame_mbs_only_flag as u32)
+            .saturating_mul(self.pic_height_in_map_units_minus1.saturating_add(1))
+    }
+
+    /// QpBdOffsetY (7-4)
+    pub fn qp_bd_offset_y(&self) -> i32 {
+        6 * self.bit_depth_luma_minus8 as i32
+    }
+
+    /// Width and height of a decoded frame in luma samples, before cropping
+    pub fn coded_resolution(&self) -> (u32, u32) {
+        (
+            self.pic_width_in_mbs().saturating_mul(16),
+            self.frame_height_in_mbs().saturating_mul(16),
+        )
+    }
+
+    /// The DPB size in frames: MaxDpbFrames of the level (A.3.1, A.3.2), capped by the VUI
+    /// `max_dec_frame_buffering` when a bitstream restriction is present
+    pub fn max_dpb_frames(&self) -> u32 {
+        let width_in_mbs = self.pic_width_in_mbs_minus1 as u64 + 1;
+        let height_in_mbs = self.frame_height_in_mbs() as u64;
+        let level_frames = match max_dpb_mbs(
+            self.level_idc,
+            self.profile_idc,
+            self.constraint_set3_flag(),
+        ) {
+            Some(mbs) => (mbs / (width_in_mbs * height_in_mbs)).min(16) as u32,
+            None => 16,
+        };
+
+        match &self.vui_parameters {
+            Some(vui) if vui.bitstream_restriction_flag => {
+                level_frames.min(vui.max_dec_frame_buffering)
+            }
+            // max_dec_frame_buffering is inferred as 0 for intra profiles (E.2.1)
+            _ if self.intra_only() => 0,
+            _ => level_frames,
+        }
+    }
+
+    /// The VUI `max_num_reorder_frames`, or its inferred value without a bitstream restriction
+    /// (E.2.1): 0 for intra profiles, MaxDpbFrames otherwise. Absent isn't 0, which would output
+    /// pictures coded out of order too early.
+    pub fn max_num_reorder_frames(&self) -> u32 {
+        match &self.vui_parameters {
+            Some(vui) if vui.bitstream_restriction_flag => vui.max_num_reorder_frames,
+            _ => self.max_dpb_frames(),
+        }
+    }
+
+    /// Whether constraint_set3_flag marks an intra-only stream, for the profiles E.2.1 lists
+    fn intra_only(&self) -> bool {
+        self.constraint_set3_flag() && [44, 86, 100, 110, 122, 244].contains(&self.profile_idc)
+    }
+
+    fn constraint_set3_flag(&self) -> bool {
+        self.constraint_flags & 0b0001_0000 != 0
+    }
+
+    /// Whether switching from this SPS to `other` changes the frame size, sample format or DPB
+    /// size, so frame buffers and the DPB can't carry over. Such a switch may only happen at an
+    /// IDR picture (7.4.1.2.1).
+    pub fn requires_reinit(&self, other: &Sps) -> bool {
+        self.pic_width_in_mbs_minus1 != other.pic_width_in_mbs_minus1
+            || self.pic_height_in_map_units_minus1 != other.pic_height_in_map_units_minus1
+            || self.frame_mbs_only_flag != other.frame_mbs_only_flag
+            || self.chroma_array_type() != other.chroma_array_type()
+            || self.bit_depth_luma_minus8 != other.bit_depth_luma_minus8
+            || self.bit_depth_chroma_minus8 != other.bit_depth_chroma_minus8
+            || self.max_dpb_frames() != other.max_dpb_frames()
+    }
+}
+
+/// MaxDpbMbs of a level_idc (Table A-1). Level 1b shares level 1's limit whether it's signalled
+/// as level_idc 9 or, in the Baseline, Main and Extended profiles, as 11 with
+/// constraint_set3_flag (A.3.1, A.3.2).
+fn max_dpb_mbs(level_idc: u8, profile_idc: u8, constraint_set3_flag: bool) -> Option<u64> {
+    let level_1b = level_idc == 11 && constraint_set3_flag && [66, 77, 88].contains(&profile_idc);
+    Some(match level_idc {
+        9 | 10 => 396,
+        11 if level_1b => 396,
+        11 => 900,
+        12 | 13 | 20 => 2376,
+        21 => 4752,
+        22 | 30 => 8100,
+        31 => 18000,
+        32 => 20480,
+        40 | 41 => 32768,
+        42 => 34816,
+        50 => 110400,
+        51 | 52 => 184320,
+        60..=62 => 696320,
+        _ => return None,
+    })
+}
+
+/// Implemented as in 7.3.2.1.2 Sequence parameter set extension RBSP syntax
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SpsExtension {
+    pub seq_parameter_set_id: u32,
+    pub aux_format_idc: u32,
+    pub bit_depth_aux_minus8: u32,
+    pub alpha_incr_flag: bool,
+    pub alpha_opaque_value: u32,
+    pub alpha_transparent_value: u32,
+    pub additional_extension_flag: bool,
+}
+
+impl SpsExtension {
+    pub fn parse(reader: &mut BitReader) -> Result<Self> {
+        let mut ext = SpsExtension {
+            seq_parameter_set_id: read_ue_max(reader, 31, "seq_parameter_set_id in SPS extension")?,
+            aux_format_idc: reader.read_ue()?,
+            ..Default::default()
+        };
+
+        if ext.aux_format_idc != 0 {
+            ext.bit_depth_aux_minus8 =
+                read_ue_max(reader, 4, "bit_depth_aux_minus8 in SPS extension")?;
+            ext.alpha_incr_flag = reader.read_flag()?;
+            let alpha_bits = ext.bit_depth_aux_minus8 as usize + 9;
+            ext.alpha_opaque_value = reader.read_uv(alpha_bits)?;
+            ext.alpha_transparent_value = reader.read_uv(alpha_bits)?;
+        }
+        ext.additional_extension_flag = reader.read_flag()?;
+
+        Ok(ext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::baseline_sps_rbsp;
+    use bitstream::avcc::AVCHeader;
+    use bitstream::rbsp::{ebsp_to_rbsp_cow, rbsp_to_ebsp};
+    use std::borrow::Cow;
+
+    #[test]
+    fn test_parse_baseline_sps() -> Result<()> {
+        let rbsp = baseline_sps_rbsp(0, 20, 15);
+        let sps = Sps::parse(&mut BitReader::from_bytes(&rbsp))?;
+
+        assert_eq!(sps.profile_idc, 66);
+        assert_eq!(sps.seq_parameter_set_id, 0);
+        assert_eq!(sps.chroma_format_idc, 1);
+        assert_eq!(sps.pic_width_in_mbs(), 20);
+        assert_eq!(sps.pic_height_in_map_units_minus1, 14);
+        assert!(sps.frame_mbs_only_flag);
+        assert!(sps.vui_parameters.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_high_profile_sps() -> Result<()> {
+        let mut writer = BitWriter::new();
+        writer.write(100, 8); // profile_idc
+        writer.write(0, 8);
+        writer.write(40, 8); // level_idc
+        writer.write_ue(3); // seq_parameter_set_id
+        writer.write_ue(1); // chroma_format_idc
+        writer.write_ue(0);
+        writer.write_ue(0);
+        writer.write_flag(false); // qpprime_y_zero_transform_bypass_flag
+        writer.write_flag(true); // seq_scaling_matrix_present_flag
+        writer.write_flag(true);
+        writer.write_se(-8); // list 0 uses the default matrix
+        for _ in 1..8 {
+            writer.write_flag(false);
+        }
+        writer.write_ue(0); // log2_max_frame_num_minus4
+        writer.write_ue(1); // pic_order_cnt_type
+        writer.write_flag(false);
+        writer.write_se(-2);
+        writer.write_se(1);
+        writer.write_ue(2);
+        writer.write_se(4);
+        writer.write_se(-4);
+        writer.write_ue(4); // max_num_ref_frames
+        writer.write_flag(false);
+        writer.write_ue(119);
+        writer.write_ue(33);
+        writer.write_flag(false); // frame_mbs_only_flag
+        writer.write_flag(true); // mb_adaptive_frame_field_flag
+        writer.write_flag(true);
+        writer.write_flag(true); // frame_cropping_flag
+        writer.write_ue(0);
+        writer.write_ue(0);
+        writer.write_ue(0);
+        writer.write_ue(4);
+        writer.write_flag(false); // vui_parameters_present_flag
+        writer.write_rbsp_trailing_bits();
+        let rbsp = writer.into_bytes();
+
+        let sps = Sps::parse(&mut BitReader::from_bytes(&rbsp))?;
+        assert_eq!(sps.seq_parameter_set_id, 3);
+        assert_eq!(sps.seq_scaling_lists.len(), 8);
+        assert_eq!(sps.seq_scaling_lists[0], ScalingList::UseDefault);
+        assert_eq!(sps.offset_for_non_ref_pic, -2);
+        assert_eq!(sps.offset_for_ref_frame, &[4, -4]);
+        assert!(!sps.frame_mbs_only_flag);
+        assert!(sps.mb_adaptive_frame_field_flag);
+        assert_eq!(sps.frame_crop_bottom_offset, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_8x8_transform_and_scaling_matrix_predicates() -> Result<()> {
+        let base = Sps::parse(&mut BitReader::from_bytes(&baseline_sps_rbsp(0, 20, 15)))?;
+        let parse_as = |profile_idc, seq_scaling_lists: Option<Vec<ScalingList>>| {
+            let sps = Sps {
+                profile_idc,
+                seq_scaling_matrix_present_flag: seq_scaling_lists.is_some(),
+                seq_scaling_lists: seq_scaling_lists.unwrap_or_default(),
+                ..base.clone()
+            };
+            let mut writer = BitWriter::new();
+            sps.write(&mut writer);
+            Sps::parse(&mut BitReader::from_bytes(&writer.into_bytes()))
+        };
+
+        // Baseline and Main only have the 4x4 transform and flat scaling
+        for profile_idc in [66, 77] {
+            let sps = parse_as(profile_idc, None)?;
+            assert!(!sps.supports_8x8_transform());
+            assert!(!sps.has_scaling_matrix());
+        }
+
+        // High allows the 8x8 transform with or without a scaling matrix
+        let sps = parse_as(100, None)?;
+        assert!(sps.supports_8x8_transform());
+        assert!(!sps.has_scaling_matrix());
+        let sps = parse_as(100, Some(vec![ScalingList::UseDefault; 8]))?;
+        assert!(sps.supports_8x8_transform());
+        assert!(sps.has_scaling_matrix());
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_round_trip() -> Result<()> {
+        let round_trip = |sps: &Sps| -> Result<(Sps, Vec<u8>)> {
+            let mut writer = BitWriter::new();
+            sps.write(&mut writer);
+            let rbsp = writer.into_bytes();
+            Ok((Sps::parse(&mut BitReader::from_bytes(&rbsp))?, rbsp))
+        };
+
+        // A parsed SPS is written back bit for bit
+        let rbsp = baseline_sps_rbsp(1, 20, 15);
+        let sps = Sps::parse(&mut BitReader::from_bytes(&rbsp))?;
+        assert_eq!(round_trip(&sps)?, (sps, rbsp));
+
+        let interlaced = Sps {
+            profile_idc: 77,
+            constraint_flags: 0b0100_0000,
+            level_idc: 41,
+            seq_parameter_set_id: 7,
+            chroma_format_idc: 1,
+            log2_max_frame_num_minus4: 5,
+            pic_order_cnt_type: 1,
+            offset_for_non_ref_pic: -2,
+            offset_for_top_to_bottom_field: 1,
+            offset_for_ref_frame: vec![4, -4, 0],
+            max_num_ref_frames: 4,
+            pic_width_in_mbs_minus1: 119,
+            pic_height_in_map_units_minus1: 33,
+            frame_mbs_only_flag: false,
+            mb_adaptive_frame_field_flag: true,
+            frame_cropping_flag: true,
+            frame_crop_bottom_offset: 4,
+            vui_parameters: Some(VuiParameters {
+                aspect_ratio_info_present_flag: true,
+                aspect_ratio_idc: crate::vui::EXTENDED_SAR,
+                sar_width: 4,
+                sar_height: 3,
+                timing_info_present_flag: true,
+                num_units_in_tick: 1001,
+                time_scale: 60000,
+                nal_hrd_parameters: Some(crate::vui::HrdParameters {
+                    cpb_cnt_minus1: 1,
+                    bit_rate_value_minus1: vec![1000, 2000],
+                    cpb_size_value_minus1: vec![3000, 4000],
+                    cbr_flag: vec![false, true],
+                    cpb_removal_delay_length_minus1: 23,
+                    dpb_output_delay_length_minus1: 23,
+                    ..Default::default()
+                }),
+                pic_struct_present_flag: true,
+                bitstream_restriction_flag: true,
+                max_num_reorder_frames: 2,
+                max_dec_frame_buffering: 4,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(round_trip(&interlaced)?.0, interlaced);
+        assert_eq!(interlaced.sample_aspect_ratio(), Some((4, 3)));
+
+        let high_444 = Sps {
+            profile_idc: 244,
+            level_idc: 51,
+            chroma_format_idc: 3,
+            separate_colour_plane_flag: true,
+            bit_depth_luma_minus8: 2,
+            bit_depth_chroma_minus8: 2,
+            seq_scaling_matrix_present_flag: true,
+            seq_scaling_lists: (0..12)
+                .map(|i| match i % 3 {
+                    0 => ScalingList::NotPresent,
+                    1 => ScalingList::UseDefault,
+                    _ if i < 6 => ScalingList::Explicit(vec![i as u8 * 10; 16]),
+                    _ => ScalingList::Explicit(vec![i as u8 * 10; 64]),
+                })
+                .collect(),
+            pic_order_cnt_type: 0,
+            log2_max_pic_order_cnt_lsb_minus4: 2,
+            frame_mbs_only_flag: true,
+            direct_8x8_inference_flag: true,
+            vui_parameters: Some(VuiParameters {
+                video_signal_type_present_flag: true,
+                video_format: 5,
+                video_full_range_flag: true,
+                colour_description_present_flag: true,
+                colour_primaries: 9,
+                transfer_characteristics: 16,
+                matrix_coefficients: 9,
+                chroma_loc_info_present_flag: true,
+                chroma_sample_loc_type_top_field: 2,
+                chroma_sample_loc_type_bottom_field: 2,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(round_trip(&high_444)?.0, high_444);
+        Ok(())
+    }
+
+    #[test]
+    fn test_coded_resolution_doubles_map_units_of_interlaced_streams() {
+        let progressive = Sps {
+            pic_width_in_mbs_minus1: 119,
+            pic_height_in_map_units_minus1: 33,
+            frame_mbs_only_flag: true,
+            ..Default::default()
+        };
+        assert_eq!(progressive.frame_height_in_mbs(), 34);
+        assert_eq!(progressive.coded_resolution(), (1920, 544));
+
+        // The same map units are macroblock pairs when fields may be coded
+        let interlaced = Sps {
+            frame_mbs_only_flag: false,
+            ..progressive
+        };
+        assert_eq!(interlaced.frame_height_in_mbs(), 68);
+        assert_eq!(interlaced.coded_resolution(), (1920, 1088));
+    }
+
+    #[test]
+    fn test_max_dpb_frames() {
+        // 1920x1088 at level 4.0: 32768 / 8160 macroblocks
+        let mut sps = Sps {
+            level_idc: 40,
+            pic_width_in_mbs_minus1: 119,
+            pic_height_in_map_units_minus1: 67,
+            frame_mbs_only_flag: true,
+            ..Default::default()
+        };
+        assert_eq!(sps.max_dpb_frames(), 4);
+
+        sps.vui_parameters = Some(VuiParameters {
+            bitstream_restriction_flag: true,
+            max_dec_frame_buffering: 2,
+            ..Default::default()
+        });
+        assert_eq!(sps.max_dpb_frames(), 2);
+
+        // QCIF at level 3.0 would fit 81 frames, but the DPB never holds more than 16
+        let qcif = Sps {
+            level_idc: 30,
+            pic_width_in_mbs_minus1: 10,
+            pic_height_in_map_units_minus1: 8,
+            frame_mbs_only_flag: true,
+            ..Default::default()
+        };
+        assert_eq!(qcif.max_dpb_frames(), 16);
+    }
+
+    #[test]
+    fn test_max_dpb_frames_at_level_1b() {
+        // QCIF is 99 macroblocks, so level 1b (MaxDpbMbs 396) fits 4 frames and level 1.1 fits 9
+        let mut sps = Sps {
+            profile_idc: 66,
+            constraint_flags: 0b0001_0000,
+            level_idc: 11,
+            pic_width_in_mbs_minus1: 10,
+            pic_height_in_map_units_minus1: 8,
+            frame_mbs_only_flag: true,
+            ..Default::default()
+        };
+        assert_eq!(sps.max_dpb_frames(), 4);
+
+        sps.profile_idc = 77;
+        assert_eq!(sps.max_dpb_frames(), 4);
+
+        sps.level_idc = 9;
+        sps.constraint_flags = 0;
+        assert_eq!(sps.max_dpb_frames(), 4);
+
+        sps.level_idc = 11;
+        assert_eq!(sps.max_dpb_frames(), 9);
+    }
+
+    #[test]
+    fn test_max_num_reorder_frames_inference() {
+        let mut sps = Sps {
+            profile_idc: 100,
+            level_idc: 40,
+            pic_width_in_mbs_minus1: 119,
+            pic_height_in_map_units_minus1: 67,
+            frame_mbs_only_flag: true,
+            ..Default::default()
+        };
+        // Without a bitstream restriction, any picture in the DPB may be waiting for output
+        assert_eq!(sps.max_num_reorder_frames(), 4);
+        sps.vui_parameters = Some(VuiParameters::default());
+        assert_eq!(sps.max_num_reorder_frames(), 4);
+
+        sps.vui_parameters = Some(VuiParameters {
+            bitstream_restriction_flag: true,
+            max_num_reorder_frames: 1,
+            max_dec_frame_buffering: 3,
+            ..Default::default()
+        });
+        assert_eq!(sps.max_num_reorder_frames(), 1);
+
+        // High 10 Intra and the like never reorder
+        let intra = Sps {
+            profile_idc: 110,
+            constraint_flags: 0b0001_0000,
+            vui_parameters: None,
+            ..sps.clone()
+        };
+        assert_eq!(
+            (intra.max_num_reorder_frames(), intra.max_dpb_frames()),
+            (0, 0)
+        );
+        // constraint_set3_flag means level 1b for Baseline, not intra
+        let baseline = Sps {
+            profile_idc: 66,
+            ..intra
+        };
+        assert_eq!(baseline.max_num_reorder_frames(), 4);
+    }
+
+    #[test]
+    fn test_requires_reinit() {
+        let vga = Sps {
+            level_idc: 30,
+            pic_width_in_mbs_minus1: 39,
+            pic_height_in_map_units_minus1: 29,
+            frame_mbs_only_flag: true,
+            chroma_format_idc: 1,
+            ..Default::default()
+        };
+        let renumbered = Sps {
+            seq_parameter_set_id: 1,
+            log2_max_frame_num_minus4: 4,
+            ..vga.clone()
+        };
+        assert!(!vga.requires_reinit(&renumbered));
+
+        let hd = Sps {
+            pic_width_in_mbs_minus1: 79,
+            pic_height_in_map_units_minus1: 44,
+            ..vga.clone()
+        };
+        assert!(vga.requires_reinit(&hd));
+        let monochrome = Sps {
+            chroma_format_idc: 0,
+            ..vga.clone()
+        };
+        assert!(vga.requires_reinit(&monochrome));
+    }
+
+    #[test]
+    fn test_parse_sps_borrowed_from_avcc() -> Result<()> {
+        let mut sps_nalu = vec![0x67];
+        sps_nalu.extend(rbsp_to_ebsp(&baseline_sps_rbsp(0, 20, 15)));
+        let mut record = vec![1, 66, 0, 30, 0xFF, 0xE1];
+        record.extend((sps_nalu.len() as u16).to_be_bytes());
+        record.extend(&sps_nalu);
+        record.extend([0x01, 0x00, 0x01, 0x68]);
+
+        let header = AVCHeader::new(&record)?;
+        let nalu: &[u8] = header.sps_nalus()[0];
+        let rbsp = ebsp_to_rbsp_cow(&nalu[1..]);
+        assert!(matches!(rbsp, Cow::Borrowed(_)));
+        let sps = Sps::parse(&mut BitReader::from_bytes(&rbsp))?;
+        assert_eq!(sps.pic_width_in_mbs(), 20);
+        assert_eq!(header.pps_nalus(), [&[0x68][..]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_sps() {
+        let rbsp = &baseline_sps_rbsp(0, 20, 15)[..4];
+        assert!(Sps::parse(&mut BitReader::from_bytes(rbsp)).is_err());
+    }
+
+    #[test]
+    fn test_out_of_range_fields() {
+        let rbsp = baseline_sps_rbsp(32, 20, 15);
+        let err = Sps::parse(&mut BitReader::from_bytes(&rbsp)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid seq_parameter_set_id in SPS: 32, expected at most 31"
+        );
+
+        // MaxFrameNum would be 2^17
+        let mut writer = BitWriter::new();
+        writer.write(66, 8); // profile_idc
+        writer.write(0, 8); // constraint flags
+        writer.write(30, 8); // level_idc
+        writer.write_ue(0); // seq_parameter_set_id
+        writer.write_ue(13); // log2_max_frame_num_minus4
+        writer.write_rbsp_trailing_bits();
+        let err = Sps::parse(&mut BitReader::from_bytes(&writer.into_bytes())).unwrap_err();
+        assert!(err.to_string().contains("log2_max_frame_num_minus4"));
+
+        // No level allows a side of more than 1055 macroblocks
+        let sps = Sps::parse(&mut BitReader::from_bytes(&baseline_sps_rbsp(
+            0, 1055, 1055,
+        )));
+        assert_eq!(
+            sps.map(|sps| sps.coded_resolution()).ok(),
+            Some((16880, 16880))
+        );
+        let rbsp = baseline_sps_rbsp(0, 70000, 15);
+        let err = Sps::parse(&mut BitReader::from_bytes(&rbsp)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid pic_width_in_mbs_minus1 in SPS: 69999, expected at most 1054"
+        );
+        let rbsp = baseline_sps_rbsp(0, 20, 1056);
+        let err = Sps::parse(&mut BitReader::from_bytes(&rbsp)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid pic_height_in_map_units_minus1 in SPS: 1055, expected at most 1054"
+        );
+    }
+
+    #[test]
+    fn test_offset_for_ref_frame_cycle() -> Result<()> {
+        let write = |count: u32, offsets: &[i32]| {
+            let mut writer = BitWriter::new();
+            writer.write(77, 8); // profile_idc
+            writer.write(0, 8); // constraint flags
+            writer.write(30, 8); // level_idc
+            writer.write_ue(0); // seq_parameter_set_id
+            writer.write_ue(0); // log2_max_frame_num_minus4
+            writer.write_ue(1); // pic_order_cnt_type
+            writer.write_flag(false); // delta_pic_order_always_zero_flag
+            writer.write_se(-2); // offset_for_non_ref_pic
+            writer.write_se(1); // offset_for_top_to_bottom_field
+            writer.write_ue(count);
+            for &offset in offsets {
+                writer.write_se(offset);
+            }
+            writer.into_bytes()
+        };
+
+        // A complete SPS after a 3 entry cycle
+        let sps = Sps {
+            profile_idc: 77,
+            level_idc: 30,
+            pic_order_cnt_type: 1,
+            offset_for_non_ref_pic: -2,
+            offset_for_top_to_bottom_field: 1,
+            offset_for_ref_frame: vec![6, -300, 0],
+            ..Default::default()
+        };
+        let mut writer = BitWriter::new();
+        sps.write(&mut writer);
+        let parsed = Sps::parse(&mut BitReader::from_bytes(&writer.into_bytes()))?;
+        assert_eq!(parsed.offset_for_ref_frame, [6, -300, 0]);
+
+        // 200 entries claimed, where only two and a few bits are left
+        let rbsp = write(200, &[4, 4]);
+        let err = Sps::parse(&mut BitReader::from_bytes(&rbsp)).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("too short for its 200 offset_for_ref_frame")
+        );
+
+        // Enough bits for the count, but the last value runs off the end
+        let mut rbsp = write(3, &[1, 1000]);
+        rbsp.push(0);
+        let err = Sps::parse(&mut BitReader::from_bytes(&rbsp)).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("offset_for_ref_frame[2] of 3 is missing")
+        );
+
+        let err = Sps::parse(&mut BitReader::from_bytes(&write(256, &[]))).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("num_ref_frames_in_pic_order_cnt_cycle")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sps_without_its_fixed_prefix() {
+        let rbsp = &baseline_sps_rbsp(0, 20, 15)[..2];
+        let err = Sps::parse(&mut BitReader::from_bytes(rbsp)).unwrap_err();
+        assert!(err.to_string().contains("SPS is truncated"));
+        assert!(err.to_string().contains("level_idc"));
+    }
+
+    #[test]
+    fn test_absent_aspect_ratio_is_not_square() -> Result<()> {
+        let parse_sar = |vui_parameters: Option<VuiParameters>| -> Result<Option<(u16, u16)>> {
+            let sps = Sps {
+                profile_idc: 66,
+                level_idc: 30,
+                vui_parameters,
+                ..Default::default()
+            };
+            let mut writer = BitWriter::new();
+            sps.write(&mut writer);
+            let parsed = Sps::parse(&mut BitReader::from_bytes(&writer.into_bytes()))?;
+            Ok(parsed.sample_aspect_ratio())
+        };
+
+        // Neither a VUI without aspect_ratio_info nor a missing VUI specifies a SAR
+        assert_eq!(parse_sar(None)?, None);
+        assert_eq!(parse_sar(Some(VuiParameters::default()))?, None);
+        // While aspect_ratio_idc 1 says square samples explicitly
+        let square = VuiParameters {
+            aspect_ratio_info_present_flag: true,
+            aspect_ratio_idc: 1,
+            ..Default::default()
+        };
+        assert_eq!(parse_sar(Some(square))?, Some((1, 1)));
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+use crate::pps::Pps;
+use crate::sps::{Sps, SpsExtension};
+use anyhow::{Result, anyhow};
+use bitstream::annexb::split_annexb_nalus;
+use bitstream::rbsp::ebsp_to_rbsp_cow;
+use bitstream::{BitReader, NalUnitType, NaluHeader};
+use std::collections::HashMap;
+
+/// The parameter sets in effect for a slice: its PPS and the SPS that PPS refers to
+#[derive(Debug, Clone, Copy)]
+pub struct ActiveParams<'a> {
+    pub sps: &'a Sps,
+    pub pps: &'a Pps,
+}
+
+/// Holds every parameter set seen so far, keyed by id. A newly received set replaces any
+/// previous set with the same id.
+#[derive(Debug, Clone, Default)]
+pub struct ParameterSetStore {
+    sps: HashMap<u32, Sps>,
+    subset_sps: HashMap<u32, Sps>,
+    sps_extensions: HashMap<u32, SpsExtension>,
+    pps: HashMap<u32, Pps>,
+}
+
+impl ParameterSetStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert_sps(&mut self, sps: Sps) {
+        self.sps.insert(sps.id(), sps);
+    }
+
+    /// Subset SPSs (SVC/MVC) share the id space with each other, but not with regular SPSs
+    pub fn insert_subset_sps(&mut self, sps: Sps) {
+        self.subset_sps.insert(sps.id(), sps);
+    }
+
+    pub fn insert_sps_extension(&mut self, ext: SpsExtension) {
+        self.sps_extensions.insert(ext.seq_parameter_set_id, ext);
+    }
+
+    pub fn insert_pps(&mut self, pps: Pps) {
+        self.pps.insert(pps.id(), pps);
+    }
+
+    /// Removes an SPS, returning it. PPSs referring to it stay, but can't be activated until an
+    /// SPS with the same id arrives.
+    pub fn remove_sps(&mut self, id: u32) -> Option<Sps> {
+        self.sps.remove(&id)
+    }
+
+    pub fn remove_pps(&mut self, id: u32) -> Option<Pps> {
+        self.pps.remove(&id)
+    }
+
+    /// Drops every parameter set, as at a discontinuity where the old ids no longer apply
+    pub fn clear(&mut self) {
+        self.sps.clear();
+        self.subset_sps.clear();
+        self.sps_extensions.clear();
+        self.pps.clear();
+    }
+
+    pub fn sps(&self, id: u32) -> Option<&Sps> {
+        self.sps.get(&id)
+    }
+
+    pub fn subset_sps(&self, id: u32) -> Option<&Sps> {
+        self.subset_sps.get(&id)
+    }
+
+    pub fn sps_extension(&self, id: u32) -> Option<&SpsExtension> {
+        self.sps_extensions.get(&id)
+    }
+
+    pub fn pps(&self, id: u32) -> Option<&Pps> {
+        self.pps.get(&id)
+    }
+
+    /// Looks up the PPS a slice names along with the SPS it refers to, as activated in 7.4.1.2.1
+    pub fn activate(&self, pps_id: u32) -> Result<ActiveParams<'_>> {
+        let pps = self
+            .pps(pps_id)
+            .ok_or_else(|| anyhow!("Slice references missing PPS {}", pps_id))?;
+        let sps = self
+            .sps(pps.sps_id())
+            .ok_or_else(|| anyhow!("PPS {} references missing SPS {}", pps.id(), pps.sps_id()))?;
+        Ok(ActiveParams { sps, pps })
+    }
+
+    pub fn sps_count(&self) -> usize {
+        self.sps.len()
+    }
+
+    pub fn pps_count(&self) -> usize {
+        self.pps.len()
+    }
+}
+
+/// Parses every SPS and PPS of an Annex B byte stream, in stream order, and skips all other
+/// NALUs. A PPS with a scaling matrix needs its SPS earlier in the stream.
+pub fn collect_parameter_sets(data: &[u8]) -> Result<(Vec<Sps>, Vec<Pps>)> {
+    let mut store = ParameterSetStore::new();
+    let (mut sps_list, mut pps_list) = (Vec::new(), Vec::new());
+    for data in split_annexb_nalus(data) {
+        let kind = NaluHeader::new(data[0])?.kind();
+        if !matches!(kind, NalUnitType::Sps | NalUnitType::Pps) {
+            continue;
+        }
+        let rbsp = ebsp_to_rbsp_cow(&data[1..]);
+        let mut reader = BitReader::from_bytes(&rbsp);
+        if kind == NalUnitType::Sps {
+            let sps = Sps::parse(&mut reader)?;
+            store.insert_sps(sps.clone());
+            sps_list.push(sps);
+        } else {
+            pps_list.push(Pps::parse(&mut reader, &store)?);
+        }
+    }
+    Ok((sps_list, pps_list))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{baseline_pps_rbsp, baseline_sps_rbsp};
+    use bitstream::rbsp::rbsp_to_ebsp;
+
+    #[test]
+    fn test_insert_replaces_same_id() {
+        let mut store = ParameterSetStore::new();
+        store.insert_sps(Sps {
+            seq_parameter_set_id: 1,
+            level_idc: 30,
+            ..Default::default()
+        });
+        store.insert_sps(Sps {
+            seq_parameter_set_id: 1,
+            level_idc: 40,
+            ..Default::default()
+        });
+
+        assert_eq!(store.sps_count(), 1);
+        assert_eq!(store.sps(1).map(|s| s.level_idc), Some(40));
+        assert!(store.subset_sps(1).is_none());
+    }
+
+    #[test]
+    fn test_activate_resolves_pps_and_its_sps() {
+        let mut store = ParameterSetStore::new();
+        store.insert_sps(Sps {
+            seq_parameter_set_id: 3,
+            ..Default::default()
+        });
+        store.insert_pps(Pps {
+            pic_parameter_set_id: 7,
+            seq_parameter_set_id: 3,
+            ..Default::default()
+        });
+        store.insert_pps(Pps {
+            pic_parameter_set_id: 8,
+            seq_parameter_set_id: 4,
+            ..Default::default()
+        });
+
+        let active = store.activate(7).unwrap();
+        assert_eq!(active.pps.id(), 7);
+        assert_eq!(active.pps.sps_id(), 3);
+        assert_eq!(active.sps.id(), 3);
+
+        assert!(store.activate(8).is_err(), "SPS 4 was never received");
+        assert!(store.activate(9).is_err());
+    }
+
+    #[test]
+    fn test_remove_and_clear() {
+        let mut store = ParameterSetStore::new();
+        for id in 0..3 {
+            store.insert_sps(Sps {
+                seq_parameter_set_id: id,
+                ..Default::default()
+            });
+            store.insert_pps(Pps {
+                pic_parameter_set_id: id,
+                seq_parameter_set_id: id,
+                ..Default::default()
+            });
+        }
+        store.insert_subset_sps(Sps::default());
+
+        assert_eq!(store.remove_sps(1).map(|s| s.id()), Some(1));
+        assert!(store.remove_sps(1).is_none());
+        assert_eq!(store.sps_count(), 2);
+        assert!(store.sps(1).is_none());
+        // PPS 1 is still there, but its SPS isn't
+        assert!(store.pps(1).is_some());
+        assert!(store.activate(1).is_err());
+
+        assert_eq!(store.remove_pps(2).map(|p| p.id()), Some(2));
+        assert_eq!(store.pps_count(), 2);
+        assert!(store.activate(2).is_err());
+        assert!(store.activate(0).is_ok());
+
+        store.clear();
+        assert_eq!((store.sps_count(), store.pps_count()), (0, 0));
+        assert!(store.subset_sps(0).is_none());
+        assert!(store.activate(0).is_err());
+    }
+
+    #[test]
+    fn test_collect_parameter_sets() -> Result<()> {
+        let mut stream = Vec::new();
+        let mut push = |header: u8, rbsp: &[u8]| {
+            stream.extend([0, 0, 0, 1, header]);
+            stream.extend(rbsp_to_ebsp(rbsp));
+        };
+        push(0x09, &[0xf0]); // access unit delimiter
+        push(0x67, &baseline_sps_rbsp(0, 2, 2));
+        push(0x68, &baseline_pps_rbsp(0, 0));
+        push(0x68, &baseline_pps_rbsp(1, 0));
+        push(0x65, &[0x88, 0x80]); // an IDR slice, left unparsed
+        push(0x67, &baseline_sps_rbsp(1, 4, 3));
+        push(0x68, &baseline_pps_rbsp(2, 1));
+
+        let (sps, pps) = collect_parameter_sets(&stream)?;
+        assert_eq!(
+            sps.iter()
+                .map(|s| (s.id(), s.pic_width_in_mbs()))
+                .collect::<Vec<_>>(),
+            [(0, 2), (1, 4)]
+        );
+        assert_eq!(
+            pps.iter().map(|p| (p.id(), p.sps_id())).collect::<Vec<_>>(),
+            [(0, 0), (1, 0), (2, 1)]
+        );
+
+        assert_eq!(collect_parameter_sets(&[])?, (vec![], vec![]));
+        Ok(())
+    }
+}
@@ -1 +1,46 @@
+//! Helpers for building parameter sets in tests
 
+use bitstream::BitWriter;
+
+/// A Baseline profile, 4:2:0, progressive SPS RBSP with POC type 2 and no VUI
+pub(crate) fn baseline_sps_rbsp(sps_id: u32, width_in_mbs: u32, height_in_mbs: u32) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    writer.write(66, 8); // profile_idc
+    writer.write(0, 8); // constraint flags
+    writer.write(30, 8); // level_idc
+    writer.write_ue(sps_id);
+    writer.write_ue(0); // log2_max_frame_num_minus4
+    writer.write_ue(2); // pic_order_cnt_type
+    writer.write_ue(1); // max_num_ref_frames
+    writer.write_flag(false); // gaps_in_frame_num_value_allowed_flag
+    writer.write_ue(width_in_mbs - 1);
+    writer.write_ue(height_in_mbs - 1);
+    writer.write_flag(true); // frame_mbs_only_flag
+    writer.write_flag(true); // direct_8x8_inference_flag
+    writer.write_flag(false); // frame_cropping_flag
+    writer.write_flag(false); // vui_parameters_present_flag
+    writer.write_rbsp_trailing_bits();
+    writer.into_bytes()
+}
+
+/// A CAVLC PPS RBSP with a single slice group and every optional tool disabled
+pub(crate) fn baseline_pps_rbsp(pps_id: u32, sps_id: u32) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    writer.write_ue(pps_id);
+    writer.write_ue(sps_id);
+    writer.write_flag(false); // entropy_coding_mode_flag
+    writer.write_flag(false); // bottom_field_pic_order_in_frame_present_flag
+    writer.write_ue(0); // num_slice_groups_minus1
+    writer.write_ue(0); // num_ref_idx_l0_default_active_minus1
+    writer.write_ue(0); // num_ref_idx_l1_default_active_minus1
+    writer.write_flag(false); // weighted_pred_flag
+    writer.write(0, 2); // weighted_bipred_idc
+    writer.write_se(0); // pic_init_qp_minus26
+    writer.write_se(0); // pic_init_qs_minus26
+    writer.write_se(0); // chroma_qp_index_offset
+    writer.write_flag(true); // deblocking_filter_control_present_flag
+    writer.write_flag(false); // constrained_intra_pred_flag
+    writer.write_flag(false); // redundant_pic_cnt_present_flag
+    writer.write_rbsp_trailing_bits();
+    writer.into_bytes()
+}
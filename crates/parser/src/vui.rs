@@ -0,0 +1,557 @@
+use crate::read_ue_max;
+use anyhow::{Result, anyhow};
+use bitstream::{BitReader, BitWriter};
+
+/// `aspect_ratio_idc` value signalling an explicit `sar_width`/`sar_height` (Table E-1)
+pub const EXTENDED_SAR: u8 = 255;
+
+/// Sample aspect ratios of aspect_ratio_idc 1 to 16 (Table E-1)
+const SAMPLE_ASPECT_RATIOS: [(u16, u16); 16] = [
+    (1, 1),
+    (12, 11),
+    (10, 11),
+    (16, 11),
+    (40, 33),
+    (24, 11),
+    (20, 11),
+    (32, 11),
+    (80, 33),
+    (18, 11),
+    (15, 11),
+    (64, 33),
+    (160, 99),
+    (4, 3),
+    (3, 2),
+    (2, 1),
+];
+
+/// Implemented as in E.1.2 HRD parameters syntax
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct HrdParameters {
+    pub cpb_cnt_minus1: u32,
+    pub bit_rate_scale: u8,
+    pub cpb_size_scale: u8,
+    pub bit_rate_value_minus1: Vec<u32>,
+    pub cpb_size_value_minus1: Vec<u32>,
+    pub cbr_flag: Vec<bool>,
+    pub initial_cpb_removal_delay_length_minus1: u8,
+    pub cpb_removal_delay_length_minus1: u8,
+    pub dpb_output_delay_length_minus1: u8,
+    pub time_offset_length: u8,
+}
+
+impl HrdParameters {
+    pub fn parse(reader: &mut BitReader) -> Result<Self> {
+        let cpb_cnt_minus1 = read_ue_max(reader, 31, "cpb_cnt_minus1 in HRD parameters")?;
+        let bit_rate_scale = reader.read(4)? as u8;
+        let cpb_size_scale = reader.read(4)? as u8;
+
+        let mut bit_rate_value_minus1 = Vec::new();
+        let mut cpb_size_value_minus1 = Vec::new();
+        let mut cbr_flag = Vec::new();
+        for _ in 0..=cpb_cnt_minus1 {
+            bit_rate_value_minus1.push(reader.read_ue()?);
+            cpb_size_value_minus1.push(reader.read_ue()?);
+            cbr_flag.push(reader.read_flag()?);
+        }
+
+        Ok(Self {
+            cpb_cnt_minus1,
+            bit_rate_scale,
+            cpb_size_scale,
+            bit_rate_value_minus1,
+            cpb_size_value_minus1,
+            cbr_flag,
+            initial_cpb_removal_delay_length_minus1: reader.read(5)? as u8,
+            cpb_removal_delay_length_minus1: reader.read(5)? as u8,
+            dpb_output_delay_length_minus1: reader.read(5)? as u8,
+            time_offset_length: reader.read(5)? as u8,
+        })
+    }
+
+    /// Writes the fields as `parse` reads them
+    pub fn write(&self, writer: &mut BitWriter) {
+        writer.write_ue(self.cpb_cnt_minus1);
+        writer.write(self.bit_rate_scale as u32, 4);
+        writer.write(self.cpb_size_scale as u32, 4);
+        for ((&bit_rate, &cpb_size), &cbr) in self
+            .bit_rate_value_minus1
+            .iter()
+            .zip(&self.cpb_size_value_minus1)
+            .zip(&self.cbr_flag)
+        {
+            writer.write_ue(bit_rate);
+            writer.write_ue(cpb_size);
+            writer.write_flag(cbr);
+        }
+        writer.write(self.initial_cpb_removal_delay_length_minus1 as u32, 5);
+        writer.write(self.cpb_removal_delay_length_minus1 as u32, 5);
+        writer.write(self.dpb_output_delay_length_minus1 as u32, 5);
+        writer.write(self.time_offset_length as u32, 5);
+    }
+}
+
+/// Implemented as in E.1.1 VUI parameters syntax. Fields that aren't present keep their default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VuiParameters {
+    pub aspect_ratio_info_present_flag: bool,
+    pub aspect_ratio_idc: u8,
+    pub sar_width: u16,
+    pub sar_height: u16,
+    pub overscan_info_present_flag: bool,
+    pub overscan_appropriate_flag: bool,
+    pub video_signal_type_present_flag: bool,
+    pub video_format: u8,
+    pub video_full_range_flag: bool,
+    pub colour_description_present_flag: bool,
+    pub colour_primaries: u8,
+    pub transfer_characteristics: u8,
+    pub matrix_coefficients: u8,
+    pub chroma_loc_info_present_flag: bool,
+    pub chroma_sample_loc_type_top_field: u32,
+    pub chroma_sample_loc_type_bottom_field: u32,
+    pub timing_info_present_flag: bool,
+    pub num_units_in_tick: u32,
+    pub time_scale: u32,
+    pub fixed_frame_rate_flag: bool,
+    pub nal_hrd_parameters: Option<HrdParameters>,
+    pub vcl_hrd_parameters: Option<HrdParameters>,
+    pub low_delay_hrd_flag: bool,
+    pub pic_struct_present_flag: bool,
+    pub bitstream_restriction_flag: bool,
+    pub motion_vectors_over_pic_boundaries_flag: bool,
+    pub max_bytes_per_pic_denom: u32,
+    pub max_bits_per_mb_denom: u32,
+    pub log2_max_mv_length_horizontal: u32,
+    pub log2_max_mv_length_vertical: u32,
+    pub max_num_reorder_frames: u32,
+    pub max_dec_frame_buffering: u32,
+}
+
+/// Absent video_signal_type fields are inferred as unspecified (E.2.1): video_format 5, and
+/// colour_primaries, transfer_characteristics and matrix_coefficients 2
+impl Default for VuiParameters {
+    fn default() -> Self {
+        Self {
+            aspect_ratio_info_present_flag: false,
+            aspect_ratio_idc: 0,
+            sar_width: 0,
+            sar_height: 0,
+            overscan_info_present_flag: false,
+            overscan_appropriate_flag: false,
+            video_signal_type_present_flag: false,
+            video_format: 5,
+            video_full_range_flag: false,
+            colour_description_present_flag: false,
+            colour_primaries: 2,
+            transfer_characteristics: 2,
+            matrix_coefficients: 2,
+            chroma_loc_info_present_flag: false,
+            chroma_sample_loc_type_top_field: 0,
+            chroma_sample_loc_type_bottom_field: 0,
+            timing_info_present_flag: false,
+            num_units_in_tick: 0,
+            time_scale: 0,
+            fixed_frame_rate_flag: false,
+            nal_hrd_parameters: None,
+            vcl_hrd_parameters: None,
+            low_delay_hrd_flag: false,
+            pic_struct_present_flag: false,
+            bitstream_restriction_flag: false,
+            motion_vectors_over_pic_boundaries_flag: false,
+            max_bytes_per_pic_denom: 0,
+            max_bits_per_mb_denom: 0,
+            log2_max_mv_length_horizontal: 0,
+            log2_max_mv_length_vertical: 0,
+            max_num_reorder_frames: 0,
+            max_dec_frame_buffering: 0,
+        }
+    }
+}
+
+impl VuiParameters {
+    pub fn parse(reader: &mut BitReader) -> Result<Self> {
+        let mut vui = VuiParameters {
+            aspect_ratio_info_present_flag: reader.read_flag()?,
+            ..Default::default()
+        };
+
+        if vui.aspect_ratio_info_present_flag {
+            vui.aspect_ratio_idc = reader.read(8)? as u8;
+            if vui.aspect_ratio_idc == EXTENDED_SAR {
+                vui.sar_width = reader.read(16)? as u16;
+                vui.sar_height = reader.read(16)? as u16;
+            }
+        }
+
+        vui.overscan_info_present_flag = reader.read_flag()?;
+        if vui.overscan_info_present_flag {
+            vui.overscan_appropriate_flag = reader.read_flag()?;
+        }
+
+        vui.video_signal_type_present_flag = reader.read_flag()?;
+        if vui.video_signal_type_present_flag {
+            vui.video_format = reader.read(3)? as u8;
+            vui.video_full_range_flag = reader.read_flag()?;
+            vui.colour_description_present_flag = reader.read_flag()?;
+            if vui.colour_description_present_flag {
+                vui.colour_primaries = reader.read(8)? as u8;
+                vui.transfer_characteristics = reader.read(8)? as u8;
+                vui.matrix_coefficients = reader.read(8)? as u8;
+            }
+        }
+
+        vui.chroma_loc_info_present_flag = reader.read_flag()?;
+        if vui.chroma_loc_info_present_flag {
+            vui.chroma_sample_loc_type_top_field = reader.read_ue()?;
+            vui.chroma_sample_loc_type_bottom_field = reader.read_ue()?;
+        }
+
+        vui.timing_info_present_flag = reader.read_flag()?;
+        if vui.timing_info_present_flag {
+            vui.num_units_in_tick = reader.read(32)?;
+            vui.time_scale = reader.read(32)?;
+            vui.fixed_frame_rate_flag = reader.read_flag()?;
+        }
+
+        if reader.read_flag()? {
+            vui.nal_hrd_parameters = Some(HrdParameters::parse(reader)?);
+        }
+        if reader.read_flag()? {
+            vui.vcl_hrd_parameters = Some(HrdParameters::parse(reader)?);
+        }
+        if vui.nal_hrd_parameters.is_some() || vui.vcl_hrd_parameters.is_some() {
+            vui.low_delay_hrd_flag = reader.read_flag()?;
+        }
+
+        vui.pic_struct_present_flag = reader.read_flag()?;
+        vui.bitstream_restriction_flag = reader.read_flag()?;
+        if vui.bitstream_restriction_flag {
+            vui.motion_vectors_over_pic_boundaries_flag = reader.read_flag()?;
+            vui.max_bytes_per_pic_denom = reader.read_ue()?;
+            vui.max_bits_per_mb_denom = reader.read_ue()?;
+            vui.log2_max_mv_length_horizontal = reader.read_ue()?;
+            vui.log2_max_mv_length_vertical = reader.read_ue()?;
+            vui.max_num_reorder_frames = reader.read_ue()?;
+            vui.max_dec_frame_buffering = reader.read_ue()?;
+            if vui.max_bytes_per_pic_denom > 16 || vui.max_bits_per_mb_denom > 16 {
+                return Err(anyhow!(
+                    "Invalid max_bytes_per_pic_denom {} or max_bits_per_mb_denom {}",
+                    vui.max_bytes_per_pic_denom,
+                    vui.max_bits_per_mb_denom
+                ));
+            }
+            if vui.max_num_reorder_frames > vui.max_dec_frame_buffering {
+                return Err(anyhow!(
+                    "max_num_reorder_frames {} exceeds max_dec_frame_buffering {}",
+                    vui.max_num_reorder_frames,
+                    vui.max_dec_frame_buffering
+                ));
+            }
+        }
+
+        Ok(vui)
+    }
+
+    /// The sample aspect ratio as (horizontal, vertical), or None when it's unspecified. That
+    /// includes reserved aspect_ratio_idc values and an Extended_SAR with a zero dimension, which
+    /// E.2.1 treats as unspecified.
+    pub fn sample_aspect_ratio(&self) -> Option<(u16, u16)> {
+        if !self.aspect_ratio_info_present_flag {
+            return None;
+        }
+        match self.aspect_ratio_idc {
+            EXTENDED_SAR if self.sar_width == 0 || self.sar_height == 0 => None,
+            EXTENDED_SAR => Some((self.sar_width, self.sar_height)),
+            idc @ 1..=16 => Some(SAMPLE_ASPECT_RATIOS[idc as usize - 1]),
+            _ => None,
+        }
+    }
+
+    /// Writes the fields as `parse` reads them. Fields behind a flag that's not set are skipped.
+    pub fn write(&self, writer: &mut BitWriter) {
+        writer.write_flag(self.aspect_ratio_info_present_flag);
+        if self.aspect_ratio_info_present_flag {
+            writer.write(self.aspect_ratio_idc as u32, 8);
+            if self.aspect_ratio_idc == EXTENDED_SAR {
+                writer.write(self.sar_width as u32, 16);
+                writer.write(self.sar_height as u32, 16);
+            }
+        }
+
+        writer.write_flag(self.overscan_info_present_flag);
+        if self.overscan_info_present_flag {
+            writer.write_flag(self.overscan_appropriate_flag);
+        }
+
+        writer.write_flag(self.video_signal_type_present_flag);
+        if self.video_signal_type_present_flag {
+            writer.write(self.video_format as u32, 3);
+            writer.write_flag(self.video_full_range_flag);
+            writer.write_flag(self.colour_description_present_flag);
+            if self.colour_description_present_flag {
+                writer.write(self.colour_primaries as u32, 8);
+                writer.write(self.transfer_characteristics as u32, 8);
+                writer.write(self.matrix_coefficients as u32, 8);
+            }
+        }
+
+        writer.write_flag(self.chroma_loc_info_present_flag);
+        if self.chroma_loc_info_present_flag {
+            writer.write_ue(self.chroma_sample_loc_type_top_field);
+            writer.write_ue(self.chroma_sample_loc_type_bottom_field);
+        }
+
+        writer.write_flag(self.timing_info_present_flag);
+        if self.timing_info_present_flag {
+            writer.write(self.num_units_in_tick, 32);
+            writer.write(self.time_scale, 32);
+            writer.write_flag(self.fixed_frame_rate_flag);
+        }
+
+        for hrd in [&self.nal_hrd_parameters, &self.vcl_hrd_parameters] {
+            writer.write_flag(hrd.is_some());
+            if let Some(hrd) = hrd {
+                hrd.write(writer);
+            }
+        }
+        if self.nal_hrd_parameters.is_some() || self.vcl_hrd_parameters.is_some() {
+            writer.write_flag(self.low_delay_hrd_flag);
+        }
+
+        writer.write_flag(self.pic_struct_present_flag);
+        writer.write_flag(self.bitstream_restriction_flag);
+        if self.bitstream_restriction_flag {
+            writer.write_flag(self.motion_vectors_over_pic_boundaries_flag);
+            writer.write_ue(self.max_bytes_per_pic_denom);
+            writer.write_ue(self.max_bits_per_mb_denom);
+            writer.write_ue(self.log2_max_mv_length_horizontal);
+            writer.write_ue(self.log2_max_mv_length_vertical);
+            writer.write_ue(self.max_num_reorder_frames);
+            writer.write_ue(self.max_dec_frame_buffering);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minimal_vui() -> Result<()> {
+        let mut writer = BitWriter::new();
+        for _ in 0..9 {
+            writer.write_flag(false);
+        }
+        let bytes = writer.into_bytes();
+
+        let vui = VuiParameters::parse(&mut BitReader::from_bytes(&bytes))?;
+        assert_eq!(vui, VuiParameters::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample_aspect_ratio() {
+        let vui = |aspect_ratio_idc, sar_width, sar_height| VuiParameters {
+            aspect_ratio_info_present_flag: true,
+            aspect_ratio_idc,
+            sar_width,
+            sar_height,
+            ..Default::default()
+        };
+        assert_eq!(vui(1, 0, 0).sample_aspect_ratio(), Some((1, 1)));
+        assert_eq!(vui(13, 0, 0).sample_aspect_ratio(), Some((160, 99)));
+        assert_eq!(
+            vui(EXTENDED_SAR, 64, 45).sample_aspect_ratio(),
+            Some((64, 45))
+        );
+
+        // Unspecified, reserved, or an Extended_SAR without both dimensions
+        assert_eq!(vui(0, 0, 0).sample_aspect_ratio(), None);
+        assert_eq!(vui(17, 0, 0).sample_aspect_ratio(), None);
+        assert_eq!(vui(EXTENDED_SAR, 0, 9).sample_aspect_ratio(), None);
+        assert_eq!(vui(EXTENDED_SAR, 16, 0).sample_aspect_ratio(), None);
+        assert_eq!(VuiParameters::default().sample_aspect_ratio(), None);
+    }
+
+    #[test]
+    fn test_vui_timing_and_extended_sar() -> Result<()> {
+        let mut writer = BitWriter::new();
+        writer.write_flag(true); // aspect_ratio_info_present_flag
+        writer.write(EXTENDED_SAR as u32, 8);
+        writer.write(4, 16);
+        writer.write(3, 16);
+        writer.write_flag(false); // overscan_info_present_flag
+        writer.write_flag(true); // video_signal_type_present_flag
+        writer.write(5, 3);
+        writer.write_flag(true); // video_full_range_flag
+        writer.write_flag(false); // colour_description_present_flag
+        writer.write_flag(false); // chroma_loc_info_present_flag
+        writer.write_flag(true); // timing_info_present_flag
+        writer.write(1001, 32);
+        writer.write(60000, 32);
+        writer.write_flag(true);
+        writer.write_flag(false); // nal_hrd_parameters_present_flag
+        writer.write_flag(false); // vcl_hrd_parameters_present_flag
+        writer.write_flag(true); // pic_struct_present_flag
+        writer.write_flag(false); // bitstream_restriction_flag
+        let bytes = writer.into_bytes();
+
+        let vui = VuiParameters::parse(&mut BitReader::from_bytes(&bytes))?;
+        assert_eq!((vui.sar_width, vui.sar_height), (4, 3));
+        assert!(vui.video_full_range_flag);
+        assert_eq!(vui.num_units_in_tick, 1001);
+        assert_eq!(vui.time_scale, 60000);
+        assert!(vui.fixed_frame_rate_flag);
+        assert!(vui.pic_struct_present_flag);
+        Ok(())
+    }
+
+    #[test]
+    fn test_colour_description() -> Result<()> {
+        let vui_rbsp = |colour_description: Option<[u32; 3]>| {
+            let mut writer = BitWriter::new();
+            writer.write_flag(false); // aspect_ratio_info_present_flag
+            writer.write_flag(false); // overscan_info_present_flag
+            writer.write_flag(true); // video_signal_type_present_flag
+            writer.write(2, 3); // video_format, PAL
+            writer.write_flag(false); // video_full_range_flag
+            writer.write_flag(colour_description.is_some());
+            for value in colour_description.into_iter().flatten() {
+                writer.write(value, 8);
+            }
+            for _ in 0..6 {
+                writer.write_flag(false);
+            }
+            writer.into_bytes()
+        };
+
+        // BT.709 primaries, transfer characteristics and matrix
+        let vui = VuiParameters::parse(&mut BitReader::from_bytes(&vui_rbsp(Some([1, 1, 1]))))?;
+        assert_eq!(
+            (
+                vui.colour_primaries,
+                vui.transfer_characteristics,
+                vui.matrix_coefficients
+            ),
+            (1, 1, 1)
+        );
+
+        // Without the description, all three are unspecified
+        let vui = VuiParameters::parse(&mut BitReader::from_bytes(&vui_rbsp(None)))?;
+        assert_eq!(vui.video_format, 2);
+        assert_eq!(
+            (
+                vui.colour_primaries,
+                vui.transfer_characteristics,
+                vui.matrix_coefficients
+            ),
+            (2, 2, 2)
+        );
+        // Just like without video_signal_type, which leaves video_format unspecified too
+        let vui = VuiParameters::default();
+        assert_eq!((vui.video_format, vui.matrix_coefficients), (5, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_vui_hrd_parameters() -> Result<()> {
+        let mut writer = BitWriter::new();
+        for _ in 0..5 {
+            writer.write_flag(false);
+        }
+        writer.write_flag(true); // nal_hrd_parameters_present_flag
+        writer.write_ue(0); // cpb_cnt_minus1
+        writer.write(4, 4);
+        writer.write(6, 4);
+        writer.write_ue(1000);
+        writer.write_ue(2000);
+        writer.write_flag(true);
+        writer.write(23, 5);
+        writer.write(23, 5);
+        writer.write(23, 5);
+        writer.write(24, 5);
+        writer.write_flag(false); // vcl_hrd_parameters_present_flag
+        writer.write_flag(true); // low_delay_hrd_flag
+        writer.write_flag(false);
+        writer.write_flag(true); // bitstream_restriction_flag
+        writer.write_flag(true);
+        writer.write_ue(2);
+        writer.write_ue(1);
+        writer.write_ue(16);
+        writer.write_ue(16);
+        writer.write_ue(2);
+        writer.write_ue(4);
+        let bytes = writer.into_bytes();
+
+        let vui = VuiParameters::parse(&mut BitReader::from_bytes(&bytes))?;
+        let hrd = vui.nal_hrd_parameters.expect("NAL HRD should be present");
+        assert_eq!(hrd.bit_rate_value_minus1, &[1000]);
+        assert_eq!(hrd.cpb_removal_delay_length_minus1, 23);
+        assert_eq!(hrd.time_offset_length, 24);
+        assert!(vui.low_delay_hrd_flag);
+        assert_eq!(vui.max_num_reorder_frames, 2);
+        assert_eq!(vui.max_dec_frame_buffering, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hrd_cpb_cnt_out_of_range() {
+        let mut writer = BitWriter::new();
+        writer.write_ue(32); // cpb_cnt_minus1
+        writer.write(4, 4);
+        writer.write(6, 4);
+        for _ in 0..33 {
+            writer.write_ue(0);
+            writer.write_ue(0);
+            writer.write_flag(false);
+        }
+        writer.write(0, 20);
+        let err =
+            HrdParameters::parse(&mut BitReader::from_bytes(&writer.into_bytes())).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid cpb_cnt_minus1 in HRD parameters: 32, expected at most 31"
+        );
+    }
+
+    #[test]
+    fn test_vui_bitstream_restriction() -> Result<()> {
+        let write = |num_reorder_frames, max_dec_frame_buffering| {
+            let mut writer = BitWriter::new();
+            for _ in 0..8 {
+                writer.write_flag(false);
+            }
+            writer.write_flag(true); // bitstream_restriction_flag
+            writer.write_flag(true); // motion_vectors_over_pic_boundaries_flag
+            writer.write_ue(2); // max_bytes_per_pic_denom
+            writer.write_ue(1); // max_bits_per_mb_denom
+            writer.write_ue(15); // log2_max_mv_length_horizontal
+            writer.write_ue(13); // log2_max_mv_length_vertical
+            writer.write_ue(num_reorder_frames);
+            writer.write_ue(max_dec_frame_buffering);
+            writer.into_bytes()
+        };
+
+        let vui = VuiParameters::parse(&mut BitReader::from_bytes(&write(2, 3)))?;
+        assert!(vui.motion_vectors_over_pic_boundaries_flag);
+        assert_eq!(
+            (vui.max_bytes_per_pic_denom, vui.max_bits_per_mb_denom),
+            (2, 1)
+        );
+        assert_eq!(
+            (
+                vui.log2_max_mv_length_horizontal,
+                vui.log2_max_mv_length_vertical
+            ),
+            (15, 13)
+        );
+        assert_eq!(
+            (vui.max_num_reorder_frames, vui.max_dec_frame_buffering),
+            (2, 3)
+        );
+
+        assert!(VuiParameters::parse(&mut BitReader::from_bytes(&write(3, 2))).is_err());
+        Ok(())
+    }
+}